@@ -0,0 +1,54 @@
+// How long claiming a device takes, with adp's own self-test workers claiming fake devices from a pool in a temp dir.
+// Each worker reports the time it spent claiming, so starting the processes isn't counted.
+
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+fn run_workers(dir: &Path, workers: usize, devices: usize, rounds: u64) -> Duration {
+    let children: Vec<_> = (0..workers)
+        .map(|_| {
+            Command::new(env!("CARGO_BIN_EXE_adp"))
+                .arg("self-test-worker")
+                .arg(dir)
+                .args(["--devices", &devices.to_string(), "--rounds", &rounds.to_string()])
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    let total: u64 = children.into_iter()
+        .map(|child| {
+            let output = child.wait_with_output().unwrap();
+            assert!(output.status.success(), "worker failed: {}", output.status);
+            let output = String::from_utf8(output.stdout).unwrap();
+            output.lines().last().unwrap().parse::<u64>().unwrap()
+        })
+        .sum();
+    Duration::from_nanos(total / workers as u64)
+}
+
+fn acquire(c: &mut Criterion) {
+    let mut group = c.benchmark_group("acquire");
+    // The common case, a free device and nobody else after one.
+    group.bench_function("uncontended", |b| {
+        let dir = tempdir();
+        b.iter_custom(|rounds| run_workers(&dir, 1, 4, rounds));
+    });
+    group.bench_function("contended", |b| {
+        let dir = tempdir();
+        b.iter_custom(|rounds| run_workers(&dir, 4, 2, rounds));
+    });
+    group.finish();
+}
+
+fn tempdir() -> temp_testdir::TempDir {
+    let dir = temp_testdir::TempDir::default();
+    std::fs::create_dir_all(dir.join("held")).unwrap();
+    dir
+}
+
+criterion_group!(benches, acquire);
+criterion_main!(benches);
@@ -1,4 +1,4 @@
-use std::io::BufRead;
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
@@ -6,39 +6,200 @@ use crate::exitstatus::ExitStatusExt;
 
 pub type Result<T> = std::result::Result<T, anyhow::Error>;
 
+// A line of `adb devices -l`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdbDevice {
+    pub serial: String,
+    // ex: device, offline, unauthorized
+    pub state: String,
+    // ex: model, product, transport_id
+    pub attributes: BTreeMap<String, String>,
+    // The ADB_SERVER_SOCKET of the adb server it's on, None for the default one.
+    pub server: Option<String>,
+}
+
+// Which device a command is for. Going by transport id, `adb -t`, still works when devices share a serial, which
+// `adb -s` refuses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target<'a> {
+    Serial(&'a str),
+    TransportId(&'a str),
+}
+
+impl Target<'_> {
+    fn args(&self) -> [&str; 2] {
+        match self {
+            Target::Serial(serial) => ["-s", serial],
+            Target::TransportId(id) => ["-t", id],
+        }
+    }
+}
+
+impl<'a, S: AsRef<str> + ?Sized> From<&'a S> for Target<'a> {
+    fn from(serial: &'a S) -> Target<'a> {
+        Target::Serial(serial.as_ref())
+    }
+}
+
+// A line of `adb mdns services`, how devices with wireless debugging on advertise themselves.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MdnsService {
+    pub name: String,
+    // ex: _adb-tls-connect._tcp or _adb-tls-pairing._tcp
+    pub service: String,
+    // ex: 192.168.1.20:37123
+    pub addr: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Battery {
+    // Percent.
+    pub level: Option<u8>,
+    pub charging: bool,
+    // Degrees celsius.
+    pub temperature: Option<f32>,
+}
+
 #[derive(Debug)]
 pub struct Adb {
     path: PathBuf,
+    // ex: tcp:localhost:5038, the default server if None
+    server_socket: Option<String>,
 }
 
 impl Adb {
     pub fn new(path: impl AsRef<Path>) -> Adb {
-        return Adb {
-            path: path.as_ref().to_path_buf()
-        };
+        Adb {
+            path: path.as_ref().to_path_buf(),
+            server_socket: None,
+        }
+    }
+
+    pub fn with_server(path: impl AsRef<Path>, server_socket: &str) -> Adb {
+        Adb {
+            path: path.as_ref().to_path_buf(),
+            server_socket: Some(server_socket.to_string()),
+        }
+    }
+
+    pub fn server_socket(&self) -> Option<&str> {
+        self.server_socket.as_deref()
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.path);
+        if let Some(server_socket) = &self.server_socket {
+            command.env("ADB_SERVER_SOCKET", server_socket);
+        }
+        command
+    }
+
+    pub fn shell<'a>(&self, device: impl Into<Target<'a>>, args: &[&str]) -> Result<String> {
+        let output = self.command()
+            .args(device.into().args())
+            .arg("shell")
+            .args(args)
+            .stdout(Stdio::piped())
+            .spawn()?
+            .wait_with_output()?;
+        output.status.exit_ok_()?;
+
+        Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+    }
+
+    pub fn run<'a>(&self, device: impl Into<Target<'a>>, args: &[&str]) -> Result<String> {
+        let output = self.command()
+            .args(device.into().args())
+            .args(args)
+            .stdout(Stdio::piped())
+            .spawn()?
+            .wait_with_output()?;
+        output.status.exit_ok_()?;
+
+        Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+    }
+
+    // As a png.
+    pub fn screencap<'a>(&self, device: impl Into<Target<'a>>) -> Result<Vec<u8>> {
+        // exec-out rather than shell so the image isn't mangled by line ending conversion.
+        let output = self.command()
+            .args(device.into().args())
+            .args(["exec-out", "screencap", "-p"])
+            .stdout(Stdio::piped())
+            .spawn()?
+            .wait_with_output()?;
+        output.status.exit_ok_()?;
+
+        Ok(output.stdout)
+    }
+
+    pub fn shell_getprop<'a>(&self, device: impl Into<Target<'a>>, name: &str) -> Result<String> {
+        let output = self.command()
+            .args(device.into().args())
+            .args(["shell", "getprop", name])
+            .stdout(Stdio::piped())
+            .spawn()?
+            .wait_with_output()?;
+        output.status.exit_ok_()?;
+
+        Ok(String::from_utf8(output.stdout)?.trim().to_owned())
     }
 
-    pub fn wait_for_device(&self) -> Result<()> {
-        Command::new(&self.path)
-            .arg("wait-for-device")
+    pub fn emu_kill<'a>(&self, device: impl Into<Target<'a>>) -> Result<()> {
+        self.command()
+            .args(device.into().args())
+            .args(["emu", "kill"])
+            .stdout(Stdio::null())
             .status()?
             .exit_ok_()?;
         Ok(())
     }
 
-    pub fn shell_getprop(&self, serial: &str, name: &str) -> Result<String> {
-        let output = Command::new(&self.path)
-            .args(&["-s", serial, "shell", "getprop", name])
+    // Returns whether adb was able to connect to the device.
+    pub fn connect(&self, addr: &str) -> Result<bool> {
+        let output = self.command()
+            .args(["connect", addr])
+            .stdout(Stdio::piped())
+            .spawn()?
+            .wait_with_output()?;
+        let stdout = String::from_utf8(output.stdout)?;
+        Ok(output.status.success() && stdout.contains("connected to"))
+    }
+
+    // Returns whether pairing with the device for wireless debugging worked.
+    pub fn pair(&self, addr: &str, code: &str) -> Result<bool> {
+        let output = self.command()
+            .args(["pair", addr, code])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()?
+            .wait_with_output()?;
+        let stdout = String::from_utf8(output.stdout)?;
+        Ok(output.status.success() && stdout.contains("Successfully paired"))
+    }
+
+    pub fn mdns_services(&self) -> Result<Vec<MdnsService>> {
+        let output = self.command()
+            .args(["mdns", "services"])
             .stdout(Stdio::piped())
             .spawn()?
             .wait_with_output()?;
         output.status.exit_ok_()?;
 
-        Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+        Ok(parse_mdns_services(&String::from_utf8(output.stdout)?))
     }
 
-    pub fn devices(&self) -> Result<Vec<String>> {
-        let output = Command::new(&self.path)
+    pub fn disconnect(&self, addr: &str) -> Result<()> {
+        self.command()
+            .args(["disconnect", addr])
+            .stdout(Stdio::null())
+            .status()?
+            .exit_ok_()?;
+        Ok(())
+    }
+
+    pub fn devices_long(&self) -> Result<Vec<AdbDevice>> {
+        let output = self.command()
             .arg("devices")
             .arg("-l")
             .stdout(Stdio::piped())
@@ -46,12 +207,124 @@ impl Adb {
             .wait_with_output()?;
         output.status.exit_ok_()?;
 
-        let devices: Vec<_> = output.stdout.lines().skip(1)
-            .map(|line| line.unwrap())
-            .filter(|line| line.len() > 0)
-            .map(|line| line.split_ascii_whitespace().next().unwrap().to_owned())
-            .collect();
+        Ok(parse_devices(&String::from_utf8(output.stdout)?))
+    }
 
-        Ok(devices)
+    pub fn battery<'a>(&self, device: impl Into<Target<'a>>) -> Result<Battery> {
+        Ok(parse_battery(&self.shell(device, &["dumpsys", "battery"])?))
     }
-}
\ No newline at end of file
+}
+
+fn parse_devices(output: &str) -> Vec<AdbDevice> {
+    output.lines().skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_ascii_whitespace();
+            let serial = fields.next()?.to_owned();
+            let state = fields.next().unwrap_or_default().to_owned();
+            let attributes = fields
+                .filter_map(|field| field.split_once(':'))
+                .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                .collect();
+            Some(AdbDevice { serial, state, attributes, server: None })
+        })
+        .collect()
+}
+
+fn parse_mdns_services(output: &str) -> Vec<MdnsService> {
+    output.lines().skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_ascii_whitespace();
+            Some(MdnsService {
+                name: fields.next()?.to_owned(),
+                service: fields.next()?.trim_end_matches('.').to_owned(),
+                addr: fields.next()?.to_owned(),
+            })
+        })
+        .collect()
+}
+
+// Parses the output of `dumpsys battery`, ex:
+//   Current Battery Service state:
+//     AC powered: false
+//     USB powered: true
+//     level: 85
+//     temperature: 310
+fn parse_battery(output: &str) -> Battery {
+    let mut battery = Battery::default();
+    for line in output.lines() {
+        let Some((key, value)) = line.trim().split_once(':') else { continue };
+        let value = value.trim();
+        match key {
+            "level" => battery.level = value.parse().ok(),
+            // Reported in tenths of a degree.
+            "temperature" => battery.temperature = value.parse::<f32>().ok().map(|tenths| tenths / 10.0),
+            "AC powered" | "USB powered" | "Wireless powered" | "Dock powered" if value == "true" => battery.charging = true,
+            _ => {}
+        }
+    }
+    battery
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::adb::{AdbDevice, Battery, MdnsService, parse_battery, parse_devices, parse_mdns_services, Target};
+
+    #[test]
+    fn parses_long_device_list() {
+        let output = "List of devices attached\n\
+            emulator-5554          device product:sdk_gphone64_x86_64 model:sdk_gphone64_x86_64 transport_id:1\n\
+            R58M123ABC             unauthorized usb:1-1 transport_id:2\n\
+            \n";
+
+        assert_eq!(parse_devices(output), vec![
+            AdbDevice {
+                serial: "emulator-5554".to_string(),
+                state: "device".to_string(),
+                attributes: BTreeMap::from([
+                    ("product".to_string(), "sdk_gphone64_x86_64".to_string()),
+                    ("model".to_string(), "sdk_gphone64_x86_64".to_string()),
+                    ("transport_id".to_string(), "1".to_string()),
+                ]),
+                server: None,
+            },
+            AdbDevice {
+                serial: "R58M123ABC".to_string(),
+                state: "unauthorized".to_string(),
+                attributes: BTreeMap::from([
+                    ("usb".to_string(), "1-1".to_string()),
+                    ("transport_id".to_string(), "2".to_string()),
+                ]),
+                server: None,
+            },
+        ]);
+    }
+
+    #[test]
+    fn parses_mdns_services() {
+        let output = "List of discovered mdns services\n\
+            adb-R58M123ABC-AbCdEf\t_adb-tls-connect._tcp.\t192.168.1.20:37123\n\
+            adb-R58M123ABC-AbCdEf\t_adb-tls-pairing._tcp.\t192.168.1.20:41234\n";
+
+        assert_eq!(parse_mdns_services(output)[0], MdnsService {
+            name: "adb-R58M123ABC-AbCdEf".to_string(),
+            service: "_adb-tls-connect._tcp".to_string(),
+            addr: "192.168.1.20:37123".to_string(),
+        });
+        assert_eq!(parse_mdns_services(output)[1].service, "_adb-tls-pairing._tcp");
+    }
+
+    #[test]
+    fn parses_battery() {
+        let output = "Current Battery Service state:\n  AC powered: false\n  USB powered: true\n  status: 2\n  level: 85\n  scale: 100\n  temperature: 310\n";
+
+        assert_eq!(parse_battery(output), Battery { level: Some(85), charging: true, temperature: Some(31.0) });
+    }
+
+    #[test]
+    fn addresses_devices_by_serial_or_transport_id() {
+        assert_eq!(Target::from("R58M123ABC").args(), ["-s", "R58M123ABC"]);
+        assert_eq!(Target::TransportId("7").args(), ["-t", "7"]);
+    }
+}
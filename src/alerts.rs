@@ -0,0 +1,126 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::SystemTime;
+
+use sysinfo::{System, SystemExt};
+use tracing::debug;
+
+use crate::PoolState;
+use crate::config::AlertsConfig;
+use crate::duration::HumanDuration;
+use crate::notify;
+use crate::runtime::Serial;
+use crate::status::format_age;
+use crate::waiters::WaiterId;
+
+// Lets the bench owner know about problems with the pool from the daemon, before developers run into them. Each
+// problem is only alerted on once, when it starts.
+#[derive(Debug)]
+pub struct Alerts<'a> {
+    config: &'a AlertsConfig,
+    // Which bench the alert came from.
+    host: String,
+    // None until the first check, so whatever state the pool is already in when the daemon starts isn't alerted on.
+    online: Option<BTreeSet<Serial>>,
+    quarantined: BTreeSet<Serial>,
+    // The waiters file doesn't say when a process started waiting, so it's from when the daemon first saw it.
+    waiting_since: BTreeMap<WaiterId, SystemTime>,
+    alerted_waiters: BTreeSet<WaiterId>,
+}
+
+impl<'a> Alerts<'a> {
+    pub fn new(config: &'a AlertsConfig) -> Alerts<'a> {
+        Alerts {
+            config,
+            host: System::new().host_name().unwrap_or_default(),
+            online: None,
+            quarantined: BTreeSet::new(),
+            waiting_since: BTreeMap::new(),
+            alerted_waiters: BTreeSet::new(),
+        }
+    }
+
+    // What's gone wrong since the last check. Instances the daemon started itself come and go on purpose, so they're
+    // left out of `online`.
+    pub fn check(&mut self, state: &PoolState, online: BTreeSet<Serial>, now: SystemTime) -> Vec<String> {
+        let mut alerts = Vec::new();
+
+        let quarantined: BTreeSet<Serial> = state.entries.iter()
+            .filter(|(_, entry)| entry.quarantined)
+            .map(|(serial, _)| serial.clone())
+            .collect();
+        if let Some(previous) = &self.online {
+            for serial in previous.difference(&online) {
+                alerts.push(format!("{} dropped offline", serial));
+            }
+            for serial in quarantined.difference(&self.quarantined) {
+                alerts.push(format!("{} was quarantined after jobs kept failing on it", serial));
+            }
+        }
+        self.online = Some(online);
+        self.quarantined = quarantined;
+
+        self.waiting_since.retain(|id, _| state.waiters.contains(id));
+        self.alerted_waiters.retain(|id| state.waiters.contains(id));
+        for id in state.waiters.iter() {
+            let since = *self.waiting_since.entry(id.clone()).or_insert(now);
+            let Some(threshold) = self.config.wait else { continue };
+            let waited = now.duration_since(since).unwrap_or_default();
+            if waited >= threshold.0 && self.alerted_waiters.insert(id.clone()) {
+                alerts.push(format!(
+                    "{} has been waiting {} for a device, longer than {}",
+                    id, format_age(now, since), HumanDuration(threshold.0),
+                ));
+            }
+        }
+        debug!(alerts = ?alerts);
+        alerts
+    }
+
+    pub fn send(&self, alerts: &[String]) {
+        for alert in alerts {
+            eprintln!("alert: {}", alert);
+            notify::send_all(&self.config.notify, &format!("adp on {}: {}", self.host, alert));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::PoolState;
+    use crate::alerts::Alerts;
+    use crate::config::Config;
+    use crate::lockfile::{Entry, LockFileEntries};
+    use crate::runtime::Serial;
+    use crate::waiters::{WaiterId, Waiters};
+
+    type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+    fn serials(serials: &[&str]) -> BTreeSet<Serial> {
+        serials.iter().map(|serial| Serial::new(*serial).unwrap()).collect()
+    }
+
+    #[test]
+    fn alerts_once_on_each_new_problem() -> Result {
+        let config = Config::parse("[alerts]\nnotify = [\"https://hooks.slack.com/services/x\"]\nwait = \"10m\"\n")?;
+        let mut alerts = Alerts::new(config.alerts.as_ref().unwrap());
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let mut state = PoolState { entries: LockFileEntries::default(), waiters: Waiters::default() };
+        state.waiters.insert(WaiterId::local(7), at(0));
+
+        assert!(alerts.check(&state, serials(&["serial1", "serial2"]), at(0)).is_empty());
+
+        state.entries.insert(Serial::new("serial2")?, Entry { quarantined: true, ..Entry::default() });
+        assert_eq!(alerts.check(&state, serials(&["serial2"]), at(10 * 60)), vec![
+            "serial1 dropped offline",
+            "serial2 was quarantined after jobs kept failing on it",
+            "7 has been waiting 10m for a device, longer than 10m",
+        ]);
+
+        assert!(alerts.check(&state, serials(&["serial2"]), at(20 * 60)).is_empty());
+
+        Ok(())
+    }
+}
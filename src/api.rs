@@ -0,0 +1,240 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+use tracing::{debug, instrument};
+
+use crate::{App, Resource};
+use crate::config::{ApiConfig, LeaseConfig};
+use crate::duration::HumanDuration;
+use crate::events::EventLog;
+use crate::list_devices;
+use crate::lockfile::Owner;
+use crate::runtime::{Pid, Runtime};
+use crate::status::describe;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// Served at /, it asks for the token and reads everything from /pool.
+const DASHBOARD: &str = include_str!("dashboard.html");
+// Finished leases sent for the dashboard's history.
+const RECENT: usize = 500;
+// Who leases made with the admin token belong to when they don't say, its quota is set like any other user's.
+const ADMIN_USER: &str = "api";
+
+// A device claimed over the api. The daemon holds the claim on the client's behalf until it's deleted.
+#[derive(Debug)]
+struct Lease<'a, R: Runtime + Debug> {
+    resource: Resource<'a, R>,
+    since: SystemTime,
+}
+
+// Body of POST /leases, all of it optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LeaseRequest {
+    user: String,
+    cmd: String,
+}
+
+// HTTP api served by the daemon so tooling that isn't written in rust can use the pool without running adp:
+//   GET /devices, the same as `adp list-devices --json`
+//   GET /pool, what the dashboard shows: devices with their current job, waiters and recent leases
+//   POST /leases, claims a free device, 503 if there isn't one
+//   DELETE /leases/{id}, puts the device back
+// Every request needs an `Authorization: Bearer <token>` header, apart from the dashboard's page itself. Tokens
+// that map to a user lease devices as that user, counting against their quota.
+pub struct Api<'a, R: Runtime + Debug> {
+    server: Server,
+    token: Option<String>,
+    tokens: BTreeMap<String, String>,
+    events: &'a EventLog,
+    leases: BTreeMap<String, Lease<'a, R>>,
+}
+
+impl<'a, R: Runtime + Debug> Api<'a, R> {
+    pub fn new(config: &ApiConfig, events: &'a EventLog) -> Result<Api<'a, R>> {
+        if config.token.is_none() && config.tokens.is_empty() {
+            return Err(anyhow!("the api needs a token or tokens to be configured"));
+        }
+        let server = Server::http(&config.listen)
+            .map_err(|e| anyhow!("failed to listen on {}: {}", config.listen, e))?;
+        Ok(Api {
+            server,
+            token: config.token.clone(),
+            tokens: config.tokens.clone(),
+            events,
+            leases: BTreeMap::new(),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.server.server_addr().to_ip().unwrap()
+    }
+
+    // Answers requests for the given time, in place of the daemon sleeping between passes over the pool. Requests are
+    // answered one at a time, so none of them wait on the pool: leasing only takes a device that's free right now.
+    pub fn serve(&mut self, app: &'a App<'a, R>, duration: Duration) -> Result {
+        let deadline = Instant::now() + duration;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(());
+            }
+            if let Some(request) = self.server.recv_timeout(left)? {
+                self.handle(app, request);
+            }
+        }
+    }
+
+    // Takes back devices from leases that have run past the lease limit, there's no job to kill.
+    pub fn expire(&mut self, config: &LeaseConfig, now: SystemTime) {
+        self.leases.retain(|id, lease| {
+            let Some(limit) = config.limit(&lease.resource.serial) else { return true };
+            let held = now.duration_since(lease.since).unwrap_or_default();
+            if held <= limit {
+                return true;
+            }
+            eprintln!("api lease {} has held {} for longer than {}, releasing it", id, lease.resource.serial, HumanDuration(limit));
+            false
+        });
+    }
+
+    #[instrument(skip(self, app, request), fields(method = %request.method(), url = request.url()))]
+    fn handle(&mut self, app: &'a App<'a, R>, mut request: Request) {
+        if *request.method() == Method::Get && request.url() == "/" {
+            let response = Response::from_string(DASHBOARD)
+                .with_header(Header::from_bytes("Content-Type", "text/html; charset=utf-8").unwrap());
+            if let Err(e) = request.respond(response) {
+                debug!(error = %e);
+            }
+            return;
+        }
+        let authorization = request.headers().iter()
+            .find(|header| header.field.equiv("Authorization"))
+            .map(|header| header.value.to_string());
+        let mut body = String::new();
+        let (status, value) = match request.as_reader().read_to_string(&mut body) {
+            Ok(_) => self.route(app, request.method(), request.url(), authorization.as_deref(), &body),
+            Err(e) => (400, json!({ "error": format!("failed to read the body: {}", e) })),
+        };
+        debug!(status, response = %value);
+        let response = Response::from_string(value.to_string())
+            .with_status_code(status)
+            .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+        if let Err(e) = request.respond(response) {
+            debug!(error = %e);
+        }
+    }
+
+    fn route(
+        &mut self,
+        app: &'a App<'a, R>,
+        method: &Method,
+        url: &str,
+        authorization: Option<&str>,
+        body: &str,
+    ) -> (u16, Value) {
+        // None for the token that can act as anyone.
+        let user = match authorization.and_then(|value| value.strip_prefix("Bearer ")) {
+            Some(token) if self.token.as_deref() == Some(token) => None,
+            Some(token) if self.tokens.contains_key(token) => Some(self.tokens[token].clone()),
+            _ => return (401, json!({ "error": "missing or invalid token" })),
+        };
+        let path = url.split('?').next().unwrap_or_default();
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let result = match (method, segments.as_slice()) {
+            (Method::Get, ["devices"]) => list_devices::rows(app).and_then(|rows| Ok((200, serde_json::to_value(rows)?))),
+            (Method::Get, ["pool"]) => self.pool(app).map(|pool| (200, pool)),
+            (Method::Post, ["leases"]) => self.create_lease(app, user, body),
+            (Method::Delete, ["leases", id]) => self.delete_lease(id),
+            (_, ["devices"] | ["pool"] | ["leases"] | ["leases", _]) => Ok((405, json!({ "error": "method not allowed" }))),
+            _ => Ok((404, json!({ "error": "not found" }))),
+        };
+        result.unwrap_or_else(|e| (500, json!({ "error": format!("{:#}", e) })))
+    }
+
+    // Times are in seconds since the epoch, along with the daemon's idea of now so ages don't depend on the browser's
+    // clock.
+    fn pool(&self, app: &'a App<'a, R>) -> Result<Value> {
+        let secs = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let state = app.snapshot()?;
+        let rows = list_devices::rows(app)?;
+        let devices: Vec<Value> = state.entries.iter()
+            .map(|(serial, entry)| {
+                let row = rows.iter().find(|row| &row.serial == serial);
+                let owner = entry.owner.clone().unwrap_or_default();
+                let since = if entry.pid.is_some() { entry.claimed_at } else { entry.released_at };
+                json!({
+                    "serial": serial,
+                    "state": describe(entry),
+                    "since": since.map(secs),
+                    "pid": entry.pid,
+                    "user": owner.user,
+                    "cmd": owner.cmd,
+                    "model": row.and_then(|row| row.model.clone()),
+                    "api_level": row.and_then(|row| row.api_level),
+                    "battery": row.and_then(|row| row.battery),
+                })
+            })
+            .collect();
+        let waiting: Vec<Value> = state.waiters.iter()
+            .map(|id| json!({ "pid": id.pid, "instance": id.instance, "wants": state.waiters.wants(id) }))
+            .collect();
+        let leases = self.events.leases()?;
+        let recent = &leases[leases.len().saturating_sub(RECENT)..];
+        Ok(json!({ "now": secs(app.now()), "devices": devices, "waiting": waiting, "recent": recent }))
+    }
+
+    fn create_lease(&mut self, app: &'a App<'a, R>, user: Option<String>, body: &str) -> Result<(u16, Value)> {
+        let mut request: LeaseRequest = if body.trim().is_empty() {
+            LeaseRequest::default()
+        } else {
+            match serde_json::from_str(body) {
+                Ok(request) => request,
+                Err(e) => return Ok((400, json!({ "error": format!("invalid body: {}", e) }))),
+            }
+        };
+        if let Some(user) = user {
+            if !request.user.is_empty() && request.user != user {
+                return Ok((403, json!({ "error": format!("this token can only lease devices as {}", user) })));
+            }
+            request.user = user;
+        }
+        if request.user.is_empty() {
+            request.user = ADMIN_USER.to_string();
+        }
+        let owner = Owner { user: request.user, cmd: request.cmd, ..Owner::current(&[]) };
+        if let Some(quota) = app.quota(&owner.user) {
+            if app.entries()?.claimed_by(&owner.user) >= quota {
+                return Ok((429, json!({ "error": format!("{} already holds {} devices, the most they're allowed", owner.user, quota) })));
+            }
+        }
+        // The daemon is the one holding the claim, so it's its pid that keeps it alive.
+        let Some(resource) = app.try_acquire_resource_for(std::process::id() as Pid, &owner)? else {
+            return Ok((503, json!({ "error": "no device is free" })));
+        };
+        let id = format!("{:016x}", app.random());
+        let value = json!({
+            "id": id,
+            "serial": resource.serial,
+            "adb_server_socket": app.server_socket(&resource.serial),
+        });
+        self.leases.insert(id, Lease { resource, since: app.now() });
+        Ok((201, value))
+    }
+
+    fn delete_lease(&mut self, id: &str) -> Result<(u16, Value)> {
+        let Some(lease) = self.leases.remove(id) else {
+            return Ok((404, json!({ "error": format!("no lease {}", id) })));
+        };
+        let serial = lease.resource.serial.clone();
+        lease.resource.release()?;
+        Ok((200, json!({ "serial": serial })))
+    }
+}
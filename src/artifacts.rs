@@ -0,0 +1,34 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use tracing::instrument;
+
+use crate::runtime::{Runtime, Serial};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// Saves a bugreport of the device to dir, before it goes back in the pool and the next job wipes out what happened.
+#[instrument(skip(runtime))]
+pub fn bugreport(runtime: &impl Runtime, serial: &Serial, dir: &Path, at: SystemTime) -> Result<PathBuf> {
+    let path = path(dir, serial, at, "zip")?;
+    runtime.run_adb(serial, &["bugreport", &path.to_string_lossy()])
+        .with_context(|| format!("failed to take a bugreport of {}", serial))?;
+    Ok(path)
+}
+
+// Quicker than a bugreport, it's taken first so it shows the screen as the job left it.
+#[instrument(skip(runtime))]
+pub fn screenshot(runtime: &impl Runtime, serial: &Serial, dir: &Path, at: SystemTime) -> Result<PathBuf> {
+    let path = path(dir, serial, at, "png")?;
+    let png = runtime.screencap(serial).with_context(|| format!("failed to take a screenshot of {}", serial))?;
+    std::fs::write(&path, png).with_context(|| format!("failed to write {:?}", path))?;
+    Ok(path)
+}
+
+// ex: <dir>/emulator-5554-1700000000.zip
+fn path(dir: &Path, serial: &Serial, at: SystemTime, extension: &str) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir).with_context(|| format!("failed to create {:?}", dir))?;
+    let secs = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    Ok(dir.join(format!("{}-{}.{}", serial, secs, extension)))
+}
@@ -0,0 +1,197 @@
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, instrument};
+
+use crate::filelock::FileLockGuardExt;
+use crate::lockfile::Owner;
+use crate::observer::Observer;
+use crate::runtime::{Pid, Serial};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// What the first record's prev is.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+// Plenty for a record, they don't hold anything unbounded like the command itself.
+const TAIL: u64 = 4096;
+
+// Something that happened to a device, as kept in the audit log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    // Counts up from 0, so a record taken out of the middle shows.
+    pub seq: u64,
+    // seconds since the epoch
+    pub at: u64,
+    // acquire, release or reclaim
+    pub event: String,
+    pub serial: Serial,
+    pub pid: Option<Pid>,
+    pub user: Option<String>,
+    pub host: Option<String>,
+    // The command is hashed rather than kept, it can have secrets in it.
+    pub cmd_sha256: Option<String>,
+    // hash of the record before this one
+    pub prev: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Line {
+    #[serde(flatten)]
+    record: AuditRecord,
+    // sha256 of the record as JSON, which includes prev so each record vouches for all the ones before it.
+    hash: String,
+}
+
+// Append only log of who had which device when, each record chained to the one before by its hash so changing or
+// removing one breaks the chain from there on.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: impl AsRef<Path>) -> AuditLog {
+        AuditLog { path: path.as_ref().to_path_buf() }
+    }
+
+    #[instrument]
+    pub fn append(&self, event: &str, serial: &Serial, pid: Option<Pid>, owner: Option<&Owner>, at: SystemTime) -> Result {
+        let file = OpenOptions::new().read(true).append(true).create(true).open(&self.path)?;
+        let mut file = file.into_lock_exclusive()?;
+        let (seq, prev) = match last_line(&mut *file)? {
+            Some(line) => {
+                let line: Line = serde_json::from_str(&line)
+                    .map_err(|e| anyhow!("can't append to {:?}, its last record is unreadable: {}", self.path, e))?;
+                (line.record.seq + 1, line.hash)
+            }
+            None => (0, GENESIS.to_string()),
+        };
+        let record = AuditRecord {
+            seq,
+            at: at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            event: event.to_string(),
+            serial: serial.clone(),
+            pid,
+            user: owner.map(|owner| owner.user.clone()),
+            host: owner.map(|owner| owner.host.clone()),
+            cmd_sha256: owner.map(|owner| sha256(&owner.cmd)),
+            prev,
+        };
+        let hash = hash(&record)?;
+        debug!(record = ?record, hash = %hash);
+        writeln!(&*file, "{}", serde_json::to_string(&Line { record, hash })?)?;
+        Ok(())
+    }
+
+    // Checks every record is intact and chained to the one before, returns how many there are and the hash of the
+    // last one. Records cut off the end can only be caught by comparing that hash with one noted down earlier.
+    #[instrument]
+    pub fn verify(&self) -> Result<(u64, String)> {
+        let file = OpenOptions::new().read(true).open(&self.path)?;
+        let mut prev = GENESIS.to_string();
+        let mut count = 0;
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let number = i + 1;
+            let line: Line = serde_json::from_str(&line?).map_err(|e| anyhow!("line {} is unreadable: {}", number, e))?;
+            if line.record.seq != count {
+                return Err(anyhow!("line {} is record {}, expected {}, records are missing", number, line.record.seq, count));
+            }
+            if line.record.prev != prev {
+                return Err(anyhow!("line {} doesn't follow on from the record before it", number));
+            }
+            if hash(&line.record)? != line.hash {
+                return Err(anyhow!("line {} has been changed, its hash doesn't match", number));
+            }
+            prev = line.hash;
+            count += 1;
+        }
+        Ok((count, prev))
+    }
+}
+
+fn sha256(value: &str) -> String {
+    Sha256::digest(value.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hash(record: &AuditRecord) -> Result<String> {
+    Ok(sha256(&serde_json::to_string(record)?))
+}
+
+// Only the end of the file is read, it's only ever appended to so it can get big.
+fn last_line(file: &mut (impl Read + Seek)) -> Result<Option<String>> {
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(len.saturating_sub(TAIL)))?;
+    let mut tail = String::new();
+    file.read_to_string(&mut tail)?;
+    Ok(tail.lines().rev().find(|line| !line.is_empty()).map(String::from))
+}
+
+// Records every claim and release to the audit log.
+#[derive(Debug)]
+pub struct AuditObserver(pub AuditLog);
+
+impl AuditObserver {
+    fn append(&self, event: &str, serial: &Serial, pid: Option<Pid>, owner: Option<&Owner>, now: SystemTime) {
+        if let Err(e) = self.0.append(event, serial, pid, owner, now) {
+            eprintln!("adp: failed to write to the audit log: {:#}", e);
+        }
+    }
+}
+
+impl Observer for AuditObserver {
+    fn on_acquire(&self, serial: &Serial, pid: Pid, owner: Option<&Owner>, now: SystemTime) {
+        self.append("acquire", serial, Some(pid), owner, now);
+    }
+
+    fn on_release(&self, serial: &Serial, now: SystemTime) {
+        self.append("release", serial, None, None, now);
+    }
+
+    fn on_reclaim(&self, serial: &Serial, pid: Pid, now: SystemTime) {
+        self.append("reclaim", serial, Some(pid), None, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use temp_testdir::TempDir;
+
+    use crate::audit::AuditLog;
+    use crate::lockfile::Owner;
+    use crate::runtime::Serial;
+
+    type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+    #[test]
+    fn verifies_the_chain() -> Result {
+        let dir = TempDir::default();
+        let path = dir.join("audit.log");
+        let log = AuditLog::new(&path);
+        let serial = Serial::new("emulator-5554")?;
+        let owner = Owner { user: "evan".to_string(), host: "bench".to_string(), cmd: "./gradlew".to_string() };
+        for secs in 0..3 {
+            log.append("acquire", &serial, Some(1), Some(&owner), UNIX_EPOCH + Duration::from_secs(secs))?;
+        }
+
+        let (count, last) = log.verify()?;
+        assert_eq!(count, 3);
+        assert_eq!(last.len(), 64);
+
+        let contents = std::fs::read_to_string(&path)?;
+        std::fs::write(&path, contents.replacen("\"user\":\"evan\"", "\"user\":\"sam\"", 1))?;
+        assert!(log.verify().unwrap_err().to_string().contains("line 1 has been changed"));
+
+        let lines: Vec<&str> = contents.lines().collect();
+        std::fs::write(&path, format!("{}\n{}\n", lines[0], lines[2]))?;
+        assert!(log.verify().unwrap_err().to_string().contains("records are missing"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,198 @@
+use std::time::Instant;
+
+use sysinfo::{RefreshKind, System, SystemExt};
+use tracing::{debug, instrument};
+
+use crate::config::{AutoscaleConfig, ResourcesConfig};
+use crate::PoolState;
+use crate::provider::{self, Instance, Provider};
+use crate::resources::{Budget, Cost};
+use crate::runtime::Serial;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+#[derive(Debug, PartialEq)]
+enum Scale {
+    Up,
+    Down,
+    Hold,
+}
+
+fn decide(waiting: usize, free: usize, pending: usize, running: usize, max: usize) -> Scale {
+    if waiting > free + pending && running < max {
+        Scale::Up
+    } else if waiting == 0 {
+        Scale::Down
+    } else {
+        Scale::Hold
+    }
+}
+
+#[derive(Debug)]
+struct Managed {
+    instance: Instance,
+    idle_since: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct Autoscaler<'a> {
+    config: &'a AutoscaleConfig,
+    provider: Box<dyn Provider>,
+    sys: System,
+    budget: Budget,
+    cost: Cost,
+    instances: Vec<Managed>,
+}
+
+impl<'a> Autoscaler<'a> {
+    pub fn new(config: &'a AutoscaleConfig, resources: &ResourcesConfig) -> Result<Autoscaler<'a>> {
+        let provider = provider::from_config(config)?;
+        let sys = System::new_with_specifics(RefreshKind::new().with_memory().with_cpu());
+        let budget = Budget::new(resources, &sys);
+        let default_cost = provider.cost();
+        let cost = Cost {
+            cpus: config.instance_cpus.unwrap_or(default_cost.cpus),
+            memory_mb: config.instance_memory_mb.map_or(default_cost.memory_mb, |memory| memory.0),
+        };
+        debug!(budget = ?budget, cost = ?cost);
+        Ok(Autoscaler { config, provider, sys, budget, cost, instances: Vec::new() })
+    }
+
+    // Ephemeral instances that have shown up in the pool but haven't been marked as single use yet.
+    pub fn unmarked(&self, state: &PoolState) -> Vec<Serial> {
+        if !self.config.ephemeral {
+            return Vec::new();
+        }
+        self.instances.iter()
+            .map(|managed| &managed.instance.serial)
+            .filter(|serial| matches!(state.entries.get(serial), Some(entry) if !entry.single_use))
+            .cloned()
+            .collect()
+    }
+
+    // Instances this has started and may shut down again.
+    pub fn serials(&self) -> impl Iterator<Item=&Serial> {
+        self.instances.iter().map(|managed| &managed.instance.serial)
+    }
+
+    #[instrument(skip(self, state))]
+    pub fn tick(&mut self, state: &PoolState) -> Result {
+        self.reap();
+
+        // Ephemeral instances are done as soon as they've been used.
+        while let Some(index) = self.instances.iter()
+            .position(|managed| matches!(state.entries.get(&managed.instance.serial), Some(entry) if entry.spent)) {
+            self.shutdown(index);
+        }
+
+        let now = Instant::now();
+        for managed in &mut self.instances {
+            if state.entries.is_available(&managed.instance.serial) {
+                managed.idle_since.get_or_insert(now);
+            } else {
+                managed.idle_since = None;
+            }
+        }
+
+        let waiting = state.waiters.demand();
+        let free = state.entries.count_available();
+        // Instances that haven't shown up in adb yet will be able to take a waiter soon.
+        let pending = self.instances.iter()
+            .filter(|managed| !state.entries.contains(&managed.instance.serial))
+            .count();
+
+        // New instances would only sit there while the pool is paused, and idle ones are wanted once it's resumed.
+        let scale = match state.entries.paused() {
+            Some(_) => Scale::Hold,
+            None => decide(waiting, free, pending, self.instances.len(), self.config.max),
+        };
+        debug!(waiting, free, pending, running = self.instances.len(), scale = ?scale);
+        match scale {
+            Scale::Up => {
+                match self.has_capacity() {
+                    Ok(_) => self.launch(state)?,
+                    Err(reason) => eprintln!("not starting another instance ({}): {}", self.cost, reason),
+                }
+            }
+            Scale::Down => {
+                let scale_down_after = self.config.scale_down_after.0;
+                let idle = self.instances.iter()
+                    .position(|managed| matches!(managed.idle_since, Some(since) if now - since >= scale_down_after));
+                if let Some(index) = idle {
+                    self.shutdown(index);
+                }
+            }
+            Scale::Hold => {}
+        }
+        Ok(())
+    }
+
+    fn has_capacity(&mut self) -> std::result::Result<(), String> {
+        self.budget.check(self.instances.len(), self.cost)?;
+        // Other things running on the host may be using memory outside of our budget.
+        self.sys.refresh_memory();
+        let available_mb = self.sys.available_memory() / 1024;
+        debug!(available_mb);
+        if available_mb < self.cost.memory_mb {
+            return Err(format!("only {}MB of memory is available", available_mb));
+        }
+        Ok(())
+    }
+
+    fn launch(&mut self, state: &PoolState) -> Result {
+        let instances = &self.instances;
+        let in_use = |serial: &_| {
+            state.entries.contains(serial) || instances.iter().any(|managed| &managed.instance.serial == serial)
+        };
+        let instance = self.provider.create(&in_use)?;
+        eprintln!("started {} ({})", instance.serial, self.provider.name());
+        self.instances.push(Managed { instance, idle_since: None });
+        Ok(())
+    }
+
+    fn shutdown(&mut self, index: usize) {
+        let managed = self.instances.remove(index);
+        let serial = managed.instance.serial.clone();
+        match self.provider.destroy(managed.instance) {
+            Ok(_) => eprintln!("stopped {}", serial),
+            Err(e) => eprintln!("failed to stop {}: {:#}", serial, e),
+        }
+    }
+
+    // Forget about any instances that have exited on their own.
+    fn reap(&mut self) {
+        let provider = &mut self.provider;
+        self.instances.retain_mut(|managed| {
+            let running = provider.is_running(&mut managed.instance);
+            if !running {
+                eprintln!("{} exited", managed.instance.serial);
+            }
+            running
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::autoscale::{decide, Scale};
+
+    #[test]
+    fn scales_up_when_waiters_exceed_free_devices() {
+        assert_eq!(decide(2, 1, 0, 0, 2), Scale::Up);
+    }
+
+    #[test]
+    fn holds_when_pending_instances_cover_waiters() {
+        assert_eq!(decide(2, 1, 1, 1, 2), Scale::Hold);
+    }
+
+    #[test]
+    fn holds_at_max() {
+        assert_eq!(decide(3, 0, 0, 2, 2), Scale::Hold);
+    }
+
+    #[test]
+    fn scales_down_when_queue_drains() {
+        assert_eq!(decide(0, 2, 0, 2, 2), Scale::Down);
+    }
+}
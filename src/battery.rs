@@ -0,0 +1,49 @@
+use crate::adb::Battery;
+use crate::config::BatteryConfig;
+
+impl BatteryConfig {
+    // Whether a device should be kept out of the pool so it doesn't die part way through a job.
+    pub fn is_low(&self, battery: &Battery) -> bool {
+        !battery.charging && battery.level.is_some_and(|level| level < self.min)
+    }
+
+    // Whether a device that was kept out of the pool can go back in.
+    pub fn has_recharged(&self, battery: &Battery) -> bool {
+        match self.resume {
+            Some(resume) => battery.level.is_some_and(|level| level >= resume),
+            None => !self.is_low(battery),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::adb::Battery;
+    use crate::config::Config;
+
+    type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+    #[test]
+    fn low_only_when_not_charging() -> Result {
+        let config = Config::parse("[battery]\nmin = 20\n")?.battery.unwrap();
+
+        assert!(config.is_low(&Battery { level: Some(15), charging: false, temperature: None }));
+        assert!(!config.is_low(&Battery { level: Some(15), charging: true, temperature: None }));
+        assert!(!config.is_low(&Battery { level: Some(25), charging: false, temperature: None }));
+        assert!(!config.is_low(&Battery { level: None, charging: false, temperature: None }));
+        Ok(())
+    }
+
+    #[test]
+    fn waits_to_recharge_when_configured() -> Result {
+        let config = Config::parse("[battery]\nmin = 20\nresume = 80\n")?.battery.unwrap();
+
+        assert!(!config.has_recharged(&Battery { level: Some(50), charging: true, temperature: None }));
+        assert!(config.has_recharged(&Battery { level: Some(80), charging: true, temperature: None }));
+
+        let config = Config::parse("[battery]\nmin = 20\n")?.battery.unwrap();
+
+        assert!(config.has_recharged(&Battery { level: Some(15), charging: true, temperature: None }));
+        Ok(())
+    }
+}
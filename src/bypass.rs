@@ -0,0 +1,89 @@
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::process::Command;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail};
+use tracing::{info, instrument};
+
+use crate::{App, lease_record};
+use crate::audit::AuditLog;
+use crate::config::Config;
+use crate::events::{EventLog, LeaseRecord};
+use crate::exitstatus::ExitStatusExt;
+use crate::lockfile::Owner;
+use crate::runtime::{Pid, Runtime, Serial};
+use crate::status::{format_age, print_table};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// Runs a command on a device without claiming it, for when something has to be done on one right away, ex: getting a
+// device out of a state only a person can fix while a job is stuck on it. It's recorded in the event log, and the
+// audit log if there is one, so it's clear afterwards who went around the pool rather than them using adb directly.
+#[instrument(skip(app, events, config))]
+pub fn run<R: Runtime + Debug>(
+    app: &App<R>,
+    events: &EventLog,
+    config: &Config,
+    serial: Option<Serial>,
+    args: Vec<OsString>,
+) -> Result {
+    let owner = Owner::current(&args);
+    let (cmd, args) = args.split_first().ok_or(anyhow!("missing command"))?;
+    let serial = match serial.or_else(|| std::env::var("ANDROID_SERIAL").ok().and_then(|serial| Serial::new(serial).ok())) {
+        Some(serial) => serial,
+        None => match app.connected_devices()?.as_slice() {
+            [serial] => serial.clone(),
+            [] => bail!("no devices are connected"),
+            _ => bail!("more than one device is connected, pick one with --serial"),
+        },
+    };
+    if let Some(pid) = app.entries()?.get(&serial).and_then(|entry| entry.pid) {
+        eprintln!("adp: {} is claimed by pid {}, running on it anyway", serial, pid);
+    }
+    eprintln!("adp: bypassing the pool on {}, it's recorded in the event log", serial);
+    if let Some(audit) = &config.audit {
+        AuditLog::new(&audit.path).append("bypass", &serial, Some(std::process::id() as Pid), Some(&owner), app.now())?;
+    }
+
+    let mut command = Command::new(cmd);
+    command.args(args).env("ANDROID_SERIAL", app.adb_serial(&serial));
+    if let Some(server_socket) = app.server_socket(&serial) {
+        command.env("ADB_SERVER_SOCKET", server_socket);
+    }
+    info!(bypass = %serial, cmd = ?command);
+    let started_at = app.now();
+    // Recorded before it runs, so there's a record of it even if adp is killed first, and filled in once it's done.
+    let started = LeaseRecord { bypassed: true, ..lease_record(app, &serial, &owner, None, started_at, None) };
+    events.append(&started)?;
+    let result = command.status();
+    let exit_code = result.as_ref().ok().and_then(|status| status.code());
+    let record = LeaseRecord { bypassed: true, ..lease_record(app, &serial, &owner, None, started_at, exit_code) };
+    if let Err(e) = events.replace(&started, &record) {
+        eprintln!("adp: failed to record how the bypass went in the event log: {:#}", e);
+    }
+    result?.exit_ok_()?;
+    Ok(())
+}
+
+// Every bypass still in the event log, oldest first.
+#[instrument(skip(app, events))]
+pub fn list<R: Runtime + Debug>(app: &App<R>, events: &EventLog) -> Result {
+    let bypasses = events.bypasses()?;
+    if bypasses.is_empty() {
+        println!("nobody has bypassed the pool");
+        return Ok(());
+    }
+    let mut rows = vec![["WHEN", "USER", "HOST", "SERIAL", "COMMAND"].map(str::to_string).to_vec()];
+    for bypass in bypasses {
+        rows.push(vec![
+            format!("{} ago", format_age(app.now(), UNIX_EPOCH + Duration::from_secs(bypass.started_at))),
+            bypass.user,
+            bypass.host,
+            bypass.serial.to_string(),
+            bypass.cmd,
+        ]);
+    }
+    print_table(&rows);
+    Ok(())
+}
@@ -0,0 +1,283 @@
+use std::cell::Cell;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tracing::instrument;
+
+use crate::lockfile::Owner;
+use crate::observer::Observer;
+use crate::runtime::{Pid, Serial};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// The CI runner adp is under, for it to mark up its output the way the runner's UI understands.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ci {
+    // with the file step outputs go to, GITHUB_OUTPUT
+    GitHub { output: Option<PathBuf> },
+    GitLab,
+}
+
+// The parts of a job adp folds away in the runner's log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Section {
+    Acquire,
+    Boot,
+    Job,
+}
+
+impl Section {
+    fn id(self) -> &'static str {
+        match self {
+            Section::Acquire => "adp_acquire",
+            Section::Boot => "adp_boot",
+            Section::Job => "adp_job",
+        }
+    }
+}
+
+impl Ci {
+    // Only under CI, a developer running a workflow's script by hand doesn't want the markers.
+    pub fn detect(var: impl Fn(&str) -> Option<String>) -> Option<Ci> {
+        let set = |name: &str| var(name).is_some_and(|value| !value.is_empty() && value != "false");
+        if !set("CI") {
+            return None;
+        }
+        if set("GITHUB_ACTIONS") {
+            return Some(Ci::GitHub { output: var("GITHUB_OUTPUT").filter(|output| !output.is_empty()).map(PathBuf::from) });
+        }
+        if set("GITLAB_CI") {
+            return Some(Ci::GitLab);
+        }
+        None
+    }
+
+    pub fn start(&self, section: Section, title: &str, now: SystemTime) -> String {
+        match self {
+            Ci::GitHub { .. } => format!("::group::{}", title),
+            // The job's own output is what people open the log for, so that's left expanded.
+            Ci::GitLab => format!(
+                "\x1b[0Ksection_start:{}:{}[collapsed={}]\r\x1b[0K{}",
+                secs(now), section.id(), section != Section::Job, title,
+            ),
+        }
+    }
+
+    pub fn end(&self, section: Section, now: SystemTime) -> String {
+        match self {
+            Ci::GitHub { .. } => "::endgroup::".to_string(),
+            Ci::GitLab => format!("\x1b[0Ksection_end:{}:{}\r\x1b[0K", secs(now), section.id()),
+        }
+    }
+
+    // Shown on the run's summary, GitLab has nothing like it so the log has to do.
+    pub fn error(&self, message: &str) -> Option<String> {
+        match self {
+            Ci::GitHub { .. } => Some(format!("::error title=adp::{}", escape(message))),
+            Ci::GitLab => None,
+        }
+    }
+
+    // For later steps to use, ex: ${{ steps.test.outputs.serial }}. GitLab can only pass them on through a dotenv
+    // report the job has to name itself, the lease file has them there.
+    #[instrument]
+    pub fn set_outputs(&self, outputs: &[(&str, &str)]) -> Result {
+        if let Ci::GitHub { output: Some(path) } = self {
+            write_outputs(path, outputs)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_outputs(path: &Path, outputs: &[(&str, &str)]) -> Result {
+    let mut file = File::options().create(true).append(true).open(path)?;
+    for (name, value) in outputs {
+        writeln!(file, "{}={}", name, value)?;
+    }
+    Ok(())
+}
+
+// Workflow commands end at the line, so the message has to be kept to one.
+fn escape(message: &str) -> String {
+    message.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+// Writes the markers to stderr, for them to stay out of what the job's output is piped into. GitHub can't nest groups,
+// so only one section is open at a time, starting one ends the one before.
+#[derive(Debug)]
+pub struct CiLog {
+    pub ci: Ci,
+    open: Cell<Option<Section>>,
+}
+
+impl CiLog {
+    pub fn new(ci: Ci) -> CiLog {
+        CiLog { ci, open: Cell::new(None) }
+    }
+
+    pub fn start(&self, section: Section, title: &str, now: SystemTime) {
+        match self.open.get() {
+            Some(open) if open == section => return,
+            Some(open) => eprintln!("{}", self.ci.end(open, now)),
+            None => {}
+        }
+        eprintln!("{}", self.ci.start(section, title, now));
+        self.open.set(Some(section));
+    }
+
+    // Only if it's the one open, it may have been ended already by starting another.
+    pub fn end(&self, section: Section, now: SystemTime) {
+        if self.open.get() == Some(section) {
+            eprintln!("{}", self.ci.end(section, now));
+            self.open.set(None);
+        }
+    }
+
+    // Ends whatever section is open, for the error to be seen outside of it.
+    pub fn fail(&self, message: &str, now: SystemTime) {
+        if let Some(open) = self.open.take() {
+            eprintln!("{}", self.ci.end(open, now));
+        }
+        if let Some(annotation) = self.ci.error(message) {
+            eprintln!("{}", annotation);
+        }
+    }
+
+    #[cfg(test)]
+    fn open(&self) -> Option<Section> {
+        self.open.get()
+    }
+}
+
+// Folds the device's boot into a section of its own, the time it takes is worth seeing apart from the wait for one.
+#[derive(Debug)]
+pub struct CiObserver(pub Rc<CiLog>);
+
+impl Observer for CiObserver {
+    fn on_acquire(&self, serial: &Serial, _pid: Pid, _owner: Option<&Owner>, now: SystemTime) {
+        self.0.start(Section::Boot, &format!("adp: waiting for {} to boot", serial), now);
+    }
+
+    fn on_boot_wait(&self, _serial: &Serial, _waited: Duration, now: SystemTime) {
+        self.0.end(Section::Boot, now);
+    }
+
+    // It didn't boot, or was put back for a low battery, either way it's back to waiting for a device. Once the job
+    // started, putting the device back isn't.
+    fn on_release(&self, _serial: &Serial, now: SystemTime) {
+        if self.0.open.get() == Some(Section::Boot) {
+            self.0.start(Section::Acquire, "adp: waiting for a device", now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use temp_testdir::TempDir;
+
+    use crate::ci::{Ci, CiLog, CiObserver, Section};
+    use crate::observer::Observer;
+    use crate::runtime::Serial;
+
+    type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+    fn detect(vars: &[(&str, &str)]) -> Option<Ci> {
+        let vars: HashMap<String, String> = vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        Ci::detect(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn detects_the_runner() {
+        assert_eq!(detect(&[("CI", "true"), ("GITHUB_ACTIONS", "true"), ("GITHUB_OUTPUT", "/tmp/out")]),
+            Some(Ci::GitHub { output: Some("/tmp/out".into()) }));
+        assert_eq!(detect(&[("CI", "true"), ("GITLAB_CI", "true")]), Some(Ci::GitLab));
+        assert_eq!(detect(&[("GITHUB_ACTIONS", "true")]), None);
+        assert_eq!(detect(&[("CI", "false"), ("GITLAB_CI", "true")]), None);
+        assert_eq!(detect(&[("CI", "true"), ("JENKINS_URL", "http://jenkins")]), None);
+    }
+
+    #[test]
+    fn marks_sections_the_runners_way() {
+        let now = UNIX_EPOCH + Duration::from_secs(1700000000);
+        let github = Ci::GitHub { output: None };
+
+        assert_eq!(github.start(Section::Acquire, "adp: waiting for a device", now), "::group::adp: waiting for a device");
+        assert_eq!(github.end(Section::Acquire, now), "::endgroup::");
+        assert_eq!(
+            Ci::GitLab.start(Section::Boot, "adp: waiting for emulator-5554 to boot", now),
+            "\x1b[0Ksection_start:1700000000:adp_boot[collapsed=true]\r\x1b[0Kadp: waiting for emulator-5554 to boot",
+        );
+        assert_eq!(Ci::GitLab.start(Section::Job, "adp: ./gradlew", now), "\x1b[0Ksection_start:1700000000:adp_job[collapsed=false]\r\x1b[0Kadp: ./gradlew");
+        assert_eq!(Ci::GitLab.end(Section::Job, now), "\x1b[0Ksection_end:1700000000:adp_job\r\x1b[0K");
+    }
+
+    #[test]
+    fn keeps_annotations_to_one_line() {
+        assert_eq!(
+            Ci::GitHub { output: None }.error("the job failed on emulator-5554\n100% sure"),
+            Some("::error title=adp::the job failed on emulator-5554%0A100%25 sure".to_string()),
+        );
+        assert_eq!(Ci::GitLab.error("the job failed"), None);
+    }
+
+    #[test]
+    fn appends_step_outputs() -> Result {
+        let dir = TempDir::default();
+        let output = dir.join("github_output");
+        std::fs::write(&output, "earlier=1\n")?;
+
+        Ci::GitHub { output: Some(output.clone()) }.set_outputs(&[("serial", "emulator-5554"), ("lease_id", "4242")])?;
+
+        assert_eq!(std::fs::read_to_string(&output)?, "earlier=1\nserial=emulator-5554\nlease_id=4242\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_one_section_open_at_a_time() {
+        let log = CiLog::new(Ci::GitHub { output: None });
+
+        log.start(Section::Acquire, "adp: waiting for a device", UNIX_EPOCH);
+        log.start(Section::Boot, "adp: waiting for emulator-5554 to boot", UNIX_EPOCH);
+        assert_eq!(log.open(), Some(Section::Boot));
+
+        log.end(Section::Acquire, UNIX_EPOCH);
+        assert_eq!(log.open(), Some(Section::Boot));
+
+        log.start(Section::Job, "adp: ./gradlew on emulator-5554", UNIX_EPOCH);
+        assert_eq!(log.open(), Some(Section::Job));
+
+        log.fail("failed to set up emulator-5554", UNIX_EPOCH);
+        assert_eq!(log.open(), None);
+    }
+
+    #[test]
+    fn waits_for_another_device_in_a_section_of_its_own() -> Result {
+        let log = Rc::new(CiLog::new(Ci::GitLab));
+        let observer = CiObserver(log.clone());
+        let serial = Serial::new("emulator-5554")?;
+
+        log.start(Section::Acquire, "adp: waiting for a device", UNIX_EPOCH);
+        observer.on_acquire(&serial, 1, None, UNIX_EPOCH);
+        observer.on_release(&serial, UNIX_EPOCH);
+        assert_eq!(log.open(), Some(Section::Acquire));
+
+        observer.on_acquire(&serial, 1, None, UNIX_EPOCH);
+        log.start(Section::Job, "adp: ./gradlew on emulator-5554", UNIX_EPOCH);
+        observer.on_release(&serial, UNIX_EPOCH);
+        assert_eq!(log.open(), Some(Section::Job));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,405 @@
+use std::ffi::OsString;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::completions::{Shell, SERIALS_COMMAND};
+use crate::duration::HumanDuration;
+use crate::filelock::PROBE_COMMAND;
+use crate::filter::DeviceFilter;
+use crate::notify::Notifier;
+use crate::runtime::Serial;
+use crate::self_test::WORKER_COMMAND;
+use crate::shard;
+use crate::store::LockMode;
+use crate::template;
+use crate::usage::GroupBy;
+
+#[derive(Debug, Parser)]
+#[command(name = "adp", version, about = "Android Device Pool", allow_external_subcommands = true)]
+pub struct Cli {
+    /// Path to the config file [default: <config dir>/adp/config.toml]
+    #[arg(long, env = "ADP_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Where to keep the pool's state, ex: a volume shared by CI containers [default: <runtime dir>/adp]
+    #[arg(long, env = "ADP_RUNTIME_DIR", global = true)]
+    pub runtime_dir: Option<PathBuf>,
+
+    /// How claims are held, ex: lock-file-only where locks on a file per device aren't available [default: from the config]
+    #[arg(long, value_enum, env = "ADP_LOCK_MODE", global = true)]
+    pub lock_mode: Option<LockMode>,
+
+    /// Most jobs that may hold a device on this host at once, however many are free [default: from the config]
+    #[arg(long, env = "ADP_MAX_CONCURRENT", global = true)]
+    pub max_concurrent: Option<NonZeroUsize>,
+
+    /// Tell someone when a job that had to wait gets a device: desktop, command:<shell command> or a webhook url
+    #[arg(long)]
+    pub notify: Vec<Notifier>,
+
+    /// Also notify when a job has been waiting this long, ex: 10m
+    #[arg(long, requires = "notify")]
+    pub notify_after: Option<HumanDuration>,
+
+    /// Only run on a device from this group in the config, ex: tablets
+    #[arg(long)]
+    pub group: Option<String>,
+
+    /// Only run on a device with this prop, ex: --prop 'ro.product.model=Pixel 7'
+    #[arg(long, value_name = "NAME=VALUE", value_parser = DeviceFilter::prop)]
+    pub prop: Vec<DeviceFilter>,
+
+    /// Only run on a device with a prop matching this regex, ex: --prop-regex 'ro.product.model=Pixel [78]'
+    #[arg(long, value_name = "NAME=REGEX", value_parser = DeviceFilter::prop_regex)]
+    pub prop_regex: Vec<DeviceFilter>,
+
+    /// Only run on a device that has this, root or gms (Google Play Services)
+    #[arg(long, value_name = "CAPABILITY", value_parser = DeviceFilter::require)]
+    pub require: Vec<DeviceFilter>,
+
+    /// Only run on a device with at least this screen density, ex: 420
+    #[arg(long, value_name = "DPI", value_parser = DeviceFilter::min_density)]
+    pub min_density: Option<DeviceFilter>,
+
+    /// Only run on a device attached to this host, not one on an adb server elsewhere
+    #[arg(long)]
+    pub local: bool,
+
+    #[command(flatten)]
+    pub job: JobOptions,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+impl Cli {
+    // Every filter on which device to run on.
+    pub fn filters(&self) -> Vec<DeviceFilter> {
+        self.prop.iter().chain(&self.prop_regex).chain(&self.require).chain(&self.min_density).cloned()
+            .chain(self.local.then_some(DeviceFilter::Local))
+            .chain(self.job.locale.clone().map(DeviceFilter::Locale))
+            .collect()
+    }
+}
+
+// How to run the job itself.
+#[derive(Debug, Default, Args)]
+pub struct JobOptions {
+    /// Run adb as root on the device for the job, only userdebug and eng builds are picked
+    #[arg(long)]
+    pub require_root: bool,
+
+    /// Prefix every line the job writes with [serial], ex: to tell apart shards running side by side in CI
+    #[arg(long)]
+    pub prefix: bool,
+
+    /// Color the prefix, picked by serial
+    #[arg(long, requires = "prefix")]
+    pub color: bool,
+
+    /// Also write the job's output to <serial>.log in this directory
+    #[arg(long)]
+    pub log_dir: Option<PathBuf>,
+
+    /// Record the device's screen while the job runs and save it to this directory
+    #[arg(long, value_name = "DIR")]
+    pub record: Option<PathBuf>,
+
+    /// Save a screenshot of the device to this directory if the job fails
+    #[arg(long, value_name = "DIR")]
+    pub screenshot_on_failure: Option<PathBuf>,
+
+    /// Save a bugreport of the device to this directory if the job fails
+    #[arg(long, value_name = "DIR")]
+    pub bugreport_on_failure: Option<PathBuf>,
+
+    /// Add the device's serial, model and API level to the JUnit XML reports the job writes here, a file or directory
+    #[arg(long, value_name = "PATH")]
+    pub junit_append: Option<PathBuf>,
+
+    /// Run the job again this many times if it fails, on another device than the ones it failed on if one's free
+    #[arg(long, default_value_t = 0)]
+    pub retries: usize,
+
+    /// Team to count the job's device time against in `adp usage`
+    #[arg(long, env = "ADP_TEAM")]
+    pub team: Option<String>,
+
+    /// Also export the serial under this name, or anything else with NAME=TEMPLATE, ex: DEVICE_MODEL={{model}}
+    #[arg(long, value_name = "NAME[=TEMPLATE]", value_parser = template::export)]
+    pub export_env: Vec<(String, Option<String>)>,
+
+    /// Only run on a device in this locale, or a rooted one that's put in it for the job, ex: fr-FR
+    #[arg(long)]
+    pub locale: Option<String>,
+
+    /// Throttle the emulator's network for the job, ex: edge, or <up>:<down> in kbps
+    #[arg(long, value_name = "SPEED")]
+    pub net_speed: Option<String>,
+
+    /// Slow down the emulator's network for the job, ex: gprs, or <min>:<max> in ms
+    #[arg(long, value_name = "DELAY")]
+    pub net_delay: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Keep running in the foreground, managing the pool
+    Daemon,
+    /// Show the devices in the pool and who is using them
+    Status,
+    /// Kill the job using a device and put the device back in the pool
+    Kill {
+        /// Serial of the device, or pid of the adp process using it
+        target: String,
+        /// Don't ask for confirmation
+        #[arg(long)]
+        force: bool,
+    },
+    /// Fix up the pool's bookkeeping after a crash, like claims held by processes that are gone
+    Repair,
+    /// List the devices adb can see with their model, API level, battery and whether they're claimed
+    ListDevices {
+        /// Print as JSON for other tools to consume
+        #[arg(long)]
+        json: bool,
+    },
+    /// Put a device that was quarantined for failing too many jobs back in the pool
+    Unquarantine {
+        serial: Serial,
+    },
+    /// Stop handing out devices, ex: for maintenance on the bench, jobs already running carry on
+    Pause {
+        /// Shown to anyone waiting on the pool
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Start handing out devices again after `adp pause`
+    Resume,
+    /// Pause the pool ahead of maintenance on the host, ex: rebooting it or updating adb
+    Drain {
+        /// Shown to anyone waiting on the pool
+        #[arg(long)]
+        reason: Option<String>,
+        /// Block until every job has given its device back
+        #[arg(long)]
+        wait: bool,
+        /// Give up waiting after this long, ex: 30m
+        #[arg(long, requires = "wait")]
+        timeout: Option<HumanDuration>,
+    },
+    /// Pair with a device over wireless debugging (Android 11+) and add it to the pool, ex: adp pair 192.168.1.20:41234 123456
+    Pair {
+        /// The IP address and port from the pairing dialog
+        addr: String,
+        /// The pairing code from the pairing dialog
+        code: String,
+        /// The IP address and port to connect on from the wireless debugging screen, looked up over mDNS if not given
+        #[arg(long)]
+        connect: Option<String>,
+    },
+    /// Print the config, quarantines and whether the pool is paused as JSON, for `adp import` on another machine
+    Export {
+        /// Include the tokens, passwords and urls in the config, they're redacted otherwise
+        #[arg(long)]
+        include_secrets: bool,
+    },
+    /// Set up the pool from the output of `adp export`
+    Import {
+        file: PathBuf,
+        /// Replace the config file if it's different
+        #[arg(long)]
+        force: bool,
+    },
+    /// Wait until there are at least this many healthy devices in the pool, ex: before fanning out into shards
+    WaitForDevices {
+        count: usize,
+        /// Give up after this long, ex: 10m
+        #[arg(long)]
+        timeout: Option<HumanDuration>,
+    },
+    /// Show devices, waiting jobs and recent leases live, like top
+    Top,
+    /// Show the device, command, duration and exit status of your last job, to be able to reproduce it
+    Last,
+    /// Show how much device time each team or user has used, from the jobs in the event log
+    Usage {
+        /// How far back to go, ex: 7d
+        #[arg(long, default_value = "7d")]
+        since: HumanDuration,
+        #[arg(long, value_enum, default_value_t)]
+        by: GroupBy,
+        /// Print as JSON for other tools to consume
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show how many jobs have run and how they went, from the event log
+    Stats {
+        /// How far back to go, ex: 7d
+        #[arg(long, default_value = "7d")]
+        since: HumanDuration,
+        /// Break down how long jobs took to get a device instead, needs [telemetry] latency on
+        #[arg(long)]
+        latency: bool,
+        /// Print as JSON for other tools to consume
+        #[arg(long)]
+        json: bool,
+    },
+    /// Start the lease limit over on the device, from within the job holding it
+    Renew,
+    /// Send a command to the console of the emulator the job holds, from within the job, ex: adp emu geo fix -122.08 37.42
+    Emu {
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Run a command on a device without claiming it, recording who did in the event log, for emergencies only
+    Bypass {
+        /// The device to run on [default: ANDROID_SERIAL, or the only one connected]
+        #[arg(long)]
+        serial: Option<Serial>,
+        /// List who has bypassed the pool instead
+        #[arg(long, conflicts_with = "command")]
+        list: bool,
+        #[arg(required_unless_present = "list", trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<OsString>,
+    },
+    /// Split a list of tests across the free devices and run the command for each share, ex: adp shard --tests tests.txt -- ./gradlew connectedCheck -Pandroid.testInstrumentationRunnerArguments.class={tests}
+    #[command(name = shard::COMMAND)]
+    Shard {
+        /// File with a test per line, substituted for {tests} in the command
+        #[arg(long)]
+        tests: PathBuf,
+        /// How many shards to split them into [default: as many as there are free devices]
+        #[arg(long)]
+        shards: Option<usize>,
+        /// What to join each shard's tests with
+        #[arg(long, default_value = ",")]
+        separator: String,
+        #[arg(required = true, last = true)]
+        command: Vec<OsString>,
+    },
+    /// Work with the audit log
+    Audit {
+        #[command(subcommand)]
+        command: AuditCommand,
+    },
+    /// Check the pool works on this machine, with processes claiming and releasing fake devices as fast as they can
+    SelfTest {
+        #[arg(long, default_value_t = 4)]
+        devices: usize,
+        /// Processes to run at once
+        #[arg(long, default_value_t = 8)]
+        workers: usize,
+        /// Claims each process makes
+        #[arg(long, default_value_t = 25)]
+        rounds: usize,
+    },
+    /// One of the processes started by self-test
+    #[command(name = WORKER_COMMAND, hide = true)]
+    SelfTestWorker {
+        dir: PathBuf,
+        #[arg(long)]
+        devices: usize,
+        #[arg(long)]
+        rounds: usize,
+        #[arg(long, default_value_t = 0)]
+        hold_ms: u64,
+        #[arg(long)]
+        abandon: bool,
+    },
+    /// Checks from another process whether adp's lock on a file keeps it out
+    #[command(name = PROBE_COMMAND, hide = true)]
+    LockProbe,
+    /// Print a completion script for the shell, ex: `adp completions zsh > ~/.zfunc/_adp`
+    Completions {
+        shell: Shell,
+    },
+    /// List the serials in the pool, for the completion scripts
+    #[command(name = SERIALS_COMMAND, hide = true)]
+    CompleteSerials,
+    /// Run a command against a device from the pool
+    #[command(external_subcommand)]
+    Exec(Vec<OsString>),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AuditCommand {
+    /// Check no record in the audit log has been changed or removed, and print the hash of the last one
+    Verify,
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use crate::cli::{Cli, Command};
+
+    #[test]
+    fn passes_through_wrapped_command() {
+        let cli = Cli::try_parse_from(["adp", "./gradlew", "connectedAndroidTest", "-Pfoo=1", "--info"]).unwrap();
+
+        match cli.command {
+            Command::Exec(args) => assert_eq!(args, ["./gradlew", "connectedAndroidTest", "-Pfoo=1", "--info"]),
+            command => panic!("unexpected command {:?}", command),
+        }
+    }
+
+    #[test]
+    fn takes_job_options_before_the_command() {
+        let cli = Cli::try_parse_from(["adp", "--prefix", "--log-dir", "logs", "./gradlew", "--prefix"]).unwrap();
+
+        assert!(cli.job.prefix);
+        assert_eq!(cli.job.log_dir.unwrap().to_str(), Some("logs"));
+        match cli.command {
+            Command::Exec(args) => assert_eq!(args, ["./gradlew", "--prefix"]),
+            command => panic!("unexpected command {:?}", command),
+        }
+    }
+
+    #[test]
+    fn passes_emulator_console_commands_through() {
+        let cli = Cli::try_parse_from(["adp", "emu", "geo", "fix", "-122.08", "37.42"]).unwrap();
+
+        match cli.command {
+            Command::Emu { command } => assert_eq!(command, ["geo", "fix", "-122.08", "37.42"]),
+            command => panic!("unexpected command {:?}", command),
+        }
+    }
+
+    #[test]
+    fn passes_the_bypass_command_through() {
+        let cli = Cli::try_parse_from(["adp", "bypass", "--serial", "R58M123", "--", "adb", "reboot", "-p"]).unwrap();
+
+        match cli.command {
+            Command::Bypass { serial, list, command } => {
+                assert_eq!(serial.unwrap(), "R58M123");
+                assert!(!list);
+                assert_eq!(command, ["adb", "reboot", "-p"]);
+            }
+            command => panic!("unexpected command {:?}", command),
+        }
+        assert!(Cli::try_parse_from(["adp", "bypass"]).is_err());
+        assert!(Cli::try_parse_from(["adp", "bypass", "--list"]).is_ok());
+    }
+
+    #[test]
+    fn passes_the_shard_command_through() {
+        let cli = Cli::try_parse_from([
+            "adp", "--prefix", "shard", "--tests", "tests.txt", "--", "./gradlew", "-Pclass={tests}", "--info",
+        ]).unwrap();
+
+        assert!(cli.job.prefix);
+        match cli.command {
+            Command::Shard { tests, shards, separator, command } => {
+                assert_eq!(tests.to_str(), Some("tests.txt"));
+                assert_eq!(shards, None);
+                assert_eq!(separator, ",");
+                assert_eq!(command, ["./gradlew", "-Pclass={tests}", "--info"]);
+            }
+            command => panic!("unexpected command {:?}", command),
+        }
+        assert!(Cli::try_parse_from(["adp", "shard", "--tests", "tests.txt"]).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+use std::fmt::{Debug, Write};
+
+use clap::{Arg, Command, CommandFactory, ValueEnum};
+use tracing::instrument;
+
+use crate::App;
+use crate::cli::Cli;
+use crate::runtime::Runtime;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// The hidden subcommand the scripts call back into for the serials in the pool.
+pub const SERIALS_COMMAND: &str = "complete-serials";
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+// A subcommand as far as completing it goes, read from the cli definition so new ones are picked up on their own.
+#[derive(Debug)]
+struct Subcommand {
+    name: String,
+    about: String,
+    // Its flags, and the values of any arguments that only take certain ones.
+    words: Vec<String>,
+    // Takes a serial, which is completed from the devices in the pool.
+    serial: bool,
+}
+
+pub fn run(shell: Shell) -> Result {
+    print!("{}", generate(shell));
+    Ok(())
+}
+
+// Lists the serials in the pool for the scripts, without waiting on adb.
+#[instrument(skip(app))]
+pub fn serials<R: Runtime + Debug>(app: &App<R>) -> Result {
+    for (serial, _) in app.entries()?.iter() {
+        println!("{}", serial);
+    }
+    Ok(())
+}
+
+pub fn generate(shell: Shell) -> String {
+    let cli = Cli::command();
+    let global = flags(cli.get_arguments());
+    let subcommands: Vec<Subcommand> = cli.get_subcommands()
+        .filter(|subcommand| !subcommand.is_hide_set())
+        .map(|subcommand| Subcommand {
+            name: subcommand.get_name().to_string(),
+            about: subcommand.get_about().map(|about| about.to_string()).unwrap_or_default(),
+            words: flags(subcommand.get_arguments()).into_iter().chain(values(subcommand)).collect(),
+            serial: takes_serial(subcommand),
+        })
+        .collect();
+    match shell {
+        Shell::Bash => bash(&global, &subcommands),
+        Shell::Zsh => zsh(&global, &subcommands),
+        Shell::Fish => fish(&global, &subcommands),
+    }
+}
+
+fn flags<'a>(args: impl Iterator<Item = &'a Arg>) -> Vec<String> {
+    args.filter(|arg| !arg.is_hide_set())
+        .filter_map(|arg| arg.get_long())
+        .map(|long| format!("--{}", long))
+        .chain(["--help".to_string()])
+        .collect()
+}
+
+fn values(subcommand: &Command) -> Vec<String> {
+    subcommand.get_positionals()
+        .flat_map(|arg| arg.get_possible_values())
+        .map(|value| value.get_name().to_string())
+        .collect()
+}
+
+fn takes_serial(subcommand: &Command) -> bool {
+    subcommand.get_positionals().any(|arg| matches!(arg.get_id().as_str(), "serial" | "target"))
+}
+
+fn bash(global: &[String], subcommands: &[Subcommand]) -> String {
+    let names: Vec<&str> = subcommands.iter().map(|subcommand| subcommand.name.as_str()).collect();
+    let mut script = String::new();
+    writeln!(script, "_adp() {{").unwrap();
+    writeln!(script, "    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\"").unwrap();
+    writeln!(script, "    if [ \"$COMP_CWORD\" -eq 1 ]; then").unwrap();
+    writeln!(script, "        COMPREPLY=($(compgen -W \"{} {} --version\" -- \"$cur\") $(compgen -c -- \"$cur\"))", names.join(" "), global.join(" ")).unwrap();
+    writeln!(script, "        return").unwrap();
+    writeln!(script, "    fi").unwrap();
+    writeln!(script, "    case \"${{COMP_WORDS[1]}}\" in").unwrap();
+    for subcommand in subcommands {
+        let serials = if subcommand.serial { format!(" $(adp {} 2>/dev/null)", SERIALS_COMMAND) } else { String::new() };
+        writeln!(
+            script,
+            "        {}) COMPREPLY=($(compgen -W \"{}{}\" -- \"$cur\")) ;;",
+            subcommand.name, subcommand.words.join(" "), serials,
+        ).unwrap();
+    }
+    // Anything else is a command being run against a device.
+    writeln!(script, "        *) COMPREPLY=($(compgen -f -- \"$cur\")) ;;").unwrap();
+    writeln!(script, "    esac").unwrap();
+    writeln!(script, "}}").unwrap();
+    writeln!(script, "complete -F _adp adp").unwrap();
+    script
+}
+
+fn zsh(global: &[String], subcommands: &[Subcommand]) -> String {
+    let mut script = String::new();
+    writeln!(script, "#compdef adp").unwrap();
+    writeln!(script, "_adp() {{").unwrap();
+    writeln!(script, "    if (( CURRENT == 2 )); then").unwrap();
+    writeln!(script, "        local -a subcommands").unwrap();
+    writeln!(script, "        subcommands=(").unwrap();
+    for subcommand in subcommands {
+        writeln!(script, "            '{}:{}'", subcommand.name, zsh_quote(&subcommand.about)).unwrap();
+    }
+    writeln!(script, "        )").unwrap();
+    writeln!(script, "        _describe 'command' subcommands").unwrap();
+    writeln!(script, "        compadd -- {} --version", global.join(" ")).unwrap();
+    writeln!(script, "        _command_names").unwrap();
+    writeln!(script, "        return").unwrap();
+    writeln!(script, "    fi").unwrap();
+    writeln!(script, "    case $words[2] in").unwrap();
+    for subcommand in subcommands {
+        let serials = if subcommand.serial {
+            format!(" ${{(f)\"$(adp {} 2>/dev/null)\"}}", SERIALS_COMMAND)
+        } else {
+            String::new()
+        };
+        writeln!(script, "        {}) compadd -- {}{} ;;", subcommand.name, subcommand.words.join(" "), serials).unwrap();
+    }
+    writeln!(script, "        *) _files ;;").unwrap();
+    writeln!(script, "    esac").unwrap();
+    writeln!(script, "}}").unwrap();
+    writeln!(script, "compdef _adp adp").unwrap();
+    script
+}
+
+fn fish(global: &[String], subcommands: &[Subcommand]) -> String {
+    let mut script = String::new();
+    let names: Vec<&str> = subcommands.iter().map(|subcommand| subcommand.name.as_str()).collect();
+    for flag in global {
+        writeln!(script, "complete -c adp -l {}", flag.trim_start_matches("--")).unwrap();
+    }
+    writeln!(script, "complete -c adp -n __fish_use_subcommand -l version").unwrap();
+    for subcommand in subcommands {
+        writeln!(
+            script,
+            "complete -c adp -n __fish_use_subcommand -f -a {} -d '{}'",
+            subcommand.name, fish_quote(&subcommand.about),
+        ).unwrap();
+        let seen = format!("'__fish_seen_subcommand_from {}'", subcommand.name);
+        for word in &subcommand.words {
+            match word.strip_prefix("--") {
+                Some(flag) => writeln!(script, "complete -c adp -n {} -l {}", seen, flag).unwrap(),
+                None => writeln!(script, "complete -c adp -n {} -f -a {}", seen, word).unwrap(),
+            }
+        }
+        if subcommand.serial {
+            writeln!(script, "complete -c adp -n {} -f -a '(adp {} 2>/dev/null)'", seen, SERIALS_COMMAND).unwrap();
+        }
+    }
+    // Anything else is a command being run against a device.
+    writeln!(script, "complete -c adp -n 'not __fish_seen_subcommand_from {}' -a '(__fish_complete_command)'", names.join(" ")).unwrap();
+    script
+}
+
+fn zsh_quote(value: &str) -> String {
+    value.replace('\'', "'\\''").replace(':', "\\:")
+}
+
+fn fish_quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::completions::{generate, Shell};
+
+    #[test]
+    fn completes_subcommands_flags_and_serials() {
+        let bash = generate(Shell::Bash);
+
+        assert!(bash.contains("wait-for-devices"), "{}", bash);
+        assert!(bash.contains("wait-for-devices) COMPREPLY=($(compgen -W \"--timeout --help\""), "{}", bash);
+        assert!(bash.contains("kill) COMPREPLY=($(compgen -W \"--force --help $(adp complete-serials 2>/dev/null)\""), "{}", bash);
+        assert!(!bash.contains("complete-serials)"), "{}", bash);
+    }
+
+    #[test]
+    fn quotes_descriptions_for_fish() {
+        let fish = generate(Shell::Fish);
+
+        assert!(fish.contains("-a kill -d 'Kill the job using a device and put the device back in the pool'"), "{}", fish);
+        assert!(fish.contains("complete -c adp -n '__fish_seen_subcommand_from unquarantine' -f -a '(adp complete-serials 2>/dev/null)'"), "{}", fish);
+    }
+}
@@ -0,0 +1,743 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+
+use crate::conflicts::OnConflict;
+use crate::duration::HumanDuration;
+use crate::fixtures::{Charge, Geo};
+use crate::forward::Forward;
+use crate::heartbeat::HeartbeatMode;
+use crate::lockfile::LockFormat;
+use crate::lease::WarnSignal;
+use crate::notify::Notifier;
+use crate::schedule::Schedule;
+use crate::size::Megabytes;
+use crate::store::LockMode;
+use crate::runtime::Serial;
+
+pub type Result<T> = std::result::Result<T, anyhow::Error>;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub adb: AdbConfig,
+    pub daemon: DaemonConfig,
+    pub resources: ResourcesConfig,
+    pub lease: LeaseConfig,
+    pub shared: Option<SharedConfig>,
+    pub redis: Option<RedisConfig>,
+    pub battery: Option<BatteryConfig>,
+    pub thermal: Option<ThermalConfig>,
+    pub quarantine: Option<QuarantineConfig>,
+    pub autoscale: Option<AutoscaleConfig>,
+    pub alerts: Option<AlertsConfig>,
+    pub api: Option<ApiConfig>,
+    pub quotas: Option<QuotaConfig>,
+    pub audit: Option<AuditConfig>,
+    pub ports: Option<PortsConfig>,
+    pub provision: ProvisionConfig,
+    pub fixtures: FixturesConfig,
+    pub wireless: WirelessConfig,
+    pub power_cycle: Option<PowerCycleConfig>,
+    pub sweep: Option<SweepConfig>,
+    // how the lock file is written, lines, json or toml, every adp sharing the runtime dir has to be able to read it
+    pub lock_format: LockFormat,
+    // how claims are held, auto, device-locks or lock-file-only, ex: where a lock on a file per device can't be held for
+    // as long as a job runs, auto goes without them there
+    pub lock_mode: LockMode,
+    // most jobs that may hold a device on this host at once, however many are free, ex: where the host's CPU is what
+    // runs out first
+    pub max_concurrent: Option<NonZeroUsize>,
+    pub telemetry: TelemetryConfig,
+    pub boot: BootConfig,
+    pub heartbeat: HeartbeatConfig,
+    // name to keep the pool's state under, pools on one host with different names don't interfere, ex: for projects
+    // with devices of their own, by default pools are told apart by their adb servers
+    pub pool: Option<String>,
+    // each device gets a directory of its own under here for jobs to work in, exported as ADP_WORK_DIR
+    pub work_dir: Option<PathBuf>,
+    // exported to each job with `{{name}}` expanded from its device, ex: DEVICE_NAME = "{{model}}"
+    pub env: BTreeMap<String, String>,
+    // named sets of devices that jobs can ask for with --group, ex: tablets
+    pub groups: BTreeMap<String, Vec<Serial>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdbConfig {
+    // ADB_SERVER_SOCKET of each adb server to pool devices from, ex: tcp:localhost:5038, defaults to adb's own
+    pub servers: Vec<String>,
+    // set up on the device for each job and removed once it's done, ex: tcp:8081 tcp:8081
+    pub forward: Vec<Forward>,
+    pub reverse: Vec<Forward>,
+    // what to do when a device is being used outside the pool as a job starts on it, ignore, warn or fail
+    pub on_conflict: OnConflict,
+    // how long a device that dropped off adb keeps its place in the pool, and any claim on it, in case it comes back,
+    // ex: 30s for a flaky USB hub
+    pub reconnect_grace: Option<HumanDuration>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    // time between each pass over the pool
+    pub poll_interval: HumanDuration,
+    // idle devices to keep booted and health checked ahead of time so jobs can start on them right away
+    pub prewarm: usize,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig { poll_interval: HumanDuration(Duration::from_secs(5)), prewarm: 0 }
+    }
+}
+
+// Budget for instances adp starts, defaults to the host's capacity.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourcesConfig {
+    pub cpus: Option<f64>,
+    pub memory_mb: Option<Megabytes>,
+}
+
+// Extra detail recorded with each job in the event log.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    // how long getting the device took, broken down by phase, for `adp stats --latency`
+    pub latency: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BootConfig {
+    // checked in order once sys.boot_completed is set, each one waited on like it
+    pub checks: Vec<BootCheck>,
+}
+
+// Claims kept alive by the job checking in instead of by its pid, for jobs in containers that can't see each other's
+// processes.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    // auto, on or off, auto is on only from inside a container
+    pub mode: HeartbeatMode,
+    // how long without a heartbeat before the claim is taken back, every adp sharing the runtime dir should agree
+    pub timeout: HumanDuration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig { mode: HeartbeatMode::default(), timeout: HumanDuration(Duration::from_secs(30)) }
+    }
+}
+
+// Something else that has to be true before a device counts as booted, for devices that set sys.boot_completed long
+// before they're usable, ex: Samsungs whose launcher comes up well after it. Each one can be limited to devices whose
+// ro.product.manufacturer is `manufacturer`, ignoring case.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum BootCheck {
+    // the prop is set, to `value` if given
+    Prop { name: String, value: Option<String>, manufacturer: Option<String> },
+    // the shell command exits successfully, ex: pm path com.sec.android.app.launcher
+    Shell { command: String, manufacturer: Option<String> },
+    // the system service is running, ex: package
+    Service { name: String, manufacturer: Option<String> },
+}
+
+impl BootCheck {
+    // None if it's for every device.
+    pub fn manufacturer(&self) -> Option<&str> {
+        let (BootCheck::Prop { manufacturer, .. }
+            | BootCheck::Shell { manufacturer, .. }
+            | BootCheck::Service { manufacturer, .. }) = self;
+        manufacturer.as_deref()
+    }
+
+    pub fn applies_to(&self, manufacturer: &str) -> bool {
+        self.manufacturer().is_none_or(|only| only.eq_ignore_ascii_case(manufacturer))
+    }
+}
+
+impl Display for BootCheck {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BootCheck::Prop { name, value: Some(value), .. } => write!(f, "{} = {}", name, value),
+            BootCheck::Prop { name, value: None, .. } => write!(f, "{}", name),
+            BootCheck::Shell { command, .. } => write!(f, "`{}`", command),
+            BootCheck::Service { name, .. } => write!(f, "the {} service", name),
+        }
+    }
+}
+
+// How long a job may hold on to a device.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LeaseConfig {
+    pub max: Option<HumanDuration>,
+    // kill the job and reclaim the device instead of just warning
+    pub kill: bool,
+    // per-device overrides of max
+    pub devices: BTreeMap<Serial, HumanDuration>,
+    // signal the job this long before its lease runs out, so it can `adp renew` it
+    pub warn_before: Option<HumanDuration>,
+    // the longest a job can hold a device however often it renews, twice its limit unless set
+    pub max_total: Option<HumanDuration>,
+    pub warn_signal: WarnSignal,
+}
+
+// State to put devices in before each job, so tests don't depend on what the last job or someone at the bench left.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProvisionConfig {
+    // set the device's clock from the host's if they've drifted apart
+    pub sync_clock: bool,
+    // ex: Europe/London
+    pub timezone: Option<String>,
+    // ex: en-US
+    pub locale: Option<String>,
+    pub airplane_mode_off: bool,
+    pub wifi: Option<WifiConfig>,
+    // host the device has to be able to ping or it's quarantined, ex: 8.8.8.8
+    pub ping: Option<String>,
+    // devices with less free than this are cleaned up, and quarantined if that doesn't free up enough, ex: 2G
+    pub min_free_storage: Option<Megabytes>,
+    // removed as well as app caches when cleaning up, expanded by the device's shell, ex: /sdcard/Download/*
+    pub cleanup_paths: Vec<String>,
+}
+
+// State emulators are put into through their console before each job, real devices are left as they are.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FixturesConfig {
+    // latitude and longitude, ex: "37.42 -122.08"
+    pub geo: Option<Geo>,
+    // percent charged, it's also taken off the charger so it stays there
+    pub battery: Option<Charge>,
+    // sensor to its values, colon separated, ex: acceleration = "0:9.8:0"
+    pub sensors: BTreeMap<String, String>,
+}
+
+// Devices on wireless debugging (Android 11+), which the daemon reconnects whenever they drop off, ex: after rebooting.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WirelessConfig {
+    // adb connect addresses, ex: 192.168.1.20:37123, `adp pair` adds to these
+    pub endpoints: Vec<String>,
+}
+
+// Bench devices whose usb port the daemon power cycles when adb loses them, before alerting that they dropped offline.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PowerCycleConfig {
+    // run with sh, `{{hub}}`, `{{port}}` and `{{serial}}` are filled in from the device
+    #[serde(default = "default_power_cycle_command")]
+    pub command: String,
+    // how long to wait for the device to come back before giving up on it
+    #[serde(default = "default_power_cycle_timeout")]
+    pub timeout: HumanDuration,
+    // where each device is plugged in, ex: R58M123ABC = { hub = "1-1", port = 2 }
+    pub devices: BTreeMap<Serial, UsbPort>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UsbPort {
+    // as uhubctl -l takes it, ex: 1-1
+    pub hub: String,
+    pub port: u32,
+}
+
+// Upkeep the daemon does on idle devices on a schedule, so the bench doesn't need a cron job fighting the pool for them.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SweepConfig {
+    // like a crontab line, in UTC, ex: "0 3 * * *"
+    pub schedule: Schedule,
+    // clear app caches and /data/local/tmp on devices with less free than this
+    pub min_free_storage: Option<Megabytes>,
+    // reboot devices that have been up for longer than this
+    pub max_uptime: Option<HumanDuration>,
+}
+
+// Network to keep devices connected to.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WifiConfig {
+    pub ssid: String,
+    // None for an open network
+    pub password: Option<String>,
+}
+
+// Host ports to give each job a range of its own from, exported as ADP_PORT_BASE and ADP_PORT_COUNT, ex: for adb
+// forward or appium.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "RawPortsConfig")]
+pub struct PortsConfig {
+    pub start: u16,
+    // per job
+    pub count: u16,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPortsConfig {
+    start: u16,
+    #[serde(default = "default_port_count")]
+    count: u16,
+}
+
+impl TryFrom<RawPortsConfig> for PortsConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(RawPortsConfig { start, count }: RawPortsConfig) -> Result<Self> {
+        if count == 0 {
+            return Err(anyhow!("expected each job to get at least one port"));
+        }
+        if start.checked_add(count).is_none() {
+            return Err(anyhow!("{} ports from {} run past the last port, 65535", count, start));
+        }
+        Ok(PortsConfig { start, count })
+    }
+}
+
+fn default_port_count() -> u16 {
+    10
+}
+
+// Keeps the pool's state on a filesystem shared between hosts, like an NFS mount, so they can share devices.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SharedConfig {
+    pub dir: PathBuf,
+    // how often to check for a free device, hosts can't wake each other up when they release one
+    #[serde(default = "default_shared_poll_interval")]
+    pub poll_interval: HumanDuration,
+    // how long to keep retrying to take the lock before giving up
+    #[serde(default = "default_lock_timeout")]
+    pub lock_timeout: HumanDuration,
+}
+
+// Keeps claims in redis instead of the lock file, requires building with the redis feature.
+#[cfg_attr(not(feature = "redis"), allow(dead_code))]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedisConfig {
+    pub url: String,
+    // prefix for every key adp uses, pools sharing a redis need different ones
+    #[serde(default = "default_redis_prefix")]
+    pub prefix: String,
+    // how long a claim lasts without being renewed, adp renews it while the job is running
+    #[serde(default = "default_redis_ttl")]
+    pub ttl: HumanDuration,
+    // how often to check for a free device
+    #[serde(default = "default_shared_poll_interval")]
+    pub poll_interval: HumanDuration,
+}
+
+// Keeps physical devices with a low battery out of the pool until they've charged.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatteryConfig {
+    // percent below which a device that isn't charging is skipped
+    pub min: u8,
+    // percent a skipped device has to charge back up to before it's used again, defaults to as soon as it's charging
+    pub resume: Option<u8>,
+    // how often to check on devices that are charging
+    #[serde(default = "default_battery_check_interval")]
+    pub check_interval: HumanDuration,
+}
+
+// Rests devices that come back hot, overheated phones throttle and make performance tests flaky.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThermalConfig {
+    // degrees celsius above which a device is rested when it's released
+    pub max: f32,
+    #[serde(default = "default_cooldown")]
+    pub cooldown: HumanDuration,
+}
+
+// Takes devices out of the pool that jobs keep failing on.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuarantineConfig {
+    // failures in a row, while jobs pass on other devices, before a device is quarantined
+    pub after: u32,
+}
+
+// Tells the bench owner about problems with the pool, from the daemon.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertsConfig {
+    // where to send them, written like --notify
+    pub notify: Vec<Notifier>,
+    // alert when a job has been waiting this long for a device
+    pub wait: Option<HumanDuration>,
+}
+
+// HTTP api served by the daemon.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiConfig {
+    // address to listen on, ex: 0.0.0.0:7007
+    pub listen: String,
+    // clients need to send this or one of tokens as a bearer token, this one can lease devices as any user
+    pub token: Option<String>,
+    // tokens that lease devices as the user they map to
+    #[serde(default)]
+    pub tokens: BTreeMap<String, String>,
+}
+
+// How many devices each user can hold at once, so one user's big run can't starve everyone else.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    // for users that aren't listed, no limit if unset
+    pub default: Option<usize>,
+    pub users: BTreeMap<String, usize>,
+}
+
+impl QuotaConfig {
+    pub fn limit(&self, user: &str) -> Option<usize> {
+        self.users.get(user).copied().or(self.default)
+    }
+}
+
+// Where to keep a tamper-evident record of every claim and release, for benches that need to account for who had
+// which device.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditConfig {
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AutoscaleConfig {
+    #[serde(default = "default_max")]
+    pub max: usize,
+    // how long an instance we started can sit idle with an empty queue before it's shut down
+    #[serde(default = "default_scale_down_after")]
+    pub scale_down_after: HumanDuration,
+    // shut instances down as soon as they've been used once instead of returning them to the pool
+    #[serde(default)]
+    pub ephemeral: bool,
+    // estimated cost of each instance, defaults depend on the provider
+    pub instance_cpus: Option<f64>,
+    pub instance_memory_mb: Option<Megabytes>,
+    // exactly one provider must be configured
+    pub avd: Option<AvdConfig>,
+    pub cuttlefish: Option<CuttlefishConfig>,
+    pub genymotion: Option<GenymotionConfig>,
+    pub docker: Option<DockerConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AvdConfig {
+    pub name: String,
+    #[serde(default = "default_emulator")]
+    pub emulator: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CuttlefishConfig {
+    // directory the cuttlefish host package and images were unpacked into
+    pub home: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GenymotionConfig {
+    pub recipe: String,
+    #[serde(default = "default_gmsaas")]
+    pub gmsaas: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DockerConfig {
+    pub image: String,
+    #[serde(default = "default_docker")]
+    pub docker: PathBuf,
+    // extra arguments for docker run
+    #[serde(default = "default_docker_args")]
+    pub args: Vec<String>,
+    // port adb listens on inside the container
+    #[serde(default = "default_container_adb_port")]
+    pub adb_port: u16,
+    // how long to wait for adb to be able to connect to a new container
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: HumanDuration,
+}
+
+fn default_max() -> usize {
+    1
+}
+
+fn default_emulator() -> PathBuf {
+    PathBuf::from("emulator")
+}
+
+fn default_gmsaas() -> PathBuf {
+    PathBuf::from("gmsaas")
+}
+
+fn default_docker() -> PathBuf {
+    PathBuf::from("docker")
+}
+
+fn default_docker_args() -> Vec<String> {
+    vec!["--device".to_string(), "/dev/kvm".to_string()]
+}
+
+fn default_container_adb_port() -> u16 {
+    5555
+}
+
+fn default_connect_timeout() -> HumanDuration {
+    HumanDuration(Duration::from_secs(2 * 60))
+}
+
+fn default_scale_down_after() -> HumanDuration {
+    HumanDuration(Duration::from_secs(5 * 60))
+}
+
+fn default_shared_poll_interval() -> HumanDuration {
+    HumanDuration(Duration::from_secs(2))
+}
+
+fn default_redis_prefix() -> String {
+    "adp".to_string()
+}
+
+fn default_redis_ttl() -> HumanDuration {
+    HumanDuration(Duration::from_secs(60))
+}
+
+fn default_battery_check_interval() -> HumanDuration {
+    HumanDuration(Duration::from_secs(60))
+}
+
+fn default_cooldown() -> HumanDuration {
+    HumanDuration(Duration::from_secs(5 * 60))
+}
+
+fn default_power_cycle_command() -> String {
+    "uhubctl -l {{hub}} -p {{port}} -a cycle".to_string()
+}
+
+fn default_power_cycle_timeout() -> HumanDuration {
+    HumanDuration(Duration::from_secs(60))
+}
+
+fn default_lock_timeout() -> HumanDuration {
+    HumanDuration(Duration::from_secs(30))
+}
+
+impl Config {
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let required = path.is_some();
+        let Some(path) = Config::path(path) else { return Ok(Config::default()) };
+        if !required && !path.exists() {
+            return Ok(Config::default());
+        }
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {:?}", path))?;
+        Config::parse(&contents).with_context(|| format!("invalid config {:?}", path))
+    }
+
+    // The given path, or where the config lives by default.
+    pub fn path(path: Option<&Path>) -> Option<PathBuf> {
+        match path {
+            Some(path) => Some(path.to_path_buf()),
+            None => dirs::config_dir().map(|dir| dir.join("adp").join("config.toml")),
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Config> {
+        Ok(toml::from_str(contents)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::config::{BootCheck, Config, QuotaConfig};
+    use crate::runtime::Serial;
+    use crate::size::Megabytes;
+
+    use super::Result;
+
+    #[test]
+    fn parses_empty_config() -> Result<()> {
+        let config = Config::parse("")?;
+
+        assert_eq!(config.daemon.poll_interval.0, Duration::from_secs(5));
+        assert!(config.autoscale.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_autoscale_config() -> Result<()> {
+        let config = Config::parse("[autoscale]\nmax = 4\n[autoscale.avd]\nname = \"Pixel_6_API_33\"\n")?;
+        let autoscale = config.autoscale.unwrap();
+
+        assert_eq!(autoscale.avd.unwrap().name, "Pixel_6_API_33");
+        assert!(autoscale.cuttlefish.is_none());
+        assert_eq!(autoscale.max, 4);
+        assert_eq!(autoscale.scale_down_after.0, Duration::from_secs(5 * 60));
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_lease_config() -> Result<()> {
+        let config = Config::parse("[lease]\nmax = \"30m\"\nkill = true\n[lease.devices]\n\"emulator-5554\" = \"2h\"\n")?;
+
+        assert_eq!(config.lease.max.unwrap().0, Duration::from_secs(30 * 60));
+        assert!(config.lease.kill);
+        assert_eq!(config.lease.devices["emulator-5554"].0, Duration::from_secs(2 * 60 * 60));
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert!(Config::parse("[lease]\nmax = \"30 minutes\"\n").is_err());
+        assert!(Config::parse("[daemon]\npoll_interval = -5\n").is_err());
+    }
+
+    #[test]
+    fn parses_durations_and_sizes_as_numbers_or_with_units() -> Result<()> {
+        let config = Config::parse("[daemon]\npoll_interval = 10\n[resources]\nmemory_mb = 16384\n")?;
+
+        assert_eq!(config.daemon.poll_interval.0, Duration::from_secs(10));
+        assert_eq!(config.resources.memory_mb, Some(Megabytes(16384)));
+
+        let config = Config::parse("[daemon]\npoll_interval = \"1m30s\"\n[resources]\nmemory_mb = \"16G\"\n")?;
+
+        assert_eq!(config.daemon.poll_interval.0, Duration::from_secs(90));
+        assert_eq!(config.resources.memory_mb, Some(Megabytes(16384)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_adb_servers() -> Result<()> {
+        let config = Config::parse("[adb]\nservers = [\"tcp:localhost:5037\", \"tcp:localhost:5038\"]\n")?;
+
+        assert_eq!(config.adb.servers, ["tcp:localhost:5037", "tcp:localhost:5038"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_redis_config() -> Result<()> {
+        let config = Config::parse("[redis]\nurl = \"redis://ci:6379\"\n")?;
+        let redis = config.redis.unwrap();
+
+        assert_eq!(redis.url, "redis://ci:6379");
+        assert_eq!(redis.prefix, "adp");
+        assert_eq!(redis.ttl.0, Duration::from_secs(60));
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_alerts_config() -> Result<()> {
+        let config = Config::parse("[alerts]\nnotify = [\"desktop\", \"https://hooks.slack.com/services/x\"]\nwait = \"15m\"\n")?;
+        let alerts = config.alerts.unwrap();
+
+        assert_eq!(alerts.notify.len(), 2);
+        assert_eq!(alerts.wait.unwrap().0, Duration::from_secs(15 * 60));
+        assert!(Config::parse("[alerts]\nnotify = [\"slack\"]\n").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_boot_checks() -> Result<()> {
+        let config = Config::parse(concat!(
+            "[[boot.checks]]\ntype = \"prop\"\nname = \"sys.launcher.ready\"\nvalue = \"1\"\n",
+            "[[boot.checks]]\ntype = \"shell\"\ncommand = \"pm path com.sec.android.app.launcher\"\n",
+            "manufacturer = \"samsung\"\n",
+            "[[boot.checks]]\ntype = \"service\"\nname = \"package\"\n",
+        ))?;
+        let checks = config.boot.checks;
+
+        assert_eq!(checks[0], BootCheck::Prop {
+            name: "sys.launcher.ready".to_string(),
+            value: Some("1".to_string()),
+            manufacturer: None,
+        });
+        assert!(checks[1].applies_to("Samsung"));
+        assert!(!checks[1].applies_to("Google"));
+        assert!(checks[2].applies_to("Google"));
+        assert_eq!(checks[2].to_string(), "the package service");
+        assert!(Config::parse("[[boot.checks]]\ntype = \"shell\"\ncommand = \"true\"\nvalue = \"1\"\n").is_err());
+        let error = Config::parse("[[boot.checks]]\ntype = \"propp\"\nname = \"sys.launcher.ready\"\n").unwrap_err();
+        assert!(format!("{:#}", error).contains("unknown variant `propp`"), "{:#}", error);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_groups() -> Result<()> {
+        let config = Config::parse("[groups]\ntablets = [\"R52N1\", \"emulator-5556\"]\n")?;
+
+        assert_eq!(config.groups["tablets"], vec![Serial::new("R52N1")?, Serial::new("emulator-5556")?]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_quotas() -> Result<()> {
+        let config = Config::parse("[quotas]\ndefault = 2\n[quotas.users]\nci = 8\n")?;
+        let quotas = config.quotas.unwrap();
+
+        assert_eq!(quotas.limit("ci"), Some(8));
+        assert_eq!(quotas.limit("evan"), Some(2));
+        assert_eq!(QuotaConfig::default().limit("evan"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_no_concurrent_jobs() -> Result<()> {
+        assert_eq!(Config::parse("max_concurrent = 4\n")?.max_concurrent.map(|max| max.get()), Some(4));
+        assert!(Config::parse("max_concurrent = 0\n").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_port_ranges_that_dont_fit() -> Result<()> {
+        let ports = Config::parse("[ports]\nstart = 65525\n")?.ports.unwrap();
+        assert_eq!((ports.start, ports.count), (65525, 10));
+
+        assert!(Config::parse("[ports]\nstart = 8000\ncount = 0\n").is_err());
+        assert!(Config::parse("[ports]\nstart = 65530\ncount = 10\n").is_err());
+        assert!(Config::parse("[ports]\nstart = 8000\nend = 9000\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(Config::parse("[autoscale]\nmaxx = 4\n").is_err());
+    }
+}
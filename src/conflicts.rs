@@ -0,0 +1,72 @@
+use anyhow::anyhow;
+use serde::Deserialize;
+use tracing::{debug, instrument};
+
+use crate::runtime::{Runtime, Serial};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// What to do about a device that's being used outside the pool, ex: from Android Studio.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+    #[default]
+    Ignore,
+    Warn,
+    // Don't run the job on it.
+    Fail,
+}
+
+// Checked before a job starts, when anything adb is running on the device is someone else's.
+#[instrument(skip(runtime))]
+pub fn check(runtime: &impl Runtime, serial: &Serial, on_conflict: OnConflict) -> Result {
+    if on_conflict == OnConflict::Ignore {
+        return Ok(());
+    }
+    let processes = match foreign_processes(runtime, serial) {
+        Ok(processes) => processes,
+        Err(e) => {
+            debug!(error = %e, "couldn't check for other adb clients");
+            return Ok(());
+        }
+    };
+    if processes.is_empty() {
+        return Ok(());
+    }
+    let message = format!(
+        "{} is being used outside the pool, adb is running {} on it, ex: for Android Studio",
+        serial, processes.join(", "),
+    );
+    match on_conflict {
+        OnConflict::Fail => Err(anyhow!("{}, not running the job on it", message)),
+        _ => {
+            eprintln!("adp: {}", message);
+            Ok(())
+        }
+    }
+}
+
+// Processes adbd has started, other than the ps we use to find them.
+fn foreign_processes(runtime: &impl Runtime, serial: &Serial) -> Result<Vec<String>> {
+    let output = runtime.run_adb(serial, &["shell", "ps", "-A", "-o", "PID,PPID,NAME"])?;
+    let processes: Vec<(&str, &str, &str)> = output.lines()
+        .skip(1)
+        .filter_map(|line| match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [pid, ppid, name] => Some((*pid, *ppid, *name)),
+            _ => None,
+        })
+        .collect();
+    let adbd = processes.iter()
+        .find(|(_, _, name)| *name == "adbd")
+        .map(|(pid, _, _)| *pid)
+        .ok_or_else(|| anyhow!("adbd isn't in the process list"))?;
+    // ps may have been started by a shell.
+    let ours: Vec<&str> = processes.iter()
+        .filter(|(_, _, name)| *name == "ps")
+        .flat_map(|(pid, ppid, _)| [*pid, *ppid])
+        .collect();
+    Ok(processes.iter()
+        .filter(|(pid, ppid, _)| *ppid == adbd && !ours.contains(pid))
+        .map(|(_, _, name)| name.to_string())
+        .collect())
+}
@@ -0,0 +1,166 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::Debug;
+
+use tracing::instrument;
+
+use crate::{App, PoolState};
+use crate::alerts::Alerts;
+use crate::api::Api;
+use crate::autoscale::Autoscaler;
+use crate::config::{Config, LeaseConfig};
+use crate::duration::HumanDuration;
+use crate::events::EventLog;
+use crate::kill;
+use crate::lockfile::LockFileEntries;
+use crate::power::{self, PowerCycler};
+use crate::runtime::{Pid, pool_serial, Runtime, Serial, shared_serials};
+use crate::wireless;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+#[instrument(skip(app, config, events))]
+pub fn run<R: Runtime + Debug>(app: &App<R>, config: &Config, events: &EventLog) -> Result {
+    let mut autoscaler = config.autoscale.as_ref()
+        .map(|autoscale| Autoscaler::new(autoscale, &config.resources))
+        .transpose()?;
+    let mut alerts = config.alerts.as_ref().map(Alerts::new);
+    let mut api = config.api.as_ref().map(|api| Api::new(api, events)).transpose()?;
+    let poll_interval = config.daemon.poll_interval.0;
+    let mut warned = HashSet::new();
+    let adb = wireless::adb(&config.adb);
+    let mut power_cycler = config.power_cycle.as_ref().map(PowerCycler::new);
+    let mut last_sweep = app.now();
+    // The devices swept so far while a sweep is under way, one a tick.
+    let mut sweeping: Option<BTreeSet<Serial>> = None;
+
+    loop {
+        if !config.wireless.endpoints.is_empty() {
+            if let Err(e) = wireless::reconnect(app, &adb, &config.wireless.endpoints) {
+                eprintln!("wireless: {:#}", e);
+            }
+        }
+        if let Some(api) = &mut api {
+            api.expire(&config.lease, app.now());
+        }
+        if let Some(power_cycler) = &mut power_cycler {
+            // Devices that don't come back are alerted on as having dropped offline.
+            match app.connected_devices() {
+                Ok(connected) => {
+                    power_cycler.check(&connected, app.now(), &power::run);
+                }
+                Err(e) => eprintln!("power cycle: {:#}", e),
+            }
+        }
+        let state = app.reconcile()?;
+        // Before anything else here claims them. The state may be stale by now, the lock is what's checked.
+        if let Some(autoscaler) = &autoscaler {
+            if !autoscaler.unmarked(&state).is_empty() {
+                let serials: Vec<Serial> = autoscaler.serials().cloned().collect();
+                if let Err(e) = app.mark_single_use(&serials) {
+                    eprintln!("autoscale: {:#}", e);
+                }
+            }
+        }
+        if let Err(e) = enforce_leases(app, &config.lease, &state.entries, &mut warned) {
+            eprintln!("lease: {:#}", e);
+        }
+        if let Err(e) = app.recheck_batteries() {
+            eprintln!("battery: {:#}", e);
+        }
+        if let Err(e) = app.end_cooldowns() {
+            eprintln!("thermal: {:#}", e);
+        }
+        if config.daemon.prewarm > 0 {
+            if let Err(e) = app.prewarm(config.daemon.prewarm, std::process::id() as Pid) {
+                eprintln!("prewarm: {:#}", e);
+            }
+        }
+        if let Some(sweep) = &config.sweep {
+            let now = app.now();
+            if sweep.schedule.due(last_sweep, now) {
+                last_sweep = now;
+                sweeping.get_or_insert_with(BTreeSet::new);
+            }
+            if let Some(swept) = &mut sweeping {
+                match app.sweep(sweep, std::process::id() as Pid, swept) {
+                    Ok(true) => {}
+                    Ok(false) => sweeping = None,
+                    Err(e) => {
+                        eprintln!("sweep: {:#}", e);
+                        sweeping = None;
+                    }
+                }
+            }
+        }
+        if let Some(alerts) = &mut alerts {
+            if let Err(e) = check_alerts(app, alerts, &state, autoscaler.as_ref(), power_cycler.as_ref()) {
+                eprintln!("alerts: {:#}", e);
+            }
+        }
+        if let Some(autoscaler) = &mut autoscaler {
+            if let Err(e) = autoscaler.tick(&state) {
+                eprintln!("autoscale: {:#}", e);
+            }
+        }
+        match &mut api {
+            // The api failing to take a request is no reason to stop looking after the pool.
+            Some(api) => if let Err(e) = api.serve(app, poll_interval) {
+                eprintln!("api: {:#}", e);
+                std::thread::sleep(poll_interval);
+            },
+            None => std::thread::sleep(poll_interval),
+        }
+    }
+}
+
+fn check_alerts<R: Runtime + Debug>(
+    app: &App<R>,
+    alerts: &mut Alerts,
+    state: &PoolState,
+    autoscaler: Option<&Autoscaler>,
+    power_cycler: Option<&PowerCycler>,
+) -> Result {
+    let managed: BTreeSet<&Serial> = autoscaler.into_iter().flat_map(|autoscaler| autoscaler.serials()).collect();
+    let devices = app.adb_devices()?;
+    let shared = shared_serials(&devices);
+    // Devices being power cycled haven't dropped offline until they fail to come back.
+    let recovering = power_cycler.into_iter().flat_map(|power_cycler| power_cycler.recovering()).cloned();
+    let online = devices.iter()
+        .filter(|device| device.state == "device")
+        .filter_map(|device| pool_serial(device, &shared).ok())
+        .chain(recovering)
+        .filter(|serial| !managed.contains(serial))
+        .collect();
+    let found = alerts.check(state, online, app.now());
+    alerts.send(&found);
+    Ok(())
+}
+
+// Catches jobs holding a device for too long, even ones whose own adp process isn't around to notice.
+fn enforce_leases<R: Runtime + Debug>(
+    app: &App<R>,
+    config: &LeaseConfig,
+    entries: &LockFileEntries,
+    warned: &mut HashSet<(Serial, Pid)>,
+) -> Result {
+    let overdue = config.overdue(entries, app.now());
+    warned.retain(|(serial, pid)| overdue.iter().any(|o| &o.serial == serial && &o.pid == pid));
+    for o in overdue {
+        // Other hosts look after their own jobs.
+        if !entries.get(&o.serial).is_some_and(|entry| app.is_local(entry)) {
+            continue;
+        }
+        // Leases held over the api are claimed with the daemon's own pid, the api takes those back itself.
+        if o.pid == std::process::id() as Pid {
+            continue;
+        }
+        if config.kill {
+            eprintln!("{} has been held by {} for longer than {}, killing it", o.serial, o.pid, HumanDuration(o.limit));
+            kill::terminate(o.pid);
+            app.evict(&o.serial, o.pid)?;
+        } else if warned.insert((o.serial.clone(), o.pid)) {
+            eprintln!("{} has been held by {} for longer than {}", o.serial, o.pid, HumanDuration(o.limit));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument};
+
+use crate::adb::AdbDevice;
+use crate::runtime::{pool_serial, Runtime, Serial, server_host, shared_serials};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// What adp knows about a device, from `adb devices -l` and its props.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeviceInfo {
+    pub serial: Serial,
+    // As reported by adb, ex: device, offline, unauthorized.
+    pub state: String,
+    pub model: Option<String>,
+    pub abi: Option<String>,
+    pub sdk: Option<u32>,
+    pub transport: Transport,
+    // The host it's attached to when that's not this one, through an adb server there.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    // Any other props that were asked for, ex: by a --prop filter.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub props: BTreeMap<String, String>,
+    // Only probed for when asked, it takes a few round trips to the device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Capabilities>,
+}
+
+// What a device can do, for tests that can't run everywhere.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Capabilities {
+    // ex: 1080x2400
+    pub screen_size: Option<String>,
+    // dpi
+    pub density: Option<u32>,
+    // Google Play Services is installed.
+    pub gms: bool,
+    // adb runs as root or su is available.
+    pub root: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Usb,
+    Tcp,
+    Emulator,
+    Unknown,
+}
+
+impl Display for Transport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Transport::Usb => "usb",
+            Transport::Tcp => "tcp",
+            Transport::Emulator => "emulator",
+            Transport::Unknown => "unknown",
+        };
+        f.write_str(name)
+    }
+}
+
+impl DeviceInfo {
+    pub fn is_online(&self) -> bool {
+        self.state == "device"
+    }
+}
+
+impl Transport {
+    fn of(device: &AdbDevice) -> Transport {
+        if device.serial.starts_with("emulator-") {
+            Transport::Emulator
+        } else if device.attributes.contains_key("usb") {
+            Transport::Usb
+        } else if device.serial.contains(':') {
+            Transport::Tcp
+        } else {
+            Transport::Unknown
+        }
+    }
+}
+
+// Props don't change while a device stays connected, so they're only read once per connection.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct CachedProps {
+    // Changes each time the device connects, so a device that was reconnected gets its props read again.
+    transport_id: Option<String>,
+    model: Option<String>,
+    abi: Option<String>,
+    sdk: Option<u32>,
+    // Read when they're first asked for, empty if the device doesn't have it.
+    #[serde(default)]
+    props: BTreeMap<String, String>,
+    #[serde(default)]
+    capabilities: Option<Capabilities>,
+}
+
+// Props of each connected device, persisted so every adp invocation doesn't have to ask again.
+#[derive(Debug)]
+pub struct DeviceCache {
+    path: PathBuf,
+}
+
+impl DeviceCache {
+    pub fn new(runtime_dir: impl AsRef<Path>) -> DeviceCache {
+        DeviceCache { path: runtime_dir.as_ref().join("adp.devices") }
+    }
+
+    // A cache that can't be read is as good as empty.
+    fn load(&self) -> BTreeMap<Serial, CachedProps> {
+        let Ok(file) = File::open(&self.path) else { return BTreeMap::new() };
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| debug!(invalid_cache = %e))
+            .unwrap_or_default()
+    }
+
+    // Written to the side and moved into place so a reader never sees half of it.
+    fn save(&self, props: &BTreeMap<Serial, CachedProps>) -> Result {
+        let tmp = self.path.with_extension(format!("devices.{}", std::process::id()));
+        serde_json::to_writer(BufWriter::new(File::create(&tmp)?), props)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+// Every device adb can see, using the cache where it can. Devices that have disconnected are dropped from the cache.
+// Along with the usual props, online devices also have the given ones read, and their capabilities probed if asked.
+#[instrument(skip(runtime))]
+pub fn list<R: Runtime>(
+    runtime: &R,
+    cache: Option<&DeviceCache>,
+    extra: &[&str],
+    probe: bool,
+) -> Result<Vec<DeviceInfo>> {
+    let devices = runtime.adb_devices()?;
+    let shared = shared_serials(&devices);
+    let cached = cache.map(|cache| cache.load()).unwrap_or_default();
+    let mut props = BTreeMap::new();
+    let mut infos = Vec::new();
+    for device in devices {
+        // The runtime warns about devices with a serial it can't use.
+        let Ok(serial) = pool_serial(&device, &shared) else { continue };
+        let online = device.state == "device";
+        let transport_id = device.attributes.get("transport_id").cloned();
+        let mut device_props = match cached.get(&serial) {
+            Some(cached) if online && cached.transport_id.is_some() && cached.transport_id == transport_id => cached.clone(),
+            _ if online => read_props(runtime, &serial, transport_id),
+            // Only devices that are online will answer.
+            _ => CachedProps { model: device.attributes.get("model").cloned(), ..CachedProps::default() },
+        };
+        if online {
+            for name in extra {
+                if device_props.props.contains_key(*name) {
+                    continue;
+                }
+                if let Ok(value) = runtime.getprop(&serial, name) {
+                    device_props.props.insert(name.to_string(), value);
+                }
+            }
+            if probe && device_props.capabilities.is_none() {
+                device_props.capabilities = probe_capabilities(runtime, &serial)
+                    .map_err(|e| debug!(serial = %serial, error = %e, "failed to probe capabilities"))
+                    .ok();
+            }
+        }
+        infos.push(DeviceInfo {
+            serial: serial.clone(),
+            state: device.state.clone(),
+            model: device_props.model.clone().or_else(|| device.attributes.get("model").cloned()),
+            abi: device_props.abi.clone(),
+            sdk: device_props.sdk,
+            transport: Transport::of(&device),
+            // The runtime only keeps track of which server online devices are on.
+            host: online.then(|| runtime.server_socket(&serial)).flatten()
+                .and_then(|server_socket| server_host(&server_socket).map(String::from)),
+            props: extra.iter()
+                .filter_map(|name| Some((name.to_string(), device_props.props.get(*name)?.clone())))
+                .collect(),
+            capabilities: device_props.capabilities.clone().filter(|_| probe),
+        });
+        // Try again next time if the device didn't answer.
+        if online && device_props.model.is_some() {
+            props.insert(serial, device_props);
+        }
+    }
+    if let Some(cache) = cache {
+        if props != cached {
+            cache.save(&props)?;
+        }
+    }
+    Ok(infos)
+}
+
+fn read_props<R: Runtime>(runtime: &R, serial: &Serial, transport_id: Option<String>) -> CachedProps {
+    let prop = |name| runtime.getprop(serial, name).ok().filter(|value| !value.is_empty());
+    CachedProps {
+        transport_id,
+        model: prop("ro.product.model"),
+        abi: prop("ro.product.cpu.abi"),
+        sdk: prop("ro.build.version.sdk").and_then(|sdk| sdk.parse().ok()),
+        props: BTreeMap::new(),
+        capabilities: None,
+    }
+}
+
+// Errors when the device doesn't answer, ex: it's still booting, so it's probed again next time rather than being
+// taken not to have them.
+fn probe_capabilities<R: Runtime>(runtime: &R, serial: &Serial) -> Result<Capabilities> {
+    let shell = |args: &[&str]| runtime.run_adb(serial, &[&["shell"], args].concat());
+    // ex: Physical size: 1080x2400, followed by an Override size if it's been changed.
+    let last_value = |output: String, what: &str| output.lines().last()
+        .and_then(|line| line.split_once(": "))
+        .map(|(_, value)| value.trim().to_string())
+        .ok_or_else(|| anyhow!("{} didn't say its {}: {}", serial, what, output.trim()));
+    let screen_size = last_value(shell(&["wm", "size"])?, "screen size")?;
+    let valid_size = screen_size.split_once('x')
+        .is_some_and(|(width, height)| width.parse::<u32>().is_ok() && height.parse::<u32>().is_ok());
+    if !valid_size {
+        return Err(anyhow!("{} has an unexpected screen size: {}", serial, screen_size));
+    }
+    let density = last_value(shell(&["wm", "density"])?, "density")?;
+    // Not having them is an answer too, only adb failing isn't.
+    let gms = shell(&["pm path com.google.android.gms 2>/dev/null || true"])?;
+    let root = shell(&["id", "-u"])? == "0" || shell(&["su 0 id -u 2>/dev/null || true"])? == "0";
+    Ok(Capabilities {
+        screen_size: Some(screen_size),
+        density: Some(density.parse().map_err(|_| anyhow!("{} has an unexpected density: {}", serial, density))?),
+        gms: gms.starts_with("package:"),
+        root,
+    })
+}
@@ -0,0 +1,71 @@
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use tracing::{debug, instrument};
+
+use crate::App;
+use crate::duration::HumanDuration;
+use crate::lockfile::Owner;
+use crate::runtime::Runtime;
+use crate::status::format_age;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// Pauses the pool and, with `wait`, blocks until every job has given its device back, so automation can safely reboot
+// the host or update adb. The pool stays paused afterwards until `adp resume`.
+#[instrument(skip(app))]
+pub fn run<R: Runtime + Debug>(
+    app: &mut App<R>,
+    reason: Option<String>,
+    wait: bool,
+    timeout: Option<HumanDuration>,
+) -> Result {
+    app.set_owner(Owner::current(&[]));
+    if !app.pause(reason)? {
+        println!("the pool is already paused");
+    }
+    if !wait {
+        println!("paused the pool, jobs already running carry on but no new ones will start until `adp resume`");
+        return Ok(());
+    }
+    self::wait(app, timeout.map(|timeout| timeout.0), POLL_INTERVAL)?;
+    println!("drained the pool, no new jobs will start until `adp resume`");
+    Ok(())
+}
+
+// Blocks until no device in the pool is claimed, printing the claims still held whenever they change.
+pub fn wait<R: Runtime + Debug>(app: &App<R>, timeout: Option<Duration>, poll_interval: Duration) -> Result {
+    let start = Instant::now();
+    let mut last = None;
+    loop {
+        let state = app.reconcile()?;
+        let now = app.now();
+        let held: Vec<String> = state.entries.iter()
+            .filter_map(|(serial, entry)| {
+                let pid = entry.pid?;
+                let user = entry.owner.as_ref().map(|owner| format!(" by {}", owner.user)).unwrap_or_default();
+                let age = entry.claimed_at
+                    .map(|at| format!(" for {}", format_age(now, at)))
+                    .unwrap_or_default();
+                Some(format!("{} (pid {}{}{})", serial, pid, user, age))
+            })
+            .collect();
+        debug!(held = held.len());
+        if held.is_empty() {
+            return Ok(());
+        }
+        if let Some(timeout) = timeout {
+            if start.elapsed() >= timeout {
+                return Err(anyhow!("timed out with {} devices still claimed: {}", held.len(), held.join(", ")));
+            }
+        }
+        if last != Some(held.len()) {
+            println!("waiting on {} devices: {}", held.len(), held.join(", "));
+            last = Some(held.len());
+        }
+        std::thread::sleep(poll_interval);
+    }
+}
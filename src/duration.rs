@@ -0,0 +1,157 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Deserializer};
+use serde::de::Visitor;
+
+const MINUTE: u64 = 60;
+const HOUR: u64 = 60 * MINUTE;
+const DAY: u64 = 24 * HOUR;
+
+// A duration written the way people write them in config and flags, like "90s", "30m" or "2m30s". A bare number is
+// seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HumanDuration(pub Duration);
+
+impl FromStr for HumanDuration {
+    type Err = anyhow::Error;
+
+    // Parts can be combined largest unit first, like "2m30s" or "1h30m".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+            let secs = s.parse().map_err(|_| anyhow!("invalid duration {:?}, it's too long", s))?;
+            return Ok(HumanDuration(Duration::from_secs(secs)));
+        }
+        if s.is_empty() {
+            return Err(anyhow!("invalid duration, it's empty"));
+        }
+        let mut rest = s;
+        let mut secs: u64 = 0;
+        let mut smallest = u64::MAX;
+        while !rest.is_empty() {
+            let split = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+            let (number, tail) = rest.split_at(split);
+            let number: u64 = number.parse().map_err(|_| anyhow!("invalid duration {:?}, expected a number like 90s, 30m or 2m30s", s))?;
+            let split = tail.find(|c: char| c.is_ascii_digit()).unwrap_or(tail.len());
+            let (unit, tail) = tail.split_at(split);
+            let scale = match unit.trim() {
+                "s" => 1,
+                "m" => MINUTE,
+                "h" => HOUR,
+                "d" => DAY,
+                "" => return Err(anyhow!("invalid duration {:?}, the {} at the end needs a unit of s, m, h or d", s, number)),
+                "M" => return Err(anyhow!("invalid duration {:?}, use m for minutes", s)),
+                "ms" | "us" | "ns" => return Err(anyhow!("invalid duration {:?}, durations are in whole seconds", s)),
+                _ => return Err(anyhow!("invalid duration {:?}, expected a unit of s, m, h or d", s)),
+            };
+            if scale >= smallest {
+                return Err(anyhow!("invalid duration {:?}, units must go from largest to smallest and not repeat", s));
+            }
+            smallest = scale;
+            secs = number.checked_mul(scale)
+                .and_then(|part| secs.checked_add(part))
+                .ok_or(anyhow!("invalid duration {:?}, it's too long", s))?;
+            rest = tail;
+        }
+        Ok(HumanDuration(Duration::from_secs(secs)))
+    }
+}
+
+impl Display for HumanDuration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let secs = self.0.as_secs();
+        match secs {
+            0 => write!(f, "0s"),
+            _ if secs.is_multiple_of(DAY) => write!(f, "{}d", secs / DAY),
+            _ if secs.is_multiple_of(HOUR) => write!(f, "{}h", secs / HOUR),
+            _ if secs.is_multiple_of(MINUTE) => write!(f, "{}m", secs / MINUTE),
+            _ => write!(f, "{}s", secs),
+        }
+    }
+}
+
+// Also takes a bare number of seconds, which is what these settings used to be.
+impl<'de> Deserialize<'de> for HumanDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(HumanDurationVisitor)
+    }
+}
+
+struct HumanDurationVisitor;
+
+impl Visitor<'_> for HumanDurationVisitor {
+    type Value = HumanDuration;
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "a duration like \"90s\", \"30m\" or \"2m30s\", or a number of seconds")
+    }
+
+    fn visit_u64<E: serde::de::Error>(self, secs: u64) -> Result<Self::Value, E> {
+        Ok(HumanDuration(Duration::from_secs(secs)))
+    }
+
+    fn visit_i64<E: serde::de::Error>(self, secs: i64) -> Result<Self::Value, E> {
+        u64::try_from(secs)
+            .map(|secs| HumanDuration(Duration::from_secs(secs)))
+            .map_err(|_| E::custom(format!("invalid duration {}, it can't be negative", secs)))
+    }
+
+    fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<Self::Value, E> {
+        s.parse().map_err(E::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::duration::HumanDuration;
+
+    #[test]
+    fn parses_units() {
+        assert_eq!("90".parse::<HumanDuration>().unwrap().0, Duration::from_secs(90));
+        assert_eq!("90s".parse::<HumanDuration>().unwrap().0, Duration::from_secs(90));
+        assert_eq!("30m".parse::<HumanDuration>().unwrap().0, Duration::from_secs(30 * 60));
+        assert_eq!("2h".parse::<HumanDuration>().unwrap().0, Duration::from_secs(2 * 60 * 60));
+        assert_eq!("1d".parse::<HumanDuration>().unwrap().0, Duration::from_secs(24 * 60 * 60));
+    }
+
+    #[test]
+    fn parses_compound_durations() {
+        assert_eq!("2m30s".parse::<HumanDuration>().unwrap().0, Duration::from_secs(2 * 60 + 30));
+        assert_eq!("1h30m".parse::<HumanDuration>().unwrap().0, Duration::from_secs(90 * 60));
+        assert_eq!("1d2h3m4s".parse::<HumanDuration>().unwrap().0, Duration::from_secs(93784));
+    }
+
+    #[test]
+    fn rejects_ambiguous_durations() {
+        let error = "2m30".parse::<HumanDuration>().unwrap_err().to_string();
+        assert!(error.contains("needs a unit"), "{}", error);
+        let error = "5M".parse::<HumanDuration>().unwrap_err().to_string();
+        assert!(error.contains("use m for minutes"), "{}", error);
+        let error = "500ms".parse::<HumanDuration>().unwrap_err().to_string();
+        assert!(error.contains("whole seconds"), "{}", error);
+        let error = "30s2m".parse::<HumanDuration>().unwrap_err().to_string();
+        assert!(error.contains("largest to smallest"), "{}", error);
+        assert!("1m1m".parse::<HumanDuration>().is_err());
+        assert!("99999999999999999999d".parse::<HumanDuration>().is_err());
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!("".parse::<HumanDuration>().is_err());
+        assert!("m".parse::<HumanDuration>().is_err());
+        assert!("30 minutes".parse::<HumanDuration>().is_err());
+        assert!("-5m".parse::<HumanDuration>().is_err());
+    }
+
+    #[test]
+    fn displays_largest_whole_unit() {
+        assert_eq!(HumanDuration(Duration::from_secs(90)).to_string(), "90s");
+        assert_eq!(HumanDuration(Duration::from_secs(30 * 60)).to_string(), "30m");
+        assert_eq!(HumanDuration(Duration::from_secs(2 * 60 * 60)).to_string(), "2h");
+    }
+}
@@ -0,0 +1,164 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+
+use crate::runtime::Serial;
+
+pub type Result<T> = std::result::Result<T, anyhow::Error>;
+
+// Console ports the emulator will accept, the adb port is always console port + 1.
+const FIRST_PORT: u16 = 5554;
+const LAST_PORT: u16 = 5682;
+// Where the emulator keeps the token its console wants before taking commands, in the home dir.
+const AUTH_TOKEN_FILE: &str = ".emulator_console_auth_token";
+// Some commands take a while to answer, ex: `avd snapshot load`.
+const CONSOLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct Emulator {
+    path: PathBuf,
+}
+
+impl Emulator {
+    pub fn new(path: impl AsRef<Path>) -> Emulator {
+        Emulator {
+            path: path.as_ref().to_path_buf()
+        }
+    }
+
+    pub fn launch(&self, avd: &str, port: u16, args: &[String]) -> Result<Child> {
+        let port = port.to_string();
+        let child = Command::new(&self.path)
+            // -read-only allows running multiple instances of the same avd.
+            .args(["-avd", avd, "-port", &port, "-read-only", "-no-window", "-no-audio", "-no-snapshot-save"])
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("failed to launch {:?}", self.path))?;
+        Ok(child)
+    }
+}
+
+pub fn serial(port: u16) -> Serial {
+    Serial::new(format!("emulator-{}", port)).expect("emulator serials are valid")
+}
+
+// The console port of an emulator, from its serial, None if it isn't one.
+pub fn console_port(serial: &Serial) -> Option<u16> {
+    serial.as_str().strip_prefix("emulator-")?.parse().ok()
+}
+
+// The token the emulator's console wants, None if it doesn't want one.
+pub fn auth_token() -> Option<String> {
+    let token = std::fs::read_to_string(dirs::home_dir()?.join(AUTH_TOKEN_FILE)).ok()?;
+    Some(token.trim().to_string()).filter(|token| !token.is_empty())
+}
+
+// The emulator's console, the telnet interface on its console port for faking calls, locations, battery and so on.
+#[derive(Debug)]
+pub struct Console {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Console {
+    pub fn connect(port: u16, auth_token: Option<&str>) -> Result<Console> {
+        let stream = TcpStream::connect(("127.0.0.1", port))
+            .with_context(|| format!("failed to connect to the emulator console on port {}", port))?;
+        stream.set_read_timeout(Some(CONSOLE_TIMEOUT))?;
+        let mut console = Console { reader: BufReader::new(stream.try_clone()?), writer: stream };
+        // The banner ends in OK like the answer to any command.
+        console.response()?;
+        if let Some(token) = auth_token {
+            console.send(&format!("auth {}", token)).context("the emulator console refused the auth token")?;
+        }
+        Ok(console)
+    }
+
+    // Returns what the console answered, ex: send("gsm call 5551234").
+    pub fn send(&mut self, command: &str) -> Result<String> {
+        write!(self.writer, "{}\r\n", command)?;
+        self.response()
+    }
+
+    // Lines up to OK, or the error after KO.
+    fn response(&mut self) -> Result<String> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                bail!("the emulator closed its console");
+            }
+            let line = line.trim_end();
+            if line == "OK" {
+                return Ok(lines.join("\n"));
+            }
+            if let Some(error) = line.strip_prefix("KO:") {
+                bail!("{}", error.trim());
+            }
+            lines.push(line.to_string());
+        }
+    }
+}
+
+pub fn free_port(in_use: impl Fn(&Serial) -> bool) -> Option<u16> {
+    (FIRST_PORT..=LAST_PORT).step_by(2).find(|port| !in_use(&serial(*port)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    use crate::emulator::{console_port, free_port, Console};
+    use crate::runtime::Serial;
+
+    #[test]
+    fn talks_to_the_console() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let server = std::thread::spawn(move || -> std::io::Result<Vec<String>> {
+            let (mut stream, _) = listener.accept()?;
+            write!(stream, "Android Console: Authentication required\r\nOK\r\n")?;
+            let mut received = Vec::new();
+            for line in BufReader::new(stream.try_clone()?).lines() {
+                let line = line?;
+                match line.as_str() {
+                    "auth secret" => write!(stream, "Android Console: type 'help' for a list of commands\r\nOK\r\n")?,
+                    "power capacity 50" => write!(stream, "OK\r\n")?,
+                    _ => write!(stream, "KO: unknown command, try 'help'\r\n")?,
+                }
+                received.push(line);
+            }
+            Ok(received)
+        });
+
+        let mut console = Console::connect(port, Some("secret"))?;
+        assert_eq!(console.send("power capacity 50")?, "");
+        assert_eq!(console.send("nonsense").unwrap_err().to_string(), "unknown command, try 'help'");
+        drop(console);
+
+        assert_eq!(server.join().unwrap()?, ["auth secret", "power capacity 50", "nonsense"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn finds_first_free_port() {
+        let port = free_port(|serial| serial == "emulator-5554" || serial == "emulator-5558");
+
+        assert_eq!(port, Some(5556));
+    }
+
+    #[test]
+    fn reads_console_port_from_serial() {
+        assert_eq!(console_port(&Serial::new("emulator-5556").unwrap()), Some(5556));
+        assert_eq!(console_port(&Serial::new("R58M123").unwrap()), None);
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument};
+
+use crate::filelock::FileLockGuardExt;
+use crate::runtime::{Pid, Serial};
+use crate::stats::Latency;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// Older records are dropped once there are more than this many.
+const MAX_RECORDS: usize = 1000;
+
+// Props recorded against each lease, enough to tell one device from another when reproducing a failure.
+pub const PROPS: &[&str] = &["ro.product.model", "ro.build.version.sdk", "ro.product.cpu.abi", "ro.build.fingerprint"];
+
+// A finished lease, as recorded in the event log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LeaseRecord {
+    pub serial: Serial,
+    pub pid: Pid,
+    pub user: String,
+    pub host: String,
+    pub cmd: String,
+    // From --team, for `adp usage`. Missing from records written before it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<String>,
+    // seconds since the epoch
+    pub started_at: u64,
+    // seconds
+    pub duration: u64,
+    // None if the job was killed by a signal or couldn't be started.
+    pub exit_code: Option<i32>,
+    pub props: BTreeMap<String, String>,
+    // Where the time went getting the device, only with [telemetry] latency on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency: Option<Latency>,
+    // Run with `adp bypass`, on a device that wasn't claimed from the pool.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub bypassed: bool,
+    // The devices earlier attempts at the job failed on with --retries, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed_on: Vec<Serial>,
+}
+
+// A shard of `adp shard` whose device dropped out mid-run, its tests dealt out again to new shards.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RebalanceRecord {
+    pub rebalanced_from: Serial,
+    // of the shard's adp
+    pub pid: Pid,
+    pub shard: usize,
+    pub tests: usize,
+    // the shards they went to
+    pub into: Vec<usize>,
+    // seconds since the epoch
+    pub at: u64,
+}
+
+// What has happened in the pool, one JSON record per line.
+#[derive(Debug)]
+pub struct EventLog {
+    path: PathBuf,
+}
+
+impl EventLog {
+    pub fn new(runtime_dir: impl AsRef<Path>) -> EventLog {
+        EventLog { path: runtime_dir.as_ref().join("adp.events") }
+    }
+
+    // The runtime dir it's kept in, for other files that go with the pool.
+    pub fn runtime_dir(&self) -> &Path {
+        self.path.parent().unwrap_or(Path::new("."))
+    }
+
+    #[instrument(skip(record))]
+    pub fn append(&self, record: &impl Serialize) -> Result {
+        let line = serde_json::to_string(record)?;
+        self.rewrite(|lines| lines.push(line))
+    }
+
+    // Swaps a record appended earlier for what it turned out to be, ex: once a command it was written ahead of has
+    // finished. Appended if it's been dropped since.
+    #[instrument(skip(old, new))]
+    pub fn replace(&self, old: &impl Serialize, new: &impl Serialize) -> Result {
+        let (old, new) = (serde_json::to_string(old)?, serde_json::to_string(new)?);
+        self.rewrite(|lines| match lines.iter_mut().rfind(|line| **line == old) {
+            Some(line) => *line = new,
+            None => lines.push(new),
+        })
+    }
+
+    fn rewrite(&self, f: impl FnOnce(&mut Vec<String>)) -> Result {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&self.path)?;
+        let mut file = file.into_lock_exclusive()?;
+        let mut lines = BufReader::new(&*file).lines().collect::<std::io::Result<Vec<_>>>()?;
+        f(&mut lines);
+        let keep = lines.len().saturating_sub(MAX_RECORDS);
+        debug!(records = lines.len(), dropping = keep);
+
+        file.seek(SeekFrom::Start(0))?;
+        file.set_len(0)?;
+        for line in &lines[keep..] {
+            writeln!(&*file, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    // Whether it's holding as many records as it keeps, so older ones may have been dropped.
+    pub fn is_full(&self) -> Result<bool> {
+        if !self.path.exists() {
+            return Ok(false);
+        }
+        let file = OpenOptions::new().read(true).write(true).open(&self.path)?.into_lock_exclusive()?;
+        Ok(BufReader::new(&*file).lines().count() >= MAX_RECORDS)
+    }
+
+    // Oldest first, leaving out bypasses, which weren't jobs the pool handed a device to.
+    pub fn leases(&self) -> Result<Vec<LeaseRecord>> {
+        Ok(self.records::<LeaseRecord>()?.into_iter().filter(|lease| !lease.bypassed).collect())
+    }
+
+    // Commands run with `adp bypass`, oldest first.
+    pub fn bypasses(&self) -> Result<Vec<LeaseRecord>> {
+        Ok(self.records::<LeaseRecord>()?.into_iter().filter(|lease| lease.bypassed).collect())
+    }
+
+    // Oldest first, lines that can't be read as one, like other kinds of records, are skipped.
+    #[instrument]
+    fn records<T: DeserializeOwned>(&self) -> Result<Vec<T>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let file = OpenOptions::new().read(true).write(true).open(&self.path)?.into_lock_exclusive()?;
+        let records = BufReader::new(&*file).lines()
+            .filter_map(|line| match line.map(|line| serde_json::from_str(&line)) {
+                Ok(Ok(record)) => Some(Ok(record)),
+                Ok(Err(e)) => {
+                    debug!(skipped = %e);
+                    None
+                }
+                Err(e) => Some(Err(e.into())),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use temp_testdir::TempDir;
+
+    use crate::events::{EventLog, LeaseRecord, RebalanceRecord, MAX_RECORDS};
+
+    type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+    fn record(pid: u32) -> LeaseRecord {
+        LeaseRecord {
+            serial: "serial1".parse().unwrap(),
+            pid: pid as _,
+            user: "evan".to_string(),
+            host: "bench".to_string(),
+            cmd: "./gradlew connectedAndroidTest".to_string(),
+            team: None,
+            started_at: 100,
+            duration: 60,
+            exit_code: Some(1),
+            props: BTreeMap::from([("ro.product.model".to_string(), "Pixel 6".to_string())]),
+            latency: None,
+            bypassed: false,
+            failed_on: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn round_trips_records() -> Result {
+        let runtime_dir = TempDir::default();
+        let events = EventLog::new(&runtime_dir);
+        events.append(&record(1))?;
+        events.append(&record(2))?;
+
+        assert_eq!(events.leases()?, vec![record(1), record(2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn fills_in_records_written_ahead() -> Result {
+        let runtime_dir = TempDir::default();
+        let events = EventLog::new(&runtime_dir);
+        let started = LeaseRecord { exit_code: None, duration: 0, ..record(1) };
+        events.append(&started)?;
+        events.append(&record(2))?;
+
+        events.replace(&started, &record(1))?;
+        assert_eq!(events.leases()?, vec![record(1), record(2)]);
+        // Dropped since.
+        events.replace(&started, &record(3))?;
+        assert_eq!(events.leases()?, vec![record(1), record(2), record(3)]);
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_rebalances_apart_from_leases() -> Result {
+        let runtime_dir = TempDir::default();
+        let events = EventLog::new(&runtime_dir);
+        let rebalance = RebalanceRecord {
+            rebalanced_from: "serial2".parse().unwrap(),
+            pid: 2,
+            shard: 1,
+            tests: 40,
+            into: vec![3, 4],
+            at: 160,
+        };
+        events.append(&record(1))?;
+        events.append(&rebalance)?;
+
+        assert_eq!(events.leases()?, vec![record(1)]);
+        assert_eq!(events.records::<RebalanceRecord>()?, vec![rebalance]);
+        Ok(())
+    }
+
+    #[test]
+    fn drops_oldest_records() -> Result {
+        let runtime_dir = TempDir::default();
+        let events = EventLog::new(&runtime_dir);
+        for pid in 0..=MAX_RECORDS as u32 {
+            events.append(&record(pid))?;
+        }
+
+        let leases = events.leases()?;
+        assert_eq!(leases.len(), MAX_RECORDS);
+        assert!(events.is_full()?);
+        assert_eq!(leases[0].pid, 1);
+        Ok(())
+    }
+}
@@ -21,7 +21,7 @@ impl ExitStatusExt for ExitStatus {
         if self.success() {
             Ok(())
         } else {
-            Err(ExitStatusError(self.clone()))
+            Err(ExitStatusError(*self))
         }
     }
 }
@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::App;
+use crate::config::Config;
+use crate::runtime::{Runtime, Serial};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// Where in the config secrets are kept, ex: tokens and urls with passwords in them. Left out of exports unless asked
+// for, since they end up pasted into tickets.
+const SECRETS: &[&[&str]] = &[
+    &["api", "token"],
+    &["api", "tokens"],
+    &["provision", "wifi", "password"],
+    &["redis", "url"],
+    &["alerts", "notify"],
+];
+const REDACTED: &str = "<redacted>";
+
+// Bumped whenever older versions would misread an export, so they can refuse to import it instead.
+const VERSION: u32 = 1;
+
+// The bench's setup, to be kept under version control or copied to other runner machines. Claims aren't included,
+// they belong to processes on this machine.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PoolExport {
+    pub version: u32,
+    // The config file as is, none if there isn't one.
+    pub config: Option<toml::Table>,
+    pub devices: BTreeMap<Serial, DeviceState>,
+    pub paused: Option<PausedState>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceState {
+    pub quarantined: bool,
+    // Jobs that have failed on the device in a row.
+    pub failures: u32,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PausedState {
+    pub user: String,
+    pub reason: Option<String>,
+}
+
+#[instrument(skip(app))]
+pub fn run_export<R: Runtime + Debug>(app: &App<R>, config_path: Option<&Path>, include_secrets: bool) -> Result {
+    let mut config = match Config::path(config_path) {
+        Some(path) if path.exists() => Some(read_config(&path)?),
+        _ => None,
+    };
+    if let (Some(config), false) = (&mut config, include_secrets) {
+        redact(config);
+    }
+    println!("{}", serde_json::to_string_pretty(&export(app, config)?)?);
+    Ok(())
+}
+
+#[instrument(skip(app))]
+pub fn run_import<R: Runtime + Debug>(app: &App<R>, config_path: Option<&Path>, file: &Path, force: bool) -> Result {
+    let contents = std::fs::read_to_string(file).with_context(|| format!("failed to read {:?}", file))?;
+    let export: PoolExport = serde_json::from_str(&contents).with_context(|| format!("invalid export {:?}", file))?;
+    if export.version > VERSION {
+        return Err(anyhow!("{:?} was exported by a newer version of adp", file));
+    }
+    if let Some(config) = &export.config {
+        let path = Config::path(config_path).ok_or_else(|| anyhow!("there's no config dir to import the config to"))?;
+        let existing = if path.exists() { Some(read_config(&path)?) } else { None };
+        let mut config = config.clone();
+        unredact(&mut config, existing.as_ref()).with_context(|| format!("can't import the config in {:?}", file))?;
+        let contents = toml::to_string(&config)?;
+        Config::parse(&contents).with_context(|| format!("invalid config in {:?}", file))?;
+        if existing.is_some_and(|existing| existing != config) && !force {
+            return Err(anyhow!("{:?} already exists and is different, pass --force to replace it", path));
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, contents).with_context(|| format!("failed to write {:?}", path))?;
+        println!("imported the config to {:?}", path);
+    }
+    app.restore(&export)?;
+    println!("imported {} devices", export.devices.len());
+    Ok(())
+}
+
+pub fn export<R: Runtime + Debug>(app: &App<R>, config: Option<toml::Table>) -> Result<PoolExport> {
+    let entries = app.entries()?;
+    let devices = entries.iter()
+        .map(|(serial, entry)| {
+            (serial.clone(), DeviceState { quarantined: entry.quarantined, failures: entry.failures })
+        })
+        .collect();
+    let paused = entries.paused()
+        .map(|pause| PausedState { user: pause.user.clone(), reason: pause.reason.clone() });
+    Ok(PoolExport { version: VERSION, config, devices, paused })
+}
+
+fn read_config(path: &Path) -> Result<toml::Table> {
+    let contents = std::fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+    toml::from_str(&contents).with_context(|| format!("invalid config {:?}", path))
+}
+
+fn redact(config: &mut toml::Table) {
+    for path in SECRETS {
+        if let Some(value) = lookup(config, path) {
+            *value = toml::Value::String(REDACTED.to_string());
+        }
+    }
+}
+
+// Fills the secrets an export left out back in from the config already here.
+fn unredact(config: &mut toml::Table, existing: Option<&toml::Table>) -> Result {
+    let mut existing = existing.cloned();
+    for path in SECRETS {
+        let Some(value) = lookup(config, path) else { continue };
+        if value.as_str() != Some(REDACTED) {
+            continue;
+        }
+        *value = existing.as_mut().and_then(|existing| lookup(existing, path)).cloned().ok_or_else(|| anyhow!(
+            "it leaves out {}, export it with --include-secrets or set it in the config here first",
+            path.join("."),
+        ))?;
+    }
+    Ok(())
+}
+
+fn lookup<'a>(table: &'a mut toml::Table, path: &[&str]) -> Option<&'a mut toml::Value> {
+    let (last, parents) = path.split_last()?;
+    let mut table = table;
+    for key in parents {
+        table = table.get_mut(*key)?.as_table_mut()?;
+    }
+    table.get_mut(*last)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::export::{redact, unredact};
+
+    type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+    #[test]
+    fn leaves_secrets_out_and_fills_them_back_in_from_the_config_here() -> Result {
+        let mut config: toml::Table = toml::from_str(
+            "max_concurrent = 2\n[api]\nlisten = \"0.0.0.0:7007\"\ntoken = \"s3cret\"\n[redis]\nurl = \"redis://:pw@ci:6379\"\n",
+        )?;
+        let original = config.clone();
+
+        redact(&mut config);
+
+        let exported = toml::to_string(&config)?;
+        assert!(!exported.contains("s3cret") && !exported.contains("pw@"));
+        assert!(exported.contains("listen = \"0.0.0.0:7007\""));
+        assert!(unredact(&mut config.clone(), None).is_err());
+        unredact(&mut config, Some(&original))?;
+        assert_eq!(config, original);
+        Ok(())
+    }
+}
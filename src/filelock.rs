@@ -1,14 +1,32 @@
 use core::result::Result::Ok;
-use std::fs::File;
-use std::io::Result;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Result};
 use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
 
+use anyhow::anyhow;
 use fs2::FileExt;
 
+// The hidden command that checks a lock from another process, and where it finds the file to check.
+pub const PROBE_COMMAND: &str = "lock-probe";
+const PROBE_VAR: &str = "ADP_LOCK_PROBE";
+// How the probe exits, anything else means it couldn't check, ex: it wasn't adp.
+const KEPT_OUT: i32 = 3;
+const LET_IN: i32 = 4;
+
+#[derive(Debug)]
 pub(crate) struct FileLockGuard(File);
 
 pub(crate) trait FileLockGuardExt {
     fn into_lock_exclusive(self) -> Result<FileLockGuard>;
+
+    // Fails with WouldBlock if someone else holds the lock.
+    fn try_into_lock_exclusive(self) -> Result<FileLockGuard>;
+
+    // For network filesystems, where a blocking lock can hang forever if the server goes away.
+    fn into_lock_exclusive_timeout(self, timeout: Duration) -> Result<FileLockGuard>;
 }
 
 impl FileLockGuardExt for File {
@@ -16,6 +34,27 @@ impl FileLockGuardExt for File {
         self.lock_exclusive()?;
         Ok(FileLockGuard(self))
     }
+
+    fn try_into_lock_exclusive(self) -> Result<FileLockGuard> {
+        self.try_lock_exclusive()?;
+        Ok(FileLockGuard(self))
+    }
+
+    fn into_lock_exclusive_timeout(self, timeout: Duration) -> Result<FileLockGuard> {
+        let start = Instant::now();
+        let mut backoff = Duration::from_millis(10);
+        loop {
+            // NFS can fail lock requests for transient reasons (ENOLCK, lost server state) so retry on any error.
+            match self.try_lock_exclusive() {
+                Ok(_) => return Ok(FileLockGuard(self)),
+                Err(e) if start.elapsed() >= timeout => return Err(e),
+                Err(_) => {
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(Duration::from_millis(500));
+                }
+            }
+        }
+    }
 }
 
 impl Drop for FileLockGuard {
@@ -37,3 +76,104 @@ impl DerefMut for FileLockGuard {
         &mut self.0
     }
 }
+
+// Whether locks taken on files in the directory keep others out, some network and FUSE filesystems accept them without
+// enforcing anything. Checked from the given probe command, as on NFS and CIFS Linux emulates these locks with fcntl
+// ones, which never conflict within one process. Errors if they can't be taken at all.
+pub(crate) fn supports_locking(dir: &Path, probe: &mut Command) -> Result<bool> {
+    let path = dir.join(format!(".adp-lock-check-{}", std::process::id()));
+    let mut check = || {
+        let _held = OpenOptions::new().write(true).create(true).truncate(false).open(&path)?.into_lock_exclusive()?;
+        let status = probe.env(PROBE_VAR, &path).stdout(Stdio::null()).stderr(Stdio::null()).status()?;
+        match status.code() {
+            Some(KEPT_OUT) => Ok(true),
+            Some(LET_IN) => Ok(false),
+            _ => Err(Error::other(format!("the lock probe failed with {}", status))),
+        }
+    };
+    let supported = check();
+    let _ = std::fs::remove_file(&path);
+    supported
+}
+
+// Run as the probe command, tries to take the lock on the file supports_locking holds and returns the exit code.
+pub(crate) fn probe() -> i32 {
+    let Some(path) = std::env::var_os(PROBE_VAR) else {
+        return 1;
+    };
+    match OpenOptions::new().write(true).open(path).and_then(|file| file.try_into_lock_exclusive()) {
+        Ok(_) => LET_IN,
+        Err(e) if e.kind() == ErrorKind::WouldBlock => KEPT_OUT,
+        Err(_) => 1,
+    }
+}
+
+// The test binary standing in for adp, running the test that acts as the probe command.
+#[cfg(test)]
+pub(crate) fn test_probe() -> Command {
+    let mut command = Command::new(std::env::current_exe().unwrap());
+    command.args(["--exact", "filelock::tests::probes_for_the_other_tests", "--nocapture"]);
+    command
+}
+
+// The OS keeping adp out rather than something going wrong, ex: SELinux on a hardened CI image, a read-only mount or a
+// filesystem that can't lock files.
+pub(crate) fn is_denied(e: &Error) -> bool {
+    matches!(e.kind(), ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem | ErrorKind::Unsupported)
+}
+
+// What went wrong with a file adp keeps its state in, and what to do about it if the OS kept it out.
+pub(crate) fn explain(e: Error, path: &Path) -> anyhow::Error {
+    if !is_denied(&e) {
+        return anyhow::Error::new(e).context(format!("failed to open {:?}", path));
+    }
+    anyhow!(
+        "can't use {:?}: {}. adp needs to be able to create, write and lock files in its runtime dir, check it's writable \
+        by this user and whether SELinux or AppArmor is denying it (ls -Z, ausearch -m avc), or keep the pool elsewhere \
+        with --runtime-dir, or in redis with [redis] in the config", path, e,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_testdir::TempDir;
+
+    use std::io::{Error, ErrorKind};
+    use std::path::Path;
+
+    use crate::filelock::{explain, probe, supports_locking, test_probe, PROBE_VAR};
+
+    #[test]
+    fn checks_locks_are_enforced() -> std::io::Result<()> {
+        let dir = TempDir::default();
+
+        assert!(supports_locking(&dir, &mut test_probe())?);
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn fails_when_the_probe_does() {
+        let dir = TempDir::default();
+
+        assert!(supports_locking(&dir, &mut std::process::Command::new("false")).is_err());
+    }
+
+    // Does nothing unless run by test_probe.
+    #[test]
+    fn probes_for_the_other_tests() {
+        if std::env::var_os(PROBE_VAR).is_some() {
+            std::process::exit(probe());
+        }
+    }
+
+    #[test]
+    fn explains_what_to_do_when_denied() {
+        let path = Path::new("/run/user/1000/adp/adp.lock");
+        let denied = explain(Error::from(ErrorKind::PermissionDenied), path).to_string();
+        let missing = explain(Error::from(ErrorKind::NotFound), path).to_string();
+
+        assert!(denied.contains("SELinux") && denied.contains("--runtime-dir"), "{}", denied);
+        assert_eq!(missing, "failed to open \"/run/user/1000/adp/adp.lock\"");
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+use anyhow::anyhow;
+use clap::ValueEnum;
+use regex::Regex;
+
+use crate::device_info::DeviceInfo;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// Something a device has to have for a job to run on it, devices that don't are left for other jobs.
+#[derive(Debug, Clone)]
+pub enum DeviceFilter {
+    // ex: ro.product.model=Pixel 7
+    Prop { name: String, value: String },
+    // ex: ro.product.model=Pixel [78], matched against the whole value
+    PropRegex { name: String, regex: Regex },
+    Require(Capability),
+    // dpi
+    MinDensity(u32),
+    // Attached to this host rather than through an adb server on another one.
+    Local,
+    // In the locale already or rooted so it can be put in it, ex: fr-FR.
+    Locale(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Capability {
+    Root,
+    // Google Play Services
+    Gms,
+}
+
+impl DeviceFilter {
+    pub fn prop(arg: &str) -> Result<DeviceFilter> {
+        let (name, value) = split(arg)?;
+        Ok(DeviceFilter::Prop { name, value })
+    }
+
+    pub fn prop_regex(arg: &str) -> Result<DeviceFilter> {
+        let (name, pattern) = split(arg)?;
+        let regex = Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| anyhow!("invalid regex for {}: {}", name, e))?;
+        Ok(DeviceFilter::PropRegex { name, regex })
+    }
+
+    pub fn require(arg: &str) -> Result<DeviceFilter> {
+        let capability = Capability::from_str(arg, true).map_err(|_| anyhow!("unknown capability {}, expected root or gms", arg))?;
+        Ok(DeviceFilter::Require(capability))
+    }
+
+    pub fn min_density(arg: &str) -> Result<DeviceFilter> {
+        Ok(DeviceFilter::MinDensity(arg.parse().map_err(|_| anyhow!("invalid density {}", arg))?))
+    }
+
+    // Props that need reading from the device to check it.
+    pub fn props(&self) -> Vec<&str> {
+        match self {
+            DeviceFilter::Prop { name, .. } | DeviceFilter::PropRegex { name, .. } => vec![name],
+            DeviceFilter::Locale(_) => LOCALE_PROPS.to_vec(),
+            DeviceFilter::Require(_) | DeviceFilter::MinDensity(_) | DeviceFilter::Local => vec![],
+        }
+    }
+
+    // Whether the device's capabilities need probing to check it.
+    pub fn probes(&self) -> bool {
+        matches!(self, DeviceFilter::Require(_) | DeviceFilter::MinDensity(_) | DeviceFilter::Locale(_))
+    }
+
+    // Whether some of the devices it matches suit the job better than the rest.
+    pub fn ranks(&self) -> bool {
+        matches!(self, DeviceFilter::Locale(_))
+    }
+
+    // Whether a device it matches is one of the better ones, ex: already in the locale rather than having to have its
+    // framework restarted to be put in it.
+    pub fn prefers(&self, device: &DeviceInfo) -> bool {
+        match self {
+            DeviceFilter::Locale(locale) => locale_of(&device.props) == Some(locale),
+            _ => true,
+        }
+    }
+
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        let capabilities = device.capabilities.as_ref();
+        match self {
+            DeviceFilter::Prop { name, value } => device.props.get(name) == Some(value),
+            DeviceFilter::PropRegex { name, regex } => device.props.get(name).is_some_and(|value| regex.is_match(value)),
+            DeviceFilter::Require(Capability::Root) => capabilities.is_some_and(|capabilities| capabilities.root),
+            DeviceFilter::Require(Capability::Gms) => capabilities.is_some_and(|capabilities| capabilities.gms),
+            DeviceFilter::MinDensity(min) => {
+                capabilities.and_then(|capabilities| capabilities.density).is_some_and(|density| density >= *min)
+            }
+            DeviceFilter::Local => device.host.is_none(),
+            DeviceFilter::Locale(locale) => {
+                locale_of(&device.props) == Some(locale) || capabilities.is_some_and(|capabilities| capabilities.root)
+            }
+        }
+    }
+}
+
+// The one set by the user, or the one it came with if it's never been changed.
+const LOCALE_PROPS: [&str; 2] = ["persist.sys.locale", "ro.product.locale"];
+
+fn locale_of(props: &BTreeMap<String, String>) -> Option<&String> {
+    LOCALE_PROPS.iter().filter_map(|name| props.get(*name)).find(|locale| !locale.is_empty())
+}
+
+impl Display for DeviceFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceFilter::Prop { name, value } => write!(f, "{}={}", name, value),
+            DeviceFilter::PropRegex { name, regex } => {
+                let pattern = regex.as_str();
+                write!(f, "{}~{}", name, &pattern[4..pattern.len() - 2])
+            }
+            DeviceFilter::Require(Capability::Root) => write!(f, "root"),
+            DeviceFilter::Require(Capability::Gms) => write!(f, "gms"),
+            DeviceFilter::MinDensity(min) => write!(f, "density>={}", min),
+            DeviceFilter::Local => write!(f, "local"),
+            DeviceFilter::Locale(locale) => write!(f, "locale={}", locale),
+        }
+    }
+}
+
+fn split(arg: &str) -> Result<(String, String)> {
+    let (name, value) = arg.split_once('=').ok_or_else(|| anyhow!("expected <prop>=<value>, got {}", arg))?;
+    if name.is_empty() {
+        return Err(anyhow!("missing the prop name in {}", arg));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::device_info::{Capabilities, DeviceInfo, Transport};
+    use crate::filter::DeviceFilter;
+    use crate::runtime::Serial;
+
+    type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+    #[test]
+    fn matches_props() -> Result {
+        let device = DeviceInfo {
+            serial: Serial::new("serial1")?,
+            state: "device".to_string(),
+            model: Some("Pixel 7".to_string()),
+            abi: None,
+            sdk: None,
+            transport: Transport::Usb,
+            host: None,
+            props: BTreeMap::from([("ro.product.model".to_string(), "Pixel 7".to_string())]),
+            capabilities: None,
+        };
+
+        assert!(DeviceFilter::prop("ro.product.model=Pixel 7")?.matches(&device));
+        assert!(!DeviceFilter::prop("ro.product.model=Pixel")?.matches(&device));
+        assert!(DeviceFilter::prop_regex("ro.product.model=Pixel [78]")?.matches(&device));
+        assert!(!DeviceFilter::prop_regex("ro.product.model=Pixel")?.matches(&device));
+        assert!(!DeviceFilter::prop("ro.build.type=user")?.matches(&device));
+        assert_eq!(DeviceFilter::prop_regex("ro.product.model=Pixel [78]")?.to_string(), "ro.product.model~Pixel [78]");
+        assert!(DeviceFilter::prop("ro.product.model").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn matches_capabilities() -> Result {
+        let device = DeviceInfo {
+            serial: Serial::new("serial1")?,
+            state: "device".to_string(),
+            model: None,
+            abi: None,
+            sdk: None,
+            transport: Transport::Usb,
+            host: None,
+            props: BTreeMap::new(),
+            capabilities: Some(Capabilities { screen_size: None, density: Some(420), gms: true, root: false }),
+        };
+
+        assert!(DeviceFilter::min_density("420")?.matches(&device));
+        assert!(!DeviceFilter::min_density("440")?.matches(&device));
+        assert!(DeviceFilter::require("gms")?.matches(&device));
+        assert!(!DeviceFilter::require("root")?.matches(&device));
+        assert!(DeviceFilter::require("wifi").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn matches_devices_in_the_locale_or_that_can_be_put_in_it() -> Result {
+        let mut device = DeviceInfo {
+            serial: Serial::new("serial1")?,
+            state: "device".to_string(),
+            model: None,
+            abi: None,
+            sdk: None,
+            transport: Transport::Usb,
+            host: None,
+            props: BTreeMap::from([
+                ("persist.sys.locale".to_string(), "".to_string()),
+                ("ro.product.locale".to_string(), "fr-FR".to_string()),
+            ]),
+            capabilities: Some(Capabilities::default()),
+        };
+        let locale = DeviceFilter::Locale("fr-FR".to_string());
+
+        assert!(locale.matches(&device));
+        assert!(!DeviceFilter::Locale("de-DE".to_string()).matches(&device));
+        device.props.insert("persist.sys.locale".to_string(), "en-US".to_string());
+        assert!(!locale.matches(&device));
+        device.capabilities = Some(Capabilities { root: true, ..Capabilities::default() });
+        assert!(locale.matches(&device));
+        assert!(!locale.prefers(&device));
+        device.props.insert("persist.sys.locale".to_string(), "fr-FR".to_string());
+        assert!(locale.prefers(&device));
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+use std::fmt::{Display, Formatter};
+
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use tracing::instrument;
+
+use crate::config::FixturesConfig;
+use crate::emulator::{self, Console};
+use crate::runtime::{Runtime, Serial};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// The developer option that limits a real device's download rate, from Android 13 on. Real devices can't be shaped any
+// other way without root.
+const RATE_LIMIT_SETTING: &str = "ingress_rate_limit_bytes_per_second";
+const RATE_LIMIT_MIN_SDK: u32 = 33;
+// The download speed of each of the console's network speeds, in kbps.
+const SPEEDS: &[(&str, f64)] = &[
+    ("gsm", 14.4),
+    ("hscsd", 57.6),
+    ("gprs", 57.6),
+    ("edge", 473.6),
+    ("umts", 384.0),
+    ("hsdpa", 13980.0),
+    ("lte", 173000.0),
+    ("evdo", 75600.0),
+];
+
+// Where an emulator says it is, as latitude and longitude, ex: 37.42 -122.08.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Geo {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl TryFrom<String> for Geo {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        let invalid = || anyhow!("expected a latitude and longitude like \"37.42 -122.08\", got \"{}\"", value);
+        match value.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [latitude, longitude] => {
+                let geo = Geo {
+                    latitude: latitude.parse().map_err(|_| invalid())?,
+                    longitude: longitude.parse().map_err(|_| invalid())?,
+                };
+                if geo.latitude.abs() > 90.0 || geo.longitude.abs() > 180.0 {
+                    return Err(invalid());
+                }
+                Ok(geo)
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+// How charged an emulator's battery is, in percent.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "u8")]
+pub struct Charge(u8);
+
+impl TryFrom<u8> for Charge {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+        match value {
+            0..=100 => Ok(Charge(value)),
+            _ => Err(anyhow!("expected a battery percentage up to 100, got {}", value)),
+        }
+    }
+}
+
+impl Display for Charge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Display for Geo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.latitude, self.longitude)
+    }
+}
+
+// Puts the emulator on the given console port into the state the config asks for before a job runs on it, so tests
+// don't each have to. Unlike provisioning, an emulator that can't be set up fails the job, it would only fail its
+// tests otherwise.
+#[instrument]
+pub fn apply(port: u16, config: &FixturesConfig) -> Result {
+    send(port, &commands(config))
+}
+
+// Undoes what apply changed that would otherwise outlast the job, ex: a drained battery the next job doesn't expect.
+#[instrument]
+pub fn restore(port: u16, config: &FixturesConfig) -> Result {
+    send(port, &restore_commands(config))
+}
+
+// Shapes the emulator's network for a job, reset_network puts it back once it's done so the next job doesn't get a
+// throttled one.
+#[instrument]
+pub fn shape_network(port: u16, speed: Option<&str>, delay: Option<&str>) -> Result {
+    send(port, &network_commands(speed, delay))
+}
+
+#[instrument]
+pub fn reset_network(port: u16) -> Result {
+    send(port, &network_commands(Some("full"), Some("none")))
+}
+
+// Like shape_network for a real device, only its download rate can be limited.
+#[instrument(skip(runtime))]
+pub fn shape_device_network(runtime: &impl Runtime, serial: &Serial, speed: &str) -> Result {
+    let sdk = runtime.getprop(serial, "ro.build.version.sdk")?;
+    if sdk.parse::<u32>().map_or(true, |sdk| sdk < RATE_LIMIT_MIN_SDK) {
+        return Err(anyhow!("only emulators and devices on Android 13 or later can have their network shaped, {} is on sdk {}", serial, sdk));
+    }
+    let rate = download_rate(speed)?.map_or("-1".to_string(), |rate| rate.to_string());
+    runtime.run_adb(serial, &["shell", "settings", "put", "global", RATE_LIMIT_SETTING, &rate])?;
+    Ok(())
+}
+
+#[instrument(skip(runtime))]
+pub fn reset_device_network(runtime: &impl Runtime, serial: &Serial) -> Result {
+    runtime.run_adb(serial, &["shell", "settings", "put", "global", RATE_LIMIT_SETTING, "-1"])?;
+    Ok(())
+}
+
+// In bytes per second, None for full speed. Takes the same speeds as the console, ex: edge, or <up>:<down> in kbps.
+fn download_rate(speed: &str) -> Result<Option<u64>> {
+    let kbps = match SPEEDS.iter().find(|(name, _)| *name == speed) {
+        Some((_, kbps)) => *kbps,
+        None if speed == "full" => return Ok(None),
+        None => {
+            let down = speed.rsplit_once(':').map_or(speed, |(_, down)| down);
+            down.parse::<f64>().ok().filter(|kbps| *kbps > 0.0)
+                .ok_or_else(|| anyhow!("invalid network speed {:?}, expected one like edge, or <up>:<down> in kbps", speed))?
+        }
+    };
+    Ok(Some((kbps * 1000.0 / 8.0) as u64))
+}
+
+fn send(port: u16, commands: &[String]) -> Result {
+    if commands.is_empty() {
+        return Ok(());
+    }
+    let mut console = Console::connect(port, emulator::auth_token().as_deref())?;
+    for command in commands {
+        console.send(command).with_context(|| format!("the emulator refused `{}`", command))?;
+    }
+    Ok(())
+}
+
+fn network_commands(speed: Option<&str>, delay: Option<&str>) -> Vec<String> {
+    speed.map(|speed| format!("network speed {}", speed)).into_iter()
+        .chain(delay.map(|delay| format!("network delay {}", delay)))
+        .collect()
+}
+
+fn restore_commands(config: &FixturesConfig) -> Vec<String> {
+    match config.battery {
+        Some(_) => vec!["power ac on".to_string(), "power capacity 100".to_string()],
+        None => Vec::new(),
+    }
+}
+
+fn commands(config: &FixturesConfig) -> Vec<String> {
+    let mut commands = Vec::new();
+    if let Some(geo) = config.geo {
+        // The console takes longitude first.
+        commands.push(format!("geo fix {} {}", geo.longitude, geo.latitude));
+    }
+    if let Some(battery) = config.battery {
+        // Otherwise it charges back up from the virtual charger.
+        commands.push("power ac off".to_string());
+        commands.push(format!("power capacity {}", battery));
+    }
+    for (sensor, values) in &config.sensors {
+        commands.push(format!("sensor set {} {}", sensor, values));
+    }
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::fixtures::{commands, download_rate, network_commands, restore_commands, Geo};
+
+    use super::Result;
+
+    #[test]
+    fn turns_fixtures_into_console_commands() -> Result {
+        let config = Config::parse("[fixtures]\ngeo = \"37.42 -122.08\"\nbattery = 50\nsensors = { acceleration = \"0:9.8:0\" }\n")?;
+
+        assert_eq!(commands(&config.fixtures), [
+            "geo fix -122.08 37.42", "power ac off", "power capacity 50", "sensor set acceleration 0:9.8:0",
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn puts_the_battery_back_on_the_charger() -> Result {
+        let config = Config::parse("[fixtures]\nbattery = 50\n")?;
+
+        assert_eq!(restore_commands(&config.fixtures), ["power ac on", "power capacity 100"]);
+        assert!(restore_commands(&Config::parse("[fixtures]\ngeo = \"37.42 -122.08\"\n")?.fixtures).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_batteries_over_full() {
+        assert!(Config::parse("[fixtures]\nbattery = 101\n").is_err());
+    }
+
+    #[test]
+    fn shapes_only_what_was_asked_for() {
+        assert_eq!(network_commands(Some("edge"), None), ["network speed edge"]);
+        assert_eq!(network_commands(Some("full"), Some("none")), ["network speed full", "network delay none"]);
+        assert!(network_commands(None, None).is_empty());
+    }
+
+    #[test]
+    fn limits_real_devices_to_the_download_speed() -> Result {
+        assert_eq!(download_rate("edge")?, Some(59200));
+        assert_eq!(download_rate("128:1024")?, Some(128000));
+        assert_eq!(download_rate("full")?, None);
+        assert!(download_rate("warp").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_locations() {
+        assert!(Geo::try_from("37.42".to_string()).is_err());
+        assert!(Geo::try_from("north west".to_string()).is_err());
+        assert!(Geo::try_from("-122.08 37.42".to_string()).is_err());
+    }
+}
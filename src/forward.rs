@@ -0,0 +1,89 @@
+use std::fmt::{Display, Formatter};
+
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use tracing::instrument;
+
+use crate::config::AdbConfig;
+use crate::runtime::{Runtime, Serial};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// Two adb socket specs, ex: tcp:8081 tcp:8081. For a forward the first is on the host, for a reverse on the device.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Forward {
+    pub from: String,
+    pub to: String,
+}
+
+impl TryFrom<String> for Forward {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        match value.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [from, to] => Ok(Forward { from: from.to_string(), to: to.to_string() }),
+            _ => Err(anyhow!("expected two socket specs like \"tcp:8081 tcp:8081\", got \"{}\"", value)),
+        }
+    }
+}
+
+impl Display for Forward {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.from, self.to)
+    }
+}
+
+// Sets up the configured forwards and reverses on the device for a job, adding each to set_up as it goes so tear_down
+// removes only those once it's done, they'd get in the way of the next job. Forwards are on ports of the host, which
+// every job shares, so one another job already has fails rather than being taken over.
+#[instrument(skip(runtime, set_up))]
+pub fn set_up<'a>(
+    runtime: &impl Runtime,
+    serial: &Serial,
+    config: &'a AdbConfig,
+    set_up: &mut Vec<(&'static str, &'a Forward)>,
+) -> Result {
+    for forward in &config.forward {
+        runtime.run_adb(serial, &["forward", "--no-rebind", &forward.from, &forward.to])
+            .with_context(|| format!(
+                "failed to forward {} on {}, another job may have the port, give each its own with [ports]",
+                forward, serial,
+            ))?;
+        set_up.push(("forward", forward));
+    }
+    for forward in &config.reverse {
+        runtime.run_adb(serial, &["reverse", &forward.from, &forward.to])
+            .with_context(|| format!("failed to reverse {} on {}", forward, serial))?;
+        set_up.push(("reverse", forward));
+    }
+    Ok(())
+}
+
+// Carries on past ones that fail.
+#[instrument(skip(runtime))]
+pub fn tear_down(runtime: &impl Runtime, serial: &Serial, set_up: &[(&str, &Forward)]) {
+    for (command, forward) in set_up {
+        if let Err(e) = runtime.run_adb(serial, &[command, "--remove", &forward.from]) {
+            eprintln!("adp: failed to remove {} {} on {}: {:#}", command, forward, serial, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::forward::Forward;
+
+    type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+    #[test]
+    fn parses_forwards() -> Result {
+        let config = Config::parse("[adb]\nforward = [\"tcp:8081  tcp:8081\"]\nreverse = [\"tcp:9000 tcp:8000\"]\n")?;
+
+        assert_eq!(config.adb.forward, [Forward { from: "tcp:8081".to_string(), to: "tcp:8081".to_string() }]);
+        assert_eq!(config.adb.reverse[0].to_string(), "tcp:9000 tcp:8000");
+        assert!(Config::parse("[adb]\nforward = [\"tcp:8081\"]\n").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::config::HeartbeatConfig;
+use crate::lockfile::Entry;
+
+// Whether this process keeps its claims alive by heartbeats instead of by its pid. Processes in different containers
+// sharing a runtime dir each have their own pids, so a pid in the lock file means nothing to the others.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeartbeatMode {
+    // Only from inside a container.
+    #[default]
+    Auto,
+    On,
+    Off,
+}
+
+// Files container runtimes leave in the root of every container, docker and podman.
+const MARKERS: &[&str] = &["/.dockerenv", "/run/.containerenv"];
+// Found in the cgroups of processes in a container, on hosts still on cgroup v1.
+const CGROUP_RUNTIMES: &[&str] = &["docker", "kubepods", "containerd", "libpod", "lxc"];
+
+pub fn enabled(config: &HeartbeatConfig) -> bool {
+    match config.mode {
+        HeartbeatMode::Auto => {
+            let in_container = in_container();
+            debug!(in_container);
+            in_container
+        }
+        HeartbeatMode::On => true,
+        HeartbeatMode::Off => false,
+    }
+}
+
+pub fn in_container() -> bool {
+    MARKERS.iter().any(|marker| Path::new(marker).exists())
+        || std::fs::read_to_string("/proc/1/cgroup").is_ok_and(|cgroup| in_container_cgroup(&cgroup))
+}
+
+fn in_container_cgroup(cgroup: &str) -> bool {
+    cgroup.lines().any(|line| CGROUP_RUNTIMES.iter().any(|runtime| line.contains(runtime)))
+}
+
+// Often enough that a slow beat or two doesn't lose the claim.
+pub fn interval(timeout: Duration) -> Duration {
+    timeout / 3
+}
+
+// Whether the claim is kept alive by heartbeats and the job has stopped sending them.
+pub fn expired(entry: &Entry, timeout: Duration, now: SystemTime) -> bool {
+    entry.pid.is_some()
+        && entry.heartbeat_at.is_some_and(|at| now.duration_since(at).unwrap_or_default() > timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::heartbeat::{expired, in_container_cgroup};
+    use crate::lockfile::Entry;
+
+    #[test]
+    fn finds_container_runtimes_in_cgroups() {
+        assert!(in_container_cgroup("12:pids:/docker/3f2a9c\n11:memory:/docker/3f2a9c\n"));
+        assert!(in_container_cgroup("1:name=systemd:/kubepods/besteffort/pod1234/abcd\n"));
+        assert!(!in_container_cgroup("12:pids:/user.slice/user-1000.slice\n"));
+        assert!(!in_container_cgroup("0::/\n"));
+    }
+
+    #[test]
+    fn expires_claims_that_stopped_beating() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let timeout = Duration::from_secs(30);
+        let beat = |secs_ago| Entry {
+            pid: Some(1),
+            heartbeat_at: Some(now - Duration::from_secs(secs_ago)),
+            ..Entry::default()
+        };
+
+        assert!(!expired(&beat(10), timeout, now));
+        assert!(expired(&beat(31), timeout, now));
+        // Checked by its pid instead.
+        assert!(!expired(&Entry { pid: Some(1), ..Entry::default() }, timeout, now));
+    }
+}
@@ -0,0 +1,180 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::Context;
+use tracing::{debug, instrument};
+
+use crate::device_info::DeviceInfo;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// Marks a report that already has the device in it, ex: from a retry.
+const SERIAL_PROPERTY: &str = "adp.serial";
+
+// The device's properties to add to each test suite, so CI reports show which device ran each shard.
+pub fn properties(device: &DeviceInfo) -> Vec<(&'static str, String)> {
+    [
+        Some((SERIAL_PROPERTY, device.serial.to_string())),
+        device.model.clone().map(|model| ("adp.model", model)),
+        device.sdk.map(|sdk| ("adp.api", sdk.to_string())),
+        device.abi.clone().map(|abi| ("adp.abi", abi)),
+    ].into_iter().flatten().collect()
+}
+
+// Adds the properties to the reports at the path, a file or a directory of them, that were written since the job
+// started. Nothing in a report says which job wrote it, so shards running at once need a directory each, or one would
+// tag the other's reports with its device. Returns the reports it added them to.
+#[instrument]
+pub fn append(path: &Path, properties: &[(&str, String)], since: SystemTime) -> Result<Vec<PathBuf>> {
+    let mut appended = Vec::new();
+    for report in reports(path)? {
+        let written_at = std::fs::metadata(&report)?.modified()?;
+        if written_at < since {
+            debug!(older = ?report);
+            continue;
+        }
+        let xml = std::fs::read_to_string(&report).with_context(|| format!("failed to read {:?}", report))?;
+        if xml.contains(&format!("name=\"{}\"", SERIAL_PROPERTY)) {
+            continue;
+        }
+        std::fs::write(&report, add_properties(&xml, properties))
+            .with_context(|| format!("failed to write {:?}", report))?;
+        appended.push(report);
+    }
+    Ok(appended)
+}
+
+fn reports(path: &Path) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(if path.exists() { vec![path.to_path_buf()] } else { Vec::new() });
+    }
+    let mut found = Vec::new();
+    for entry in std::fs::read_dir(path).with_context(|| format!("failed to read {:?}", path))? {
+        let path = entry?.path();
+        // Gradle nests them a couple of directories deep, ex: connected/<device>/TEST-*.xml.
+        if path.is_dir() {
+            found.extend(reports(&path)?);
+        } else if path.extension().is_some_and(|extension| extension == "xml") {
+            found.push(path);
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+// Puts the properties first in every <testsuite>, in its <properties> if it has one.
+fn add_properties(xml: &str, properties: &[(&str, String)]) -> String {
+    let elements: String = properties.iter()
+        .map(|(name, value)| format!("<property name=\"{}\" value=\"{}\"/>", escape(name), escape(value)))
+        .collect();
+    let mut out = String::with_capacity(xml.len() + elements.len());
+    let mut rest = xml;
+    while let Some(start) = find_suite(rest) {
+        let Some(end) = tag_end(&rest[start..]).map(|end| start + end) else {
+            break;
+        };
+        let tag = &rest[start..end];
+        out.push_str(&rest[..start]);
+        rest = &rest[end..];
+        if let Some(tag) = tag.strip_suffix("/>") {
+            out.push_str(&format!("{}><properties>{}</properties></testsuite>", tag, elements));
+            continue;
+        }
+        out.push_str(tag);
+        let content = rest.trim_start();
+        let space = &rest[..rest.len() - content.len()];
+        if let Some(after) = content.strip_prefix("<properties/>") {
+            out.push_str(&format!("{}<properties>{}</properties>", space, elements));
+            rest = after;
+        } else if let Some(after) = content.strip_prefix("<properties>") {
+            out.push_str(&format!("{}<properties>{}", space, elements));
+            rest = after;
+        } else {
+            out.push_str(&format!("<properties>{}</properties>", elements));
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+// Where the next <testsuite> starts, not counting <testsuites>.
+fn find_suite(xml: &str) -> Option<usize> {
+    let mut from = 0;
+    while let Some(found) = xml[from..].find("<testsuite") {
+        let start = from + found;
+        let next = xml[start + "<testsuite".len()..].chars().next();
+        if next.is_some_and(|c| c.is_whitespace() || c == '>' || c == '/') {
+            return Some(start);
+        }
+        from = start + 1;
+    }
+    None
+}
+
+// Just past the tag's closing '>', skipping any in its attributes' values.
+fn tag_end(tag: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in tag.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            (None, '>') => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn escape(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use temp_testdir::TempDir;
+
+    use crate::junit::{add_properties, append};
+
+    type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+    fn properties() -> Vec<(&'static str, String)> {
+        vec![("adp.serial", "emulator-5554".to_string()), ("adp.model", "Pixel \"7\"".to_string())]
+    }
+
+    #[test]
+    fn adds_properties_to_each_suite() {
+        let xml = "<?xml version=\"1.0\"?>\n<testsuites>\n<testsuite name=\"a\" tests=\"1\">\n<testcase name=\"t\"/>\n</testsuite>\n<testsuite name=\"b\"/>\n</testsuites>\n";
+
+        assert_eq!(add_properties(xml, &properties()), "<?xml version=\"1.0\"?>\n<testsuites>\n<testsuite name=\"a\" tests=\"1\"><properties><property name=\"adp.serial\" value=\"emulator-5554\"/><property name=\"adp.model\" value=\"Pixel &quot;7&quot;\"/></properties>\n<testcase name=\"t\"/>\n</testsuite>\n<testsuite name=\"b\"><properties><property name=\"adp.serial\" value=\"emulator-5554\"/><property name=\"adp.model\" value=\"Pixel &quot;7&quot;\"/></properties></testsuite>\n</testsuites>\n");
+    }
+
+    #[test]
+    fn adds_to_existing_properties() {
+        let xml = "<testsuite name=\"a>b\">\n  <properties>\n    <property name=\"device\" value=\"x\"/>\n  </properties>\n</testsuite>";
+
+        assert_eq!(add_properties(xml, &properties()[..1]), "<testsuite name=\"a>b\">\n  <properties><property name=\"adp.serial\" value=\"emulator-5554\"/>\n    <property name=\"device\" value=\"x\"/>\n  </properties>\n</testsuite>");
+    }
+
+    #[test]
+    fn only_touches_reports_the_job_wrote() -> Result {
+        let dir = TempDir::default();
+        let connected = dir.join("connected").join("emulator-5554");
+        std::fs::create_dir_all(&connected)?;
+        std::fs::write(connected.join("TEST-a.xml"), "<testsuite name=\"a\"/>")?;
+        std::fs::write(connected.join("notes.txt"), "<testsuite name=\"a\"/>")?;
+        let since = SystemTime::now() + Duration::from_secs(60);
+        std::fs::write(dir.join("TEST-old.xml"), "<testsuite name=\"old\"/>")?;
+        std::fs::File::options().write(true).open(dir.join("TEST-old.xml"))?.set_modified(SystemTime::UNIX_EPOCH)?;
+        std::fs::File::options().write(true).open(connected.join("TEST-a.xml"))?.set_modified(since)?;
+
+        assert_eq!(append(&dir, &properties(), since)?, vec![connected.join("TEST-a.xml")]);
+        assert!(std::fs::read_to_string(connected.join("TEST-a.xml"))?.contains("adp.serial"));
+        assert_eq!(std::fs::read_to_string(dir.join("TEST-old.xml"))?, "<testsuite name=\"old\"/>");
+        // Not twice.
+        assert!(append(&connected.join("TEST-a.xml"), &properties(), SystemTime::UNIX_EPOCH)?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+use std::fmt::Debug;
+use std::io::{BufRead, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use sysinfo::{Pid, ProcessExt, Signal, System, SystemExt};
+use tracing::{debug, instrument};
+
+use crate::App;
+use crate::runtime::{Runtime, Serial};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// How long to give a process to exit after SIGTERM before sending SIGKILL.
+const TERM_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Kills the process holding the given serial (or the given pid) along with everything it started, and puts the
+// device back in the pool.
+#[instrument(skip(app))]
+pub fn run<R: Runtime + Debug>(app: &App<R>, target: &str, force: bool) -> Result {
+    let (serial, pid) = find_claim(app, target)?;
+
+    if !force && !confirm(&format!("kill {} which is using {}?", pid, serial))? {
+        return Ok(());
+    }
+
+    let remote = app.entries()?.get(&serial)
+        .filter(|entry| !app.is_local(entry))
+        .and_then(|entry| entry.owner.as_ref().map(|owner| owner.host.clone()));
+    if let Some(host) = remote {
+        // The pid belongs to another host, all we can do from here is take the device back.
+        app.evict(&serial, pid)?;
+        println!("released {}, {} on {} may still be running", serial, pid, host);
+        return Ok(());
+    }
+
+    terminate(pid);
+    app.evict(&serial, pid)?;
+    println!("killed {} and released {}", pid, serial);
+    Ok(())
+}
+
+fn find_claim<R: Runtime + Debug>(app: &App<R>, target: &str) -> Result<(Serial, Pid)> {
+    let entries = app.entries()?;
+    if let Some(serial) = target.parse::<Serial>().ok().filter(|serial| entries.contains(serial)) {
+        return match entries.unavialble().find(|(s, _)| **s == serial) {
+            Some((_, pid)) => Ok((serial, *pid)),
+            None => Err(anyhow!("{} isn't in use", serial)),
+        };
+    }
+    let pid: Pid = target.parse().map_err(|_| anyhow!("{} isn't a known serial or pid", target))?;
+    let claim = entries.unavialble()
+        .find(|(_, p)| **p == pid)
+        .map(|(serial, pid)| (serial.clone(), *pid));
+    claim.ok_or_else(|| anyhow!("{} isn't using any device", pid))
+}
+
+fn confirm(prompt: &str) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow!("refusing to {} without confirmation, pass --force", prompt.trim_end_matches('?')));
+    }
+    eprint!("{} [y/N] ", prompt);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+pub fn terminate(pid: Pid) {
+    let mut sys = System::new();
+    sys.refresh_processes();
+    let mut pids = descendants(&sys, pid);
+    pids.push(pid);
+    debug!(pids = ?pids);
+
+    signal(&sys, &pids, Signal::Term);
+    let start = Instant::now();
+    while start.elapsed() < TERM_TIMEOUT {
+        sys.refresh_processes();
+        if pids.iter().all(|pid| sys.process(*pid).is_none()) {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    signal(&sys, &pids, Signal::Kill);
+}
+
+// Just the one process, ex: to warn a job.
+pub fn send(pid: Pid, signal: Signal) {
+    let mut sys = System::new();
+    sys.refresh_process(pid);
+    if let Some(process) = sys.process(pid) {
+        debug!(pid, signal = ?signal);
+        process.kill(signal);
+    }
+}
+
+fn signal(sys: &System, pids: &[Pid], signal: Signal) {
+    for pid in pids {
+        if let Some(process) = sys.process(*pid) {
+            debug!(pid, signal = ?signal);
+            process.kill(signal);
+        }
+    }
+}
+
+fn descendants(sys: &System, pid: Pid) -> Vec<Pid> {
+    let mut result = Vec::new();
+    for (child, process) in sys.processes() {
+        if process.parent() == Some(pid) {
+            result.extend(descendants(sys, *child));
+            result.push(*child);
+        }
+    }
+    result
+}
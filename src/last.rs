@@ -0,0 +1,39 @@
+use std::fmt::Debug;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use tracing::instrument;
+
+use crate::App;
+use crate::duration::HumanDuration;
+use crate::events::EventLog;
+use crate::lockfile::Owner;
+use crate::runtime::Runtime;
+use crate::status::{format_age, print_table};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// Prints what's needed to reproduce the last job the current user ran through adp on this host, ex: to request the
+// same device again with ANDROID_SERIAL.
+#[instrument(skip(app, events))]
+pub fn run<R: Runtime + Debug>(app: &App<R>, events: &EventLog) -> Result {
+    let me = Owner::current(&[]);
+    let lease = events.leases()?.into_iter()
+        .rev()
+        .find(|lease| lease.user == me.user && lease.host == me.host)
+        .ok_or_else(|| anyhow!("no previous job found for {}", me.user))?;
+
+    let started_at = UNIX_EPOCH + Duration::from_secs(lease.started_at);
+    let mut rows = vec![
+        vec!["serial".to_string(), lease.serial.to_string()],
+        vec!["command".to_string(), lease.cmd],
+        vec!["started".to_string(), format!("{} ago", format_age(app.now(), started_at))],
+        vec!["duration".to_string(), HumanDuration(Duration::from_secs(lease.duration)).to_string()],
+        vec!["exit status".to_string(), lease.exit_code.map(|code| code.to_string()).unwrap_or_else(|| "killed".to_string())],
+    ];
+    for (prop, value) in lease.props {
+        rows.push(vec![prop, value]);
+    }
+    print_table(&rows);
+    Ok(())
+}
@@ -0,0 +1,281 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::process::{Child, ExitStatus};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use sysinfo::Signal;
+use tracing::{debug, instrument};
+
+use crate::config::LeaseConfig;
+use crate::duration::HumanDuration;
+use crate::kill;
+use crate::lockfile::LockFileEntries;
+use crate::runtime::{Pid, Serial};
+
+// How often to check on a job that has a lease limit.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// How often to check whether a job has renewed its lease, it means reading the pool.
+const RENEWAL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// Signals that make sense to warn a job with.
+const SIGNALS: &[(&str, Signal)] = &[
+    ("SIGHUP", Signal::Hangup),
+    ("SIGINT", Signal::Interrupt),
+    ("SIGQUIT", Signal::Quit),
+    ("SIGUSR1", Signal::User1),
+    ("SIGUSR2", Signal::User2),
+    ("SIGALRM", Signal::Alarm),
+    ("SIGTERM", Signal::Term),
+    ("SIGWINCH", Signal::Winch),
+];
+
+// Sent to a job whose lease is about to run out.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct WarnSignal(pub Signal);
+
+impl Default for WarnSignal {
+    fn default() -> Self {
+        WarnSignal(Signal::User1)
+    }
+}
+
+impl TryFrom<String> for WarnSignal {
+    type Error = anyhow::Error;
+
+    // With or without the SIG, ex: SIGUSR1 or USR1.
+    fn try_from(name: String) -> std::result::Result<Self, Self::Error> {
+        let upper = name.to_uppercase();
+        let name = if upper.starts_with("SIG") { upper } else { format!("SIG{}", upper) };
+        SIGNALS.iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, signal)| WarnSignal(*signal))
+            .ok_or_else(|| anyhow!(
+                "unsupported signal {}, expected one of {}",
+                name, SIGNALS.iter().map(|(known, _)| *known).collect::<Vec<_>>().join(", "),
+            ))
+    }
+}
+
+impl Display for WarnSignal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = SIGNALS.iter().find(|(_, signal)| *signal == self.0).map_or("?", |(name, _)| *name);
+        write!(f, "{}", name)
+    }
+}
+
+// How a job that was waited on ended.
+#[derive(Debug, Clone, Copy)]
+pub struct Ended {
+    pub status: ExitStatus,
+    // It was killed for holding the device past its lease limit.
+    pub over_limit: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Overdue {
+    pub serial: Serial,
+    pub pid: Pid,
+    pub held: Duration,
+    pub limit: Duration,
+}
+
+impl LeaseConfig {
+    pub fn limit(&self, serial: &Serial) -> Option<Duration> {
+        self.devices.get(serial).or(self.max.as_ref()).map(|max| max.0)
+    }
+
+    // How long a job can hold the device in all, renewals only start the limit over up to this.
+    pub fn total_limit(&self, serial: &Serial) -> Option<Duration> {
+        let limit = self.limit(serial)?;
+        Some(self.max_total.map_or(limit * 2, |max_total| max_total.0.max(limit)))
+    }
+
+    // When a lease claimed and last renewed at the given times runs out, None if it has no limit.
+    pub fn expires_at(&self, serial: &Serial, claimed_at: SystemTime, renewed_at: SystemTime) -> Option<SystemTime> {
+        Some((renewed_at + self.limit(serial)?).min(claimed_at + self.total_limit(serial)?))
+    }
+
+    // Claims that have been held for longer than they're allowed.
+    pub fn overdue(&self, entries: &LockFileEntries, now: SystemTime) -> Vec<Overdue> {
+        entries.iter()
+            .filter_map(|(serial, entry)| {
+                let pid = entry.pid?;
+                let limit = self.limit(serial)?;
+                let since_renewal = now.duration_since(entry.lease_started_at()?).ok()?;
+                if since_renewal > limit {
+                    return Some(Overdue { serial: serial.clone(), pid, held: since_renewal, limit });
+                }
+                // Held past what renewals can stretch it to.
+                let total = self.total_limit(serial)?;
+                let held = now.duration_since(entry.claimed_at?).ok()?;
+                (held > total).then(|| Overdue { serial: serial.clone(), pid, held, limit: total })
+            })
+            .collect()
+    }
+}
+
+// Waits for a job holding the given device, warning if it runs past the lease limit and killing it if configured to.
+// The job can start the limit over with `adp renew`, which renewed_at picks up, and is sent a signal ahead of the limit
+// if configured to so it knows to.
+#[instrument(skip(child, config, renewed_at))]
+pub fn wait(
+    child: &mut Child,
+    serial: &Serial,
+    config: &LeaseConfig,
+    renewed_at: &dyn Fn() -> Option<SystemTime>,
+) -> io::Result<Ended> {
+    let limit = config.limit(serial);
+    if limit.is_none() {
+        return Ok(Ended { status: child.wait()?, over_limit: false });
+    }
+    let began = Instant::now();
+    let total = config.total_limit(serial);
+    let mut start = began;
+    let mut checked = start;
+    let mut last_renewal = None;
+    let mut signalled = false;
+    let mut warned = false;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Ended { status, over_limit: false });
+        }
+        if let (Some(limit), Some(total)) = (limit, total) {
+            // Until the limit runs out, or renewals can't stretch it any further.
+            let left = limit.saturating_sub(start.elapsed()).min(total.saturating_sub(began.elapsed()));
+            if checked.elapsed() >= RENEWAL_CHECK_INTERVAL {
+                checked = Instant::now();
+                let renewal = renewed_at();
+                if renewal.is_some() && renewal != last_renewal {
+                    last_renewal = renewal;
+                    start = Instant::now();
+                    signalled = false;
+                    warned = false;
+                    eprintln!(
+                        "adp: the job renewed its lease on {} for another {}",
+                        serial, HumanDuration(limit.min(total.saturating_sub(began.elapsed()))),
+                    );
+                }
+            }
+            if let Some(warn_before) = config.warn_before {
+                if !signalled && left <= warn_before.0 {
+                    signalled = true;
+                    eprintln!(
+                        "adp: the lease on {} runs out in {}, sending {} to the job, it can run `adp renew` to keep it",
+                        serial, HumanDuration(left), config.warn_signal,
+                    );
+                    kill::send(child.id() as Pid, config.warn_signal.0);
+                }
+            }
+            if !warned && left.is_zero() {
+                warned = true;
+                let over = if start.elapsed() >= limit { limit } else { total };
+                debug!(limit = ?over, kill = config.kill);
+                if config.kill {
+                    eprintln!("adp: {} has been held for longer than {}, killing the job", serial, HumanDuration(over));
+                    kill::terminate(child.id() as Pid);
+                    return Ok(Ended { status: child.wait()?, over_limit: true });
+                }
+                eprintln!("adp: {} has been held for longer than {}", serial, HumanDuration(over));
+            }
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+    use std::process::Command;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use sysinfo::Signal;
+
+    use crate::config::Config;
+    use crate::lease::{wait, Overdue, WarnSignal};
+    use crate::lockfile::LockFileEntries;
+
+    type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+    #[test]
+    fn finds_overdue_claims() -> Result {
+        let config = Config::parse("[lease]\nmax = \"30m\"\n[lease.devices]\nserial2 = \"2h\"\n")?;
+        let entries = LockFileEntries::read(BufReader::new(
+            "serial1:1\tclaimed-at=0\nserial2:2\tclaimed-at=0\nserial3:3\tclaimed-at=3000\nserial4\treleased-at=0\n".as_bytes()
+        ))?;
+
+        let overdue = config.lease.overdue(&entries, UNIX_EPOCH + Duration::from_secs(3600));
+
+        assert_eq!(overdue, vec![Overdue {
+            serial: "serial1".parse()?,
+            pid: 1,
+            held: Duration::from_secs(3600),
+            limit: Duration::from_secs(30 * 60),
+        }]);
+        Ok(())
+    }
+
+    #[test]
+    fn starts_the_limit_over_on_renewal() -> Result {
+        let config = Config::parse("[lease]\nmax = \"30m\"\nwarn_before = \"5m\"\nwarn_signal = \"usr2\"\n")?;
+        let entries = LockFileEntries::read(BufReader::new(
+            "#adp-lock v2\nserial1\tpid=1\tclaimed-at=0\trenewed-at=1800\n".as_bytes()
+        ))?;
+
+        assert_eq!(config.lease.warn_signal, WarnSignal(Signal::User2));
+        assert_eq!(config.lease.warn_signal.to_string(), "SIGUSR2");
+        assert!(config.lease.overdue(&entries, UNIX_EPOCH + Duration::from_secs(3000)).is_empty());
+        assert_eq!(config.lease.overdue(&entries, UNIX_EPOCH + Duration::from_secs(3700)).len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn caps_how_long_renewals_can_keep_a_device() -> Result {
+        let config = Config::parse("[lease]\nmax = \"30m\"\n")?;
+        let serial = "serial1".parse()?;
+        let entries = LockFileEntries::read(BufReader::new(
+            "#adp-lock v2\nserial1\tpid=1\tclaimed-at=0\trenewed-at=3000\n".as_bytes()
+        ))?;
+
+        assert_eq!(config.lease.total_limit(&serial), Some(Duration::from_secs(3600)));
+        assert_eq!(
+            config.lease.expires_at(&serial, UNIX_EPOCH, UNIX_EPOCH + Duration::from_secs(3000)),
+            Some(UNIX_EPOCH + Duration::from_secs(3600)),
+        );
+        assert_eq!(config.lease.overdue(&entries, UNIX_EPOCH + Duration::from_secs(3700)), vec![Overdue {
+            serial: serial.clone(),
+            pid: 1,
+            held: Duration::from_secs(3700),
+            limit: Duration::from_secs(3600),
+        }]);
+
+        let config = Config::parse("[lease]\nmax = \"30m\"\nmax_total = \"4h\"\n")?;
+        assert_eq!(config.lease.total_limit(&serial), Some(Duration::from_secs(4 * 3600)));
+        assert!(config.lease.overdue(&entries, UNIX_EPOCH + Duration::from_secs(3700)).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn no_limit_by_default() -> Result {
+        let config = Config::parse("")?;
+
+        assert_eq!(config.lease.limit(&"serial1".parse()?), None);
+        Ok(())
+    }
+
+    #[test]
+    fn says_whether_the_job_was_killed_over_the_limit() -> Result {
+        let serial = "serial1".parse()?;
+        let killing = Config::parse("[lease]\nmax = \"0s\"\nkill = true\n")?;
+
+        let ended = wait(&mut Command::new("sleep").arg("10").spawn()?, &serial, &killing.lease, &|| None)?;
+        assert!(ended.over_limit);
+        assert!(!ended.status.success());
+
+        let ended = wait(&mut Command::new("false").spawn()?, &serial, &Config::parse("")?.lease, &|| None)?;
+        assert!(!ended.over_limit);
+        assert!(!ended.status.success());
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tracing::instrument;
+
+use crate::cli::JobOptions;
+use crate::device_info::DeviceInfo;
+use crate::runtime::Serial;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+pub const LEASE_FILE_VAR: &str = "ADP_LEASE_FILE";
+
+// What a job is told about its lease, for the test framework it wraps to put in its reports without going to adb.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct LeaseInfo {
+    pub lease_id: String,
+    pub device: DeviceInfo,
+    // seconds since the epoch
+    pub started_at: u64,
+    // seconds since the epoch, when the lease limit runs out, `adp renew` pushes it back
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    pub artifacts: Artifacts,
+    // The devices earlier attempts at the job failed on with --retries, oldest first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failed_on: Vec<Serial>,
+}
+
+// Where adp puts what it saves from the job, only the ones it was asked to.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Artifacts {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recordings: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub screenshots: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bugreports: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub work_dir: Option<PathBuf>,
+}
+
+impl Artifacts {
+    pub fn new(job: &JobOptions, work_dir: Option<PathBuf>) -> Artifacts {
+        Artifacts {
+            log_dir: job.log_dir.clone(),
+            recordings: job.record.clone(),
+            screenshots: job.screenshot_on_failure.clone(),
+            bugreports: job.bugreport_on_failure.clone(),
+            work_dir,
+        }
+    }
+}
+
+impl LeaseInfo {
+    pub fn new(
+        lease_id: String,
+        device: DeviceInfo,
+        started_at: SystemTime,
+        limit: Option<Duration>,
+        artifacts: Artifacts,
+    ) -> LeaseInfo {
+        LeaseInfo {
+            lease_id,
+            device,
+            started_at: secs(started_at),
+            expires_at: limit.map(|limit| secs(started_at + limit)),
+            artifacts,
+            failed_on: Vec::new(),
+        }
+    }
+}
+
+// In the runtime dir, where only the user can write. Pids aren't unique between containers sharing it, hence the
+// random part.
+pub fn path(runtime_dir: &Path, lease_id: &str, random: u64) -> PathBuf {
+    runtime_dir.join(format!("lease-{}-{:x}.json", lease_id, random))
+}
+
+// Fails if there's already something there rather than write through it.
+#[instrument]
+pub fn write(path: &Path, info: &LeaseInfo) -> Result {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(serde_json::to_string_pretty(info)?.as_bytes())?;
+    Ok(())
+}
+
+// From `adp renew` within the job, for the framework to see the new expiry.
+#[instrument]
+pub fn renew(path: &Path, expires_at: SystemTime) -> Result {
+    let mut info: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    info["expires_at"] = secs(expires_at).into();
+    std::fs::write(path, serde_json::to_string_pretty(&info)?)?;
+    Ok(())
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use temp_testdir::TempDir;
+
+    use crate::device_info::{DeviceInfo, Transport};
+    use crate::lease_info::{path, renew, write, Artifacts, LeaseInfo};
+
+    type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+    fn info() -> LeaseInfo {
+        let device = DeviceInfo {
+            serial: "emulator-5554".parse().unwrap(),
+            state: "device".to_string(),
+            model: Some("sdk_gphone64_x86_64".to_string()),
+            abi: Some("x86_64".to_string()),
+            sdk: Some(34),
+            transport: Transport::Emulator,
+            host: None,
+            props: BTreeMap::new(),
+            capabilities: None,
+        };
+        let artifacts = Artifacts { log_dir: Some(PathBuf::from("build/device-logs")), ..Artifacts::default() };
+        LeaseInfo::new("4242".to_string(), device, UNIX_EPOCH + Duration::from_secs(100), Some(Duration::from_secs(60)), artifacts)
+    }
+
+    #[test]
+    fn writes_what_the_job_needs_to_know() -> Result {
+        let dir = TempDir::default();
+        let path = dir.join("lease.json");
+        write(&path, &info())?;
+
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+        assert_eq!(json["lease_id"], "4242");
+        assert_eq!(json["device"]["serial"], "emulator-5554");
+        assert_eq!(json["device"]["sdk"], 34);
+        assert_eq!(json["expires_at"], 160);
+        assert_eq!(json["artifacts"], serde_json::json!({"log_dir": "build/device-logs"}));
+        assert!(json.get("failed_on").is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_the_file_to_the_user_and_doesnt_write_through_others() -> Result {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::default();
+        let path = path(&dir, "4242", 0x5eed);
+        write(&path, &info())?;
+
+        assert_eq!(path, dir.join("lease-4242-5eed.json"));
+        assert_eq!(std::fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+        assert!(write(&path, &info()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn pushes_the_expiry_back_on_renewal() -> Result {
+        let dir = TempDir::default();
+        let path = dir.join("lease.json");
+        write(&path, &info())?;
+
+        renew(&path, UNIX_EPOCH + Duration::from_secs(500))?;
+
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+        assert_eq!(json["expires_at"], 500);
+        assert_eq!(json["started_at"], 100);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use std::fmt::Debug;
+
+use serde::Serialize;
+use tracing::{debug, instrument};
+
+use crate::App;
+use crate::device_info::{DeviceInfo, Transport};
+use crate::runtime::{Pid, Runtime, Serial};
+use crate::status::print_table;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+#[derive(Debug, Serialize)]
+pub struct DeviceRow {
+    pub serial: Serial,
+    // As reported by adb, ex: device, offline, unauthorized.
+    pub state: String,
+    pub model: Option<String>,
+    pub api_level: Option<u32>,
+    pub abi: Option<String>,
+    pub transport: Transport,
+    // The host it's attached to when that's not this one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    // Percent.
+    pub battery: Option<u8>,
+    pub claimed: bool,
+    pub pid: Option<Pid>,
+}
+
+#[instrument(skip(app))]
+pub fn run<R: Runtime + Debug>(app: &App<R>, json: bool) -> Result {
+    let rows = rows(app)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&rows)?);
+        return Ok(());
+    }
+
+    let mut table = vec![["SERIAL", "STATE", "MODEL", "API", "ABI", "TRANSPORT", "HOST", "BATTERY", "CLAIMED"].map(String::from).to_vec()];
+    for row in rows {
+        table.push(vec![
+            row.serial.to_string(),
+            row.state,
+            row.model.unwrap_or_default(),
+            row.api_level.map(|level| level.to_string()).unwrap_or_default(),
+            row.abi.unwrap_or_default(),
+            row.transport.to_string(),
+            row.host.unwrap_or_else(|| "local".to_string()),
+            row.battery.map(|level| format!("{}%", level)).unwrap_or_default(),
+            row.pid.map(|pid| format!("yes ({})", pid)).unwrap_or_else(|| "no".to_string()),
+        ]);
+    }
+    print_table(&table);
+    Ok(())
+}
+
+pub fn rows<R: Runtime + Debug>(app: &App<R>) -> Result<Vec<DeviceRow>> {
+    let entries = app.entries()?;
+    let rows = app.device_info()?.into_iter()
+        .map(|info: DeviceInfo| {
+            let pid = entries.get(&info.serial).and_then(|entry| entry.pid);
+            // The battery changes all the time so it's never cached.
+            let battery = if info.is_online() {
+                app.battery(&info.serial).map_err(|e| debug!(serial = %info.serial, error = %e)).ok()
+            } else {
+                None
+            };
+            DeviceRow {
+                state: info.state,
+                model: info.model,
+                api_level: info.sdk,
+                abi: info.abi,
+                transport: info.transport,
+                host: info.host,
+                battery: battery.and_then(|battery| battery.level),
+                claimed: pid.is_some(),
+                pid,
+                serial: info.serial,
+            }
+        })
+        .collect();
+    Ok(rows)
+}
@@ -0,0 +1,65 @@
+use std::fmt::Debug;
+
+use anyhow::Context;
+use tracing::instrument;
+
+use crate::App;
+use crate::provision;
+use crate::root;
+use crate::runtime::{Runtime, Serial};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// Puts the device in the locale for a job that asked for one with --locale, remembering the one it was in so restore
+// can put it back. Only rooted devices can be changed, adbd is restarted as root for it unless the job already has it.
+#[instrument(skip(app))]
+pub fn set_up<R: Runtime + Debug>(app: &App<R>, serial: &Serial, locale: &str, rooted: bool) -> Result {
+    let current = current(app, serial)?;
+    if current == locale {
+        return Ok(());
+    }
+    // A job that didn't get to put it back leaves the one it was in before that.
+    let original = app.entries()?.get(serial).and_then(|entry| entry.restore_locale.clone()).unwrap_or(current);
+    app.set_restore_locale(serial, Some(original))?;
+    as_root(app, serial, rooted, || provision::set_locale(app, serial, locale))
+        .with_context(|| format!("couldn't put {} in the {} locale", serial, locale))
+}
+
+// Puts the device back in the locale it was in before a job changed it, if one did.
+#[instrument(skip(app))]
+pub fn restore<R: Runtime + Debug>(app: &App<R>, serial: &Serial, rooted: bool) -> Result {
+    let Some(original) = app.entries()?.get(serial).and_then(|entry| entry.restore_locale.clone()) else {
+        return Ok(());
+    };
+    as_root(app, serial, rooted, || provision::set_locale(app, serial, &original))
+        .with_context(|| format!("couldn't put {} back in the {} locale", serial, original))?;
+    app.set_restore_locale(serial, None)
+}
+
+// The one set by the user, or the one it came with if it's never been changed.
+fn current<R: Runtime + Debug>(app: &App<R>, serial: &Serial) -> Result<String> {
+    let locale = app.getprop(serial, "persist.sys.locale")?;
+    if !locale.is_empty() {
+        return Ok(locale);
+    }
+    app.getprop(serial, "ro.product.locale")
+}
+
+fn as_root<R: Runtime + Debug>(app: &App<R>, serial: &Serial, rooted: bool, f: impl FnOnce() -> Result) -> Result {
+    if rooted {
+        return f();
+    }
+    root::enable(app, serial)?;
+    let result = f();
+    let disabled = root::disable(app, serial);
+    // What went wrong changing the locale matters more than adbd being left as root.
+    match result {
+        Err(e) => {
+            if let Err(disabled) = disabled {
+                eprintln!("adp: {:#}", disabled);
+            }
+            Err(e)
+        }
+        Ok(()) => disabled,
+    }
+}
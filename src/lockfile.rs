@@ -1,132 +1,742 @@
-use core::option::Option;
-use core::option::Option::{None, Some};
-use core::result::Result::Ok;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
 use std::fmt::{Debug, Display, Formatter};
-use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::time::{Duration, SystemTime};
 
+use serde::{Deserialize, Serialize};
+use sysinfo::{System, SystemExt};
 use tracing::{debug, instrument};
 
 use crate::runtime::{Pid, Serial};
+use crate::store::Choose;
+
+mod lines;
+
+// The first line of the lock file, followed by the version of its format. Bumped whenever older versions would
+// misread it, so they can refuse to touch it instead. v2 made the pid its own field, so serials can contain ':' like
+// tcp ones do. v3 added pausing the pool, it's only written while the pool is paused so older versions refuse to hand
+// out devices rather than ignoring it. v4 added json and toml, they're written after the header so versions that only
+// know the line format refuse them rather than misreading them.
+const HEADER: &str = "#adp-lock v";
+const VERSION: u32 = 4;
+// The line format while the pool is paused.
+const PAUSED_VERSION: u32 = 3;
+// Written while the pool isn't paused, so versions from before pausing can keep using it.
+const UNPAUSED_VERSION: u32 = 2;
+// Starts the line with the pool's own fields, which can't be mistaken for a serial from before v3.
+const PAUSED: &str = "#paused";
+// Overrides the hostname claims are made under.
+const HOST_VAR: &str = "ADP_HOST";
 
 type Result<T> = std::io::Result<T>;
 
-#[derive(Debug)]
-pub struct LockFileEntries(BTreeMap<String, Option<Pid>>);
+// How the lock file is written. Whichever it's in, it's read, so the format can be changed on a running bench.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockFormat {
+    // One line per device, the only one versions of adp from before the others can read.
+    #[default]
+    Lines,
+    Json,
+    Toml,
+}
+
+// Fields are written in this order, and only when they're set.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Entry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<Pid>,
+    // Device should be taken out of the pool after its first use.
+    #[serde(skip_serializing_if = "is_false")]
+    pub single_use: bool,
+    // A single use device that has been used.
+    #[serde(skip_serializing_if = "is_false")]
+    pub spent: bool,
+    // Device needs a health check before it's used again.
+    #[serde(skip_serializing_if = "is_false")]
+    pub dirty: bool,
+    // Device has been booted and health checked ahead of time, kept across claims.
+    #[serde(skip_serializing_if = "is_false")]
+    pub ready: bool,
+    // Device is out of the pool until it has charged.
+    #[serde(skip_serializing_if = "is_false")]
+    pub low_battery: bool,
+    // Device is out of the pool until someone runs `adp unquarantine`, kept even while it's disconnected.
+    #[serde(skip_serializing_if = "is_false")]
+    pub quarantined: bool,
+    // Who claimed the device, cleared on release. Kept even if only some of it was written.
+    #[serde(flatten, deserialize_with = "partial_owner")]
+    pub owner: Option<Owner>,
+    #[serde(with = "option_secs", skip_serializing_if = "Option::is_none")]
+    pub claimed_at: Option<SystemTime>,
+    // When the job last renewed its lease, which starts its lease limit over, cleared on release.
+    #[serde(with = "option_secs", skip_serializing_if = "Option::is_none")]
+    pub renewed_at: Option<SystemTime>,
+    // When the device was last released, kept across claims.
+    #[serde(with = "option_secs", skip_serializing_if = "Option::is_none")]
+    pub released_at: Option<SystemTime>,
+    // Identifies a claim made against shared state, so a host only ever releases its own claims.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    // Host ports set aside for the job, cleared on release.
+    #[serde(with = "option_ports", skip_serializing_if = "Option::is_none")]
+    pub ports: Option<Range<u16>>,
+    // adb's transport id for the device as the job started, for it to use `adb -t`, cleared on release.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transport_id: Option<String>,
+    // Device is out of the pool until it has cooled down, kept across claims.
+    #[serde(with = "option_secs", skip_serializing_if = "Option::is_none")]
+    pub available_after: Option<SystemTime>,
+    // Jobs that have failed on the device in a row, kept across claims.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub failures: u32,
+    // When the device dropped off adb, it's out of the pool until it's back, but keeps its place for a grace period.
+    #[serde(with = "option_secs", skip_serializing_if = "Option::is_none")]
+    pub disconnected_at: Option<SystemTime>,
+    // The locale the device was in before a job asked for another one, to put it back in once the job's done. Kept
+    // across claims in case the job didn't get to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restore_locale: Option<String>,
+    // When the job last said it's still running, for a claim made where its pid means nothing to anyone else, ex: from
+    // inside a container. The claim is taken back once this gets too old, cleared on release.
+    #[serde(with = "option_secs", skip_serializing_if = "Option::is_none")]
+    pub heartbeat_at: Option<SystemTime>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Owner {
+    pub user: String,
+    pub host: String,
+    pub cmd: String,
+}
+
+// No new claims are made while the pool is paused, ex: for maintenance on the bench.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Pause {
+    pub user: String,
+    #[serde(with = "secs")]
+    pub paused_at: SystemTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+// The lock file as json or toml, which has the version in it instead of a header.
+#[derive(Serialize, Deserialize)]
+struct Document {
+    version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    paused: Option<Pause>,
+    #[serde(default)]
+    devices: BTreeMap<Serial, Entry>,
+}
+
+impl Owner {
+    // The user and host this process is running as, along with the command it will run.
+    // Containers each have a hostname of their own, ADP_HOST set to the host's for them has their jobs count as its.
+    pub fn current(cmd: &[OsString]) -> Owner {
+        let user = std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_default();
+        let host = std::env::var(HOST_VAR).ok().filter(|host| !host.is_empty())
+            .unwrap_or_else(|| System::new().host_name().unwrap_or_default());
+        let cmd = cmd.iter().map(|arg| arg.to_string_lossy()).collect::<Vec<_>>().join(" ");
+        Owner { user, host, cmd }
+    }
+}
+
+impl Entry {
+    fn is_available(&self) -> bool {
+        self.pid.is_none() && !self.spent && !self.low_battery && self.available_after.is_none() && !self.quarantined
+            && self.disconnected_at.is_none()
+    }
+
+    // What the lease limit is counted from.
+    pub fn lease_started_at(&self) -> Option<SystemTime> {
+        self.renewed_at.or(self.claimed_at)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct LockFileEntries {
+    entries: BTreeMap<Serial, Entry>,
+    paused: Option<Pause>,
+}
 
 impl LockFileEntries {
-    pub fn acquire(&mut self, pid: Pid) -> Option<Serial> {
+    pub fn acquire(&mut self, pid: Pid, now: SystemTime) -> Option<Serial> {
         let serial = self.find_available()?;
-        self.0.insert(serial.clone(), Some(pid));
+        let entry = self.entries.entry(serial.clone()).or_default();
+        entry.pid = Some(pid);
+        entry.claimed_at = Some(now);
         Some(serial)
     }
 
+    // Claims the device picked out of the entries, as long as it's available.
+    pub fn acquire_with(
+        &mut self,
+        pid: Pid,
+        now: SystemTime,
+        choose: &Choose<'_>,
+    ) -> Option<Serial> {
+        let serial = choose(self)?;
+        self.claim(&serial, pid, now).then_some(serial)
+    }
+
+    // Like acquire, but only claims one of the given devices.
+    pub fn acquire_from(&mut self, serials: &BTreeSet<Serial>, pid: Pid, now: SystemTime) -> Option<Serial> {
+        let serial = self.find_available_in(|serial| serials.contains(serial))?;
+        self.claim(&serial, pid, now).then_some(serial)
+    }
+
     fn find_available(&self) -> Option<Serial> {
-        let (serial, _) = self.0.iter()
-            .find(|(_, pid)| pid.is_none())?;
-        Some(serial.to_string())
+        self.find_available_in(|_| true)
+    }
+
+    // Prefers devices that don't need a health check, then ones that are ready to go, then the one that's been idle
+    // the longest.
+    pub fn find_available_in(&self, include: impl Fn(&Serial) -> bool) -> Option<Serial> {
+        if self.paused.is_some() {
+            return None;
+        }
+        let (serial, _) = self.entries.iter()
+            .filter(|(serial, entry)| include(serial) && entry.is_available())
+            .min_by_key(|(_, entry)| (entry.dirty, !entry.ready, entry.released_at))?;
+        Some(serial.clone())
+    }
+
+    // Claims a specific device, returns false if it isn't available.
+    pub fn claim(&mut self, serial: &Serial, pid: Pid, now: SystemTime) -> bool {
+        if self.paused.is_some() {
+            return false;
+        }
+        match self.entries.get_mut(serial) {
+            Some(entry) if entry.is_available() => {
+                entry.pid = Some(pid);
+                entry.claimed_at = Some(now);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Starts the lease limit of the claim on the device over, false if it isn't claimed.
+    pub fn renew(&mut self, serial: &Serial, now: SystemTime) -> bool {
+        match self.entries.get_mut(serial) {
+            Some(entry) if entry.pid.is_some() => {
+                entry.renewed_at = Some(now);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Sets aside the lowest run of count ports from start that no other claim has for the claim on the device,
+    // None if it isn't claimed or they've all been given out.
+    pub fn allocate_ports(&mut self, serial: &Serial, start: u16, count: u16) -> Option<Range<u16>> {
+        if self.entries.get(serial).is_none_or(|entry| entry.pid.is_none()) {
+            return None;
+        }
+        let taken: Vec<Range<u16>> = self.entries.iter()
+            .filter(|(other, _)| *other != serial)
+            .filter_map(|(_, entry)| entry.ports.clone())
+            .collect();
+        let ports = (0..)
+            .map_while(|i: u16| {
+                let base = start.checked_add(i.checked_mul(count)?)?;
+                Some(base..base.checked_add(count)?)
+            })
+            .find(|ports| taken.iter().all(|other| other.end <= ports.start || ports.end <= other.start))?;
+        self.entries.get_mut(serial)?.ports = Some(ports.clone());
+        Some(ports)
     }
 
     #[instrument]
-    pub fn release(&mut self, serial: Serial) {
+    pub fn release(&mut self, serial: Serial, now: SystemTime) {
         debug!(release = %serial);
-        self.0.insert(serial, None);
+        let entry = self.entries.entry(serial).or_default();
+        entry.pid = None;
+        entry.owner = None;
+        entry.claimed_at = None;
+        entry.renewed_at = None;
+        entry.heartbeat_at = None;
+        entry.token = None;
+        entry.ports = None;
+        entry.transport_id = None;
+        entry.released_at = Some(now);
+        // Whatever the job did to it, it needs warming up again.
+        entry.ready = false;
+        if entry.single_use {
+            entry.spent = true;
+        }
     }
 
-    pub fn release_all(&mut self, serials: Vec<Serial>) {
+    pub fn release_all(&mut self, serials: Vec<Serial>, now: SystemTime) {
         for serial in serials {
-            self.release(serial);
+            self.release(serial, now);
         }
     }
 
+    pub fn contains(&self, serial: &Serial) -> bool {
+        self.entries.contains_key(serial)
+    }
+
+    pub fn get(&self, serial: &Serial) -> Option<&Entry> {
+        self.entries.get(serial)
+    }
+
+    pub fn is_available(&self, serial: &Serial) -> bool {
+        matches!(self.entries.get(serial), Some(entry) if entry.is_available())
+    }
+
+    // None while the pool is paused, it hands none out.
     pub fn count_available(&self) -> usize {
-        self.0.iter().filter(|(_, pid)| pid.is_none()).count()
+        if self.paused.is_some() {
+            return 0;
+        }
+        self.entries.iter().filter(|(_, entry)| entry.is_available()).count()
+    }
+
+    pub fn count_available_in(&self, serials: &BTreeSet<Serial>) -> usize {
+        if self.paused.is_some() {
+            return 0;
+        }
+        self.entries.iter().filter(|(serial, entry)| serials.contains(*serial) && entry.is_available()).count()
     }
 
     pub fn unavialble(&self) -> impl Iterator<Item=(&Serial, &Pid)> {
-        self.0.iter().filter_map(|(serial, pid)| {
-            match pid {
-                None => None,
-                Some(pid) => Some((serial, pid))
+        self.entries.iter().filter_map(|(serial, entry)| entry.pid.as_ref().map(|pid| (serial, pid)))
+    }
+
+    // How many devices the user is holding.
+    pub fn claimed_by(&self, user: &str) -> usize {
+        self.entries.values()
+            .filter(|entry| entry.pid.is_some() && entry.owner.as_ref().is_some_and(|owner| owner.user == user))
+            .count()
+    }
+
+    // Jobs holding a device on the host, on any host if it isn't known. A job holding several devices counts once, by
+    // its pid, except from inside a container where the pid means nothing and each claim's token is all there is to go
+    // by. Claims nobody is attributed to can't be put on a host, so they only count when any host does.
+    pub fn jobs_on(&self, host: Option<&str>) -> usize {
+        self.entries.values()
+            .filter_map(|entry| Some((entry.pid?, entry)))
+            .filter(|(_, entry)| match (host, &entry.owner) {
+                (None, _) => true,
+                (Some(host), Some(owner)) => owner.host == host,
+                (Some(_), None) => false,
+            })
+            .map(|(pid, entry)| {
+                let token = entry.heartbeat_at.and(entry.token.as_deref());
+                (entry.owner.as_ref().map(|owner| owner.host.as_str()), pid, token)
+            })
+            .collect::<BTreeSet<_>>()
+            .len()
+    }
+
+    pub fn mark_single_use(&mut self, serial: &Serial) {
+        if let Some(entry) = self.entries.get_mut(serial) {
+            entry.single_use = true;
+        }
+    }
+
+    pub fn set_owner(&mut self, serial: &Serial, owner: Option<Owner>) {
+        if let Some(entry) = self.entries.get_mut(serial) {
+            entry.owner = owner;
+        }
+    }
+
+    pub fn set_token(&mut self, serial: &Serial, token: Option<String>) {
+        if let Some(entry) = self.entries.get_mut(serial) {
+            entry.token = token;
+        }
+    }
+
+    pub fn set_transport_id(&mut self, serial: &Serial, transport_id: Option<String>) {
+        if let Some(entry) = self.entries.get_mut(serial) {
+            entry.transport_id = transport_id;
+        }
+    }
+
+    pub fn set_heartbeat(&mut self, serial: &Serial, at: Option<SystemTime>) {
+        if let Some(entry) = self.entries.get_mut(serial) {
+            entry.heartbeat_at = at;
+        }
+    }
+
+    pub fn set_restore_locale(&mut self, serial: &Serial, locale: Option<String>) {
+        if let Some(entry) = self.entries.get_mut(serial) {
+            entry.restore_locale = locale;
+        }
+    }
+
+    pub fn insert(&mut self, serial: Serial, entry: Entry) {
+        self.entries.insert(serial, entry);
+    }
+
+    pub fn remove(&mut self, serial: &Serial) -> Option<Entry> {
+        self.entries.remove(serial)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&Serial, &Entry)> {
+        self.entries.iter()
+    }
+
+    pub fn set_low_battery(&mut self, serial: &Serial, low_battery: bool) {
+        if let Some(entry) = self.entries.get_mut(serial) {
+            entry.low_battery = low_battery;
+        }
+    }
+
+    pub fn set_available_after(&mut self, serial: &Serial, at: Option<SystemTime>) {
+        if let Some(entry) = self.entries.get_mut(serial) {
+            entry.available_after = at;
+        }
+    }
+
+    // Puts devices whose cooldown has passed back in the pool, returns when the next one will be ready.
+    pub fn end_cooldowns(&mut self, now: SystemTime) -> Option<SystemTime> {
+        for (serial, entry) in self.entries.iter_mut() {
+            if entry.available_after.is_some_and(|at| at <= now) {
+                debug!(cooled_down = %serial);
+                entry.available_after = None;
+            }
+        }
+        self.entries.values().filter_map(|entry| entry.available_after).min()
+    }
+
+    // Tracks jobs failing on a device in a row, quarantining it once there have been `limit` of them while the last
+    // job on some other device passed, as then it's more likely the device than the job. Returns whether it was
+    // quarantined.
+    pub fn record_result(&mut self, serial: &Serial, passed: bool, limit: u32) -> bool {
+        let others_passing = self.entries.iter()
+            .any(|(other, entry)| other != serial && entry.failures == 0 && entry.released_at.is_some());
+        let Some(entry) = self.entries.get_mut(serial) else { return false };
+        if passed {
+            entry.failures = 0;
+            return false;
+        }
+        entry.failures += 1;
+        if entry.failures >= limit && others_passing && !entry.quarantined {
+            debug!(quarantine = %serial, failures = entry.failures);
+            entry.quarantined = true;
+            return true;
+        }
+        false
+    }
+
+    // Returns whether the device wasn't already quarantined.
+    pub fn quarantine(&mut self, serial: &Serial) -> bool {
+        match self.entries.get_mut(serial) {
+            Some(entry) if !entry.quarantined => {
+                entry.quarantined = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Returns whether the device was quarantined.
+    pub fn unquarantine(&mut self, serial: &Serial) -> bool {
+        match self.entries.get_mut(serial) {
+            Some(entry) if entry.quarantined => {
+                entry.quarantined = false;
+                entry.failures = 0;
+                true
             }
-        })
+            _ => false,
+        }
+    }
+
+    // Returns whether the pool wasn't already paused.
+    pub fn pause(&mut self, pause: Pause) -> bool {
+        if self.paused.is_some() {
+            return false;
+        }
+        self.paused = Some(pause);
+        true
+    }
+
+    // Returns whether the pool was paused.
+    pub fn resume(&mut self) -> bool {
+        self.paused.take().is_some()
+    }
+
+    pub fn paused(&self) -> Option<&Pause> {
+        self.paused.as_ref()
+    }
+
+    pub fn set_dirty(&mut self, serial: &Serial, dirty: bool) {
+        if let Some(entry) = self.entries.get_mut(serial) {
+            entry.dirty = dirty;
+            if dirty {
+                entry.ready = false;
+            }
+        }
+    }
+
+    pub fn set_ready(&mut self, serial: &Serial, ready: bool) {
+        if let Some(entry) = self.entries.get_mut(serial) {
+            entry.ready = ready;
+        }
     }
 
     #[instrument]
     pub fn update(&mut self, serials: &[Serial]) {
+        self.update_keeping(serials, |_| false);
+    }
+
+    // Like update, but disconnected entries are kept if they match the given predicate.
+    pub fn update_keeping(&mut self, serials: &[Serial], keep: impl Fn(&Entry) -> bool) {
         // clean out disconnected
-        self.0.retain(|serial, _| {
-            debug!(remove = %serial);
-            serials.contains(serial)
+        self.entries.retain(|serial, entry| {
+            let retain = serials.contains(serial) || entry.quarantined || keep(entry);
+            if !retain {
+                debug!(remove = %serial);
+            }
+            retain
         });
         // add connected
         for serial in serials {
-            self.0.entry(serial.to_string()).or_insert_with(|| {
+            self.entries.entry(serial.clone()).or_insert_with(|| {
                 debug!(insert = %serial);
-                None
+                Entry::default()
             });
         }
     }
 
+    // Like update_keeping, but devices that disconnected also keep their place, and any claim on them, for the grace
+    // period after they were last seen, in case they come right back, ex: a USB cable that was knocked. Returns whether
+    // any device was just seen to be gone, that has to be written for its grace period to ever run out.
+    pub fn update_with_grace(
+        &mut self,
+        serials: &[Serial],
+        now: SystemTime,
+        grace: Duration,
+        keep: impl Fn(&Entry) -> bool,
+    ) -> bool {
+        let mut disconnected = false;
+        for (serial, entry) in self.entries.iter_mut() {
+            if serials.contains(serial) {
+                entry.disconnected_at = None;
+                continue;
+            }
+            // It may come back rebooted.
+            entry.ready = false;
+            if !grace.is_zero() && !entry.quarantined && !keep(entry) && entry.disconnected_at.is_none() {
+                debug!(disconnected = %serial);
+                entry.disconnected_at = Some(now);
+                disconnected = true;
+            }
+        }
+        self.update_keeping(serials, |entry| {
+            keep(entry) || entry.disconnected_at.is_some_and(|at| now.duration_since(at).unwrap_or_default() < grace)
+        });
+        disconnected
+    }
+
+    // Reads the lock file in whichever format it was written in.
     #[instrument]
-    pub fn read<R: Read + Debug>(reader: R) -> Result<LockFileEntries> {
-        let reader = BufReader::new(reader);
-        let entries: BTreeMap<_, _> = reader.lines()
-            .map(|line| line.map(|line| {
-                let mut parts = line.split(":");
-                let entry = (
-                    parts.next().unwrap().to_string(),
-                    parts.next().map(|s| s.to_string().parse().expect("invalid pid")),
-                );
-                entry
-            }))
-            .collect::<std::io::Result<_>>()?;
-        let entries = LockFileEntries(entries);
+    pub fn read<R: Read + Debug>(mut reader: R) -> Result<LockFileEntries> {
+        let mut input = String::new();
+        reader.read_to_string(&mut input)?;
+        // json and toml come after the header, once it's checked the rest is the document.
+        let document = match input.strip_prefix(HEADER).and_then(|rest| rest.split_once('\n')) {
+            Some((version, document)) if LockFormat::detect(document) != LockFormat::Lines => {
+                check_version(version.trim())?;
+                document
+            }
+            _ => &input,
+        };
+        let entries = match LockFormat::detect(document) {
+            LockFormat::Lines => lines::read(input.as_bytes())?,
+            LockFormat::Json => serde_json::from_str::<Document>(document).map_err(invalid_data)?.into_entries()?,
+            LockFormat::Toml => toml::from_str::<Document>(document).map_err(invalid_data)?.into_entries()?,
+        };
         debug!(entries = %entries);
         Ok(entries)
     }
 
-    #[instrument]
+    // Writes the lock file in the line format.
     pub fn write<W: Write + Debug>(&self, writer: W) -> Result<()> {
-        let mut writer = BufWriter::new(writer);
-        for (serial, pid) in &self.0 {
-            debug!(serial = ?serial, pid = ?pid);
-            write!(writer, "{}", serial)?;
-            match &pid {
-                Some(pid) => {
-                    write!(writer, ":{}", pid)?;
-                }
-                None => {}
+        self.write_as(writer, LockFormat::Lines)
+    }
+
+    #[instrument]
+    pub fn write_as<W: Write + Debug>(&self, mut writer: W, format: LockFormat) -> Result<()> {
+        let document = || Document { version: VERSION, paused: self.paused.clone(), devices: self.entries.clone() };
+        match format {
+            LockFormat::Lines => lines::write(self, writer),
+            LockFormat::Json => {
+                writeln!(writer, "{}{}", HEADER, VERSION)?;
+                serde_json::to_writer_pretty(&mut writer, &document()).map_err(invalid_data)?;
+                writeln!(writer)
+            }
+            LockFormat::Toml => {
+                writeln!(writer, "{}{}", HEADER, VERSION)?;
+                write!(writer, "{}", toml::to_string(&document()).map_err(invalid_data)?)
             }
-            write!(writer, "\n")?;
         }
-        Ok(())
+    }
+}
+
+impl LockFormat {
+    fn detect(input: &str) -> LockFormat {
+        let input = input.trim_start();
+        if input.starts_with('{') {
+            LockFormat::Json
+        } else if input.starts_with("version") {
+            LockFormat::Toml
+        } else {
+            LockFormat::Lines
+        }
+    }
+}
+
+impl Document {
+    fn into_entries(self) -> Result<LockFileEntries> {
+        check_version(&self.version.to_string())?;
+        Ok(LockFileEntries { entries: self.devices, paused: self.paused })
     }
 }
 
 impl Display for LockFileEntries {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        for (i, (serial, pid)) in self.0.iter().enumerate() {
+        if self.paused.is_some() {
+            write!(f, "paused ")?;
+        }
+        for (i, (serial, entry)) in self.entries.iter().enumerate() {
             if i != 0 {
                 write!(f, ",")?;
             }
             write!(f, "{}", serial)?;
-            match &pid {
-                Some(pid) => {
-                    write!(f, ":{}", pid)?;
-                }
-                None => {}
+            if let Some(pid) = &entry.pid {
+                write!(f, ":{}", pid)?;
+            }
+            // The flags that are set, as they're written.
+            for (name, _) in lines::to_fields(entry).map_err(|_| std::fmt::Error)?.iter().filter(|(_, value)| value.is_none()) {
+                write!(f, " {}", name)?;
+            }
+            if let Some(owner) = &entry.owner {
+                write!(f, " {}@{}", owner.user, owner.host)?;
             }
         }
         Ok(())
     }
 }
 
+fn check_version(version: &str) -> Result<()> {
+    if version.parse::<u32>().map_or(true, |version| version > VERSION) {
+        return Err(invalid_data(format!(
+            "the lock file was written by a newer version of adp (format v{}, this version only knows up to v{}), \
+            upgrade adp to use the pool",
+            version, VERSION,
+        )));
+    }
+    Ok(())
+}
+
+fn invalid_data(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+}
+
+fn is_false(flag: &bool) -> bool {
+    !flag
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+// Times are stored as seconds since the epoch.
+mod secs {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        Ok(UNIX_EPOCH + Duration::from_secs(u64::deserialize(deserializer)?))
+    }
+}
+
+// The owner's fields that were written, None if none of them were.
+fn partial_owner<'de, D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<Owner>, D::Error> {
+    #[derive(Deserialize)]
+    struct Partial {
+        user: Option<String>,
+        host: Option<String>,
+        cmd: Option<String>,
+    }
+    let Partial { user, host, cmd } = Partial::deserialize(deserializer)?;
+    if user.is_none() && host.is_none() && cmd.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(Owner { user: user.unwrap_or_default(), host: host.unwrap_or_default(), cmd: cmd.unwrap_or_default() }))
+}
+
+mod option_secs {
+    use std::time::SystemTime;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(time: &Option<SystemTime>, serializer: S) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => super::secs::serialize(time, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<SystemTime>, D::Error> {
+        #[derive(Deserialize)]
+        struct Secs(#[serde(with = "super::secs")] SystemTime);
+        Ok(Option::<Secs>::deserialize(deserializer)?.map(|Secs(time)| time))
+    }
+}
+
+// Ports are stored as start..end.
+mod option_ports {
+    use std::ops::Range;
+
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(ports: &Option<Range<u16>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match ports {
+            Some(ports) => serializer.serialize_str(&format!("{}..{}", ports.start, ports.end)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Range<u16>>, D::Error> {
+        let Some(ports) = Option::<String>::deserialize(deserializer)? else { return Ok(None) };
+        let parse = || {
+            let (start, end) = ports.split_once("..")?;
+            Some(start.parse().ok()?..end.parse().ok()?)
+        };
+        parse().map(Some).ok_or_else(|| D::Error::custom(format!("invalid ports {:?}", ports)))
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::io::{Cursor, Read, Result, Write};
+    use std::io::{Cursor, Result};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-    use crate::lockfile::LockFileEntries;
+    use crate::lockfile::{LockFileEntries, LockFormat, Owner, Pause};
+    use crate::runtime::Serial;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn serial(serial: &str) -> Serial {
+        Serial::new(serial).unwrap()
+    }
 
     #[test]
     fn reads_entries() -> Result<()> {
@@ -140,12 +750,49 @@ mod tests {
 
     #[test]
     fn writes_entries() -> Result<()> {
-        let input = "serial1\nserial2:2\nserial3\n";
+        let input = "#adp-lock v2\nserial1\nserial2\tpid=2\ttransport-id=7\nserial3\n";
+        let entries = LockFileEntries::read(input.as_bytes())?;
+        let mut output = Vec::new();
+        entries.write(Cursor::new(&mut output))?;
+
+        assert_eq!(String::from_utf8(output).unwrap(), input);
+
+        Ok(())
+    }
+
+    #[test]
+    fn migrates_legacy_entries() -> Result<()> {
+        let input = "serial1\nserial2:2\tclaimed-at=10\n";
         let entries = LockFileEntries::read(input.as_bytes())?;
         let mut output = Vec::new();
         entries.write(Cursor::new(&mut output))?;
 
-        assert_eq!(String::from_utf8(output).unwrap(), "serial1\nserial2:2\nserial3\n");
+        assert_eq!(String::from_utf8(output).unwrap(), "#adp-lock v2\nserial1\nserial2\tpid=2\tclaimed-at=10\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_newer_formats() {
+        let error = LockFileEntries::read("#adp-lock v5\nserial1\tpid=1\n".as_bytes()).unwrap_err();
+
+        assert!(error.to_string().contains("newer version of adp"), "{}", error);
+    }
+
+    #[test]
+    fn round_trips_tcp_serials() -> Result<()> {
+        let mut entries = LockFileEntries::read("#adp-lock v2\n192.168.1.5:5555\n127.0.0.1:6520\tpid=3\n".as_bytes())?;
+        let tcp = serial("192.168.1.5:5555");
+
+        assert_eq!(entries.get(&serial("127.0.0.1:6520")).unwrap().pid, Some(3));
+        assert_eq!(entries.acquire(2, at(10)), Some(tcp.clone()));
+
+        let mut output = Vec::new();
+        entries.write(Cursor::new(&mut output))?;
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output, "#adp-lock v2\n127.0.0.1:6520\tpid=3\n192.168.1.5:5555\tpid=2\tclaimed-at=10\n");
+        let entries = LockFileEntries::read(output.as_bytes())?;
+        assert_eq!(entries.get(&tcp).unwrap().pid, Some(2));
 
         Ok(())
     }
@@ -154,7 +801,7 @@ mod tests {
     fn inserts_new_entries() -> Result<()> {
         let input = "serial1\nserial2:2\n";
         let mut entries = LockFileEntries::read(input.as_bytes())?;
-        entries.update(&["serial1".to_string(), "serial2".to_string(), "serial3".to_string()]);
+        entries.update(&[serial("serial1"), serial("serial2"), serial("serial3")]);
 
         assert_eq!(format!("{}", entries), "serial1,serial2:2,serial3");
 
@@ -165,20 +812,55 @@ mod tests {
     fn removes_old_entries() -> Result<()> {
         let input = "serial1\nserial2:2\n";
         let mut entries = LockFileEntries::read(input.as_bytes())?;
-        entries.update(&["serial2".to_string()]);
+        entries.update(&[serial("serial2")]);
 
         assert_eq!(format!("{}", entries), "serial2:2");
 
         Ok(())
     }
 
+    #[test]
+    fn keeps_disconnected_entries_for_grace_period() -> Result<()> {
+        let mut entries = LockFileEntries::read("#adp-lock v2\nserial1\tpid=1\nserial2\n".as_bytes())?;
+        let grace = Duration::from_secs(30);
+
+        assert!(entries.update_with_grace(&[serial("serial2")], at(10), grace, |_| false));
+        assert_eq!(entries.get(&serial("serial1")).unwrap().pid, Some(1));
+        assert_eq!(entries.get(&serial("serial1")).unwrap().disconnected_at, Some(at(10)));
+        assert!(!entries.is_available(&serial("serial1")));
+
+        // Back before the grace period is up, with its claim intact.
+        entries.update_with_grace(&[serial("serial1"), serial("serial2")], at(20), grace, |_| false);
+        assert_eq!(entries.get(&serial("serial1")).unwrap().disconnected_at, None);
+        assert_eq!(format!("{}", entries), "serial1:1,serial2");
+
+        entries.update_with_grace(&[serial("serial2")], at(30), grace, |_| false);
+        assert!(!entries.update_with_grace(&[serial("serial2")], at(59), grace, |_| false));
+        assert_eq!(format!("{}", entries), "serial1:1,serial2");
+        entries.update_with_grace(&[serial("serial2")], at(60), grace, |_| false);
+        assert_eq!(format!("{}", entries), "serial2");
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_matching_disconnected_entries() -> Result<()> {
+        let input = "serial1\nserial2:2\nserial3\n";
+        let mut entries = LockFileEntries::read(input.as_bytes())?;
+        entries.update_keeping(&[serial("serial3")], |entry| entry.pid.is_some());
+
+        assert_eq!(format!("{}", entries), "serial2:2,serial3");
+
+        Ok(())
+    }
+
     #[test]
     fn acquires_entry_some() -> Result<()> {
         let input = "serial1\nserial2:2\n";
         let mut entries = LockFileEntries::read(input.as_bytes())?;
-        let serial = entries.acquire(1);
+        let acquired = entries.acquire(1, at(10));
 
-        assert_eq!(serial, Some("serial1".to_string()));
+        assert_eq!(acquired, Some(serial("serial1")));
         assert_eq!(format!("{}", entries), "serial1:1,serial2:2");
 
         Ok(())
@@ -188,10 +870,297 @@ mod tests {
     fn acquires_entry_none() -> Result<()> {
         let input = "serial1:1\nserial2:2\n";
         let mut entries = LockFileEntries::read(input.as_bytes())?;
-        let serial = entries.acquire(1);
+        let serial = entries.acquire(1, at(10));
 
         assert_eq!(serial, None);
 
         Ok(())
     }
+
+    #[test]
+    fn single_use_entries_are_spent_on_release() -> Result<()> {
+        let input = "serial1:1\tsingle-use\nserial2\n";
+        let mut entries = LockFileEntries::read(input.as_bytes())?;
+        entries.release(serial("serial1"), at(20));
+
+        assert!(!entries.is_available(&serial("serial1")));
+        assert_eq!(entries.count_available(), 1);
+        assert_eq!(entries.acquire(2, at(10)), Some(serial("serial2")));
+        assert_eq!(entries.acquire(3, at(10)), None);
+
+        let mut output = Vec::new();
+        entries.write(Cursor::new(&mut output))?;
+        assert_eq!(String::from_utf8(output).unwrap(), "#adp-lock v2\nserial1\tsingle-use\tspent\treleased-at=20\nserial2\tpid=2\tclaimed-at=10\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn prefers_clean_entries() -> Result<()> {
+        let input = "serial1\tdirty\nserial2\n";
+        let mut entries = LockFileEntries::read(input.as_bytes())?;
+
+        assert_eq!(entries.acquire(1, at(10)), Some(serial("serial2")));
+        assert_eq!(entries.acquire(2, at(10)), Some(serial("serial1")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_owner() -> Result<()> {
+        let mut entries = LockFileEntries::read("serial1\n".as_bytes())?;
+        let serial = entries.acquire(1, at(10)).unwrap();
+        entries.set_owner(&serial, Some(Owner {
+            user: "evan".to_string(),
+            host: "bench".to_string(),
+            cmd: "sh -c 'echo\ta\\b'".to_string(),
+        }));
+        let mut output = Vec::new();
+        entries.write(Cursor::new(&mut output))?;
+        let output = String::from_utf8(output).unwrap();
+
+        assert_eq!(output, "#adp-lock v2\nserial1\tpid=1\tuser=evan\thost=bench\tcmd=sh -c 'echo\\ta\\\\b'\tclaimed-at=10\n");
+        let entries = LockFileEntries::read(output.as_bytes())?;
+        assert_eq!(entries.get(&serial).unwrap().owner.as_ref().unwrap().cmd, "sh -c 'echo\ta\\b'");
+
+        Ok(())
+    }
+
+    #[test]
+    fn release_clears_owner() -> Result<()> {
+        let mut entries = LockFileEntries::read("serial1:1\tuser=evan\thost=bench\tcmd=ls\n".as_bytes())?;
+        entries.release(serial("serial1"), at(20));
+
+        assert_eq!(format!("{}", entries), "serial1");
+
+        Ok(())
+    }
+
+    #[test]
+    fn prefers_longest_idle_entry() -> Result<()> {
+        let input = "serial1\treleased-at=20\nserial2\treleased-at=10\nserial3\treleased-at=30\n";
+        let mut entries = LockFileEntries::read(input.as_bytes())?;
+
+        assert_eq!(entries.acquire(1, at(40)), Some(serial("serial2")));
+        assert_eq!(entries.acquire(2, at(40)), Some(serial("serial1")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ends_cooldowns_that_have_passed() -> Result<()> {
+        let input = "serial1\tavailable-after=10\nserial2\tavailable-after=30\nserial3\n";
+        let mut entries = LockFileEntries::read(input.as_bytes())?;
+
+        assert_eq!(entries.count_available(), 1);
+        assert_eq!(entries.end_cooldowns(at(20)), Some(at(30)));
+        assert_eq!(entries.count_available(), 2);
+        assert_eq!(entries.end_cooldowns(at(30)), None);
+        assert_eq!(entries.count_available(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn quarantines_after_repeated_failures() -> Result<()> {
+        let input = "serial1\nserial2\treleased-at=10\n";
+        let mut entries = LockFileEntries::read(input.as_bytes())?;
+
+        assert!(!entries.record_result(&serial("serial1"), false, 2));
+        assert!(entries.record_result(&serial("serial1"), false, 2));
+        assert_eq!(entries.count_available(), 1);
+
+        assert!(entries.unquarantine(&serial("serial1")));
+        assert_eq!(entries.count_available(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn no_quarantine_when_failing_everywhere() -> Result<()> {
+        let input = "serial1\nserial2\treleased-at=10\tfailures=1\n";
+        let mut entries = LockFileEntries::read(input.as_bytes())?;
+
+        entries.record_result(&serial("serial1"), false, 1);
+
+        assert_eq!(format!("{}", entries), "serial1,serial2");
+        assert_eq!(entries.get(&serial("serial1")).unwrap().failures, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn allocates_ports_no_other_claim_has() -> Result<()> {
+        let input = "#adp-lock v2\nserial1\tpid=1\tports=20000..20010\nserial2\tpid=2\nserial3\n";
+        let mut entries = LockFileEntries::read(input.as_bytes())?;
+
+        assert_eq!(entries.allocate_ports(&serial("serial2"), 20000, 10), Some(20010..20020));
+        assert_eq!(entries.allocate_ports(&serial("serial3"), 20000, 10), None);
+
+        entries.release(serial("serial1"), at(10));
+        let mut output = Cursor::new(Vec::new());
+        entries.write(&mut output)?;
+        assert_eq!(
+            String::from_utf8(output.into_inner()).unwrap(),
+            "#adp-lock v2\nserial1\treleased-at=10\nserial2\tpid=2\tports=20010..20020\nserial3\n",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn writes_pause_only_older_versions_would_refuse() -> Result<()> {
+        let mut entries = LockFileEntries::read("#adp-lock v2\nserial1\n".as_bytes())?;
+        entries.pause(Pause { user: "evan".to_string(), paused_at: at(10), reason: Some("new\thub".to_string()) });
+        assert!(!entries.claim(&serial("serial1"), 1, at(20)));
+
+        let mut output = Cursor::new(Vec::new());
+        entries.write(&mut output)?;
+        let output = String::from_utf8(output.into_inner()).unwrap();
+        assert_eq!(output, "#adp-lock v3\n#paused\tuser=evan\tpaused-at=10\treason=new\\thub\nserial1\n");
+
+        let mut entries = LockFileEntries::read(output.as_bytes())?;
+        assert_eq!(entries.paused().map(|pause| pause.reason.as_deref()), Some(Some("new\thub")));
+        assert_eq!(entries.acquire(1, at(20)), None);
+
+        entries.resume();
+        let mut output = Cursor::new(Vec::new());
+        entries.write(&mut output)?;
+        assert_eq!(String::from_utf8(output.into_inner()).unwrap(), "#adp-lock v2\nserial1\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_quarantined_entries_while_disconnected() -> Result<()> {
+        let input = "serial1\tquarantined\nserial2\n";
+        let mut entries = LockFileEntries::read(input.as_bytes())?;
+        entries.update(&[]);
+
+        assert_eq!(format!("{}", entries), "serial1 quarantined");
+
+        Ok(())
+    }
+
+    #[test]
+    fn prefers_ready_entries() -> Result<()> {
+        let input = "serial1\treleased-at=10\nserial2\tready\treleased-at=20\n";
+        let mut entries = LockFileEntries::read(input.as_bytes())?;
+
+        assert_eq!(entries.acquire(1, at(30)), Some(serial("serial2")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn forgets_devices_were_ready_once_used_or_disconnected() -> Result<()> {
+        let input = "serial1\tready\nserial2\tready\n";
+        let mut entries = LockFileEntries::read(input.as_bytes())?;
+
+        entries.release(serial("serial1"), at(10));
+        entries.update_with_grace(&[serial("serial1")], at(10), Duration::from_secs(30), |_| false);
+
+        assert!(!entries.get(&serial("serial1")).unwrap().ready);
+        assert!(!entries.get(&serial("serial2")).unwrap().ready);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_serials() {
+        assert!(LockFileEntries::read(" serial1\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn round_trips_times() -> Result<()> {
+        let input = "#adp-lock v2\nserial1\tpid=1\tclaimed-at=30\treleased-at=20\n";
+        let entries = LockFileEntries::read(input.as_bytes())?;
+        let mut output = Vec::new();
+        entries.write(Cursor::new(&mut output))?;
+
+        assert_eq!(String::from_utf8(output).unwrap(), input);
+
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_json_and_toml() -> Result<()> {
+        let input = "#adp-lock v3\n#paused\tuser=evan\tpaused-at=10\n\
+            127.0.0.1:6520\tpid=3\tready\tuser=evan\thost=bench\tcmd=ls\tclaimed-at=30\tports=8000..8004\n\
+            serial2\tquarantined\tfailures=2\n";
+        let entries = LockFileEntries::read(input.as_bytes())?;
+
+        for format in [LockFormat::Json, LockFormat::Toml] {
+            let mut output = Vec::new();
+            entries.write_as(Cursor::new(&mut output), format)?;
+            let read = LockFileEntries::read(output.as_slice())?;
+            let mut lines = Vec::new();
+            read.write(Cursor::new(&mut lines))?;
+            assert_eq!(String::from_utf8(lines).unwrap(), input, "{:?}", format);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn writes_json() -> Result<()> {
+        let entries = LockFileEntries::read("#adp-lock v2\nserial1\tpid=1\tdirty\tclaimed-at=10\n".as_bytes())?;
+        let mut output = Vec::new();
+        entries.write_as(Cursor::new(&mut output), LockFormat::Json)?;
+        let output = String::from_utf8(output).unwrap();
+        let (header, json) = output.split_once('\n').unwrap();
+        let json: serde_json::Value = serde_json::from_str(json).unwrap();
+
+        assert_eq!(header, "#adp-lock v4");
+        assert_eq!(json, serde_json::json!({
+            "version": 4,
+            "devices": { "serial1": { "pid": 1, "dirty": true, "claimed-at": 10 } },
+        }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_newer_json_and_toml() {
+        let error = LockFileEntries::read("{\"version\": 5}".as_bytes()).unwrap_err();
+        assert!(error.to_string().contains("newer version of adp"), "{}", error);
+        let error = LockFileEntries::read("version = 5\n".as_bytes()).unwrap_err();
+        assert!(error.to_string().contains("newer version of adp"), "{}", error);
+        let error = LockFileEntries::read("#adp-lock v5\nversion = 4\n".as_bytes()).unwrap_err();
+        assert!(error.to_string().contains("newer version of adp"), "{}", error);
+    }
+
+    #[test]
+    fn keeps_partial_owners() -> Result<()> {
+        let input = "#adp-lock v2\nserial1\tpid=1\tuser=evan\n";
+        let entries = LockFileEntries::read(input.as_bytes())?;
+        let owner = Owner { user: "evan".to_string(), host: String::new(), cmd: String::new() };
+        assert_eq!(entries.get(&serial("serial1")).unwrap().owner, Some(owner.clone()));
+
+        let mut output = Vec::new();
+        entries.write_as(Cursor::new(&mut output), LockFormat::Json)?;
+        assert_eq!(LockFileEntries::read(output.as_slice())?.get(&serial("serial1")).unwrap().owner, Some(owner));
+        let entries = LockFileEntries::read("#adp-lock v2\nserial1\n".as_bytes())?;
+        assert_eq!(entries.get(&serial("serial1")).unwrap().owner, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn counts_jobs_on_a_host() -> Result<()> {
+        let input = "#adp-lock v2\nserial1\tpid=1\thost=bench\nserial2\tpid=1\thost=bench\nserial3\tpid=2\thost=other\n\
+            serial4\tpid=3\nserial5\tpid=1\thost=bench\nserial6\tpid=1\thost=bench\n";
+        let mut entries = LockFileEntries::read(input.as_bytes())?;
+        // Two jobs in containers, both pid 1 in their own.
+        for (device, token) in [("serial5", "a"), ("serial6", "b")] {
+            entries.set_token(&serial(device), Some(token.to_string()));
+            entries.set_heartbeat(&serial(device), Some(UNIX_EPOCH));
+        }
+
+        assert_eq!(entries.jobs_on(Some("bench")), 3);
+        assert_eq!(entries.jobs_on(Some("other")), 1);
+        assert_eq!(entries.jobs_on(None), 5);
+
+        Ok(())
+    }
 }
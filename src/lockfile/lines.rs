@@ -0,0 +1,197 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+use serde::de::value::{Error, MapDeserializer};
+use serde::de::{Error as _, IntoDeserializer, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use toml_edit::Value;
+
+use crate::lockfile::{invalid_data, Entry, LockFileEntries, Pause, Result, HEADER, PAUSED, PAUSED_VERSION, UNPAUSED_VERSION};
+use crate::runtime::Serial;
+
+// The lock file's own format, the one versions of adp from before the others understand. After the header, each line
+// is the serial followed by the entry's fields, tab separated, with flags that are set as a bare name and everything
+// else as name=value.
+pub fn read<R: Read>(reader: R) -> Result<LockFileEntries> {
+    let mut lines = BufReader::new(reader).lines().peekable();
+    let version = match lines.peek() {
+        Some(Ok(line)) => line.strip_prefix(HEADER).map(str::to_string),
+        _ => None,
+    };
+    if let Some(version) = &version {
+        super::check_version(version)?;
+        lines.next();
+    }
+    let paused = match lines.peek() {
+        Some(Ok(line)) if line.starts_with(PAUSED) => {
+            let line = lines.next().unwrap()?;
+            Some(Pause::deserialize(fields(line.split('\t').skip(1), None)).map_err(invalid_data)?)
+        }
+        _ => None,
+    };
+    // Files from before tcp serials were supported have no header and put the pid after a ':' in the serial.
+    let legacy = version.is_none();
+    let mut entries = BTreeMap::new();
+    for line in lines {
+        let line = line?;
+        let mut split = line.split('\t');
+        let serial = split.next().unwrap();
+        let (serial, pid) = match serial.split_once(':') {
+            Some((serial, pid)) if legacy => (serial, Some(pid)),
+            _ => (serial, None),
+        };
+        let serial = Serial::new(serial).map_err(invalid_data)?;
+        let entry = Entry::deserialize(fields(split, pid))
+            .map_err(|e| invalid_data(format!("invalid entry for {}: {}", serial, e)))?;
+        entries.insert(serial, entry);
+    }
+    Ok(LockFileEntries { entries, paused })
+}
+
+pub fn write<W: Write>(entries: &LockFileEntries, writer: W) -> Result<()> {
+    let mut writer = BufWriter::new(writer);
+    match &entries.paused {
+        Some(pause) => {
+            writeln!(writer, "{}{}", HEADER, PAUSED_VERSION)?;
+            write!(writer, "{}", PAUSED)?;
+            write_fields(&mut writer, pause)?;
+            writeln!(writer)?;
+        }
+        None => writeln!(writer, "{}{}", HEADER, UNPAUSED_VERSION)?,
+    }
+    for (serial, entry) in &entries.entries {
+        write!(writer, "{}", serial)?;
+        write_fields(&mut writer, entry)?;
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+// Each field that's set, as its name and value, None for a flag.
+pub fn to_fields(value: &impl Serialize) -> Result<Vec<(String, Option<String>)>> {
+    let table = toml_edit::ser::to_document(value).map_err(invalid_data)?;
+    table.iter()
+        .filter_map(|(name, item)| {
+            let value = match item.as_value() {
+                Some(Value::Boolean(flag)) if *flag.value() => None,
+                Some(Value::Boolean(_)) => return None,
+                Some(Value::Integer(n)) => Some(n.value().to_string()),
+                Some(Value::String(s)) => Some(s.value().clone()),
+                _ => return Some(Err(invalid_data(format!("{} can't be written to the lock file", name)))),
+            };
+            Some(Ok((name.to_string(), value)))
+        })
+        .collect()
+}
+
+fn write_fields(writer: &mut impl Write, value: &impl Serialize) -> Result<()> {
+    for (name, value) in to_fields(value)? {
+        match value {
+            Some(value) => write!(writer, "\t{}={}", name, escape(&value))?,
+            None => write!(writer, "\t{}", name)?,
+        }
+    }
+    Ok(())
+}
+
+fn fields<'a>(
+    fields: impl Iterator<Item=&'a str>,
+    pid: Option<&str>,
+) -> MapDeserializer<'static, impl Iterator<Item=(String, Field)>, Error> {
+    let pid = pid.map(|pid| ("pid".to_string(), Field(Some(pid.to_string()))));
+    let fields: Vec<_> = pid.into_iter()
+        .chain(fields.map(|field| match field.split_once('=') {
+            Some((name, value)) => (name.to_string(), Field(Some(unescape(value)))),
+            None => (field.to_string(), Field(None)),
+        }))
+        .collect();
+    MapDeserializer::new(fields.into_iter())
+}
+
+// A field's value as it was written, None for a flag. It's parsed as whatever type the field is.
+struct Field(Option<String>);
+
+macro_rules! parse {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Error> {
+                let value = self.0.ok_or_else(|| Error::custom("expected a value"))?;
+                visitor.$visit(value.parse().map_err(|_| Error::custom(format!("invalid number {:?}", value)))?)
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for Field {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Error> {
+        match self.0 {
+            Some(value) => visitor.visit_string(value),
+            None => visitor.visit_bool(true),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Error> {
+        match self.0 {
+            Some(value) => visitor.visit_bool(value.parse().map_err(Error::custom)?),
+            None => visitor.visit_bool(true),
+        }
+    }
+
+    parse! {
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> std::result::Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        i8 i16 i128 u8 u128 f32 f64 char str string bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        enum identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for Field {
+    type Deserializer = Field;
+
+    fn into_deserializer(self) -> Field {
+        self
+    }
+}
+
+// Values can't contain the tab or newline delimiters.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+fn unescape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('t') => result.push('\t'),
+                Some('n') => result.push('\n'),
+                Some(c) => result.push(c),
+                None => result.push('\\'),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
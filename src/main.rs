@@ -2,33 +2,133 @@
 #[macro_use]
 extern crate derive_builder;
 
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::ffi::OsString;
 use std::fmt::Debug;
-use std::fs::OpenOptions;
-use std::io::{BufReader, BufWriter, Seek, SeekFrom};
+use std::num::NonZeroUsize;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, ExitStatus};
 use std::process::exit;
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use ambassador::Delegate;
 use anyhow::{anyhow, Context};
-use named_semaphore::{Semaphore, SemaphoreGuard};
+use clap::Parser;
 use tracing::{debug, info, instrument};
 use tracing_subscriber::FmtSubscriber;
 
 use exitstatus::{ExitStatusError, ExitStatusExt};
 
-use crate::filelock::{FileLockGuard, FileLockGuardExt};
-use crate::lockfile::LockFileEntries;
-use crate::runtime::{Pid, RealRuntime, Runtime, Serial};
+use crate::adb::{AdbDevice, Battery};
+use crate::audit::{AuditLog, AuditObserver};
+use crate::ci::{Ci, CiLog, CiObserver, Section};
+use crate::cli::{Cli, JobOptions};
+use crate::config::{BatteryConfig, Config, PortsConfig, QuotaConfig, SweepConfig, ThermalConfig};
+use crate::device_info::{DeviceCache, DeviceInfo};
+use crate::duration::HumanDuration;
+use crate::emulator::Console;
+use crate::events::{EventLog, LeaseRecord};
+use crate::lease_info::{Artifacts, LeaseInfo, LEASE_FILE_VAR};
+use crate::export::PoolExport;
+use crate::filter::DeviceFilter;
+use crate::lockfile::{Entry, LockFileEntries, Owner, Pause};
+use crate::notify::Notifier;
+use crate::observer::{LogObserver, Observer, ProgressObserver};
+use crate::record::Recording;
+use crate::output::Output;
+use crate::runtime::{BootProgress, Pid, RealRuntime, Runtime, Serial, server_host};
+use crate::selection::{SelectionPolicy, UsageHistory};
+use crate::stats::Latency;
+use crate::status::format_age;
+use crate::store::{Choose, Claim, FileStore, PoolStore, Renewal};
+use crate::teardown::Teardown;
+use crate::waiters::Waiters;
 
 mod filelock;
 mod exitstatus;
 mod lockfile;
 mod adb;
 mod runtime;
+mod cli;
+mod config;
+mod waiters;
+mod emulator;
+mod autoscale;
+mod daemon;
+mod provider;
+mod resources;
+mod kill;
+mod status;
+mod duration;
+mod size;
+mod lease;
+mod lease_info;
+mod junit;
+mod shared;
+mod heartbeat;
+mod pools;
+mod ci;
+mod store;
+mod repair;
+mod list_devices;
+mod battery;
+mod thermal;
+mod wait_for_devices;
+mod drain;
+mod export;
+mod template;
+mod wireless;
+mod power;
+mod schedule;
+mod sweep;
+mod storage;
+mod usage;
+mod stats;
+mod events;
+mod last;
+mod device_info;
+mod observer;
+mod selection;
+mod completions;
+mod top;
+mod notify;
+mod alerts;
+mod api;
+mod audit;
+mod output;
+mod forward;
+mod record;
+mod artifacts;
+mod locale;
+mod bypass;
+mod provision;
+mod filter;
+mod fixtures;
+mod root;
+mod conflicts;
+mod self_test;
+mod shard;
+mod teardown;
+#[cfg(test)]
+mod simulation;
 
 type Result<T = ()> = std::result::Result<T, anyhow::Error>;
 
+// TODO: allow custom adb path
+const ADB_PATH: &str = "adb";
+// Set on jobs to the lease they're running under, so adp run from within them can use it too.
+const LEASE_ID_VAR: &str = "ADP_LEASE_ID";
+// How long to wait each time adb lists no devices while some are in the pool, before believing it.
+const EMPTY_DEVICES_BACKOFF: [Duration; 4] = [
+    Duration::from_millis(250), Duration::from_millis(500), Duration::from_secs(1), Duration::from_secs(2),
+];
+// How often to check back when the owner is holding as many devices as their quota allows, or the host is running as
+// many jobs as it may.
+const QUOTA_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
 fn main() {
     debug_log();
 
@@ -59,398 +159,3739 @@ fn debug_log() {
 
 #[instrument]
 fn run() -> Result {
-    let (cmd, args) = {
-        let mut args = std::env::args().skip(1);
-        (args.next().ok_or(anyhow!("missing command"))?, args)
+    let cli = Cli::parse();
+    // Doesn't need the config or the pool.
+    if let cli::Command::Completions { shell } = cli.command {
+        return completions::run(shell);
+    }
+    // Run while checking the runtime dir, so it can't need one itself.
+    if let cli::Command::LockProbe = cli.command {
+        exit(filelock::probe());
+    }
+    let mut config = Config::load(cli.config.as_deref())?;
+    if let Some(mode) = cli.lock_mode {
+        config.lock_mode = mode;
+    }
+    if let Some(max) = cli.max_concurrent {
+        config.max_concurrent = Some(max);
+    }
+
+    let mut runtime = RealRuntime::new(ADB_PATH, &config.adb.servers);
+    runtime.set_boot_checks(config.boot.checks.clone());
+
+    let namespace = pools::namespace(config.pool.as_deref(), &config.adb.servers)?;
+    let runtime_dir = runtime_dir(cli.runtime_dir.as_deref(), &namespace, &mut lock_probe()?)?;
+    // Not for the completion scripts, which run on every tab.
+    if cli.runtime_dir.is_none() && !matches!(cli.command, cli::Command::CompleteSerials) {
+        warn_about_other_pools(&runtime_dir, &namespace);
+    }
+    if let Some(shared) = &config.shared {
+        check_locking(&shared.dir, "shared dir", &mut lock_probe()?)?;
+    }
+
+    let events = EventLog::new(&runtime_dir);
+    let store = store::from_config(&config, &runtime_dir)?;
+    let mut app = App::with_store(runtime, store);
+    if let Some(name) = &cli.group {
+        let serials = config.groups.get(name).ok_or_else(|| anyhow!("there's no group {} in the config", name))?;
+        app.set_group(serials.iter().cloned().collect());
+    }
+    let mut filters = cli.filters();
+    if cli.job.require_root {
+        filters.push(root::filter());
+    }
+    app.set_filters(filters);
+    app.set_battery(config.battery.clone());
+    app.set_thermal(config.thermal.clone());
+    app.set_quotas(config.quotas.clone());
+    app.set_max_concurrent(config.max_concurrent.map(NonZeroUsize::get));
+    app.set_device_cache(DeviceCache::new(&runtime_dir));
+    app.add_observer(Box::new(LogObserver));
+    app.add_observer(Box::new(ProgressObserver::default()));
+    if let Some(audit) = &config.audit {
+        app.add_observer(Box::new(AuditObserver(AuditLog::new(&audit.path))));
+    }
+    if let Some(ci) = Ci::detect(|name| std::env::var(name).ok()) {
+        app.set_ci(ci);
+    }
+
+    match cli.command {
+        cli::Command::Daemon => daemon::run(&app, &config, &events),
+        cli::Command::Kill { target, force } => kill::run(&app, &target, force),
+        cli::Command::Status => status::run(&app),
+        cli::Command::Repair => repair::run(&app),
+        cli::Command::ListDevices { json } => list_devices::run(&app, json),
+        cli::Command::Unquarantine { serial } => unquarantine(&app, &serial),
+        cli::Command::Pause { reason } => pause(&mut app, reason),
+        cli::Command::Resume => resume(&app),
+        cli::Command::Drain { reason, wait, timeout } => drain::run(&mut app, reason, wait, timeout),
+        cli::Command::Pair { addr, code, connect } => {
+            wireless::pair(&wireless::adb(&config.adb), cli.config.as_deref(), &addr, &code, connect)
+        }
+        cli::Command::Export { include_secrets } => export::run_export(&app, cli.config.as_deref(), include_secrets),
+        cli::Command::Import { file, force } => export::run_import(&app, cli.config.as_deref(), &file, force),
+        cli::Command::WaitForDevices { count, timeout } => wait_for_devices::run(&app, count, timeout),
+        cli::Command::Last => last::run(&app, &events),
+        cli::Command::Top => top::run(&app, &events, &config.lease),
+        cli::Command::Usage { since, by, json } => usage::run(&app, &events, since, by, json),
+        cli::Command::Stats { since, latency, json } => stats::run(&app, &events, since, latency, json),
+        cli::Command::Renew => renew(&app, &config),
+        cli::Command::Emu { command } => emu(&app, &command),
+        cli::Command::Bypass { list: true, .. } => bypass::list(&app, &events),
+        cli::Command::Bypass { serial, command, .. } => bypass::run(&app, &events, &config, serial, command),
+        cli::Command::Shard { tests, shards, separator, command } => shard::run(&app, &events, &tests, shards, &separator, &command),
+        cli::Command::Audit { command: cli::AuditCommand::Verify } => verify_audit(&config),
+        cli::Command::SelfTest { devices, workers, rounds } => self_test::run(&runtime_dir, devices, workers, rounds),
+        cli::Command::SelfTestWorker { dir, devices, rounds, hold_ms, abandon } => {
+            self_test::worker(&dir, devices, rounds, Duration::from_millis(hold_ms), abandon)
+        }
+        cli::Command::Completions { shell } => completions::run(shell),
+        cli::Command::LockProbe => exit(filelock::probe()),
+        cli::Command::CompleteSerials => completions::serials(&app),
+        cli::Command::Exec(args) => exec(&mut app, &config, &events, &cli.notify, cli.notify_after, &cli.job, args),
+    }
+}
+
+#[instrument(skip(app, config, events))]
+fn exec<R: Runtime + Debug>(
+    app: &mut App<R>,
+    config: &Config,
+    events: &EventLog,
+    notifiers: &[Notifier],
+    notify_after: Option<HumanDuration>,
+    options: &JobOptions,
+    args: Vec<OsString>,
+) -> Result {
+    let owner = Owner::current(&args);
+    let (cmd, args) = args.split_first().ok_or(anyhow!("missing command"))?;
+    if let Some(serial) = parent_lease(app)? {
+        // It already has ANDROID_SERIAL and ADB_SERVER_SOCKET from the parent.
+        info!(reusing = %serial, cmd = ?cmd);
+        return Ok(Command::new(cmd).args(job_args(app, &serial, args)?).status()?.exit_ok_()?);
+    }
+    if let Some(serial) = requested_device(app, std::env::var("ANDROID_SERIAL").ok())? {
+        if !app.restrict_to(&serial) {
+            return Err(anyhow!("ANDROID_SERIAL is set to {}, which isn't in the group", serial));
+        }
+        eprintln!("adp: ANDROID_SERIAL is already set, only running on {}", serial);
+    }
+    app.set_owner(owner.clone());
+    if config.telemetry.latency {
+        app.track_latency();
+    }
+
+    let job = Job { config, events, notifiers, notify_after, options, owner, cmd, args };
+    // The devices earlier attempts at the job failed on, oldest first.
+    let mut failed_on: Vec<Serial> = Vec::new();
+    loop {
+        app.set_avoid(failed_on.clone());
+        let attempt = run_attempt(app, &job, &failed_on).inspect_err(|e| {
+            if let Some(ci) = &app.ci {
+                ci.fail(&format!("{:#}", e), app.now());
+            }
+        })?;
+        // Another go would only run into the limit again.
+        if !attempt.status.success() && !attempt.over_limit && failed_on.len() < options.retries {
+            eprintln!(
+                "adp: the job failed on {} with {}, retrying on another device if there's one free ({} of {})",
+                attempt.serial, attempt.status, failed_on.len() + 1, options.retries,
+            );
+            failed_on.push(attempt.serial);
+            continue;
+        }
+        return Ok(attempt.status.exit_ok_()?);
+    }
+}
+
+// What a job is run with, the same for every attempt at it.
+struct Job<'a> {
+    config: &'a Config,
+    events: &'a EventLog,
+    notifiers: &'a [Notifier],
+    notify_after: Option<HumanDuration>,
+    options: &'a JobOptions,
+    owner: Owner,
+    cmd: &'a OsString,
+    args: &'a [OsString],
+}
+
+// How an attempt at a job went.
+struct Attempt {
+    serial: Serial,
+    status: ExitStatus,
+    // It was killed for holding the device past its lease limit.
+    over_limit: bool,
+}
+
+// Claims a device, runs the job on it once and puts the device back.
+fn run_attempt<R: Runtime + Debug>(app: &App<R>, job: &Job<'_>, failed_on: &[Serial]) -> Result<Attempt> {
+    let Job { config, events, notifiers, notify_after, options, owner, cmd, args } = job;
+    let (mut resource, mut teardown) = loop {
+        if let Some(ci) = &app.ci {
+            ci.start(Section::Acquire, "adp: waiting for a device", app.now());
+        }
+        let resource = acquire_notifying(app, std::process::id() as Pid, owner, notifiers, *notify_after)?;
+        // Declared after the resource so it's dropped first, before the device is released.
+        let mut teardown = Teardown::new(app, &resource.serial);
+        if options.require_root {
+            teardown.root = true;
+            root::enable(app, &resource.serial)?;
+        }
+        provision::run(app, &resource.serial, &config.provision)?;
+        match &options.locale {
+            Some(locale) => {
+                teardown.locale = true;
+                locale::set_up(app, &resource.serial, locale, options.require_root)?;
+            }
+            // Left over from a job that didn't get to put it back.
+            None => if let Err(e) = locale::restore(app, &resource.serial, options.require_root) {
+                eprintln!("adp: {:#}", e);
+            },
+        }
+        if let Some(port) = emulator_console(app, &resource.serial) {
+            teardown.fixtures = Some(&config.fixtures);
+            fixtures::apply(port, &config.fixtures)
+                .with_context(|| format!("failed to set up the fixtures on {}", resource.serial))?;
+        }
+        let checked = provision::check_network(app, &resource.serial, &config.provision)
+            .and_then(|_| provision::check_storage(app, &resource.serial, &config.provision));
+        match checked {
+            Ok(()) => break (resource, teardown),
+            Err(e) => {
+                eprintln!(
+                    "adp: {:#}, quarantined it, run `adp unquarantine {}` once it's fixed",
+                    e, resource.serial,
+                );
+                app.quarantine(&resource.serial)?;
+                drop(teardown);
+                resource.release()?;
+            }
+        }
+    };
+
+    let mut cmd = Command::new(cmd);
+    let cmd = cmd
+        .env("ANDROID_SERIAL", app.adb_serial(&resource.serial))
+        .env(LEASE_ID_VAR, std::process::id().to_string())
+        .args(job_args(app, &resource.serial, args)?);
+    if let Some(server_socket) = app.server_socket(&resource.serial) {
+        cmd.env("ADB_SERVER_SOCKET", server_socket);
+    }
+    if let Some(ports) = &config.ports {
+        let ports = app.allocate_ports(&resource.serial, ports)?;
+        cmd.env("ADP_PORT_BASE", ports.start.to_string()).env("ADP_PORT_COUNT", ports.len().to_string());
+    }
+    if let Some(port) = emulator_console(app, &resource.serial) {
+        cmd.env("ADP_EMULATOR_CONSOLE_PORT", port.to_string());
+        if let Some(token) = emulator::auth_token() {
+            cmd.env("ADP_EMULATOR_CONSOLE_TOKEN", token);
+        }
+    }
+    let work_dir = config.work_dir.as_ref().map(|work_dir| work_dir.join(resource.serial.as_str()));
+    if let Some(work_dir) = &work_dir {
+        std::fs::create_dir_all(work_dir).with_context(|| format!("failed to create {:?}", work_dir))?;
+        cmd.env("ADP_WORK_DIR", work_dir);
+    }
+    let mut env = config.env.clone();
+    for (name, template) in &options.export_env {
+        match template {
+            Some(template) => {
+                env.insert(name.clone(), template.clone());
+            }
+            None => {
+                cmd.env(name, app.adb_serial(&resource.serial));
+            }
+        }
+    }
+    if !env.is_empty() {
+        let device = app.device(&resource.serial, &template::props(&env))?;
+        cmd.envs(template::expand_env(&env, &device)?);
+    }
+
+    let output = Output::new(options, &resource.serial)?;
+    if output.is_some() {
+        Output::pipe(cmd);
+    }
+
+    if let Err(e) = resource.verify(std::process::id() as Pid) {
+        // It's another job's device now, so what's set up on it is theirs too.
+        teardown.disarm();
+        return Err(e);
+    }
+    conflicts::check(app, &resource.serial, config.adb.on_conflict)?;
+    forward::set_up(app, &resource.serial, &config.adb, &mut teardown.forwards)?;
+    if options.net_speed.is_some() || options.net_delay.is_some() {
+        teardown.shaped = true;
+        let shaped = match emulator_console(app, &resource.serial) {
+            Some(port) => fixtures::shape_network(port, options.net_speed.as_deref(), options.net_delay.as_deref()),
+            None => {
+                if options.net_delay.is_some() {
+                    eprintln!("adp: only emulators can have their network delayed, {} only has its speed limited", resource.serial);
+                }
+                options.net_speed.as_deref().map_or(Ok(()), |speed| fixtures::shape_device_network(app, &resource.serial, speed))
+            }
+        };
+        shaped.with_context(|| format!("failed to shape the network on {}", resource.serial))?;
+    }
+
+    // Looked up last, restarting adbd for --require-root reconnects the device under a new one.
+    let transport_id = app.transport_id(&resource.serial);
+    if let Some(transport_id) = &transport_id {
+        cmd.env("ADP_TRANSPORT_ID", transport_id);
+    }
+    app.set_transport_id(&resource.serial, transport_id)?;
+
+    info!(ANDROID_SERIAL = %resource.serial, cmd = ?cmd);
+
+    let started_at = app.now();
+    let lease_id = std::process::id().to_string();
+    let lease_file = lease_info::path(events.runtime_dir(), &lease_id, app.random());
+    let written = app.device(&resource.serial, events::PROPS).and_then(|device| {
+        let limit = config.lease.limit(&resource.serial);
+        let info = LeaseInfo {
+            failed_on: failed_on.to_vec(),
+            ..LeaseInfo::new(lease_id.clone(), device, started_at, limit, Artifacts::new(options, work_dir))
+        };
+        lease_info::write(&lease_file, &info)
+    });
+    match written {
+        Ok(()) => {
+            cmd.env(LEASE_FILE_VAR, &lease_file);
+        }
+        Err(e) => eprintln!("adp: failed to write the lease file: {:#}", e),
+    }
+    let recording = match &options.record {
+        Some(dir) => Recording::start(app, &resource.serial, dir, started_at)
+            .inspect_err(|e| eprintln!("adp: failed to start recording the screen: {:#}", e))
+            .ok(),
+        None => None,
+    };
+    if let Some(ci) = &app.ci {
+        if let Err(e) = ci.ci.set_outputs(&[("serial", &app.adb_serial(&resource.serial)), ("lease_id", &lease_id)]) {
+            eprintln!("adp: failed to set the step outputs: {:#}", e);
+        }
+        ci.start(Section::Job, &format!("adp: {} on {}", owner.cmd, resource.serial), started_at);
+    }
+    let renewed_at = || app.entries().ok()?.get(&resource.serial)?.renewed_at;
+    let ended = cmd.spawn().and_then(|mut child| {
+        let copying = output.as_ref().map(|output| output.copy(&mut child)).unwrap_or_default();
+        let ended = lease::wait(&mut child, &resource.serial, &config.lease, &renewed_at);
+        output::finish(copying);
+        ended
+    });
+    let over_limit = ended.as_ref().is_ok_and(|ended| ended.over_limit);
+    let result = ended.map(|ended| ended.status);
+    if let Some(ci) = &app.ci {
+        ci.end(Section::Job, app.now());
+        let failed = match &result {
+            Ok(status) if !status.success() => Some(format!("the job failed on {} with {}", resource.serial, status)),
+            Ok(_) => None,
+            Err(e) => Some(format!("failed to run the job on {}: {}", resource.serial, e)),
+        };
+        if let Some(failed) = failed {
+            ci.fail(&failed, app.now());
+        }
+    }
+    if let Some(recording) = recording {
+        match recording.finish(app) {
+            Ok(path) => eprintln!("adp: saved the screen recording to {:?}", path),
+            Err(e) => eprintln!("adp: failed to save the screen recording: {:#}", e),
+        }
+    }
+    if let (Some(dir), Ok(status)) = (&options.screenshot_on_failure, &result) {
+        if !status.success() {
+            match artifacts::screenshot(app, &resource.serial, dir, app.now()) {
+                Ok(path) => eprintln!("adp: the job failed, saved a screenshot of {} to {:?}", resource.serial, path),
+                Err(e) => eprintln!("adp: {:#}", e),
+            }
+        }
+    }
+    if let (Some(dir), Ok(status)) = (&options.bugreport_on_failure, &result) {
+        if !status.success() {
+            eprintln!("adp: the job failed, taking a bugreport of {}", resource.serial);
+            match artifacts::bugreport(app, &resource.serial, dir, app.now()) {
+                Ok(path) => eprintln!("adp: saved the bugreport to {:?}", path),
+                Err(e) => eprintln!("adp: {:#}", e),
+            }
+        }
+    }
+    if let (Some(path), Ok(_)) = (&options.junit_append, &result) {
+        let appended = app.device(&resource.serial, &[])
+            .and_then(|device| junit::append(path, &junit::properties(&device), started_at));
+        match appended {
+            Ok(reports) if reports.is_empty() => eprintln!("adp: the job didn't write any JUnit reports to {:?}", path),
+            Ok(reports) => debug!(junit_appended = ?reports),
+            Err(e) => eprintln!("adp: failed to add the device to the JUnit reports: {:#}", e),
+        }
+    }
+    if let Err(e) = std::fs::remove_file(&lease_file) {
+        debug!(lease_file = ?lease_file, error = %e);
+    }
+    drop(teardown);
+    if let (Some(quarantine), Ok(status)) = (&config.quarantine, &result) {
+        if app.record_result(&resource.serial, status.success(), quarantine.after)? {
+            eprintln!(
+                "adp: quarantined {} after {} failures in a row, run `adp unquarantine {}` to put it back",
+                resource.serial, quarantine.after, resource.serial,
+            );
+        }
+    }
+    let exit_code = result.as_ref().ok().and_then(|status| status.code());
+    let record = LeaseRecord {
+        failed_on: failed_on.to_vec(),
+        ..lease_record(app, &resource.serial, owner, options.team.as_deref(), started_at, exit_code)
+    };
+    if let Err(e) = events.append(&record) {
+        eprintln!("adp: failed to record the job in the event log: {:#}", e);
+    }
+    let serial = resource.serial.clone();
+    resource.release()?;
+    Ok(Attempt { serial, status: result?, over_limit })
+}
+
+// The device a script that already knows which one it wants asked for with ANDROID_SERIAL, for adp to only claim
+// that one. One that isn't in the pool is left for adp to pick another over, one the pool won't ever hand out is an
+// error rather than a wait that never ends. Devices that share a serial are pooled by where they're connected, the
+// serial adb knows them by matches them if only one of them is connected.
+fn requested_device<R: Runtime + Debug>(app: &App<R>, android_serial: Option<String>) -> Result<Option<Serial>> {
+    let Some(requested) = android_serial.and_then(|serial| Serial::new(serial).ok()) else {
+        return Ok(None);
+    };
+    let entries = app.entries()?;
+    let connected = app.connected_devices()?;
+    let matching: BTreeSet<&Serial> = entries.iter().map(|(serial, _)| serial).chain(&connected)
+        .filter(|serial| {
+            **serial == requested || serial.split_once('@').is_some_and(|(serial, _)| serial == requested.as_str())
+        })
+        .collect();
+    let serial = match matching.len() {
+        0 => {
+            debug!(not_in_pool = %requested);
+            return Ok(None);
+        }
+        1 => matching.into_iter().next().unwrap().clone(),
+        _ => {
+            let matching = matching.into_iter().map(Serial::as_str).collect::<Vec<_>>().join(", ");
+            return Err(anyhow!("ANDROID_SERIAL is set to {}, which more than one device has, set it to one of {}",
+                requested, matching));
+        }
+    };
+    match entries.get(&serial) {
+        Some(entry) if entry.quarantined => return Err(anyhow!(
+            "ANDROID_SERIAL is set to {}, which is quarantined, run `adp unquarantine {}` once it's fixed",
+            serial, serial,
+        )),
+        Some(entry) if entry.spent => {
+            return Err(anyhow!("ANDROID_SERIAL is set to {}, which was single use and has been used", serial));
+        }
+        _ => {}
+    }
+    let unmet = app.unmet_filters(&serial)?;
+    if !unmet.is_empty() {
+        let unmet = unmet.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ");
+        return Err(anyhow!("ANDROID_SERIAL is set to {}, which doesn't match {}", serial, unmet));
+    }
+    Ok(Some(serial))
+}
+
+// The job's args with the device's placeholders filled in, only looking the device up if there are any.
+fn job_args<R: Runtime + Debug>(app: &App<R>, serial: &Serial, args: &[OsString]) -> Result<Vec<OsString>> {
+    if !template::has_placeholders(args) {
+        return Ok(args.to_vec());
+    }
+    template::expand_args(args, &app.adb_serial(serial), &app.device(serial, &[])?)
+}
+
+// The device a parent adp already claimed, when we're being run from within its job, ex: a script under `adp` calling
+// `adp adb ...`. Claiming a second device there would at best waste one and at worst wait forever on a pool of one.
+fn parent_lease<R: Runtime + Debug>(app: &App<R>) -> Result<Option<Serial>> {
+    let (Ok(id), Ok(serial)) = (std::env::var(LEASE_ID_VAR), std::env::var("ANDROID_SERIAL")) else {
+        return Ok(None);
+    };
+    let (Ok(pid), Ok(serial)) = (id.parse::<Pid>(), Serial::new(serial)) else {
+        return Ok(None);
+    };
+    // Left over from a lease that has since ended, ex: copied out of the environment of an old job.
+    if !app.holds(&serial, pid)? {
+        return Ok(None);
+    }
+    Ok(Some(serial))
+}
+
+// Only lets anyone know if the job actually had to wait for a device.
+fn acquire_notifying<'a, R: Runtime + Debug>(
+    app: &'a App<R>,
+    pid: Pid,
+    owner: &Owner,
+    notifiers: &[Notifier],
+    notify_after: Option<HumanDuration>,
+) -> Result<Resource<'a, R>> {
+    if notifiers.is_empty() {
+        return app.acquire_resource(pid);
+    }
+    let started_at = app.now();
+    if let Some(resource) = app.try_acquire_resource(pid)? {
+        return Ok(resource);
+    }
+    let resource = match notify_after {
+        Some(after) => match app.acquire_resource_timeout(pid, after.0)? {
+            Some(resource) => resource,
+            None => {
+                let waited = format_age(app.now(), started_at);
+                notify::send_all(notifiers, &format!("{} has been waiting {} for a device", owner.cmd, waited));
+                app.acquire_resource(pid)?
+            }
+        },
+        None => app.acquire_resource(pid)?,
+    };
+    let waited = format_age(app.now(), started_at);
+    notify::send_all(notifiers, &format!("{} got {} after waiting {}", owner.cmd, resource.serial, waited));
+    Ok(resource)
+}
+
+// Keeps enough about the job to be able to reproduce it later with `adp last`.
+fn lease_record<R: Runtime + Debug>(
+    app: &App<R>,
+    serial: &Serial,
+    owner: &Owner,
+    team: Option<&str>,
+    started_at: SystemTime,
+    exit_code: Option<i32>,
+) -> LeaseRecord {
+    // The device may well be gone if that's what the job failed on.
+    let props = events::PROPS.iter()
+        .filter_map(|prop| Some((prop.to_string(), app.getprop(serial, prop).ok().filter(|value| !value.is_empty())?)))
+        .collect();
+    LeaseRecord {
+        serial: serial.clone(),
+        pid: std::process::id() as Pid,
+        user: owner.user.clone(),
+        host: owner.host.clone(),
+        cmd: owner.cmd.clone(),
+        team: team.map(str::to_string),
+        started_at: started_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        duration: app.now().duration_since(started_at).unwrap_or_default().as_secs(),
+        exit_code,
+        props,
+        latency: app.latency(),
+        bypassed: false,
+        failed_on: Vec::new(),
+    }
+}
+
+#[instrument(skip(app))]
+fn unquarantine<R: Runtime + Debug>(app: &App<R>, serial: &Serial) -> Result {
+    if !app.unquarantine(serial)? {
+        return Err(anyhow!("{} isn't quarantined", serial));
+    }
+    println!("put {} back in the pool", serial);
+    Ok(())
+}
+
+#[instrument(skip(app))]
+fn pause<R: Runtime + Debug>(app: &mut App<R>, reason: Option<String>) -> Result {
+    app.set_owner(Owner::current(&[]));
+    if !app.pause(reason)? {
+        return Err(anyhow!("the pool is already paused"));
+    }
+    println!("paused the pool, jobs already running carry on but no new ones will start until `adp resume`");
+    Ok(())
+}
+
+#[instrument(skip(app))]
+fn resume<R: Runtime + Debug>(app: &App<R>) -> Result {
+    if !app.resume()? {
+        return Err(anyhow!("the pool isn't paused"));
+    }
+    println!("resumed the pool");
+    Ok(())
+}
+
+// One given explicitly, ex: a volume shared by CI containers, may well be on a filesystem where adp can't keep jobs apart.
+fn runtime_dir(dir: Option<&Path>, namespace: &str, probe: &mut Command) -> Result<PathBuf> {
+    let Some(dir) = dir else {
+        let base = dirs::runtime_dir().or_else(dirs::cache_dir).expect("missing cache dir");
+        return match pools::default_runtime_dir(&base, namespace) {
+            // Hardened CI images often keep everything but the temp dir read-only.
+            Err(e) if e.downcast_ref().is_some_and(filelock::is_denied) => {
+                let fallback = std::env::temp_dir().join(format!("adp-{}", Owner::current(&[]).user));
+                pools::own_dir_in_shared(&fallback, 0o700).with_context(|| {
+                    format!("can't keep the pool in {:?} ({}), or in {:?} instead", base, e, fallback)
+                })?;
+                eprintln!(
+                    "adp: can't keep the pool in {:?} ({}), keeping it in {:?} instead. Jobs that can use {:?} will \
+                    be in another pool and can be handed the same devices, give them all the same --runtime-dir",
+                    base, e, fallback, base,
+                );
+                pools::default_runtime_dir(&fallback, namespace)
+                    .map_err(|e| match e.downcast::<std::io::Error>() {
+                        Ok(e) => filelock::explain(e, &fallback),
+                        Err(e) => e,
+                    })
+            }
+            result => result,
+        };
+    };
+    check_locking(dir, "runtime dir", probe)?;
+    Ok(dir.to_path_buf())
+}
+
+// adp run again, to check locks keep out other processes.
+fn lock_probe() -> Result<Command> {
+    let mut command = Command::new(std::env::current_exe()?);
+    command.arg(filelock::PROBE_COMMAND);
+    Ok(command)
+}
+
+// Only a local check, a filesystem shared between hosts can still leave locks taken on one unenforced on another.
+fn check_locking(dir: &Path, what: &str, probe: &mut Command) -> Result<()> {
+    std::fs::create_dir_all(dir).map_err(|e| filelock::explain(e, dir))?;
+    match filelock::supports_locking(dir, probe) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(anyhow!(
+            "{} {:?} is on a filesystem that doesn't enforce file locks, jobs using it could be handed the same \
+            device, use a local directory or a volume on the host mounted into each container", what, dir,
+        )),
+        Err(e) => Err(anyhow!(
+            "can't lock files in {} {:?} ({}), adp needs them to keep jobs from being handed the same device, use a \
+            local directory or a volume on the host mounted into each container", what, dir, e,
+        )),
+    }
+}
+
+// Other users on the host each get a pool of their own by default, for the same devices.
+fn warn_about_other_pools(runtime_dir: &Path, namespace: &str) {
+    let user = Owner::current(&[]).user;
+    match pools::register(Path::new(pools::REGISTRY_DIR), runtime_dir, namespace, &user, SystemTime::now()) {
+        Ok(others) => for other in others {
+            eprintln!(
+                "adp: {} has a pool of their own on this host in {:?}, jobs from the two can be handed the same device, \
+                run both with the same --runtime-dir", other.user, other.runtime_dir,
+            );
+        },
+        Err(e) => debug!(pools = %e),
+    }
+}
+
+// Run from within a job, the adp running it picks the renewal up and starts the limit over.
+fn renew<R: Runtime + Debug>(app: &App<R>, config: &Config) -> Result {
+    let serial = parent_lease(app)?.ok_or_else(|| anyhow!("adp renew only works from within a job run by adp"))?;
+    let now = app.now();
+    let claimed_at = app.entries()?.get(&serial).and_then(|entry| entry.claimed_at).unwrap_or(now);
+    if let Some(total) = config.lease.total_limit(&serial) {
+        if now.duration_since(claimed_at).unwrap_or_default() >= total {
+            return Err(anyhow!("{} has been held for {}, renewing can't keep it any longer", serial, HumanDuration(total)));
+        }
+    }
+    if !app.renew_lease(&serial)? {
+        return Err(anyhow!("{} isn't claimed", serial));
+    }
+    match config.lease.expires_at(&serial, claimed_at, now) {
+        Some(expires_at) => {
+            if let Some(lease_file) = std::env::var_os(LEASE_FILE_VAR) {
+                if let Err(e) = lease_info::renew(Path::new(&lease_file), expires_at) {
+                    eprintln!("adp: failed to update the lease file: {:#}", e);
+                }
+            }
+            println!("renewed {} for another {}", serial, HumanDuration(expires_at.duration_since(now).unwrap_or_default()));
+        }
+        None => println!("renewed {}, though it has no lease limit", serial),
+    }
+    Ok(())
+}
+
+fn emu<R: Runtime + Debug>(app: &App<R>, command: &[String]) -> Result {
+    let serial = parent_lease(app)?.ok_or_else(|| anyhow!("adp emu only works from within a job run by adp"))?;
+    let port = emulator_console(app, &serial).ok_or_else(|| anyhow!("{} isn't an emulator on this host", serial))?;
+    let output = Console::connect(port, emulator::auth_token().as_deref())?.send(&command.join(" "))?;
+    if !output.is_empty() {
+        println!("{}", output);
+    }
+    Ok(())
+}
+
+// The console port of the device if it's an emulator running here, the console only listens on localhost.
+fn emulator_console<R: Runtime + Debug>(app: &App<R>, serial: &Serial) -> Option<u16> {
+    if app.server_socket(serial).is_some_and(|socket| server_host(&socket).is_some()) {
+        return None;
+    }
+    emulator::console_port(serial)
+}
+
+fn verify_audit(config: &Config) -> Result {
+    let Some(audit) = &config.audit else {
+        return Err(anyhow!("there's no audit log, set [audit] path in the config"));
     };
+    let (count, last) = AuditLog::new(&audit.path).verify()
+        .map_err(|e| anyhow!("{:?} has been tampered with: {:#}", audit.path, e))?;
+    println!("{} records intact, last hash {}", count, last);
+    Ok(())
+}
+
+#[derive(Debug, Delegate)]
+#[delegate(Runtime, target = "runtime")]
+pub struct App<'a, R: Runtime + Debug> {
+    runtime: R,
+    store: Box<dyn PoolStore + 'a>,
+    // Recorded against any device this app claims.
+    owner: Option<Owner>,
+    battery: Option<BatteryConfig>,
+    thermal: Option<ThermalConfig>,
+    quotas: Option<QuotaConfig>,
+    // Most jobs that may hold a device on this host at once.
+    max_concurrent: Option<usize>,
+    // Only claim devices in this group.
+    group: Option<BTreeSet<Serial>>,
+    // and that match all of these.
+    filters: Vec<DeviceFilter>,
+    device_cache: Option<DeviceCache>,
+    observers: Vec<Box<dyn Observer + 'a>>,
+    policy: Option<Box<dyn SelectionPolicy + 'a>>,
+    // Only claim these if nothing else will do, ex: the devices earlier attempts at a job failed on.
+    avoid: Vec<Serial>,
+    // Where the time went getting a device, when it's being tracked.
+    latency: RefCell<Option<Latency>>,
+    // The runner a job is under, to mark up its output for.
+    ci: Option<Rc<CiLog>>,
+}
+
+#[derive(Debug)]
+pub struct PoolState {
+    pub entries: LockFileEntries,
+    pub waiters: Waiters,
+}
+
+#[derive(Debug)]
+pub struct Resource<'a, R: Runtime + Debug> {
+    pub serial: Serial,
+    // The process it was claimed for.
+    pid: Pid,
+    token: Option<String>,
+    // Needs a health check before it can be used.
+    dirty: bool,
+    // Needs a health check before anyone else uses it.
+    poisoned: bool,
+    released: bool,
+    // Keeps the claim from expiring until it's released, for stores where claims do.
+    renewal: Option<Renewal>,
+    app: &'a App<'a, R>,
+}
+
+impl<'a, R: Runtime + Debug> App<'a, R> {
+    pub fn new(runtime: R, runtime_dir: impl AsRef<Path>) -> App<'a, R> {
+        App::with_store(runtime, Box::new(FileStore::new(runtime_dir)))
+    }
+
+    pub fn with_store(runtime: R, store: Box<dyn PoolStore + 'a>) -> App<'a, R> {
+        App {
+            runtime,
+            store,
+            owner: None,
+            battery: None,
+            thermal: None,
+            quotas: None,
+            max_concurrent: None,
+            group: None,
+            filters: Vec::new(),
+            device_cache: None,
+            observers: Vec::new(),
+            policy: None,
+            avoid: Vec::new(),
+            latency: RefCell::new(None),
+            ci: None,
+        }
+    }
+
+    pub fn set_owner(&mut self, owner: Owner) {
+        self.owner = Some(owner);
+    }
+
+    pub fn set_battery(&mut self, battery: Option<BatteryConfig>) {
+        self.battery = battery;
+    }
+
+    pub fn set_thermal(&mut self, thermal: Option<ThermalConfig>) {
+        self.thermal = thermal;
+    }
+
+    pub fn set_quotas(&mut self, quotas: Option<QuotaConfig>) {
+        self.quotas = quotas;
+    }
+
+    pub fn set_max_concurrent(&mut self, max_concurrent: Option<usize>) {
+        self.max_concurrent = max_concurrent;
+    }
+
+    // Most devices the user may hold at once.
+    pub fn quota(&self, user: &str) -> Option<usize> {
+        self.quotas.as_ref()?.limit(user)
+    }
+
+    pub fn set_group(&mut self, serials: BTreeSet<Serial>) {
+        self.group = Some(serials);
+    }
+
+    // Narrows what it may claim down to the one device, false if its group doesn't include it.
+    pub fn restrict_to(&mut self, serial: &Serial) -> bool {
+        if self.group.as_ref().is_some_and(|group| !group.contains(serial)) {
+            return false;
+        }
+        self.group = Some(BTreeSet::from([serial.clone()]));
+        true
+    }
+
+    pub fn set_filters(&mut self, filters: Vec<DeviceFilter>) {
+        self.filters = filters;
+    }
+
+    pub fn set_device_cache(&mut self, device_cache: DeviceCache) {
+        self.device_cache = Some(device_cache);
+    }
+
+    pub fn set_ci(&mut self, ci: Ci) {
+        let log = Rc::new(CiLog::new(ci));
+        self.add_observer(Box::new(CiObserver(log.clone())));
+        self.ci = Some(log);
+    }
+
+    pub fn add_observer(&mut self, observer: Box<dyn Observer + 'a>) {
+        self.observers.push(observer);
+    }
+
+    // Only set from within adp, ex: its tests, there's no library for other programs to set one through.
+    pub fn set_selection_policy(&mut self, policy: Box<dyn SelectionPolicy + 'a>) {
+        self.policy = Some(policy);
+    }
+
+    // Steers claims away from the devices, whichever policy picks among the rest.
+    pub fn set_avoid(&mut self, serials: Vec<Serial>) {
+        self.avoid = serials;
+    }
+
+    // Starts keeping track of how long each phase of getting a device takes, for the event log.
+    pub fn track_latency(&mut self) {
+        self.latency = RefCell::new(Some(Latency::default()));
+    }
+
+    pub fn latency(&self) -> Option<Latency> {
+        self.latency.borrow().clone()
+    }
+
+    // Counts how long f took against a phase of getting a device, when that's being tracked.
+    fn timed<T>(&self, phase: fn(&mut Latency) -> &mut u64, f: impl FnOnce() -> T) -> T {
+        if self.latency.borrow().is_none() {
+            return f();
+        }
+        let start = Instant::now();
+        let result = f();
+        if let Some(latency) = self.latency.borrow_mut().as_mut() {
+            stats::add(phase(latency), start.elapsed());
+        }
+        result
+    }
+
+    // Every device adb can see along with what's known about it, features that pick devices by what they are should
+    // go through this.
+    pub fn device_info(&self) -> Result<Vec<DeviceInfo>> {
+        let props: Vec<&str> = self.filters.iter().flat_map(DeviceFilter::props).collect();
+        let probe = self.filters.iter().any(DeviceFilter::probes);
+        device_info::list(&self.runtime, self.device_cache.as_ref(), &props, probe)
+    }
+
+    // What's known about the device, along with the given props.
+    pub fn device(&self, serial: &Serial, props: &[&str]) -> Result<DeviceInfo> {
+        device_info::list(&self.runtime, self.device_cache.as_ref(), props, false)?
+            .into_iter()
+            .find(|device| device.serial == *serial)
+            .ok_or_else(|| anyhow!("{} is no longer connected", serial))
+    }
+
+    // Devices this app may claim going by its group and filters, None for any of them.
+    fn eligible(&self) -> Result<Option<BTreeSet<Serial>>> {
+        if self.filters.is_empty() {
+            return Ok(self.group.clone());
+        }
+        Ok(Some(self.device_info()?.into_iter()
+            .filter(|device| device.is_online() && self.filters.iter().all(|filter| filter.matches(device)))
+            .map(|device| device.serial)
+            .filter(|serial| self.group.as_ref().is_none_or(|group| group.contains(serial)))
+            .collect()))
+    }
+
+    // The filters a connected device doesn't match.
+    pub fn unmet_filters(&self, serial: &Serial) -> Result<Vec<&DeviceFilter>> {
+        if self.filters.is_empty() {
+            return Ok(Vec::new());
+        }
+        let Some(device) = self.device_info()?.into_iter().find(|device| device.serial == *serial) else {
+            return Ok(Vec::new());
+        };
+        Ok(self.filters.iter().filter(|filter| !filter.matches(&device)).collect())
+    }
+
+    // The host the device is attached to when that's not this one, going by the adb server it's on.
+    pub fn device_host(&self, serial: &Serial) -> Option<String> {
+        self.server_socket(serial).and_then(|server_socket| server_host(&server_socket).map(String::from))
+    }
+
+    // Whether a claim was made from this host.
+    pub fn is_local(&self, entry: &Entry) -> bool {
+        self.store.is_local(entry)
+    }
+
+    // Whether the device is still claimed by the given process, from this host.
+    pub fn holds(&self, serial: &Serial, pid: Pid) -> Result<bool> {
+        let entries = self.entries()?;
+        let Some(entry) = entries.get(serial) else { return Ok(false) };
+        Ok(entry.pid == Some(pid) && self.is_local(entry) && self.is_running(pid)?)
+    }
+
+    // Blocks until a device is free.
+    #[instrument]
+    pub fn acquire_resource(&self, pid: Pid) -> Result<Resource<'_, R>> {
+        let mut told = false;
+        loop {
+            if let Some(resource) = self.claim_resource(pid, self.owner.as_ref())? {
+                return Ok(resource);
+            }
+            self.tell_if_paused(&mut told)?;
+            // Wait for a device to be released and try again.
+            self.timed(|latency| &mut latency.wait_ms, || self.wait_for_device(None))?;
+        }
+    }
+
+    // Gives up with None if no device has freed up within the timeout, so callers can back off or queue however they
+    // like.
+    #[instrument]
+    pub fn acquire_resource_timeout(&self, pid: Pid, timeout: Duration) -> Result<Option<Resource<'_, R>>> {
+        let deadline = Instant::now() + timeout;
+        let mut told = false;
+        loop {
+            if let Some(resource) = self.claim_resource(pid, self.owner.as_ref())? {
+                return Ok(Some(resource));
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                // Not waiting anymore, so not demand the daemon should scale up for either.
+                self.store.want(pid, 0, self.now())?;
+                return Ok(None);
+            }
+            self.tell_if_paused(&mut told)?;
+            self.timed(|latency| &mut latency.wait_ms, || self.wait_for_device(Some(left)))?;
+        }
+    }
+
+    // Only claims a device if one is free right now.
+    pub fn try_acquire_resource(&self, pid: Pid) -> Result<Option<Resource<'_, R>>> {
+        self.acquire_resource_timeout(pid, Duration::ZERO)
+    }
+
+    // Like try_acquire_resource, but claims the device for someone other than this app's owner, ex: a client of the
+    // daemon's api.
+    pub fn try_acquire_resource_for(&self, pid: Pid, owner: &Owner) -> Result<Option<Resource<'_, R>>> {
+        let resource = self.claim_resource(pid, Some(owner))?;
+        if resource.is_none() {
+            self.store.want(pid, 0, self.now())?;
+        }
+        Ok(resource)
+    }
+
+    // Claims a free device and gets it ready to use, None if there aren't any or the owner is at their quota.
+    #[instrument]
+    fn claim_resource(&self, pid: Pid, owner: Option<&Owner>) -> Result<Option<Resource<'_, R>>> {
+        loop {
+            // Backs off only a few seconds on an empty listing, waiting any longer for a device is up to the callers.
+            let serials = self.timed(|latency| &mut latency.devices_ms, || self.listed_devices())?;
+            debug!(serials = %serials.join(","));
+
+            let eligible = self.eligible()?;
+            let claim = Claim {
+                pid,
+                owner: owner.cloned(),
+                claimed_at: self.now(),
+                nonce: self.random(),
+                quota: owner.and_then(|owner| self.quota(&owner.user)),
+                max_concurrent: self.max_concurrent,
+                eligible: eligible.clone(),
+            };
+            // Only look the devices up if there's a policy to hand them to, some to steer clear of or some that suit the
+            // job better than others.
+            let choosing = self.policy.is_some()
+                || !self.avoid.is_empty()
+                || self.filters.iter().any(DeviceFilter::ranks);
+            let devices = match choosing {
+                true => self.device_info()?.into_iter().filter(|device| device.is_online()).collect(),
+                false => Vec::new(),
+            };
+            let choose = |entries: &LockFileEntries| {
+                let candidates: Vec<DeviceInfo> = devices.iter()
+                    .filter(|device| entries.is_available(&device.serial))
+                    .filter(|device| eligible.as_ref().is_none_or(|eligible| eligible.contains(&device.serial)))
+                    .cloned()
+                    .collect();
+                let others: Vec<DeviceInfo> = candidates.iter()
+                    .filter(|device| !self.avoid.contains(&device.serial))
+                    .cloned()
+                    .collect();
+                let preferred = |devices: &[DeviceInfo]| -> Vec<DeviceInfo> {
+                    devices.iter()
+                        .filter(|device| self.filters.iter().all(|filter| filter.prefers(device)))
+                        .cloned()
+                        .collect()
+                };
+                let tiers = [preferred(&others), others, preferred(&candidates), candidates];
+                tiers.iter().filter(|candidates| !candidates.is_empty()).find_map(|candidates| {
+                    match &self.policy {
+                        Some(policy) => policy.choose(candidates, &UsageHistory::new(entries)),
+                        None => entries.find_available_in(|serial| candidates.iter().any(|device| device.serial == *serial)),
+                    }
+                })
+            };
+            let choose = choosing.then_some(&choose as &Choose<'_>);
+            let acquired = self.timed(|latency| &mut latency.lock_file_ms, || {
+                self.store.acquire(&serials, &claim, choose, &|pids| self.running(pids))
+            });
+            self.notify_reclaimed();
+            let acquired = acquired?;
+            let Some(acquired) = acquired else {
+                return Ok(None);
+            };
+            // From the start, so it doesn't expire while the device boots, is provisioned or restarted.
+            let renewal = self.keep_alive(&acquired.serial, acquired.token.as_deref());
+            let mut resource = Resource {
+                serial: acquired.serial,
+                pid,
+                token: acquired.token,
+                dirty: acquired.dirty,
+                poisoned: false,
+                released: false,
+                renewal,
+                app: self,
+            };
+            debug!(resource = ?resource);
+            self.observers.iter().for_each(|observer| observer.on_acquire(&resource.serial, pid, owner, self.now()));
+            if self.hold_if_low_battery(&resource)? {
+                resource.release()?;
+                continue;
+            }
+            if let Err(e) = self.timed(|latency| &mut latency.boot_wait_ms, || resource.wait_for_ready()) {
+                // So it's checked again rather than handed out as is.
+                resource.mark_device_dirty();
+                resource.release()?;
+                return Err(e);
+            }
+            return Ok(Some(resource));
+        }
+    }
+
+    // Brings the pool state in line with the devices that are actually connected and the processes that are
+    // actually running.
+    #[instrument]
+    pub fn reconcile(&self) -> Result<PoolState> {
+        let serials = self.listed_devices()?;
+        debug!(serials = %serials.join(","));
+        let state = self.store.reconcile(&serials, &|pids| self.running(pids), self.now());
+        self.notify_reclaimed();
+        state
+    }
+
+    #[instrument]
+    pub fn repair(&self) -> Result<Vec<String>> {
+        let serials = self.listed_devices()?;
+        let fixes = self.store.repair(&serials, &|pids| self.running(pids), self.now());
+        self.notify_reclaimed();
+        fixes
+    }
+
+    fn notify_reclaimed(&self) {
+        for (serial, pid) in self.store.take_reclaimed() {
+            self.observers.iter().for_each(|observer| observer.on_reclaim(&serial, pid, self.now()));
+        }
+    }
+
+    // adb lists no devices at all for a moment while its server restarts, which looks just like every device being
+    // unplugged at once. With any of them still in the pool, that's only believed once it's still the case after
+    // backing off, they'd come back with what's known about them forgotten, ex: that they're owed a health check.
+    fn listed_devices(&self) -> Result<Vec<Serial>> {
+        let mut serials = self.connected_devices()?;
+        for backoff in EMPTY_DEVICES_BACKOFF {
+            let known = |entry: &Entry| entry.disconnected_at.is_none() && !entry.quarantined;
+            if !serials.is_empty() || !self.entries()?.iter().any(|(_, entry)| known(entry)) {
+                break;
+            }
+            debug!(no_devices_retrying_in = ?backoff);
+            std::thread::sleep(backoff);
+            serials = self.connected_devices()?;
+        }
+        Ok(serials)
+    }
+
+    // The pool as it's recorded, without checking on devices or processes.
+    #[instrument]
+    pub fn snapshot(&self) -> Result<PoolState> {
+        self.store.snapshot()
+    }
+
+    #[instrument]
+    pub fn entries(&self) -> Result<LockFileEntries> {
+        Ok(self.store.snapshot()?.entries)
+    }
+
+    // Renews the claim from a thread of its own until it's dropped, for stores where claims expire.
+    fn keep_alive(&self, serial: &Serial, token: Option<&str>) -> Option<Renewal> {
+        Renewal::start(self.store.renewer()?, serial.clone(), token.map(str::to_string), self.now())
+    }
+
+    // Reads the entries, applies the given changes and writes them back, all while holding the lock.
+    fn modify_entries(&self, mut f: impl FnMut(&mut LockFileEntries)) -> Result<()> {
+        self.store.modify(&mut f)
+    }
+
+    // Devices left to charge or cool down won't wake us up when they're ready, so check back on them instead of
+    // waiting on the store while there are any.
+    fn wait_for_device(&self, timeout: Option<Duration>) -> Result<()> {
+        // Devices may well be free, so the store would wake us right back up.
+        if self.at_quota()? || self.at_max_concurrent()? || self.entries()?.paused().is_some() {
+            std::thread::sleep(timeout.map_or(QUOTA_POLL_INTERVAL, |timeout| timeout.min(QUOTA_POLL_INTERVAL)));
+            return Ok(());
+        }
+        let mut recheck = None;
+        if let Some(battery) = &self.battery {
+            if self.recheck_batteries()? {
+                recheck = Some(battery.check_interval.0);
+            }
+        }
+        let now = self.now();
+        if let Some(ready_at) = self.end_cooldowns()? {
+            let wait = ready_at.duration_since(now).unwrap_or_default();
+            recheck = Some(recheck.map_or(wait, |recheck: Duration| recheck.min(wait)));
+        }
+        match recheck {
+            // One may have just been put back.
+            Some(_) if self.available()? > 0 => {}
+            Some(wait) => std::thread::sleep(timeout.map_or(wait, |timeout| timeout.min(wait))),
+            None => self.store.wait(timeout)?,
+        }
+        Ok(())
+    }
+
+    // Otherwise a paused pool looks just like a busy one to whoever is waiting on it.
+    fn tell_if_paused(&self, told: &mut bool) -> Result<()> {
+        if *told {
+            return Ok(());
+        }
+        if let Some(pause) = self.entries()?.paused() {
+            let reason = pause.reason.as_ref().map(|reason| format!(" ({})", reason)).unwrap_or_default();
+            eprintln!(
+                "adp: the pool was paused by {} {} ago{}, waiting for `adp resume`",
+                pause.user, format_age(self.now(), pause.paused_at), reason,
+            );
+            *told = true;
+        }
+        Ok(())
+    }
+
+    // How many devices this app could claim right now.
+    fn available(&self) -> Result<usize> {
+        let entries = self.entries()?;
+        Ok(self.eligible()?.map_or_else(|| entries.count_available(), |eligible| entries.count_available_in(&eligible)))
+    }
+
+    // Whether this app's owner is already holding as many devices as they're allowed.
+    fn at_quota(&self) -> Result<bool> {
+        let Some(owner) = &self.owner else { return Ok(false) };
+        let Some(quota) = self.quota(&owner.user) else { return Ok(false) };
+        Ok(self.entries()?.claimed_by(&owner.user) >= quota)
+    }
+
+    // Whether the host already has as many jobs holding devices as it's allowed.
+    fn at_max_concurrent(&self) -> Result<bool> {
+        let Some(max) = self.max_concurrent else { return Ok(false) };
+        let host = self.owner.as_ref().map(|owner| owner.host.as_str());
+        Ok(self.entries()?.jobs_on(host) >= max)
+    }
+
+    // Puts devices that have cooled down back in the pool, returns when the next one will be ready.
+    #[instrument]
+    pub fn end_cooldowns(&self) -> Result<Option<SystemTime>> {
+        let now = self.now();
+        let mut entries = self.entries()?;
+        let before = entries.count_available();
+        let next = entries.end_cooldowns(now);
+        // Only take the lock if there's something to put back.
+        if entries.count_available() > before {
+            self.modify_entries(|entries| {
+                entries.end_cooldowns(now);
+            })?;
+        }
+        Ok(next)
+    }
+
+    // When the device will have cooled down, if it's running hot.
+    #[instrument]
+    fn cooldown_until(&self, serial: &Serial) -> Option<SystemTime> {
+        let config = self.thermal.as_ref()?;
+        match self.battery(serial) {
+            Ok(battery) => config.cooldown_until(&battery, self.now()),
+            Err(e) => {
+                debug!(serial = %serial, error = %e);
+                None
+            }
+        }
+    }
+
+    // Once it's been released, so a store that can't rest devices doesn't keep it from being released at all. Left
+    // alone if another job has already claimed it.
+    fn cool_down(&self, serial: &Serial, until: SystemTime) -> Result<()> {
+        if let Some(config) = &self.thermal {
+            eprintln!("adp: {} is running hot, resting it for {}", serial, HumanDuration(config.cooldown.0));
+        }
+        self.modify_entries(|entries| {
+            if entries.get(serial).is_some_and(|entry| entry.pid.is_none()) {
+                entries.set_available_after(serial, Some(until));
+            }
+        })
+    }
+
+    // Takes the device out of the pool if its battery is too low to be relied on, returning whether it did.
+    #[instrument]
+    fn hold_if_low_battery(&self, resource: &Resource<'_, R>) -> Result<bool> {
+        let Some(config) = &self.battery else { return Ok(false) };
+        let battery = match self.battery(&resource.serial) {
+            Ok(battery) => battery,
+            // Not every device can report it, don't hold those back.
+            Err(e) => {
+                debug!(serial = %resource.serial, error = %e);
+                return Ok(false);
+            }
+        };
+        if !config.is_low(&battery) {
+            return Ok(false);
+        }
+        eprintln!("adp: {} is low on battery ({}%), leaving it to charge", resource.serial, battery.level.unwrap_or_default());
+        self.modify_entries(|entries| entries.set_low_battery(&resource.serial, true))?;
+        Ok(true)
+    }
+
+    // Puts devices that have charged back in the pool, returning whether any are still charging.
+    #[instrument]
+    pub fn recheck_batteries(&self) -> Result<bool> {
+        let Some(config) = &self.battery else { return Ok(false) };
+        let charging: Vec<Serial> = self.entries()?.iter()
+            .filter(|(_, entry)| entry.low_battery)
+            .map(|(serial, _)| serial.clone())
+            .collect();
+        let mut recharged = Vec::new();
+        for serial in &charging {
+            match self.battery(serial) {
+                Ok(battery) if config.has_recharged(&battery) => recharged.push(serial.clone()),
+                Ok(battery) => debug!(serial = %serial, battery = ?battery),
+                Err(e) => debug!(serial = %serial, error = %e),
+            }
+        }
+        if !recharged.is_empty() {
+            self.modify_entries(|entries| {
+                for serial in &recharged {
+                    entries.set_low_battery(serial, false);
+                }
+            })?;
+        }
+        Ok(recharged.len() < charging.len())
+    }
+
+    // Boots and health checks idle devices ahead of time until `count` of them are ready to go, claiming them with the
+    // given pid while it does so jobs don't get them half way through.
+    #[instrument]
+    pub fn prewarm(&self, count: usize, pid: Pid) -> Result<()> {
+        let entries = self.entries()?;
+        let ready = entries.iter().filter(|(serial, entry)| entry.ready && entries.is_available(serial)).count();
+        let cold: Vec<Serial> = entries.iter()
+            .filter(|(serial, entry)| !entry.ready && entries.is_available(serial) && self.is_local(entry))
+            .map(|(serial, _)| serial.clone())
+            .take(count.saturating_sub(ready))
+            .collect();
+        let now = self.now();
+        // Kept alive like a job's claims, otherwise from a container the others would take them back mid-boot.
+        let heartbeat = self.store.renew_interval().is_some();
+        let mut claimed = Vec::new();
+        self.modify_entries(|entries| {
+            claimed = cold.iter().filter(|serial| entries.claim(serial, pid, now)).cloned().collect();
+            claimed.iter().for_each(|serial| entries.set_heartbeat(serial, heartbeat.then_some(now)));
+        })?;
+        debug!(prewarm = ?claimed);
+        let renewals: Vec<Renewal> = claimed.iter().filter_map(|serial| self.keep_alive(serial, None)).collect();
+        let results = self.wait_until_healthy(&claimed);
+        drop(renewals);
+        let now = self.now();
+        self.modify_entries(|entries| {
+            for (serial, result) in claimed.iter().zip(&results) {
+                if entries.get(serial).and_then(|entry| entry.pid) == Some(pid) {
+                    entries.release(serial.clone(), now);
+                    entries.set_dirty(serial, result.is_err());
+                    entries.set_ready(serial, result.is_ok());
+                }
+            }
+        })?;
+        for (serial, result) in claimed.iter().zip(results) {
+            if let Err(e) = result {
+                eprintln!("{} isn't ready: {:#}", serial, e);
+            }
+        }
+        Ok(())
+    }
+
+    // Sweeps the next idle device that hasn't been swept yet, claiming it with the given pid while it does, and adds
+    // it to those swept. One at a time so the daemon carries on between them, a reboot can take minutes. Devices that
+    // fail are marked as needing a health check before they're used again. Returns false once there are none left.
+    #[instrument]
+    pub fn sweep(&self, config: &SweepConfig, pid: Pid, swept: &mut BTreeSet<Serial>) -> Result<bool> {
+        let entries = self.entries()?;
+        let idle: Vec<Serial> = entries.iter()
+            .filter(|(serial, entry)| entries.is_available(serial) && self.is_local(entry) && !swept.contains(*serial))
+            .map(|(serial, _)| serial.clone())
+            .collect();
+        for serial in idle {
+            let now = self.now();
+            let mut claimed = false;
+            self.modify_entries(|entries| claimed = entries.claim(&serial, pid, now))?;
+            if !claimed {
+                continue;
+            }
+            swept.insert(serial.clone());
+            let result = sweep::device(self, &serial, config);
+            let now = self.now();
+            self.modify_entries(|entries| {
+                if entries.get(&serial).and_then(|entry| entry.pid) == Some(pid) {
+                    entries.release(serial.clone(), now);
+                    entries.set_dirty(&serial, result.is_err());
+                    entries.set_ready(&serial, result.is_ok());
+                }
+            })?;
+            match result {
+                Ok(done) => done.iter().for_each(|done| eprintln!("sweep: {} {}", serial, done)),
+                Err(e) => eprintln!("sweep: {} failed: {:#}", serial, e),
+            }
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    // Lets the daemon know this process is waiting on that many devices, zero once it's done.
+    #[instrument]
+    pub fn want_devices(&self, pid: Pid, count: usize) -> Result<()> {
+        self.store.want(pid, count, self.now())
+    }
+
+    // Returns whether the device was quarantined because of this result.
+    #[instrument]
+    pub fn record_result(&self, serial: &Serial, passed: bool, limit: u32) -> Result<bool> {
+        let mut quarantined = false;
+        self.modify_entries(|entries| quarantined = entries.record_result(serial, passed, limit))?;
+        Ok(quarantined)
+    }
+
+    #[instrument]
+    pub fn allocate_ports(&self, serial: &Serial, config: &PortsConfig) -> Result<Range<u16>> {
+        let mut ports = None;
+        self.modify_entries(|entries| ports = entries.allocate_ports(serial, config.start, config.count))?;
+        ports.ok_or_else(|| anyhow!("there are no ports left to give {}, starting from {}", serial, config.start))
+    }
+
+    // Returns whether the device was claimed to renew.
+    #[instrument]
+    pub fn renew_lease(&self, serial: &Serial) -> Result<bool> {
+        let now = self.now();
+        let mut renewed = false;
+        self.modify_entries(|entries| renewed = entries.renew(serial, now))?;
+        Ok(renewed)
+    }
+
+    #[instrument]
+    pub fn quarantine(&self, serial: &Serial) -> Result<()> {
+        self.modify_entries(|entries| {
+            entries.quarantine(serial);
+        })
+    }
+
+    // Stops new claims until the pool is resumed, returns whether it wasn't already paused.
+    #[instrument]
+    pub fn pause(&self, reason: Option<String>) -> Result<bool> {
+        let user = self.owner.as_ref().map(|owner| owner.user.clone()).unwrap_or_default();
+        let pause = Pause { user, paused_at: self.now(), reason };
+        let mut paused = false;
+        self.modify_entries(|entries| paused = entries.pause(pause.clone()))?;
+        Ok(paused)
+    }
+
+    // Returns whether the pool was paused.
+    #[instrument]
+    pub fn resume(&self) -> Result<bool> {
+        let mut resumed = false;
+        self.modify_entries(|entries| resumed = entries.resume())?;
+        Ok(resumed)
+    }
+
+    // Puts back the quarantines, failure counts and pause from `adp export`, devices it doesn't mention are left as is.
+    #[instrument]
+    pub fn restore(&self, export: &PoolExport) -> Result<()> {
+        let now = self.now();
+        self.modify_entries(|entries| {
+            for (serial, state) in &export.devices {
+                let mut entry = entries.get(serial).cloned().unwrap_or_default();
+                entry.quarantined = state.quarantined;
+                entry.failures = state.failures;
+                entries.insert(serial.clone(), entry);
+            }
+            match &export.paused {
+                Some(paused) => {
+                    entries.pause(Pause { user: paused.user.clone(), paused_at: now, reason: paused.reason.clone() });
+                }
+                None => {
+                    entries.resume();
+                }
+            }
+        })
+    }
+
+    // Returns whether the device was quarantined.
+    #[instrument]
+    pub fn unquarantine(&self, serial: &Serial) -> Result<bool> {
+        let mut unquarantined = false;
+        self.modify_entries(|entries| unquarantined = entries.unquarantine(serial))?;
+        Ok(unquarantined)
+    }
+
+    // Records which connection the job is using, for anyone looking at the pool.
+    #[instrument]
+    pub fn set_transport_id(&self, serial: &Serial, transport_id: Option<String>) -> Result<()> {
+        self.modify_entries(|entries| entries.set_transport_id(serial, transport_id.clone()))
+    }
+
+    pub fn set_restore_locale(&self, serial: &Serial, locale: Option<String>) -> Result<()> {
+        self.modify_entries(|entries| entries.set_restore_locale(serial, locale.clone()))
+    }
+
+    // Checks which are still unmarked under the same lock it marks them with, returning those.
+    #[instrument]
+    pub fn mark_single_use(&self, serials: &[Serial]) -> Result<Vec<Serial>> {
+        let mut marked = Vec::new();
+        self.modify_entries(|entries| {
+            marked = serials.iter()
+                .filter(|serial| entries.get(serial).is_some_and(|entry| !entry.single_use))
+                .cloned()
+                .collect();
+            for serial in &marked {
+                entries.mark_single_use(serial);
+            }
+        })?;
+        debug!(single_use = ?marked);
+        Ok(marked)
+    }
+
+    // Forcibly releases a device from the process that claimed it. As the process didn't release it itself the
+    // device is marked as needing a health check.
+    #[instrument]
+    pub fn evict(&self, serial: &Serial, pid: Pid) -> Result<()> {
+        let now = self.now();
+        let mut evicted = false;
+        self.modify_entries(|entries| {
+            evicted = entries.get(serial).and_then(|entry| entry.pid) == Some(pid);
+            if evicted {
+                entries.release(serial.clone(), now);
+                entries.set_dirty(serial, true);
+            }
+        })?;
+        if evicted {
+            self.observers.iter().for_each(|observer| observer.on_reclaim(serial, pid, self.now()));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Runtime + Debug> Resource<'_, R> {
+    pub fn wait_for_ready(&self) -> Result<()> {
+        let start = Instant::now();
+        self.app.wait_for_boot_with_progress(&self.serial, &|progress| {
+            self.app.observers.iter().for_each(|observer| observer.on_boot_progress(&self.serial, progress));
+        })?;
+        if self.dirty {
+            self.app.check_health(&self.serial)?;
+            self.app.modify_entries(|entries| entries.set_dirty(&self.serial, false))?;
+        }
+        let (waited, now) = (start.elapsed(), self.app.now());
+        self.app.observers.iter().for_each(|observer| observer.on_boot_wait(&self.serial, waited, now));
+        Ok(())
+    }
+
+    // Checks the pool still has the device down as claimed by the given process, right before a job runs on it, so a
+    // claim lost to a hand edited lock file, a repair or a bug doesn't end with two jobs on one device. A claim that's
+    // gone isn't ours to release anymore.
+    #[instrument]
+    pub fn verify(&mut self, pid: Pid) -> Result<()> {
+        let entries = self.app.snapshot()?.entries;
+        let holder = match entries.get(&self.serial) {
+            Some(entry) if entry.pid == Some(pid) && (entry.token.is_none() || entry.token == self.token) => return Ok(()),
+            Some(entry) => entry.pid.map_or_else(|| "nobody".to_string(), |pid| format!("pid {}", pid)),
+            None => "nobody, it's not in the pool".to_string(),
+        };
+        self.released = true;
+        self.renewal = None;
+        Err(anyhow!(
+            "the pool has {} down as claimed by {} rather than this job, not running it so two jobs don't end up on one device",
+            self.serial, holder,
+        ))
+    }
+
+    // For when the job left the device in a bad state, it's health checked before it's handed out again.
+    pub fn mark_device_dirty(&mut self) {
+        self.poisoned = true;
+    }
+
+    // Dropping the resource releases it too, this is for when errors releasing it need handling.
+    #[instrument]
+    pub fn release(mut self) -> Result<()> {
+        self.released = true;
+        self.release_claim()
+    }
+
+    fn release_claim(&mut self) -> Result<()> {
+        self.renewal = None;
+        if self.poisoned {
+            self.app.modify_entries(|entries| entries.set_dirty(&self.serial, true))?;
+        }
+        // Read while the device is still ours.
+        let cooldown_until = self.app.cooldown_until(&self.serial);
+        if !self.app.store.release(&self.serial, self.pid, self.token.as_deref(), self.app.now())? {
+            // Someone else has cleaned up our claim and the device may already be in use again.
+            eprintln!("adp: lost claim on {}", self.serial);
+            return Ok(());
+        }
+        self.app.observers.iter().for_each(|observer| observer.on_release(&self.serial, self.app.now()));
+        match cooldown_until {
+            Some(until) => self.app.cool_down(&self.serial, until),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<R: Runtime + Debug> Drop for Resource<'_, R> {
+    fn drop(&mut self) {
+        if !self.released {
+            if let Err(e) = self.release_claim() {
+                eprintln!("adp: failed to release {}: {:#}", self.serial, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use anyhow::anyhow;
+    use sysinfo::Pid;
+    use temp_testdir::TempDir;
+    use tracing::debug;
+
+    use crate::{App, artifacts, bypass, conflicts, debug_log, drain, exec, export, fixtures, forward, lease_record, locale,
+        PoolState, provision, requested_device, root, runtime_dir, shard, wait_for_devices, wireless};
+    use crate::adb::{Adb, AdbDevice, Battery};
+    use crate::api::Api;
+    use crate::cli::JobOptions;
+    use crate::config::{ApiConfig, Config};
+    use crate::conflicts::OnConflict;
+    use crate::device_info::{Capabilities, DeviceCache, DeviceInfo, Transport};
+    use crate::events::{EventLog, LeaseRecord};
+    use crate::filelock::{self, FileLockGuardExt};
+    use crate::filter::DeviceFilter;
+    use crate::lockfile::{Entry, LockFileEntries, Owner};
+    use crate::observer::Observer;
+    use crate::record::{device_prefix, start_command, Recording};
+    use crate::runtime::{BootProgress, Runtime, Serial};
+    use crate::selection::{SelectionPolicy, UsageHistory};
+    use crate::shared::Shared;
+    use crate::store::{Acquired, Choose, Claim, FileStore, LockMode, PoolStore, Running};
+    use crate::teardown::Teardown;
+    use crate::waiters::Waiters;
+
+    use super::Result;
+
+    fn serial(serial: &str) -> Serial {
+        Serial::new(serial).unwrap()
+    }
+
+    #[test]
+    fn single_device_single_run_first_time() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+
+        let app = App::new(runtime, &runtime_dir);
+        let resource = app.acquire_resource(1)?;
+
+        assert_eq!(resource.serial, "serial1");
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "#adp-lock v2\nserial1\tpid=1\tclaimed-at=100\n");
+
+        resource.release()?;
+
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "#adp-lock v2\nserial1\treleased-at=100\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn single_device_single_run_second_time() -> Result<()> {
+        debug_log();
+        // let adb = FakeAdb(vec![serial("serial1")]);
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1\n")?;
+
+        let app = App::new(runtime, &runtime_dir);
+        let resource = app.acquire_resource(1)?;
+
+        assert_eq!(resource.serial, "serial1");
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "#adp-lock v2\nserial1\tpid=1\tclaimed-at=100\n");
+
+        resource.release()?;
+
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "#adp-lock v2\nserial1\treleased-at=100\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn single_device_three_runs() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        let app = App::new(runtime, &runtime_dir);
+
+        for _ in 0..3 {
+            let resource = app.acquire_resource(1)?;
+            resource.release()?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn multiple_devices_multiple_runs_first_time() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+
+        let app = App::new(runtime, &runtime_dir);
+        let resource1 = app.acquire_resource(1)?;
+        let resource2 = app.acquire_resource(2)?;
+
+        assert_eq!(resource1.serial, "serial1");
+        assert_eq!(resource2.serial, "serial2");
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "#adp-lock v2\nserial1\tpid=1\tclaimed-at=100\nserial2\tpid=2\tclaimed-at=100\n");
+
+        resource1.release()?;
+        resource2.release()?;
+
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "#adp-lock v2\nserial1\treleased-at=100\nserial2\treleased-at=100\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn resource_blocks_until_one_is_released() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .processes(vec![1])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        let app = App::new(runtime.clone(), &runtime_dir);
+        let resource1 = app.acquire_resource(1)?;
+
+        let (send, recv) = std::sync::mpsc::channel();
+        // This should block until resource1 is released.
+        let handle = std::thread::spawn(move || {
+            debug_log();
+            let app = App::new(runtime.clone(), &runtime_dir);
+            let resource2 = app.acquire_resource(2).unwrap();
+            let serial = resource2.serial.clone();
+            debug!(send = %serial);
+            send.send(serial).unwrap();
+            resource2.release().unwrap();
+        });
+
+        let result = recv.recv_timeout(Duration::from_millis(500));
+        match result {
+            Ok(value) => {
+                panic!("expected to be blocked but got: {}", value);
+            }
+            Err(e) => {
+                assert_eq!(e, RecvTimeoutError::Timeout)
+            }
+        }
+
+        resource1.release()?;
+
+        let result = recv.recv_timeout(Duration::from_millis(500))?;
+        assert_eq!(result, "serial1");
+
+        handle.join().expect("failed to join thread");
+
+        Ok(())
+    }
+
+    #[test]
+    fn takes_back_devices_from_processes_that_died_holding_them() -> Result<()> {
+        debug_log();
+        // 1 being running stands in for its pid having been reused.
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .processes(vec![1, 2])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        let app = App::new(runtime.clone(), &runtime_dir);
+        std::mem::forget(app.acquire_resource(1)?);
+        drop(app);
+
+        let app = App::new(runtime, &runtime_dir);
+        let resource = app.try_acquire_resource(2)?;
+
+        assert_eq!(resource.map(|resource| resource.serial.clone()), Some(serial("serial1")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn try_acquire_returns_none_without_devices() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default().build()?;
+        let runtime_dir = TempDir::default();
+
+        let app = App::new(runtime, &runtime_dir);
+
+        assert!(app.try_acquire_resource(1)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn obtains_the_correct_resource_when_device_is_removed() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial2")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1\nserial2\n")?;
+
+        let app = App::new(runtime, &runtime_dir);
+        let resource = app.acquire_resource(1)?;
+
+        assert_eq!(resource.serial, "serial2");
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "#adp-lock v2\nserial2\tpid=1\tclaimed-at=100\n");
+
+        resource.release()?;
+
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "#adp-lock v2\nserial2\treleased-at=100\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn obtains_resource_if_process_is_no_longer_running() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1:1\n")?;
+
+        let app = App::new(runtime, &runtime_dir);
+        let resource = app.acquire_resource(2)?;
+
+        assert_eq!(resource.serial, "serial1");
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "#adp-lock v2\nserial1\tpid=2\tclaimed-at=100\treleased-at=100\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_touches_the_waiters_after_waiting() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .processes(vec![1, 2, 3])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        let app = App::new(runtime, &runtime_dir);
+
+        let first = app.acquire_resource(1)?;
+        let _second = app.acquire_resource(2)?;
+        assert!(!runtime_dir.join("adp.waiters").exists());
+
+        assert!(app.try_acquire_resource(3)?.is_none());
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.waiters"))?, "");
+        first.release()?;
+        assert!(app.try_acquire_resource(3)?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn tracks_latency_when_asked() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .processes(vec![1, 2])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1:1\n")?;
+        let mut app = App::new(runtime, &runtime_dir);
+
+        assert!(app.acquire_resource_timeout(2, Duration::from_millis(100))?.is_none());
+        assert_eq!(app.latency(), None);
+
+        app.track_latency();
+        assert!(app.acquire_resource_timeout(2, Duration::from_millis(100))?.is_none());
+        let latency = app.latency().unwrap();
+        assert!(latency.wait_ms > 0, "{:?}", latency);
+        assert_eq!(latency.boot_wait_ms, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn gives_up_when_no_device_frees_up_in_time() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .processes(vec![1, 2])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1:1\n")?;
+
+        let app = App::new(runtime, &runtime_dir);
+
+        assert!(app.try_acquire_resource(2)?.is_none());
+        assert!(app.acquire_resource_timeout(2, Duration::from_millis(200))?.is_none());
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.waiters"))?, "");
+
+        app.evict(&serial("serial1"), 1)?;
+        let resource = app.try_acquire_resource(2)?.unwrap();
+        assert_eq!(resource.serial, "serial1");
+
+        Ok(())
+    }
+
+    #[test]
+    fn releases_dropped_resources() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .processes(vec![1])
+            .build()?;
+        let runtime_dir = TempDir::default();
+
+        let app = App::new(runtime, &runtime_dir);
+        let mut resource = app.acquire_resource(1)?;
+        resource.mark_device_dirty();
+        drop(resource);
+
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "#adp-lock v2\nserial1\tdirty\treleased-at=100\n");
+
+        Ok(())
+    }
+
+    // The opposite of the default, so it's clear it was used.
+    #[derive(Debug)]
+    struct MostRecentlyUsedPolicy;
+
+    impl SelectionPolicy for MostRecentlyUsedPolicy {
+        fn choose(&self, candidates: &[DeviceInfo], history: &UsageHistory) -> Option<Serial> {
+            candidates.iter()
+                .max_by_key(|device| history.get(&device.serial).and_then(|entry| entry.released_at))
+                .map(|device| device.serial.clone())
+        }
+    }
+
+    #[test]
+    fn claims_the_device_the_policy_chooses() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2"), serial("serial3")])
+            .processes(vec![1, 3])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1\treleased-at=10\nserial2\treleased-at=20\nserial3:3\treleased-at=30\n")?;
+
+        let mut app = App::new(runtime, &runtime_dir);
+        app.set_selection_policy(Box::new(MostRecentlyUsedPolicy));
+        let resource = app.acquire_resource(1)?;
+
+        assert_eq!(resource.serial, "serial2");
+
+        Ok(())
+    }
+
+    #[test]
+    fn retries_on_another_device_if_theres_one_free() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2"), serial("serial3")])
+            .processes(vec![1, 3])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        // serial1 has been idle the longest, but the job just failed on it.
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1\treleased-at=10\nserial2\treleased-at=20\nserial3:3\treleased-at=30\n")?;
+
+        let mut app = App::new(runtime, &runtime_dir);
+        app.set_avoid(vec![serial("serial1")]);
+        let resource = app.acquire_resource(1)?;
+        assert_eq!(resource.serial, "serial2");
+        resource.release()?;
+
+        // Only the ones it failed on are free.
+        app.set_avoid(vec![serial("serial1"), serial("serial2")]);
+        let resource = app.acquire_resource(1)?;
+        assert_eq!(resource.serial, "serial1");
+        resource.release()?;
+
+        // Whichever policy is set still picks among the rest.
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1\treleased-at=10\nserial2\treleased-at=30\nserial3\treleased-at=20\n")?;
+        app.set_selection_policy(Box::new(MostRecentlyUsedPolicy));
+        app.set_avoid(vec![serial("serial2")]);
+        let resource = app.acquire_resource(1)?;
+        assert_eq!(resource.serial, "serial3");
+
+        Ok(())
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordingObserver {
+        events: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Observer for RecordingObserver {
+        fn on_acquire(&self, serial: &Serial, pid: Pid, _owner: Option<&Owner>, _now: SystemTime) {
+            self.events.borrow_mut().push(format!("acquire {} {}", serial, pid));
+        }
+
+        fn on_boot_progress(&self, serial: &Serial, progress: &BootProgress) {
+            self.events.borrow_mut().push(format!("waiting {} {} {}s", serial, progress.waiting_for, progress.elapsed.as_secs()));
+        }
+
+        fn on_boot_wait(&self, serial: &Serial, _waited: Duration, _now: SystemTime) {
+            self.events.borrow_mut().push(format!("ready {}", serial));
+        }
+
+        fn on_release(&self, serial: &Serial, _now: SystemTime) {
+            self.events.borrow_mut().push(format!("release {}", serial));
+        }
+
+        fn on_reclaim(&self, serial: &Serial, pid: Pid, _now: SystemTime) {
+            self.events.borrow_mut().push(format!("reclaim {} {}", serial, pid));
+        }
+    }
+
+    #[test]
+    fn notifies_observers_of_lease_lifecycle() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .processes(vec![1, 2])
+            .build()?;
+        let runtime_dir = TempDir::default();
+
+        let mut app = App::new(runtime, &runtime_dir);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        app.add_observer(Box::new(RecordingObserver { events: events.clone() }));
+        app.acquire_resource(1)?.release()?;
+        let resource = app.acquire_resource(2)?;
+        app.evict(&resource.serial, 2)?;
+        std::mem::forget(resource);
+
+        assert_eq!(*events.borrow(), vec![
+            "acquire serial1 1", "ready serial1", "release serial1", "acquire serial1 2", "ready serial1", "reclaim serial1 2",
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn notifies_observers_of_claims_reconciled_away() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "#adp-lock v2\nserial1\tpid=5\tclaimed-at=100\n")?;
+
+        let mut app = App::new(runtime, &runtime_dir);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        app.add_observer(Box::new(RecordingObserver { events: events.clone() }));
+        app.reconcile()?;
+        app.reconcile()?;
+
+        assert_eq!(*events.borrow(), vec!["reclaim serial1 5"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn notifies_observers_of_boot_progress() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .processes(vec![1])
+            .boot_progress(vec![1, 2])
+            .build()?;
+        let runtime_dir = TempDir::default();
+
+        let mut app = App::new(runtime, &runtime_dir);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        app.add_observer(Box::new(RecordingObserver { events: events.clone() }));
+        app.acquire_resource(1)?.release()?;
+
+        assert_eq!(*events.borrow(), vec![
+            "acquire serial1 1",
+            "waiting serial1 sys.boot_completed 1s",
+            "waiting serial1 sys.boot_completed 2s",
+            "ready serial1",
+            "release serial1",
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reconcile_drops_stopped_waiters() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .processes(vec![1, 2])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1:1\n")?;
+        std::fs::write(runtime_dir.join("adp.waiters"), "2\n3\n")?;
+
+        let app = App::new(runtime, &runtime_dir);
+        let state = app.reconcile()?;
+
+        assert_eq!(state.waiters.len(), 1);
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "#adp-lock v2\nserial1\tpid=1\nserial2\n");
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.waiters"))?, "2\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn evicted_device_is_health_checked_before_reuse() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .processes(vec![1])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1:1\n")?;
+
+        let app = App::new(runtime, &runtime_dir);
+        app.evict(&serial("serial1"), 1)?;
+
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "#adp-lock v2\nserial1\tdirty\treleased-at=100\n");
+
+        let resource = app.acquire_resource(2)?;
+
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "#adp-lock v2\nserial1\tpid=2\tclaimed-at=100\treleased-at=100\n");
+
+        resource.release()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn unhealthy_device_is_released() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .unhealthy(vec![serial("serial1")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1\tdirty\n")?;
+
+        let app = App::new(runtime, &runtime_dir);
+
+        assert!(app.acquire_resource(1).is_err());
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "#adp-lock v2\nserial1\tdirty\treleased-at=100\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn device_that_fails_to_get_ready_is_marked_dirty() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .unbootable(vec![serial("serial1")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+
+        let app = App::new(runtime, &runtime_dir);
+
+        assert!(app.acquire_resource(1).is_err());
+        assert!(app.entries()?.get(&serial("serial1")).unwrap().dirty);
+
+        Ok(())
+    }
+
+    #[test]
+    fn claims_and_releases_tcp_serials() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("192.168.1.5:5555"), serial("192.168.1.6:5555")])
+            .processes(vec![1, 2])
+            .build()?;
+        let runtime_dir = TempDir::default();
+
+        let app = App::new(runtime, &runtime_dir);
+        let first = app.acquire_resource(1)?;
+        let second = app.acquire_resource(2)?;
+
+        assert_eq!(app.reconcile()?.entries.get(&first.serial).unwrap().pid, Some(1));
+        assert_eq!(app.entries()?.get(&second.serial).unwrap().pid, Some(2));
+
+        first.release()?;
+        let entries = app.reconcile()?.entries;
+
+        assert_eq!(entries.get(&serial("192.168.1.5:5555")).unwrap().pid, None);
+        assert_eq!(entries.get(&serial("192.168.1.6:5555")).unwrap().pid, Some(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn records_owner_of_claim() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+
+        let mut app = App::new(runtime, &runtime_dir);
+        app.set_owner(Owner { user: "evan".to_string(), host: "bench".to_string(), cmd: "./gradlew".to_string() });
+        let resource = app.acquire_resource(1)?;
+
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "#adp-lock v2\nserial1\tpid=1\tuser=evan\thost=bench\tcmd=./gradlew\tclaimed-at=100\n");
+
+        resource.release()?;
+
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "#adp-lock v2\nserial1\treleased-at=100\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn saves_bugreports_named_after_the_device() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .build()?;
+        let adb_commands = runtime.adb_commands.clone();
+        let dir = TempDir::default();
+
+        let path = artifacts::bugreport(&runtime, &serial("serial1"), &dir, runtime.now())?;
+
+        assert_eq!(path, dir.join("serial1-100.zip"));
+        assert_eq!(*adb_commands.lock().unwrap(), [format!("serial1 bugreport {}", path.display())]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn saves_screenshots_named_after_the_device() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .build()?;
+        let dir = TempDir::default();
+
+        let path = artifacts::screenshot(&runtime, &serial("serial1"), &dir, runtime.now())?;
+
+        assert_eq!(path, dir.join("serial1-100.png"));
+        assert_eq!(std::fs::read(&path)?, b"\x89PNG");
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_provisions_what_needs_changing() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .props(BTreeMap::from([
+                ("persist.sys.timezone".to_string(), "Europe/London".to_string()),
+                ("persist.sys.locale".to_string(), "fr-FR".to_string()),
+            ]))
+            .build()?;
+        let adb_commands = runtime.adb_commands.clone();
+        let config = Config::parse("[provision]\ntimezone = \"Europe/London\"\nlocale = \"en-US\"\n")?;
+
+        provision::run(&runtime, &serial("serial1"), &config.provision)?;
+
+        assert_eq!(*adb_commands.lock().unwrap(), [
+            "serial1 shell setprop persist.sys.locale en-US",
+            "serial1 shell pidof zygote64 zygote",
+            "serial1 shell setprop ctl.restart zygote",
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn carries_on_when_the_framework_fails_to_restart() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .props(BTreeMap::from([("persist.sys.locale".to_string(), "fr-FR".to_string())]))
+            .failing_adb(vec!["shell setprop ctl.restart zygote".to_string()])
+            .build()?;
+        let adb_commands = runtime.adb_commands.clone();
+        let config = Config::parse("[provision]\nlocale = \"en-US\"\nairplane_mode_off = true\n")?;
+
+        provision::run(&runtime, &serial("serial1"), &config.provision)?;
+
+        assert_eq!(adb_commands.lock().unwrap().last().unwrap(), "serial1 shell cmd connectivity airplane-mode");
+
+        Ok(())
+    }
+
+    #[test]
+    fn quotes_the_wifi_network_for_the_device_shell() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .adb_outputs(BTreeMap::from([(
+                "shell cmd wifi status".to_string(),
+                vec!["Wifi is disabled".to_string(), "Wifi is connected to \"Lab's wifi\"".to_string()],
+            )]))
+            .build()?;
+        let adb_commands = runtime.adb_commands.clone();
+        let config = Config::parse(
+            "[provision]\nping = \"example.com; reboot\"\n[provision.wifi]\nssid = \"Lab's wifi\"\npassword = \"p4ss $(reboot)\"\n",
+        )?;
+
+        provision::run(&runtime, &serial("serial1"), &config.provision)?;
+        provision::check_network(&runtime, &serial("serial1"), &config.provision)?;
+
+        assert_eq!(*adb_commands.lock().unwrap(), [
+            "serial1 shell cmd wifi status",
+            "serial1 shell cmd wifi set-wifi-enabled enabled",
+            "serial1 shell cmd wifi connect-network 'Lab'\\''s wifi' wpa2 'p4ss $(reboot)'",
+            "serial1 shell cmd wifi status",
+            "serial1 shell ping -c 1 -W 5 'example.com; reboot'",
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn leaves_devices_whose_storage_couldnt_be_checked_in_the_pool() -> Result<()> {
+        debug_log();
+        let config = Config::parse("[provision]\nmin_free_storage = \"1G\"\n")?;
+
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .failing_adb(vec!["shell df -k /data".to_string()])
+            .build()?;
+        provision::check_storage(&runtime, &serial("serial1"), &config.provision)?;
+
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .adb_output(BTreeMap::from([("shell df -k /data".to_string(), "df: /data: Permission denied".to_string())]))
+            .build()?;
+        provision::check_storage(&runtime, &serial("serial1"), &config.provision)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn cleans_up_devices_low_on_storage() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .adb_output(BTreeMap::from([
+                ("shell df -k /data".to_string(), "Filesystem 1K-blocks Used Available Use% Mounted on\n\
+                    /dev/block/dm-5 8388608 8286208 102400 99% /data".to_string()),
+            ]))
+            .build()?;
+        let adb_commands = runtime.adb_commands.clone();
+        let config = Config::parse("[provision]\nmin_free_storage = \"1G\"\ncleanup_paths = [\"/sdcard/Download/*\"]\n")?;
+
+        let error = provision::check_storage(&runtime, &serial("serial1"), &config.provision).unwrap_err();
+
+        assert_eq!(error.to_string(), "serial1 only has 100M of storage free after cleaning it up, less than 1G");
+        assert_eq!(*adb_commands.lock().unwrap(), [
+            "serial1 shell df -k /data",
+            "serial1 shell pm trim-caches 1000G",
+            "serial1 shell rm -rf /sdcard/Download/*",
+            "serial1 shell df -k /data",
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn skips_quarantined_devices() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .processes(vec![1, 2])
+            .build()?;
+        let runtime_dir = TempDir::default();
+
+        let app = App::new(runtime, &runtime_dir);
+        let resource = app.acquire_resource(1)?;
+        app.quarantine(&resource.serial)?;
+        resource.release()?;
+
+        let resource = app.acquire_resource(2)?;
+        assert_eq!(resource.serial, "serial2");
+        assert!(app.try_acquire_resource(1)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn quarantines_a_device_that_fails_its_checks_and_runs_the_job_on_another() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .processes(vec![std::process::id() as Pid])
+            .failing_adb(vec!["serial1 shell ping -c 1 -W 5 'example.com'".to_string()])
+            .build()?;
+        let adb_commands = runtime.adb_commands.clone();
+        let runtime_dir = TempDir::default();
+        let config = Config::parse("[provision]\nping = \"example.com\"\n")?;
+
+        let mut app = App::new(runtime, &runtime_dir);
+        exec(&mut app, &config, &EventLog::new(&runtime_dir), &[], None, &JobOptions::default(), vec!["true".into()])?;
+
+        assert!(app.entries()?.get(&serial("serial1")).unwrap().quarantined);
+        assert!(!app.entries()?.get(&serial("serial2")).unwrap().quarantined);
+        assert!(adb_commands.lock().unwrap().contains(&"serial2 shell ping -c 1 -W 5 'example.com'".to_string()));
+        assert_eq!(app.entries()?.get(&serial("serial2")).unwrap().pid, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn paused_pool_hands_out_no_devices() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .processes(vec![1, 2])
+            .build()?;
+        let runtime_dir = TempDir::default();
+
+        let app = App::new(runtime, &runtime_dir);
+        let resource = app.acquire_resource(1)?;
+        assert!(app.pause(Some("moving benches".to_string()))?);
+        assert!(!app.pause(None)?);
+
+        assert!(app.try_acquire_resource(2)?.is_none());
+        // Running jobs carry on as normal.
+        resource.release()?;
+        assert!(app.try_acquire_resource(2)?.is_none());
+        assert_eq!(app.available()?, 0);
+        let error = wait_for_devices::wait(&app, 1, Some(Duration::ZERO), Duration::ZERO).unwrap_err();
+        assert_eq!(error.to_string(), "timed out with the pool paused, waiting for `adp resume`");
+
+        assert!(app.resume()?);
+        assert_eq!(app.available()?, 2);
+        assert!(!app.resume()?);
+        assert!(app.try_acquire_resource(2)?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn checks_adb_root_took() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .adb_output(BTreeMap::from([
+                ("shell id -u".to_string(), "2000".to_string()),
+                ("get-state".to_string(), "device".to_string()),
+            ]))
+            .build()?;
+
+        let e = root::enable(&runtime, &serial("serial1")).unwrap_err();
+
+        assert_eq!(e.to_string(), "adb root didn't take on serial1, its shell is running as uid 2000");
+        assert_eq!(runtime.adb_commands.lock().unwrap()[..2], ["serial1 root", "serial1 get-state"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn creates_the_given_runtime_dir() -> Result<()> {
+        let volume = TempDir::default();
+        let dir = volume.join("ci").join("adp");
+
+        assert_eq!(runtime_dir(Some(&dir), "default", &mut filelock::test_probe())?, dir);
+        assert!(dir.is_dir());
+        std::fs::write(volume.join("not-a-dir"), "")?;
+        assert!(runtime_dir(Some(&volume.join("not-a-dir")), "default", &mut filelock::test_probe()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_an_android_serial_the_pool_wont_hand_out() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(["serial1", "serial2", "serial3@1-1", "serial3@1-2", "serial4@1-3", "serial5"].map(serial).into())
+            .props(BTreeMap::from([("ro.product.model".to_string(), "Pixel 8".to_string())]))
+            .build()?;
+        let runtime_dir = TempDir::default();
+        let lock_file = "#adp-lock v2\nserial1\tquarantined\nserial2\tsingle-use\tspent\n";
+        std::fs::write(runtime_dir.join("adp.lock"), lock_file)?;
+
+        let mut app = App::new(runtime, &runtime_dir);
+        let error = |serial: &str| requested_device(&app, Some(serial.to_string())).unwrap_err().to_string();
+        assert!(error("serial1").ends_with("which is quarantined, run `adp unquarantine serial1` once it's fixed"));
+        assert_eq!(error("serial2"), "ANDROID_SERIAL is set to serial2, which was single use and has been used");
+        assert!(error("serial3").ends_with("which more than one device has, set it to one of serial3@1-1, serial3@1-2"));
+        assert_eq!(requested_device(&app, Some("serial3@1-2".to_string()))?, Some(serial("serial3@1-2")));
+        assert_eq!(requested_device(&app, Some("serial4".to_string()))?, Some(serial("serial4@1-3")));
+
+        app.set_filters(vec![DeviceFilter::prop("ro.product.model=Pixel 9")?]);
+        assert_eq!(requested_device(&app, Some("serial5".to_string())).unwrap_err().to_string(),
+                   "ANDROID_SERIAL is set to serial5, which doesn't match ro.product.model=Pixel 9");
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_claims_the_device_from_android_serial() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .processes(vec![1])
+            .build()?;
+        let runtime_dir = TempDir::default();
+
+        let mut app = App::new(runtime, &runtime_dir);
+        assert_eq!(requested_device(&app, Some("serial3".to_string()))?, None);
+        assert_eq!(requested_device(&app, None)?, None);
+        let requested = requested_device(&app, Some("serial2".to_string()))?.unwrap();
+        assert!(app.restrict_to(&requested));
+        let resource = app.acquire_resource(1)?;
+
+        assert_eq!(resource.serial, serial("serial2"));
+        resource.release()?;
+        app.set_group(BTreeSet::from([serial("serial1")]));
+        assert!(!app.restrict_to(&requested));
+
+        Ok(())
+    }
+
+    #[test]
+    fn records_bypasses_without_claiming() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        let events = EventLog::new(&runtime_dir);
+
+        let app = App::new(runtime, &runtime_dir);
+        bypass::run(&app, &events, &Config::default(), Some(serial("serial1")), vec!["true".into()])?;
+
+        let bypasses = events.bypasses()?;
+        assert_eq!(bypasses.len(), 1);
+        assert!(bypasses[0].bypassed);
+        assert_eq!(bypasses[0].serial, serial("serial1"));
+        assert_eq!(bypasses[0].exit_code, Some(0));
+        // Not a job the pool handed a device to.
+        assert!(events.leases()?.is_empty());
+        assert!(app.snapshot()?.entries.get(&serial("serial1")).is_none_or(|entry| entry.pid.is_none()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn finds_shards_whose_device_dropped_out() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        let events = EventLog::new(&runtime_dir);
+        let app = App::new(runtime, &runtime_dir);
+        let owner = Owner { user: "evan".to_string(), host: "bench".to_string(), cmd: "./gradlew".to_string() };
+        for (pid, device) in [(10, "serial1"), (11, "serial2")] {
+            let lease = lease_record(&app, &serial(device), &owner, None, app.now(), Some(1));
+            events.append(&LeaseRecord { pid, ..lease })?;
+        }
+
+        let spawned_at = app.now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        assert_eq!(shard::dropped_device(&app, &events, 10, spawned_at)?, None);
+        assert_eq!(shard::dropped_device(&app, &events, 11, spawned_at)?, Some(serial("serial2")));
+        assert_eq!(shard::dropped_device(&app, &events, 12, spawned_at)?, None);
+        // The lease is from an earlier process with the same pid.
+        assert_eq!(shard::dropped_device(&app, &events, 11, spawned_at + 1)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn puts_the_locale_back_after_the_job() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .processes(vec![1])
+            .props(BTreeMap::from([("persist.sys.locale".to_string(), "en-US".to_string())]))
+            .build()?;
+        let adb_commands = runtime.adb_commands.clone();
+        let runtime_dir = TempDir::default();
+
+        let app = App::new(runtime, &runtime_dir);
+        let resource = app.acquire_resource(1)?;
+        locale::set_up(&app, &resource.serial, "fr-FR", true)?;
+
+        assert_eq!(*adb_commands.lock().unwrap(), [
+            "serial1 shell setprop persist.sys.locale fr-FR",
+            "serial1 shell pidof zygote64 zygote",
+            "serial1 shell setprop ctl.restart zygote",
+        ]);
+        assert_eq!(app.entries()?.get(&resource.serial).unwrap().restore_locale.as_deref(), Some("en-US"));
+
+        locale::restore(&app, &resource.serial, true)?;
+        resource.release()?;
+
+        assert_eq!(app.entries()?.get(&serial("serial1")).unwrap().restore_locale, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn shapes_real_devices_and_puts_them_back() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .processes(vec![1])
+            .props(BTreeMap::from([("ro.build.version.sdk".to_string(), "33".to_string())]))
+            .build()?;
+        let adb_commands = runtime.adb_commands.clone();
+        let runtime_dir = TempDir::default();
+        let config = Config::parse("[adb]\nforward = [\"tcp:4723 tcp:4723\"]\n")?;
+
+        let app = App::new(runtime, &runtime_dir);
+        let resource = app.acquire_resource(1)?;
+        let mut teardown = Teardown::new(&app, &resource.serial);
+        forward::set_up(&app, &resource.serial, &config.adb, &mut teardown.forwards)?;
+        teardown.shaped = true;
+        fixtures::shape_device_network(&app, &resource.serial, "edge")?;
+        drop(teardown);
+
+        assert_eq!(*adb_commands.lock().unwrap(), [
+            "serial1 forward --no-rebind tcp:4723 tcp:4723",
+            "serial1 shell settings put global ingress_rate_limit_bytes_per_second 59200",
+            "serial1 shell settings put global ingress_rate_limit_bytes_per_second -1",
+            "serial1 forward --remove tcp:4723",
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_to_shape_devices_before_android_13() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .props(BTreeMap::from([("ro.build.version.sdk".to_string(), "30".to_string())]))
+            .build()?;
+
+        let e = fixtures::shape_device_network(&runtime, &serial("serial1"), "edge").unwrap_err();
+
+        assert_eq!(
+            e.to_string(),
+            "only emulators and devices on Android 13 or later can have their network shaped, serial1 is on sdk 30",
+        );
+        assert!(runtime.adb_commands.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn marks_only_devices_that_are_still_unmarked() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+
+        let app = App::new(runtime, &runtime_dir);
+        app.reconcile()?;
+
+        assert_eq!(app.mark_single_use(&[serial("serial1"), serial("serial3")])?, [serial("serial1")]);
+        assert_eq!(app.mark_single_use(&[serial("serial1"), serial("serial2")])?, [serial("serial2")]);
+        assert!(app.entries()?.get(&serial("serial1")).unwrap().single_use);
+
+        Ok(())
+    }
+
+    #[test]
+    fn detects_adb_clients_outside_the_pool() -> Result<()> {
+        debug_log();
+        let ps = "PID PPID NAME\n1 0 init\n500 1 adbd\n600 500 logcat\n700 500 sh\n701 700 ps";
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .adb_output(BTreeMap::from([("shell ps -A -o PID,PPID,NAME".to_string(), ps.to_string())]))
+            .build()?;
+
+        conflicts::check(&runtime, &serial("serial1"), OnConflict::Warn)?;
+        let e = conflicts::check(&runtime, &serial("serial1"), OnConflict::Fail).unwrap_err();
+
+        assert_eq!(
+            e.to_string(),
+            "serial1 is being used outside the pool, adb is running logcat on it, ex: for Android Studio, \
+            not running the job on it",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sets_up_and_removes_forwards() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .build()?;
+        let adb_commands = runtime.adb_commands.clone();
+        let config = Config::parse("[adb]\nforward = [\"tcp:4723 tcp:4723\"]\nreverse = [\"tcp:8081 tcp:8081\"]\n")?;
+
+        let mut set_up = Vec::new();
+        forward::set_up(&runtime, &serial("serial1"), &config.adb, &mut set_up)?;
+        forward::tear_down(&runtime, &serial("serial1"), &set_up);
+
+        assert_eq!(*adb_commands.lock().unwrap(), [
+            "serial1 forward --no-rebind tcp:4723 tcp:4723",
+            "serial1 reverse tcp:8081 tcp:8081",
+            "serial1 forward --remove tcp:4723",
+            "serial1 reverse --remove tcp:8081",
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn stops_only_its_own_recording_and_leaves_out_segments_that_fail_to_pull() -> Result<()> {
+        debug_log();
+        let prefix = device_prefix();
+        let dir = TempDir::default();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .adb_output(BTreeMap::from([
+                (format!("shell {}", start_command(&prefix)), "1234\n".to_string()),
+                (format!("shell ls {}-*.mp4 2>/dev/null", prefix), format!("{0}-1.mp4\n{0}-0.mp4\n", prefix)),
+            ]))
+            .failing_adb(vec![format!("pull {}-1.mp4 {}", prefix, dir.join("serial1-100.2.mp4").to_string_lossy())])
+            .build()?;
+        let adb_commands = runtime.adb_commands.clone();
+        // What pulling the first segment would have written.
+        std::fs::write(dir.join("serial1-100.1.mp4"), "mp4")?;
+
+        let recording = Recording::start(&runtime, &serial("serial1"), &dir, UNIX_EPOCH + Duration::from_secs(100))?;
+        let path = recording.finish(&runtime)?;
+
+        assert_eq!(path, dir.join("serial1-100.mp4"));
+        assert_eq!(std::fs::read_to_string(&path)?, "mp4");
+        assert_eq!(adb_commands.lock().unwrap()[1..], [
+            format!("serial1 shell touch {}.stop; pkill -INT -P 1234 screenrecord", prefix),
+            "serial1 shell kill -0 1234 2>/dev/null && echo running".to_string(),
+            format!("serial1 shell ls {}-*.mp4 2>/dev/null", prefix),
+            format!("serial1 pull {}-0.mp4 {}", prefix, dir.join("serial1-100.1.mp4").to_string_lossy()),
+            format!("serial1 pull {}-1.mp4 {}", prefix, dir.join("serial1-100.2.mp4").to_string_lossy()),
+            format!("serial1 shell rm -f {0}-*.mp4 {0}.stop", prefix),
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn leaves_forwards_another_job_has_alone() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .failing_adb(vec!["forward --no-rebind tcp:4724 tcp:4723".to_string()])
+            .build()?;
+        let adb_commands = runtime.adb_commands.clone();
+        let config = Config::parse("[adb]\nforward = [\"tcp:8081 tcp:8081\", \"tcp:4724 tcp:4723\"]\n")?;
+
+        let mut set_up = Vec::new();
+        let e = forward::set_up(&runtime, &serial("serial1"), &config.adb, &mut set_up).unwrap_err();
+        forward::tear_down(&runtime, &serial("serial1"), &set_up);
+
+        assert!(format!("{:#}", e).contains("another job may have the port"));
+        assert_eq!(*adb_commands.lock().unwrap(), [
+            "serial1 forward --no-rebind tcp:8081 tcp:8081",
+            "serial1 forward --no-rebind tcp:4724 tcp:4723",
+            "serial1 forward --remove tcp:8081",
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn catches_claims_taken_out_from_under_it() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .processes(vec![1, 2])
+            .build()?;
+        let runtime_dir = TempDir::default();
+
+        let app = App::new(runtime, &runtime_dir);
+        let mut resource = app.acquire_resource(1)?;
+        resource.verify(1)?;
+
+        std::fs::write(runtime_dir.join("adp.lock"), "#adp-lock v2\nserial1\tpid=2\tclaimed-at=100\n")?;
+
+        assert_eq!(
+            resource.verify(1).unwrap_err().to_string(),
+            "the pool has serial1 down as claimed by pid 2 rather than this job, not running it so two jobs don't end up on one device",
+        );
+        drop(resource);
+        assert_eq!(app.entries()?.get(&serial("serial1")).unwrap().pid, Some(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn leaves_another_jobs_claim_alone_on_release() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .processes(vec![1, 2])
+            .build()?;
+        let runtime_dir = TempDir::default();
+
+        let app = App::new(runtime, &runtime_dir);
+        let resource = app.acquire_resource(1)?;
+        std::fs::write(runtime_dir.join("adp.lock"), "#adp-lock v2\nserial1\tpid=2\tclaimed-at=100\n")?;
+        resource.release()?;
+
+        assert_eq!(app.entries()?.get(&serial("serial1")).unwrap().pid, Some(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn knows_which_process_holds_a_device() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .processes(vec![1])
+            .build()?;
+        let runtime_dir = TempDir::default();
+
+        let app = App::new(runtime, &runtime_dir);
+        let resource = app.acquire_resource(1)?;
+
+        assert!(app.holds(&serial("serial1"), 1)?);
+        assert!(!app.holds(&serial("serial1"), 2)?);
+
+        resource.release()?;
+
+        assert!(!app.holds(&serial("serial1"), 1)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn renews_only_claimed_devices() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .processes(vec![1])
+            .build()?;
+        let runtime_dir = TempDir::default();
+
+        let app = App::new(runtime, &runtime_dir);
+        let _resource = app.acquire_resource(1)?;
+
+        assert!(app.renew_lease(&serial("serial1"))?);
+        assert!(app.entries()?.get(&serial("serial1")).unwrap().renewed_at.is_some());
+        assert!(!app.renew_lease(&serial("serial2"))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_claims_devices_in_its_group() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .processes(vec![1])
+            .build()?;
+        let runtime_dir = TempDir::default();
+
+        let mut app = App::new(runtime, &runtime_dir);
+        app.set_group(BTreeSet::from([serial("serial2")]));
+        let resource = app.acquire_resource(1)?;
+
+        assert_eq!(resource.serial, "serial2");
+        assert!(app.try_acquire_resource(2)?.is_none());
+        assert!(app.entries()?.is_available(&serial("serial1")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn holds_users_to_their_quota() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .processes(vec![1, 2])
+            .build()?;
+        let runtime_dir = TempDir::default();
+
+        let mut app = App::new(runtime, &runtime_dir);
+        app.set_quotas(Config::parse("[quotas]\ndefault = 1\n")?.quotas);
+        let evan = Owner { user: "evan".to_string(), host: "bench".to_string(), cmd: "./gradlew".to_string() };
+        app.set_owner(evan.clone());
+        let _resource = app.acquire_resource(1)?;
+
+        assert!(app.acquire_resource_timeout(2, Duration::from_millis(100))?.is_none());
+        assert!(app.try_acquire_resource_for(2, &evan)?.is_none());
+        let sam = Owner { user: "sam".to_string(), ..evan };
+        assert_eq!(app.try_acquire_resource_for(2, &sam)?.unwrap().serial, "serial2");
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_claims_whose_jobs_are_running_when_adb_lists_no_devices() -> Result<()> {
+        debug_log();
+        // ex: while the adb server restarts
+        let runtime = FakeRuntimeBuilder::default()
+            .processes(vec![1])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1:1\nserial2:2\nserial3\n")?;
+
+        let app = App::new(runtime, &runtime_dir);
+        let state = app.reconcile()?;
+
+        assert_eq!(state.entries.get(&serial("serial1")).and_then(|entry| entry.pid), Some(1));
+        assert!(state.entries.get(&serial("serial2")).is_none());
+        assert!(state.entries.get(&serial("serial3")).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn claims_through_an_empty_listing() -> Result<()> {
+        debug_log();
+        // ex: while the adb server restarts
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .empty_listings(1)
+            .build()?;
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1\tdirty\nserial2\tsingle-use\tspent\n")?;
+
+        let app = App::new(runtime, &runtime_dir);
+        let resource = app.try_acquire_resource(1)?.unwrap();
+
+        assert_eq!(resource.serial, serial("serial1"));
+        assert!(app.entries()?.get(&serial("serial2")).unwrap().spent);
+
+        Ok(())
+    }
+
+    #[test]
+    fn caps_how_many_jobs_run_on_the_host() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2"), serial("serial3")])
+            .processes(vec![1, 2, 7])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        // A job on another host sharing the pool doesn't count.
+        std::fs::write(runtime_dir.join("adp.lock"), "serial3:7\tuser=sam\thost=other\tcmd=x\n")?;
+
+        let mut app = App::new(runtime, &runtime_dir);
+        app.set_max_concurrent(Some(1));
+        let evan = Owner { user: "evan".to_string(), host: "bench".to_string(), cmd: "./gradlew".to_string() };
+        app.set_owner(evan.clone());
+        let resource = app.acquire_resource(1)?;
+
+        assert!(app.acquire_resource_timeout(2, Duration::from_millis(100))?.is_none());
+        let sam = Owner { user: "sam".to_string(), ..evan };
+        assert!(app.try_acquire_resource_for(2, &sam)?.is_none());
+        resource.release()?;
+        assert!(app.try_acquire_resource(2)?.is_some());
+
+        Ok(())
+    }
+
+    fn test_shared(host: &str) -> Shared {
+        Shared { host: host.to_string(), poll_interval: Duration::from_millis(10), lock_timeout: Duration::from_secs(1) }
+    }
+
+    #[test]
+    fn shared_pool_leaves_claims_from_other_hosts_alone() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        let shared_dir = TempDir::default();
+        std::fs::write(shared_dir.join("adp.lock"), "serial1:7\tuser=sam\thost=other\tcmd=x\ttoken=a\nserial3:8\tuser=sam\thost=other\tcmd=y\ttoken=b\n")?;
+
+        let mut store = FileStore::new(&runtime_dir);
+        store.set_shared(&shared_dir, test_shared("bench"));
+        let mut app = App::with_store(runtime, Box::new(store));
+        app.set_owner(Owner { user: "evan".to_string(), host: "bench".to_string(), cmd: "./gradlew".to_string() });
+        let resource = app.acquire_resource(1)?;
+
+        assert_eq!(resource.serial, "serial2");
+        let entries = app.entries()?;
+        assert_eq!(entries.get(&serial("serial1")).unwrap().pid, Some(7));
+        assert_eq!(entries.get(&serial("serial3")).unwrap().pid, Some(8));
+        assert!(entries.get(&serial("serial2")).unwrap().token.is_some());
+
+        resource.release()?;
+
+        assert_eq!(app.entries()?.get(&serial("serial2")).unwrap().pid, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn shared_pool_only_releases_own_claim() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        let shared_dir = TempDir::default();
+
+        let mut store = FileStore::new(&runtime_dir);
+        store.set_shared(&shared_dir, test_shared("bench"));
+        let app = App::with_store(runtime, Box::new(store));
+        let resource = app.acquire_resource(1)?;
+        // Another host decided the claim was dead and took the device over with the same pid.
+        let taken_over = "#adp-lock v2\nserial1\tpid=1\tuser=sam\thost=other\tcmd=x\ttoken=b\n";
+        std::fs::write(shared_dir.join("adp.lock"), taken_over)?;
+
+        resource.release()?;
+
+        assert_eq!(std::fs::read_to_string(shared_dir.join("adp.lock"))?, taken_over);
+
+        Ok(())
+    }
+
+    #[test]
+    fn judges_claims_from_containers_by_their_heartbeats() -> Result<()> {
+        debug_log();
+        let runtime_dir = TempDir::default();
+        let lock_file = "serial1\tpid=7\theartbeat-at=1020\nserial2\tpid=8\theartbeat-at=1000\nserial3\tpid=9\n";
+        let serials = vec![serial("serial1"), serial("serial2"), serial("serial3")];
+        let now = UNIX_EPOCH + Duration::from_secs(1040);
+        let checked = RefCell::new(Vec::new());
+        let running = |pids: &[Pid]| {
+            checked.borrow_mut().extend_from_slice(pids);
+            Ok(BTreeSet::new())
+        };
+
+        // From inside a container, none of the pids can be checked on.
+        std::fs::write(runtime_dir.join("adp.lock"), lock_file)?;
+        let mut store = FileStore::new(&runtime_dir);
+        store.set_heartbeat(true, Duration::from_secs(30));
+        let state = store.reconcile(&serials, &running, now)?;
+
+        assert!(checked.borrow().is_empty());
+        assert_eq!(state.entries.get(&serial("serial1")).unwrap().pid, Some(7));
+        assert_eq!(state.entries.get(&serial("serial2")).unwrap().pid, None);
+        assert_eq!(state.entries.get(&serial("serial3")).unwrap().pid, Some(9));
+
+        // From the host, only the claim without heartbeats is.
+        std::fs::write(runtime_dir.join("adp.lock"), lock_file)?;
+        let mut store = FileStore::new(&runtime_dir);
+        store.set_heartbeat(false, Duration::from_secs(30));
+        let state = store.reconcile(&serials, &running, now)?;
+
+        assert_eq!(checked.into_inner(), vec![9]);
+        assert_eq!(state.entries.get(&serial("serial1")).unwrap().pid, Some(7));
+        assert_eq!(state.entries.get(&serial("serial2")).unwrap().pid, None);
+        assert_eq!(state.entries.get(&serial("serial3")).unwrap().pid, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn disconnected_devices_run_out_their_grace_period_while_jobs_wait() -> Result<()> {
+        debug_log();
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1\n")?;
+        let mut store = FileStore::new(&runtime_dir);
+        store.set_reconnect_grace(Duration::from_secs(30));
+        let running = |_: &[Pid]| Ok(BTreeSet::new());
+        let claim = |secs| Claim {
+            pid: 1,
+            owner: None,
+            claimed_at: UNIX_EPOCH + Duration::from_secs(secs),
+            nonce: 0,
+            quota: None,
+            eligible: None,
+            max_concurrent: None,
+        };
+
+        assert!(store.acquire(&[], &claim(1000), None, &running)?.is_none());
+        assert!(store.acquire(&[], &claim(1020), None, &running)?.is_none());
+        assert!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?.contains("serial1"));
+        let state = store.reconcile(&[], &running, UNIX_EPOCH + Duration::from_secs(1040))?;
+        assert!(state.entries.get(&serial("serial1")).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_reconnecting_wireless_devices_after_one_fails() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default().build()?;
+        let adb = Adb::new("/nonexistent/adb");
+        let endpoints = vec!["192.168.1.20:37123".to_string(), "192.168.1.21:40111".to_string()];
+
+        let e = wireless::reconnect(&runtime, &adb, &endpoints).unwrap_err().to_string();
+
+        assert!(e.contains("192.168.1.20:37123") && e.contains("192.168.1.21:40111"), "{}", e);
+
+        Ok(())
+    }
+
+    #[test]
+    fn claims_from_containers_send_heartbeats() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        let mut store = FileStore::new(&runtime_dir);
+        store.set_heartbeat(true, Duration::from_secs(30));
+        let app = App::with_store(runtime, Box::new(store));
+
+        assert_eq!(app.store.renew_interval(), Some(Duration::from_secs(10)));
+        let resource = app.acquire_resource(1)?;
+        let entry = app.entries()?.get(&resource.serial).unwrap().clone();
+        assert!(entry.token.is_some());
+        assert!(entry.heartbeat_at.is_some());
+
+        let later = app.now() + Duration::from_secs(5);
+        assert!(app.store.renew(&resource.serial, entry.token.as_deref(), later)?);
+        assert_eq!(app.entries()?.get(&resource.serial).unwrap().heartbeat_at, Some(later));
+        assert!(!app.store.renew(&resource.serial, Some("someone else"), later)?);
+        resource.release()?;
+        assert!(!app.store.renew(&serial("serial1"), entry.token.as_deref(), later)?);
+        assert_eq!(app.entries()?.get(&serial("serial1")).unwrap().heartbeat_at, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sends_heartbeats_from_the_claim_until_the_release() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        let mut store = FileStore::new(&runtime_dir);
+        store.set_heartbeat(true, Duration::from_secs(3));
+        let app = App::with_store(runtime, Box::new(store));
+
+        // Nothing else renews it while the job hasn't started.
+        let resource = app.acquire_resource(1)?;
+        let claimed_at = app.now();
+        std::thread::sleep(Duration::from_millis(1500));
+        let heartbeat_at = app.entries()?.get(&resource.serial).unwrap().heartbeat_at.unwrap();
+        assert!(heartbeat_at > claimed_at, "{:?}", heartbeat_at);
+
+        resource.release()?;
+        assert_eq!(app.entries()?.get(&serial("serial1")).unwrap().heartbeat_at, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tells_apart_waiters_from_containers_with_the_same_pid() -> Result<()> {
+        debug_log();
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1:1\n")?;
+        let mut store = FileStore::new(&runtime_dir);
+        store.set_heartbeat(true, Duration::from_secs(30));
+        let mut other = FileStore::new(&runtime_dir);
+        other.set_heartbeat(true, Duration::from_secs(30));
+        let now = UNIX_EPOCH + Duration::from_secs(100);
+
+        store.want(1, 2, now)?;
+        other.want(1, 3, now)?;
+        // Pids from other containers can't be checked, not even that they're running.
+        let running = |_: &[Pid]| Ok(BTreeSet::new());
+        let state = store.reconcile(&[serial("serial1")], &running, now + Duration::from_secs(10))?;
+        assert_eq!(state.waiters.len(), 2);
+        assert_eq!(state.waiters.demand(), 5);
+
+        let state = store.reconcile(&[serial("serial1")], &running, now + Duration::from_secs(60))?;
+        assert!(state.waiters.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn skips_devices_whose_lock_is_still_held() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1\treleased-at=10\nserial2\treleased-at=20\n")?;
+        std::fs::create_dir(runtime_dir.join("devices"))?;
+        // Stands in for a job that was killed but hasn't exited yet.
+        let _held = std::fs::File::create(runtime_dir.join("devices/serial1.lock"))?.try_into_lock_exclusive()?;
+
+        let app = App::new(runtime, &runtime_dir);
+        let resource = app.acquire_resource(1)?;
+
+        assert_eq!(resource.serial, "serial2");
+        assert_eq!(app.entries()?.get(&serial("serial1")).unwrap().released_at, Some(UNIX_EPOCH + Duration::from_secs(10)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn holds_claims_in_the_lock_file_alone() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        let mut store = FileStore::new(&runtime_dir);
+        store.set_lock_mode(LockMode::LockFileOnly);
+        let app = App::with_store(runtime, Box::new(store));
 
-    // TODO: allow custom adb path
-    let adb_path = "adb";
-    let runtime = RealRuntime::new(adb_path);
+        let resource = app.acquire_resource(1)?;
 
-    let runtime_dir = dirs::runtime_dir()
-        .or_else(|| dirs::cache_dir()).expect("missing cache dir")
-        .join("adp");
-    std::fs::create_dir_all(&runtime_dir)?;
+        assert_eq!(resource.serial, "serial1");
+        assert!(!runtime_dir.join("devices").exists());
+        // Still only the one claim on it.
+        assert_eq!(app.acquire_resource(2)?.serial, "serial2");
+        resource.release()?;
+        assert_eq!(app.entries()?.get(&serial("serial1")).unwrap().pid, None);
 
-    let sem = Semaphore::open("adp", 0)?;
-    let app = App::new(runtime, runtime_dir, &sem);
+        Ok(())
+    }
 
-    let resource = app.acquire_resource(std::process::id() as Pid)?;
+    #[test]
+    fn repair_reports_what_it_fixed() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1:1\nserial3\n")?;
+        std::fs::write(runtime_dir.join("adp.waiters"), "5\n")?;
 
-    let mut cmd = Command::new(cmd);
-    let cmd = cmd
-        .env("ANDROID_SERIAL", &resource.serial)
-        .args(args);
+        let app = App::new(runtime, &runtime_dir);
 
-    info!(ANDROID_SERIAL = %resource.serial, cmd = ?cmd);
+        assert_eq!(app.repair()?, vec![
+            "released serial1 from 1 which is no longer running",
+            "removed serial3 which is no longer connected",
+            "added serial2 which wasn't in the pool",
+            "removed waiter 5 which is no longer running",
+        ]);
+        assert!(app.repair()?.is_empty());
 
-    let result = cmd.status();
-    resource.release()?;
-    result?.exit_ok_()?;
+        Ok(())
+    }
 
-    Ok(())
-}
+    #[test]
+    fn finishes_writes_interrupted_by_a_crash() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .processes(vec![1])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        // Died after truncating the lock file, before writing out the claim it recorded the intent to make.
+        std::fs::write(runtime_dir.join("adp.lock"), "")?;
+        std::fs::write(runtime_dir.join("adp.lock.intent"), "serial1:1\nserial2\tquarantined\n")?;
 
-#[derive(Debug, Delegate)]
-#[delegate(Runtime, target = "runtime")]
-pub struct App<'a, R: Runtime + Debug> {
-    runtime: R,
-    sem: &'a Semaphore,
-    lock_file_path: PathBuf,
-}
+        let app = App::new(runtime, &runtime_dir);
 
-#[derive(Debug)]
-pub struct Resource<'a, R: Runtime + Debug> {
-    pub serial: String,
-    app: &'a App<'a, R>,
-    guard: SemaphoreGuard<'a>,
-}
+        assert_eq!(app.repair()?, vec!["finished writing the lock file, adp died in the middle of updating it"]);
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "#adp-lock v2\nserial1\tpid=1\nserial2\tquarantined\n");
+        assert!(!runtime_dir.join("adp.lock.intent").exists());
 
-impl<R: Runtime + Debug> App<'_, R> {
-    pub fn new(runtime: R, runtime_dir: impl AsRef<Path>, sem: &Semaphore) -> App<R> {
-        let lock_file_path = runtime_dir.as_ref().join("adp.lock");
-        App { runtime, sem, lock_file_path }
+        Ok(())
     }
 
-    #[instrument]
-    fn acquire_resource(&self, pid: Pid) -> Result<Resource<'_, R>> {
-        loop {
-            debug!("try_acquire_resource start");
-            let resource = self.try_acquire_resource(pid)?;
-            debug!("try_acquire_resource end");
-            debug!(resource = ?resource);
-            match resource {
-                Some(resource) => {
-                    resource.wait_for_ready()?;
-                    return Ok(resource);
-                }
-                None => {
-                    // try again
-                }
-            }
-        }
+    #[test]
+    fn checks_claims_and_waiters_in_one_batch() -> Result<()> {
+        debug_log();
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1:1\nserial2:2\nserial3:1\n")?;
+        std::fs::write(runtime_dir.join("adp.waiters"), "3\n4\n")?;
+        let store = FileStore::new(&runtime_dir);
+
+        let batches = RefCell::new(Vec::new());
+        let running = |pids: &[Pid]| {
+            batches.borrow_mut().push(pids.to_vec());
+            Ok(pids.iter().copied().filter(|pid| *pid != 2 && *pid != 4).collect())
+        };
+        let serials = vec![serial("serial1"), serial("serial2"), serial("serial3")];
+        let state = store.reconcile(&serials, &running, UNIX_EPOCH)?;
+
+        assert_eq!(batches.into_inner(), vec![vec![1, 2, 1, 3, 4]]);
+        assert_eq!(state.entries.get(&serial("serial2")).unwrap().pid, None);
+        assert_eq!(state.entries.get(&serial("serial3")).unwrap().pid, Some(1));
+        assert_eq!(state.waiters.iter().map(|id| id.pid).collect::<Vec<_>>(), vec![3]);
+
+        Ok(())
     }
 
-    #[instrument]
-    fn try_acquire_resource(&self, pid: Pid) -> Result<Option<Resource<'_, R>>> {
-        let serials = self.devices()?;
-        debug!(serials = %serials.join(","));
+    #[test]
+    fn leaves_low_battery_devices_to_charge() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .batteries(BTreeMap::from([(serial("serial1"), Battery { level: Some(10), charging: false, temperature: None })]))
+            .build()?;
+        let runtime_dir = TempDir::default();
 
-        let mut lock_file = open_lock_file(&self.lock_file_path)?;
-        debug!(lock_file = ?*lock_file);
+        let mut app = App::new(runtime, &runtime_dir);
+        app.set_battery(Some(Config::parse("[battery]\nmin = 20\n")?.battery.unwrap()));
+        let resource = app.acquire_resource(1)?;
 
-        let mut entries = LockFileEntries::read(BufReader::new(&*lock_file))?;
-        entries.update(&serials);
+        assert_eq!(resource.serial, "serial2");
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?,
+                   "#adp-lock v2\nserial1\tlow-battery\treleased-at=100\nserial2\tpid=1\tclaimed-at=100\n");
 
-        let mut actual_value = entries.count_available();
+        Ok(())
+    }
 
-        let mut serial = entries.acquire(pid);
-        if serial.is_none() {
-            // Check to see if any claimed serial is no longer running.
-            let mut dropped = Vec::new();
-            for (serial, pid) in entries.unavialble() {
-                debug!(check = %serial);
-                if !self.is_running(*pid)? {
-                    dropped.push(serial.clone());
-                }
-            }
-            entries.release_all(dropped);
-            // and try again.
-            actual_value = entries.count_available();
-            serial = entries.acquire(pid);
-        }
+    #[test]
+    fn returns_devices_once_recharged() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .batteries(BTreeMap::from([
+                (serial("serial1"), Battery { level: Some(85), charging: true, temperature: None }),
+                (serial("serial2"), Battery { level: Some(50), charging: true, temperature: None }),
+            ]))
+            .build()?;
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1\tlow-battery\nserial2\tlow-battery\n")?;
 
-        debug!(serial = ?serial, entries = %entries);
+        let mut app = App::new(runtime, &runtime_dir);
+        app.set_battery(Some(Config::parse("[battery]\nmin = 20\nresume = 80\n")?.battery.unwrap()));
 
-        let value = self.sem.value()?;
+        assert!(app.recheck_batteries()?);
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "#adp-lock v2\nserial1\nserial2\tlow-battery\n");
 
-        if value > actual_value {
-            debug!(value = value, adjust_to = actual_value);
-            for _ in actual_value..value {
-                self.sem.acquire()?;
-            }
-            debug!(value = self.sem.value()?);
-        } else if value < actual_value {
-            debug!(value = value, adjust_to = actual_value);
-            for _ in value..actual_value {
-                self.sem.release()?;
-            }
-            debug!(value = self.sem.value()?);
-        } else {
-            debug!(value = value);
-        }
+        Ok(())
+    }
 
-        if serial.is_some() {
-            lock_file.seek(SeekFrom::Start(0))?;
-            lock_file.set_len(0)?;
-            entries.write(BufWriter::new(&*lock_file))?;
-        }
+    #[test]
+    fn rests_hot_devices_on_release() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .batteries(BTreeMap::from([(serial("serial1"), Battery { temperature: Some(45.0), ..Battery::default() })]))
+            .build()?;
+        let runtime_dir = TempDir::default();
 
-        // Ensure lock file is dropped before we block on the resource, to not deadlock with others
-        // accessing it.
-        drop(lock_file);
-        let guard = self.sem.access()?;
+        let mut app = App::new(runtime, &runtime_dir);
+        app.set_thermal(Some(Config::parse("[thermal]\nmax = 40\n")?.thermal.unwrap()));
+        app.acquire_resource(1)?.release()?;
 
-        if let Some(serial) = serial {
-            Ok(Some(Resource { serial, app: self, guard }))
-        } else {
-            Ok(None)
-        }
-    }
-}
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?,
+                   "#adp-lock v2\nserial1\treleased-at=100\tavailable-after=400\n");
+        assert_eq!(app.end_cooldowns()?, Some(UNIX_EPOCH + Duration::from_secs(400)));
 
-impl<R: Runtime + Debug> Resource<'_, R> {
-    pub fn wait_for_ready(&self) -> Result<()> {
-        self.app.wait_for_boot(&self.serial)?;
         Ok(())
     }
 
-    #[instrument]
-    pub fn release(self) -> Result<()> {
-        let mut lock_file = open_lock_file(&self.app.lock_file_path)?;
-        let mut entries = LockFileEntries::read(BufReader::new(&*lock_file))?;
+    #[test]
+    fn prewarms_idle_devices() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2"), serial("serial3")])
+            .unhealthy(vec![serial("serial2")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1\nserial2\nserial3:3\n")?;
 
-        debug!(serial = %self.serial, entries = %entries);
-        entries.release(self.serial.clone());
-        debug!(serial = %self.serial, entries = %entries);
+        let app = App::new(runtime, &runtime_dir);
+        app.prewarm(2, 9)?;
 
-        lock_file.seek(SeekFrom::Start(0))?;
-        lock_file.set_len(0)?;
-        entries.write(BufWriter::new(&*lock_file))?;
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?,
+                   "#adp-lock v2\nserial1\tready\treleased-at=100\nserial2\tdirty\treleased-at=100\nserial3\tpid=3\n");
 
         Ok(())
     }
-}
 
-fn open_lock_file(path: impl AsRef<Path>) -> Result<FileLockGuard> {
-    let file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(path.as_ref()).with_context(|| format!("failed to open {:?}", path.as_ref()))?
-        .into_lock_exclusive()?;
-    Ok(file)
-}
+    #[test]
+    fn sweeps_idle_devices() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2"), serial("serial3")])
+            .unhealthy(vec![serial("serial2")])
+            .adb_output(BTreeMap::from([
+                ("shell df -k /data".to_string(), "Filesystem 1K-blocks Used Available Use% Mounted on\n\
+                    /dev/block/dm-5 8388608 8286208 102400 99% /data".to_string()),
+                ("shell cat /proc/uptime".to_string(), "700000.12 500000.34".to_string()),
+            ]))
+            .build()?;
+        let adb_commands = runtime.adb_commands.clone();
+        let runtime_dir = TempDir::default();
+        std::fs::write(runtime_dir.join("adp.lock"), "serial1\nserial2\nserial3:3\n")?;
+        let config = Config::parse("[sweep]\nschedule = \"0 3 * * *\"\nmin_free_storage = \"1G\"\nmax_uptime = \"7d\"\n")?;
+
+        let app = App::new(runtime, &runtime_dir);
+        let mut swept = BTreeSet::new();
+        assert!(app.sweep(config.sweep.as_ref().unwrap(), 9, &mut swept)?);
+        assert_eq!(swept, BTreeSet::from([serial("serial1")]));
+        assert!(app.sweep(config.sweep.as_ref().unwrap(), 9, &mut swept)?);
+        assert!(!app.sweep(config.sweep.as_ref().unwrap(), 9, &mut swept)?);
+
+        assert_eq!(*adb_commands.lock().unwrap(), [
+            "serial1 shell df -k /data",
+            "serial1 shell pm trim-caches 1000G",
+            "serial1 shell rm -rf /data/local/tmp/*",
+            "serial1 shell cat /proc/uptime",
+            "serial1 reboot",
+            "serial1 wait-for-disconnect",
+        ]);
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?,
+                   "#adp-lock v2\nserial1\tready\treleased-at=100\nserial2\tdirty\treleased-at=100\nserial3\tpid=3\n");
 
-#[cfg(test)]
-mod tests {
-    use std::sync::mpsc::RecvTimeoutError;
-    use std::thread::JoinHandle;
-    use std::time::Duration;
+        Ok(())
+    }
 
-    use ::function_name::named;
-    use named_semaphore::Semaphore;
-    use sysinfo::Pid;
-    use temp_testdir::TempDir;
-    use tracing::debug;
-    use try_block::try_block;
+    #[test]
+    fn waits_for_healthy_devices() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2"), serial("serial3")])
+            .unhealthy(vec![serial("serial2")])
+            .build()?;
+        let runtime_dir = TempDir::default();
 
-    use crate::{App, debug_log};
-    use crate::runtime::{Runtime, Serial};
+        let app = App::new(runtime, &runtime_dir);
+        app.want_devices(9, 3)?;
 
-    use super::Result;
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.waiters"))?, "9\t3\n");
+        assert_eq!(wait_for_devices::wait(&app, 2, None, Duration::ZERO)?, 2);
+        let error = wait_for_devices::wait(&app, 3, Some(Duration::ZERO), Duration::ZERO).unwrap_err();
+        assert_eq!(error.to_string(), "timed out with 2 of 3 devices ready");
 
-    macro_rules! test_semaphore {
-        () => {{
-            let sem = Semaphore::open(function_name!(), 0)?;
-            sem.unlink()?;
-            sem
-        }};
+        Ok(())
     }
 
     #[test]
-    #[named]
-    fn single_device_single_run_first_time() -> Result<()> {
+    fn drain_waits_for_claims_to_be_released() -> Result<()> {
         debug_log();
         let runtime = FakeRuntimeBuilder::default()
-            .devices(vec!["serial1".to_string()])
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .processes(vec![1])
             .build()?;
         let runtime_dir = TempDir::default();
-        let sem = test_semaphore!();
 
-        let app = App::new(runtime, &runtime_dir, &sem);
+        let app = App::new(runtime, &runtime_dir);
         let resource = app.acquire_resource(1)?;
+        assert!(app.pause(None)?);
 
-        assert_eq!(resource.serial, "serial1");
-        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "serial1:1\n");
-
+        let error = drain::wait(&app, Some(Duration::ZERO), Duration::ZERO).unwrap_err();
+        assert!(error.to_string().starts_with("timed out with 1 devices still claimed: "), "{}", error);
         resource.release()?;
-
-        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "serial1\n");
+        drain::wait(&app, Some(Duration::ZERO), Duration::ZERO)?;
+        assert!(app.entries()?.paused().is_some());
 
         Ok(())
     }
 
     #[test]
-    #[named]
-    fn single_device_single_run_second_time() -> Result<()> {
+    fn restores_exported_pool() -> Result<()> {
         debug_log();
-        // let adb = FakeAdb(vec!["serial1".to_string()]);
         let runtime = FakeRuntimeBuilder::default()
-            .devices(vec!["serial1".to_string()])
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .processes(vec![1])
             .build()?;
         let runtime_dir = TempDir::default();
-        std::fs::write(runtime_dir.join("adp.lock"), "serial1\n")?;
 
-        let sem = test_semaphore!();
-        let app = App::new(runtime, &runtime_dir, &sem);
-        let resource = app.acquire_resource(1)?;
+        let app = App::new(runtime, &runtime_dir);
+        let _resource = app.acquire_resource(1)?;
+        app.quarantine(&serial("serial2"))?;
+        app.pause(Some("moving benches".to_string()))?;
+        let exported = serde_json::to_string(&export::export(&app, None)?)?;
 
-        assert_eq!(resource.serial, "serial1");
-        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "serial1:1\n");
-        assert_eq!(sem.value()?, 0);
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .build()?;
+        let runtime_dir = TempDir::default();
+        let app = App::new(runtime, &runtime_dir);
+        app.restore(&serde_json::from_str(&exported)?)?;
 
-        resource.release()?;
+        assert_eq!(
+            std::fs::read_to_string(runtime_dir.join("adp.lock"))?,
+            "#adp-lock v3\n#paused\tuser=\tpaused-at=100\treason=moving benches\nserial1\nserial2\tquarantined\n",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn caches_device_info_while_connected() -> Result<()> {
+        debug_log();
+        let props = |model: &str| BTreeMap::from([
+            ("ro.product.model".to_string(), model.to_string()),
+            ("ro.build.version.sdk".to_string(), "34".to_string()),
+        ]);
+        let runtime_dir = TempDir::default();
+
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("emulator-5554")])
+            .props(props("Pixel 6"))
+            .build()?;
+        let mut app = App::new(runtime, &runtime_dir);
+        app.set_device_cache(DeviceCache::new(&runtime_dir));
+
+        assert_eq!(app.device_info()?, vec![DeviceInfo {
+            serial: serial("emulator-5554"),
+            state: "device".to_string(),
+            model: Some("Pixel 6".to_string()),
+            abi: None,
+            sdk: Some(34),
+            transport: Transport::Emulator,
+            host: None,
+            props: BTreeMap::new(),
+            capabilities: None,
+        }]);
+
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("emulator-5554")])
+            .props(props("Pixel 7"))
+            .build()?;
+        let mut app = App::new(runtime.clone(), &runtime_dir);
+        app.set_device_cache(DeviceCache::new(&runtime_dir));
 
-        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "serial1\n");
-        assert_eq!(sem.value()?, 1);
+        assert_eq!(app.device_info()?[0].model.as_deref(), Some("Pixel 6"));
+        assert_eq!(App::new(runtime, &runtime_dir).device_info()?[0].model.as_deref(), Some("Pixel 7"));
 
         Ok(())
     }
 
     #[test]
-    #[named]
-    fn single_device_three_runs() -> Result<()> {
+    fn only_claims_devices_matching_its_filters() -> Result<()> {
         debug_log();
         let runtime = FakeRuntimeBuilder::default()
-            .devices(vec!["serial1".to_string()])
+            .devices(vec![serial("serial1")])
+            .processes(vec![1])
+            .props(BTreeMap::from([("ro.build.type".to_string(), "userdebug".to_string())]))
             .build()?;
         let runtime_dir = TempDir::default();
-        let sem = test_semaphore!();
-        let app = App::new(runtime, &runtime_dir, &sem);
 
-        for _ in 0..3 {
-            let resource = app.acquire_resource(1)?;
-            resource.release()?;
-        }
+        let mut app = App::new(runtime, &runtime_dir);
+        app.set_filters(vec![DeviceFilter::prop("ro.build.type=user")?]);
+        assert!(app.try_acquire_resource(1)?.is_none());
+
+        app.set_filters(vec![DeviceFilter::prop_regex("ro.build.type=user(debug)?")?]);
+        assert_eq!(app.try_acquire_resource(1)?.unwrap().serial, "serial1");
 
         Ok(())
     }
 
     #[test]
-    #[named]
-    fn multiple_devices_multiple_runs_first_time() -> Result<()> {
+    fn only_claims_local_devices_when_asked() -> Result<()> {
         debug_log();
         let runtime = FakeRuntimeBuilder::default()
-            .devices(vec!["serial1".to_string(), "serial2".to_string()])
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .processes(vec![1, 2])
+            .server_sockets(BTreeMap::from([(serial("serial1"), "tcp:bench2:5037".to_string())]))
             .build()?;
         let runtime_dir = TempDir::default();
 
-        let sem = test_semaphore!();
-        let app = App::new(runtime, &runtime_dir, &sem);
-        let resource1 = app.acquire_resource(1)?;
-        let resource2 = app.acquire_resource(2)?;
+        let mut app = App::new(runtime, &runtime_dir);
+        assert_eq!(app.device_info()?[0].host.as_deref(), Some("bench2"));
+        app.set_filters(vec![DeviceFilter::Local]);
+        let resource = app.try_acquire_resource(1)?.unwrap();
+        assert_eq!(resource.serial, "serial2");
+        assert!(app.try_acquire_resource(2)?.is_none());
 
-        assert_eq!(resource1.serial, "serial1");
-        assert_eq!(resource2.serial, "serial2");
-        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "serial1:1\nserial2:2\n");
-        assert_eq!(sem.value()?, 0);
+        Ok(())
+    }
 
-        resource1.release()?;
-        resource2.release()?;
+    #[test]
+    fn prefers_devices_already_in_the_locale() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .props(BTreeMap::from([
+                ("serial1 persist.sys.locale".to_string(), "en-US".to_string()),
+                ("serial2 persist.sys.locale".to_string(), "fr-FR".to_string()),
+            ]))
+            .adb_output(BTreeMap::from([
+                ("shell wm size".to_string(), "Physical size: 1080x2400".to_string()),
+                ("shell wm density".to_string(), "Physical density: 420".to_string()),
+                ("shell id -u".to_string(), "0".to_string()),
+            ]))
+            .build()?;
+        let runtime_dir = TempDir::default();
 
-        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "serial1\nserial2\n");
-        assert_eq!(sem.value()?, 2);
+        let mut app = App::new(runtime, &runtime_dir);
+        app.set_filters(vec![DeviceFilter::Locale("fr-FR".to_string())]);
+        let resource = app.try_acquire_resource(1)?.unwrap();
+        assert_eq!(resource.serial, "serial2");
 
         Ok(())
     }
 
     #[test]
-    #[named]
-    fn resource_blocks_until_one_is_released() -> Result<()> {
+    fn probes_capabilities_when_filtering_on_them() -> Result<()> {
         debug_log();
         let runtime = FakeRuntimeBuilder::default()
-            .devices(vec!["serial1".to_string()])
-            .processes(vec![1])
+            .devices(vec![serial("serial1")])
+            .adb_output(BTreeMap::from([
+                ("shell wm size".to_string(), "Physical size: 1080x2400\nOverride size: 720x1600".to_string()),
+                ("shell wm density".to_string(), "Physical density: 420".to_string()),
+                (
+                    "shell pm path com.google.android.gms 2>/dev/null || true".to_string(),
+                    "package:/product/priv-app/GmsCore/GmsCore.apk".to_string(),
+                ),
+                ("shell id -u".to_string(), "2000".to_string()),
+            ]))
             .build()?;
         let runtime_dir = TempDir::default();
-        let sem_name = function_name!();
-        let sem = Semaphore::open(sem_name, 0)?;
-        let result: Result<JoinHandle<()>> = try_block! {
-            let app = App::new(runtime.clone(), &runtime_dir, &sem);
-            let resource1 = app.acquire_resource(1)?;
-
-            let (send, recv) = std::sync::mpsc::channel();
-            // This should block until resource1 is released.
-            let handle = std::thread::spawn(move || {
-                debug_log();
-                let sem = Semaphore::open(sem_name, 0).unwrap();
-                let app = App::new(runtime.clone(), &runtime_dir, &sem);
-                let resource2 = app.acquire_resource(2).unwrap();
-                let serial = resource2.serial.clone();
-                debug!(send = %serial);
-                send.send(serial).unwrap();
-                resource2.release().unwrap();
-            });
 
-            let result = recv.recv_timeout(Duration::from_millis(500));
-            match result {
-                Ok(value) => {
-                    panic!("expected to be blocked but got: {}", value);
-                }
-                Err(e) => {
-                    assert_eq!(e, RecvTimeoutError::Timeout)
-                }
-            }
+        let mut app = App::new(runtime, &runtime_dir);
+        assert_eq!(app.device_info()?[0].capabilities, None);
 
-            resource1.release()?;
-            debug!(sem = sem.value()?);
+        app.set_filters(vec![DeviceFilter::min_density("400")?]);
+        assert_eq!(app.device_info()?[0].capabilities, Some(Capabilities {
+            screen_size: Some("720x1600".to_string()),
+            density: Some(420),
+            gms: true,
+            root: false,
+        }));
 
-            let result = recv.recv_timeout(Duration::from_millis(500))?;
-            assert_eq!(result, "serial1");
+        Ok(())
+    }
 
-            Ok(handle)
-        };
-        sem.unlink()?;
+    #[test]
+    fn probes_capabilities_again_when_the_device_didnt_answer() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1")])
+            .props(BTreeMap::from([("ro.product.model".to_string(), "Pixel 6".to_string())]))
+            .adb_outputs(BTreeMap::from([(
+                "shell wm size".to_string(),
+                vec!["Can't find service: window".to_string(), "Physical size: 1080x2400".to_string()],
+            )]))
+            .adb_output(BTreeMap::from([("shell wm density".to_string(), "Physical density: 420".to_string())]))
+            .build()?;
+        let runtime_dir = TempDir::default();
 
-        result?.join().expect("failed to join thread");
+        let mut app = App::new(runtime, &runtime_dir);
+        app.set_filters(vec![DeviceFilter::min_density("400")?]);
+        assert_eq!(app.device_info()?[0].capabilities, None);
+        assert_eq!(app.device_info()?[0].capabilities.as_ref().and_then(|capabilities| capabilities.density), Some(420));
 
         Ok(())
     }
 
     #[test]
-    #[named]
-    fn obtains_the_correct_resource_when_device_is_removed() -> Result<()> {
+    fn leases_devices_over_the_api() -> Result<()> {
         debug_log();
+        // Claims are made with the daemon's pid.
         let runtime = FakeRuntimeBuilder::default()
-            .devices(vec!["serial2".to_string()])
+            .devices(vec![serial("serial1")])
+            .processes(vec![std::process::id() as Pid])
             .build()?;
         let runtime_dir = TempDir::default();
-        std::fs::write(runtime_dir.join("adp.lock"), "serial1\nserial2\n")?;
 
-        let sem = test_semaphore!();
-        let app = App::new(runtime, &runtime_dir, &sem);
-        let resource = app.acquire_resource(1)?;
+        let app = App::new(runtime, &runtime_dir);
+        let events = EventLog::new(&runtime_dir);
+        let config = ApiConfig { listen: "127.0.0.1:0".to_string(), token: Some("s3cret".to_string()), ..ApiConfig::default() };
+        let mut api = Api::new(&config, &events)?;
+        let url = format!("http://{}", api.addr());
+        let client = std::thread::spawn(move || {
+            let request = |method: &str, path: &str, token: Option<&str>| {
+                let mut request = ureq::request(method, &format!("{}{}", url, path));
+                if let Some(token) = token {
+                    request = request.set("Authorization", &format!("Bearer {}", token));
+                }
+                let response = match request.send_string(r#"{"user": "jenkins", "cmd": "job 42"}"#) {
+                    Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+                    Err(e) => panic!("{}", e),
+                };
+                (response.status(), response.into_json::<serde_json::Value>().unwrap())
+            };
+            let unauthorized = request("GET", "/devices", Some("guess")).0;
+            let (created, lease) = request("POST", "/leases", Some("s3cret"));
+            let pool = request("GET", "/pool", Some("s3cret")).1;
+            let busy = request("POST", "/leases", Some("s3cret")).0;
+            let deleted = request("DELETE", &format!("/leases/{}", lease["id"].as_str().unwrap()), Some("s3cret")).0;
+            (unauthorized, created, lease, pool, busy, deleted)
+        });
+        api.serve(&app, Duration::from_secs(1))?;
+        let (unauthorized, created, lease, pool, busy, deleted) = client.join().unwrap();
+
+        assert_eq!(unauthorized, 401);
+        assert_eq!(created, 201);
+        assert_eq!(lease["serial"], "serial1");
+        assert_eq!(pool["devices"][0]["state"], "in use");
+        assert_eq!(pool["devices"][0]["cmd"], "job 42");
+        assert_eq!(busy, 503);
+        assert_eq!(deleted, 200);
+        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "#adp-lock v2\nserial1\treleased-at=100\n");
 
-        assert_eq!(resource.serial, "serial2");
-        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "serial2:1\n");
-        assert_eq!(sem.value()?, 0);
+        Ok(())
+    }
 
-        resource.release()?;
+    #[test]
+    fn admin_leases_belong_to_the_api_user() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .processes(vec![std::process::id() as Pid])
+            .build()?;
+        let runtime_dir = TempDir::default();
 
-        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "serial2\n");
-        assert_eq!(sem.value()?, 1);
+        let mut app = App::new(runtime, &runtime_dir);
+        app.set_quotas(Config::parse("[quotas]\ndefault = 2\n[quotas.users]\napi = 1\n")?.quotas);
+        let events = EventLog::new(&runtime_dir);
+        let config = ApiConfig { listen: "127.0.0.1:0".to_string(), token: Some("s3cret".to_string()), ..ApiConfig::default() };
+        let mut api = Api::new(&config, &events)?;
+        let url = format!("http://{}", api.addr());
+        let client = std::thread::spawn(move || {
+            let lease = || match ureq::post(&format!("{}/leases", url)).set("Authorization", "Bearer s3cret").call() {
+                Ok(response) | Err(ureq::Error::Status(_, response)) => response.status(),
+                Err(e) => panic!("{}", e),
+            };
+            (lease(), lease())
+        });
+        api.serve(&app, Duration::from_secs(1))?;
+        let (first, second) = client.join().unwrap();
+
+        assert_eq!(first, 201);
+        assert_eq!(second, 429);
+        assert_eq!(app.entries()?.claimed_by("api"), 1);
 
         Ok(())
     }
 
+    // Stands in for another process releasing its device whenever the app waits.
+    #[derive(Debug, Default)]
+    struct FakeStore {
+        entries: RefCell<LockFileEntries>,
+    }
+
+    impl PoolStore for FakeStore {
+        fn acquire(
+            &self,
+            serials: &[Serial],
+            claim: &Claim,
+            _choose: Option<&Choose<'_>>,
+            _running: &Running<'_>,
+        ) -> Result<Option<Acquired>> {
+            let mut entries = self.entries.borrow_mut();
+            entries.update_keeping(serials, |entry| entry.pid.is_some());
+            Ok(entries.acquire(claim.pid, claim.claimed_at).map(|serial| Acquired { serial, token: None, dirty: false }))
+        }
+
+        fn release(&self, serial: &Serial, _pid: Pid, _token: Option<&str>, now: SystemTime) -> Result<bool> {
+            self.entries.borrow_mut().release(serial.clone(), now);
+            Ok(true)
+        }
+
+        fn snapshot(&self) -> Result<PoolState> {
+            Ok(PoolState { entries: self.entries.borrow().clone(), waiters: Waiters::default() })
+        }
+
+        fn reconcile(&self, serials: &[Serial], running: &Running<'_>, now: SystemTime) -> Result<PoolState> {
+            let mut entries = self.entries.borrow_mut();
+            entries.update_keeping(serials, |entry| entry.pid.is_some());
+            let claimed: Vec<(Serial, Pid)> = entries.unavialble().map(|(serial, pid)| (serial.clone(), *pid)).collect();
+            let running = running(&claimed.iter().map(|(_, pid)| *pid).collect::<Vec<_>>())?;
+            let stopped = claimed.into_iter().filter(|(_, pid)| !running.contains(pid)).map(|(serial, _)| serial).collect();
+            entries.release_all(stopped, now);
+            Ok(PoolState { entries: entries.clone(), waiters: Waiters::default() })
+        }
+
+        fn modify(&self, f: &mut dyn FnMut(&mut LockFileEntries)) -> Result<()> {
+            f(&mut self.entries.borrow_mut());
+            Ok(())
+        }
+
+        fn wait(&self, _timeout: Option<Duration>) -> Result<()> {
+            self.entries.borrow_mut().release_all(vec![serial("serial1")], UNIX_EPOCH);
+            Ok(())
+        }
+    }
+
     #[test]
-    #[named]
-    fn obtains_resource_if_process_is_no_longer_running() -> Result<()> {
+    fn waits_on_store_until_device_is_released() -> Result<()> {
         debug_log();
         let runtime = FakeRuntimeBuilder::default()
-            .devices(vec!["serial1".to_string()])
+            .devices(vec![serial("serial1")])
+            .processes(vec![7])
             .build()?;
-        let runtime_dir = TempDir::default();
-        std::fs::write(runtime_dir.join("adp.lock"), "serial1:1\n")?;
+        let store = FakeStore::default();
+        store.entries.borrow_mut().insert(serial("serial1"), Entry { pid: Some(7), ..Entry::default() });
 
-        let sem = test_semaphore!();
-        let app = App::new(runtime, &runtime_dir, &sem);
-        let resource = app.acquire_resource(2)?;
+        let app = App::with_store(runtime, Box::new(store));
+        let resource = app.acquire_resource(1)?;
 
         assert_eq!(resource.serial, "serial1");
-        assert_eq!(std::fs::read_to_string(runtime_dir.join("adp.lock"))?, "serial1:2\n");
+        assert_eq!(app.entries()?.get(&serial("serial1")).unwrap().pid, Some(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reconciles_against_a_custom_store() -> Result<()> {
+        debug_log();
+        let runtime = FakeRuntimeBuilder::default()
+            .devices(vec![serial("serial1"), serial("serial2")])
+            .processes(vec![7])
+            .build()?;
+        let store = FakeStore::default();
+        store.entries.borrow_mut().insert(serial("serial1"), Entry { pid: Some(7), ..Entry::default() });
+        store.entries.borrow_mut().insert(serial("serial2"), Entry { pid: Some(8), ..Entry::default() });
+        store.entries.borrow_mut().insert(serial("serial3"), Entry::default());
+
+        let app = App::with_store(runtime, Box::new(store));
+        let state = app.reconcile()?;
+
+        assert_eq!(state.entries.get(&serial("serial1")).unwrap().pid, Some(7));
+        assert_eq!(state.entries.get(&serial("serial2")).unwrap().pid, None);
+        assert!(!state.entries.contains(&serial("serial3")));
 
         Ok(())
     }
@@ -461,10 +3902,51 @@ mod tests {
         devices: Vec<Serial>,
         #[builder(default = "vec![]")]
         processes: Vec<Pid>,
+        #[builder(default = "vec![]")]
+        unhealthy: Vec<Serial>,
+        #[builder(default)]
+        batteries: BTreeMap<Serial, Battery>,
+        // the same for every device
+        #[builder(default)]
+        props: BTreeMap<String, String>,
+        // seconds since the epoch
+        #[builder(default = "100")]
+        now: u64,
+        // every run_adb, as the serial followed by the args
+        #[builder(default)]
+        adb_commands: Arc<Mutex<Vec<String>>>,
+        // what run_adb prints for the given args, the same for every device
+        #[builder(default)]
+        adb_output: BTreeMap<String, String>,
+        // what run_adb prints for the given args each time they're run, the last one from then on
+        #[builder(default)]
+        adb_outputs: BTreeMap<String, Vec<String>>,
+        // args that run_adb fails for, or the serial followed by the args to fail only on that device
+        #[builder(default)]
+        failing_adb: Vec<String>,
+        // devices on an adb server other than the default one
+        #[builder(default)]
+        server_sockets: BTreeMap<Serial, String>,
+        // seconds into waiting for sys.boot_completed that each device reports progress at
+        #[builder(default)]
+        boot_progress: Vec<u64>,
+        // devices that never finish booting
+        #[builder(default)]
+        unbootable: Vec<Serial>,
+        // how many times no devices are listed first, ex: while the adb server restarts
+        #[builder(default)]
+        empty_listings: usize,
+        #[builder(setter(skip), default)]
+        listings: Arc<Mutex<usize>>,
     }
 
     impl Runtime for FakeRuntime {
-        fn devices(&self) -> crate::runtime::Result<Vec<Serial>> {
+        fn connected_devices(&self) -> crate::runtime::Result<Vec<Serial>> {
+            let mut listings = self.listings.lock().unwrap();
+            *listings += 1;
+            if *listings <= self.empty_listings {
+                return Ok(vec![]);
+            }
             Ok(self.devices.clone())
         }
 
@@ -472,8 +3954,87 @@ mod tests {
             Ok(())
         }
 
+        fn wait_for_boot_with_progress(
+            &self,
+            serial: &Serial,
+            progress: &dyn Fn(&BootProgress),
+        ) -> crate::runtime::Result<()> {
+            if self.unbootable.contains(serial) {
+                return Err(anyhow!("{} didn't finish booting", serial));
+            }
+            for &secs in &self.boot_progress {
+                progress(&BootProgress { waiting_for: "sys.boot_completed", elapsed: Duration::from_secs(secs) });
+            }
+            Ok(())
+        }
+
+        fn check_health(&self, serial: &Serial) -> crate::runtime::Result<()> {
+            if self.unhealthy.contains(serial) {
+                return Err(anyhow!("{} failed health check", serial));
+            }
+            Ok(())
+        }
+
+        fn adb_devices(&self) -> crate::runtime::Result<Vec<AdbDevice>> {
+            Ok(self.devices.iter()
+                .map(|serial| AdbDevice {
+                    serial: serial.to_string(),
+                    state: "device".to_string(),
+                    attributes: BTreeMap::from([("transport_id".to_string(), "1".to_string())]),
+                    server: self.server_sockets.get(serial).cloned(),
+                })
+                .collect())
+        }
+
+        fn getprop(&self, serial: &Serial, name: &str) -> crate::runtime::Result<String> {
+            let prop = self.props.get(&format!("{} {}", serial, name)).or_else(|| self.props.get(name));
+            Ok(prop.cloned().unwrap_or_default())
+        }
+
+        fn battery(&self, serial: &Serial) -> crate::runtime::Result<Battery> {
+            Ok(self.batteries.get(serial).cloned().unwrap_or_default())
+        }
+
+        fn run_adb(&self, serial: &Serial, args: &[&str]) -> crate::runtime::Result<String> {
+            let command = format!("{} {}", serial, args.join(" "));
+            let mut adb_commands = self.adb_commands.lock().unwrap();
+            let runs = adb_commands.iter().filter(|run| **run == command).count();
+            adb_commands.push(command.clone());
+            if self.failing_adb.contains(&args.join(" ")) || self.failing_adb.contains(&command) {
+                return Err(anyhow!("adb {} failed", args.join(" ")));
+            }
+            if let Some(outputs) = self.adb_outputs.get(&args.join(" ")) {
+                return Ok(outputs.get(runs).or(outputs.last()).cloned().unwrap_or_default());
+            }
+            Ok(self.adb_output.get(&args.join(" ")).cloned().unwrap_or_default())
+        }
+
+        fn screencap(&self, _serial: &Serial) -> crate::runtime::Result<Vec<u8>> {
+            Ok(b"\x89PNG".to_vec())
+        }
+
+        fn server_socket(&self, serial: &Serial) -> Option<String> {
+            self.server_sockets.get(serial).cloned()
+        }
+
+        fn transport_id(&self, _serial: &Serial) -> Option<String> {
+            Some("1".to_string())
+        }
+
+        fn adb_serial(&self, serial: &Serial) -> String {
+            serial.to_string()
+        }
+
         fn is_running(&self, pid: crate::runtime::Pid) -> crate::runtime::Result<bool> {
             Ok(self.processes.contains(&pid))
         }
+
+        fn now(&self) -> SystemTime {
+            UNIX_EPOCH + Duration::from_secs(self.now)
+        }
+
+        fn random(&self) -> u64 {
+            0x5eed
+        }
     }
 }
@@ -0,0 +1,158 @@
+use std::fmt::{Display, Formatter};
+use std::process::Command;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use tracing::{debug, instrument};
+
+use crate::exitstatus::ExitStatusExt;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Somewhere to tell people that something happened, written as "desktop", "command:<shell command>" or the url of a
+// webhook.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Notifier {
+    // notify-send, or osascript on macOS
+    Desktop,
+    // run through sh with the message in ADP_MESSAGE
+    Command(String),
+    // posted as {"text": message}, which is what Slack and most chat tools' incoming webhooks take
+    Webhook(String),
+}
+
+impl Notifier {
+    #[instrument]
+    pub fn send(&self, message: &str) -> Result {
+        debug!(message);
+        match self {
+            Notifier::Desktop if cfg!(target_os = "macos") => {
+                let script = format!("display notification {:?} with title \"adp\"", message);
+                Command::new("osascript").arg("-e").arg(script).status()?.exit_ok_()?;
+            }
+            Notifier::Desktop => {
+                Command::new("notify-send").arg("adp").arg(message).status()
+                    .context("failed to run notify-send")?
+                    .exit_ok_()?;
+            }
+            Notifier::Command(command) => {
+                Command::new("sh").arg("-c").arg(command).env("ADP_MESSAGE", message).status()?.exit_ok_()?;
+            }
+            Notifier::Webhook(url) => {
+                ureq::post(url)
+                    .timeout(WEBHOOK_TIMEOUT)
+                    .send_json(serde_json::json!({ "text": message }))
+                    .with_context(|| format!("failed to post to {}", url))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Sends to each of them, one failing to go out shouldn't stop the others or whatever adp was doing.
+pub fn send_all(notifiers: &[Notifier], message: &str) {
+    for notifier in notifiers {
+        if let Err(e) = notifier.send(message) {
+            eprintln!("adp: failed to send a notification to {}: {:#}", notifier, e);
+        }
+    }
+}
+
+impl FromStr for Notifier {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s == "desktop" {
+            Ok(Notifier::Desktop)
+        } else if let Some(command) = s.strip_prefix("command:") {
+            Ok(Notifier::Command(command.to_string()))
+        } else if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(Notifier::Webhook(s.to_string()))
+        } else {
+            Err(anyhow!("invalid notifier {:?}, expected desktop, command:<shell command> or a webhook url", s))
+        }
+    }
+}
+
+impl TryFrom<String> for Notifier {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Display for Notifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Notifier::Desktop => write!(f, "desktop"),
+            Notifier::Command(command) => write!(f, "command:{}", command),
+            Notifier::Webhook(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    use temp_testdir::TempDir;
+
+    use crate::notify::Notifier;
+
+    #[test]
+    fn parses_notifiers() {
+        assert_eq!("desktop".parse::<Notifier>().unwrap(), Notifier::Desktop);
+        assert_eq!("command:say done".parse::<Notifier>().unwrap(), Notifier::Command("say done".to_string()));
+        assert_eq!(
+            "https://hooks.slack.com/services/x".parse::<Notifier>().unwrap(),
+            Notifier::Webhook("https://hooks.slack.com/services/x".to_string()),
+        );
+        assert!("slack".parse::<Notifier>().is_err());
+    }
+
+    #[test]
+    fn runs_commands_with_the_message() {
+        let dir = TempDir::default();
+        let out = dir.join("message");
+
+        Notifier::Command(format!("echo \"$ADP_MESSAGE\" > {}", out.display())).send("got emulator-5554").unwrap();
+
+        assert_eq!(std::fs::read_to_string(out).unwrap(), "got emulator-5554\n");
+    }
+
+    #[test]
+    fn posts_to_webhooks() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").unwrap();
+            String::from_utf8(body).unwrap()
+        });
+
+        Notifier::Webhook(url).send("got emulator-5554").unwrap();
+
+        assert_eq!(server.join().unwrap(), r#"{"text":"got emulator-5554"}"#);
+    }
+}
@@ -0,0 +1,67 @@
+use std::cell::Cell;
+use std::fmt::Debug;
+use std::time::{Duration, SystemTime};
+
+use tracing::info;
+
+use crate::lockfile::Owner;
+use crate::runtime::{BootProgress, Pid, Serial};
+
+// Hooks into the lifecycle of a lease, for metrics and logging. Everything defaults to doing nothing so
+// implementations only need to pick out what they care about. now is the runtime's clock.
+pub trait Observer: Debug {
+    // owner is None when the claim isn't attributed to anyone.
+    fn on_acquire(&self, _serial: &Serial, _pid: Pid, _owner: Option<&Owner>, _now: SystemTime) {}
+    // Still waiting for the device to boot, called about once a second.
+    fn on_boot_progress(&self, _serial: &Serial, _progress: &BootProgress) {}
+    // The device has booted and passed its health check if it needed one.
+    fn on_boot_wait(&self, _serial: &Serial, _waited: Duration, _now: SystemTime) {}
+    fn on_release(&self, _serial: &Serial, _now: SystemTime) {}
+    // The device was taken back from a job that didn't release it itself, ex: by `adp kill` or a lease limit.
+    fn on_reclaim(&self, _serial: &Serial, _pid: Pid, _now: SystemTime) {}
+}
+
+#[derive(Debug)]
+pub struct LogObserver;
+
+impl Observer for LogObserver {
+    fn on_acquire(&self, serial: &Serial, pid: Pid, _owner: Option<&Owner>, _now: SystemTime) {
+        info!(acquired = %serial, pid = %pid);
+    }
+
+    fn on_boot_wait(&self, serial: &Serial, waited: Duration, _now: SystemTime) {
+        info!(ready = %serial, waited = ?waited);
+    }
+
+    fn on_release(&self, serial: &Serial, _now: SystemTime) {
+        info!(released = %serial);
+    }
+
+    fn on_reclaim(&self, serial: &Serial, pid: Pid, _now: SystemTime) {
+        info!(reclaimed = %serial, pid = %pid);
+    }
+}
+
+// How often ProgressObserver says what it's waiting on.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+// Says what a job is waiting on while a device boots, so it doesn't look like it's hung.
+#[derive(Debug, Default)]
+pub struct ProgressObserver {
+    // When it last printed, as time spent waiting on the device.
+    last: Cell<Option<Duration>>,
+}
+
+impl Observer for ProgressObserver {
+    fn on_boot_progress(&self, serial: &Serial, progress: &BootProgress) {
+        if self.last.get().is_some_and(|last| progress.elapsed < last + PROGRESS_INTERVAL && progress.elapsed >= last) {
+            return;
+        }
+        self.last.set(Some(progress.elapsed));
+        eprintln!("adp: {} is waiting for {}, {}s elapsed", serial, progress.waiting_for, progress.elapsed.as_secs());
+    }
+
+    fn on_boot_wait(&self, _serial: &Serial, _waited: Duration, _now: SystemTime) {
+        self.last.set(None);
+    }
+}
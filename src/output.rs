@@ -0,0 +1,207 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tracing::debug;
+
+use crate::cli::JobOptions;
+use crate::runtime::Serial;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// Picked between by the serial, so the same device always gets the same one.
+const COLORS: &[u8] = &[31, 32, 33, 34, 35, 36];
+const RESET: &str = "\x1b[0m";
+// How long the rest of the output has to come through once the job has exited.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Copies a job's output through line by line, so the output of shards running side by side is readable in CI logs.
+// Each line can be prefixed with the device's serial and also written to a log file per device.
+#[derive(Debug)]
+pub struct Output {
+    // None when the output isn't being prefixed.
+    prefix: Option<String>,
+    log: Option<Arc<Mutex<File>>>,
+}
+
+impl Output {
+    // None when there's nothing to do to the output, the job then writes to ours directly.
+    pub fn new(options: &JobOptions, serial: &Serial) -> Result<Option<Output>> {
+        if !options.prefix && options.log_dir.is_none() {
+            return Ok(None);
+        }
+        let prefix = options.prefix.then(|| prefix(serial, options.color));
+        let log = match &options.log_dir {
+            Some(dir) => Some(Arc::new(Mutex::new(open_log(dir, serial)?))),
+            None => None,
+        };
+        Ok(Some(Output { prefix, log }))
+    }
+
+    // Sets the job up to write to us, stdin is left for it to read from directly.
+    pub fn pipe(cmd: &mut Command) {
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    }
+
+    // Starts copying the job's output, pass the returned threads to finish once it has exited.
+    pub fn copy(&self, child: &mut Child) -> Vec<JoinHandle<()>> {
+        let mut threads = Vec::new();
+        if let Some(stdout) = child.stdout.take() {
+            threads.push(self.copy_lines(stdout, io::stdout));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            threads.push(self.copy_lines(stderr, io::stderr));
+        }
+        threads
+    }
+
+    fn copy_lines<W: Write>(
+        &self,
+        from: impl Read + Send + 'static,
+        to: impl FnOnce() -> W + Send + 'static,
+    ) -> JoinHandle<()> {
+        let prefix = self.prefix.clone();
+        let log = self.log.clone();
+        std::thread::spawn(move || {
+            let mut to = to();
+            if let Err(e) = copy_lines(BufReader::new(from), &mut to, prefix.as_deref(), log.as_deref()) {
+                debug!(error = %e, "stopped copying the job's output");
+            }
+        })
+    }
+}
+
+// Waits for the job's output to come through once it has exited. Something it left running, ex: a Gradle daemon or
+// `adb logcat &`, can keep the pipes open for good, so past a timeout the copying is left to carry on in the background.
+pub fn finish(threads: Vec<JoinHandle<()>>) {
+    finish_within(threads, DRAIN_TIMEOUT);
+}
+
+// Returns whether all of the output came through in time.
+fn finish_within(threads: Vec<JoinHandle<()>>, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while threads.iter().any(|thread| !thread.is_finished()) {
+        if Instant::now() >= deadline {
+            debug!("the job's output is still open after it exited, not waiting for the rest of it");
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    for thread in threads {
+        let _ = thread.join();
+    }
+    true
+}
+
+// Lines are written whole so they don't interleave with the other stream's, and as bytes since a job's output
+// doesn't have to be utf-8.
+fn copy_lines(
+    mut from: impl BufRead,
+    to: &mut impl Write,
+    prefix: Option<&str>,
+    log: Option<&Mutex<File>>,
+) -> io::Result<()> {
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if from.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        if let Some(log) = log {
+            log.lock().unwrap().write_all(&line)?;
+        }
+        match prefix {
+            Some(prefix) => {
+                let mut prefixed = Vec::with_capacity(prefix.len() + line.len());
+                prefixed.extend_from_slice(prefix.as_bytes());
+                prefixed.extend_from_slice(&line);
+                to.write_all(&prefixed)?;
+            }
+            None => to.write_all(&line)?,
+        }
+        to.flush()?;
+    }
+}
+
+fn prefix(serial: &Serial, color: bool) -> String {
+    if !color {
+        return format!("[{}] ", serial);
+    }
+    let index = serial.as_str().bytes().fold(0usize, |hash, b| hash.wrapping_mul(31).wrapping_add(b as usize));
+    format!("\x1b[{}m[{}]{} ", COLORS[index % COLORS.len()], serial, RESET)
+}
+
+// Appended to, so a device's log covers every job run on it with the same log dir.
+fn open_log(dir: &Path, serial: &Serial) -> Result<File> {
+    std::fs::create_dir_all(dir).with_context(|| format!("failed to create {:?}", dir))?;
+    let path = dir.join(format!("{}.log", serial));
+    OpenOptions::new().create(true).append(true).open(&path)
+        .with_context(|| format!("failed to open {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::process::{Command, Stdio};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use temp_testdir::TempDir;
+
+    use crate::output::{copy_lines, finish_within, open_log, prefix, Output};
+    use crate::runtime::Serial;
+
+    type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+    #[test]
+    fn prefixes_every_line_and_logs_them_as_is() -> Result {
+        let dir = TempDir::default();
+        let serial = Serial::new("emulator-5554")?;
+        let log = Mutex::new(open_log(&dir, &serial)?);
+        let mut out = Vec::new();
+
+        copy_lines("> Task :app:test\nBUILD SUCCESSFUL".as_bytes(), &mut out, Some(&prefix(&serial, false)), Some(&log))?;
+
+        assert_eq!(String::from_utf8(out)?, "[emulator-5554] > Task :app:test\n[emulator-5554] BUILD SUCCESSFUL");
+        let mut logged = String::new();
+        std::fs::File::open(dir.join("emulator-5554.log"))?.read_to_string(&mut logged)?;
+        assert_eq!(logged, "> Task :app:test\nBUILD SUCCESSFUL");
+        Ok(())
+    }
+
+    #[test]
+    fn stops_waiting_for_output_held_open_by_a_process_the_job_left_running() -> Result {
+        let output = Output { prefix: None, log: None };
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "sleep 30 &"]);
+        Output::pipe(&mut cmd);
+        let mut child = cmd.spawn()?;
+
+        let copying = output.copy(&mut child);
+        assert!(child.wait()?.success());
+
+        let started = Instant::now();
+        assert!(!finish_within(copying, Duration::from_millis(200)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let mut child = Command::new("true").stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+        let copying = output.copy(&mut child);
+        child.wait()?;
+        assert!(finish_within(copying, Duration::from_secs(5)));
+        Ok(())
+    }
+
+    #[test]
+    fn colors_the_same_serial_the_same() -> Result {
+        let serial = Serial::new("emulator-5554")?;
+
+        assert_eq!(prefix(&serial, true), prefix(&serial, true));
+        assert!(prefix(&serial, true).starts_with("\x1b["));
+        Ok(())
+    }
+}
@@ -0,0 +1,416 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::anyhow;
+use sha2::{Digest, Sha256};
+use tracing::{debug, instrument};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// adb's server is shared by everyone on the host but the default runtime dir isn't, it's per user, and on macOS it falls
+// back to each user's cache dir. Every user then gets a pool of their own that knows nothing of the others' claims, so
+// each pool registers itself here under a name scoped by its runtime dir to be able to spot the others. Not the system
+// temp dir, on macOS that's per user too. Anyone can write to it, so each user only writes in a directory of their own
+// in it that nobody else can write to, and only reads everyone else's.
+pub const REGISTRY_DIR: &str = "/tmp/adp-pools";
+
+// Versions of adp from before pools were namespaced all kept their state in here.
+const LEGACY_DIR: &str = "adp";
+// What adb uses when it isn't given a server.
+const DEFAULT_SERVER: &str = "tcp:localhost:5037";
+// Which namespace took over the legacy dir.
+const ADOPTED_BY: &str = "adp.pool";
+
+// Pools that haven't been used in this long are left out, there's no telling when one has gone for good.
+const STALE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+// How often a pool notes it's still in use, rather than on every run.
+const REFRESH_AFTER: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pool {
+    pub user: String,
+    pub runtime_dir: PathBuf,
+}
+
+// The same for every process using the runtime dir, different for every other one.
+pub fn name(runtime_dir: &Path) -> String {
+    let runtime_dir = runtime_dir.canonicalize().unwrap_or_else(|_| runtime_dir.to_path_buf());
+    format!("pool-{}", short_hash(runtime_dir.as_os_str().as_encoded_bytes()))
+}
+
+// What the pool's state is kept under by default. Pools that aren't given a name are told apart by the adb servers
+// their devices are on, so unrelated projects on one host don't interfere.
+pub fn namespace(pool: Option<&str>, servers: &[String]) -> Result<String> {
+    if let Some(pool) = pool {
+        if pool.is_empty() || !pool.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(anyhow!("pool {:?} can only have letters, digits, - and _ in it", pool));
+        }
+        return Ok(pool.to_string());
+    }
+    let mut servers: Vec<String> = servers.iter().map(|server| normalize_server(server)).collect();
+    servers.sort();
+    servers.dedup();
+    if servers.is_empty() || servers == [DEFAULT_SERVER] {
+        return Ok("default".to_string());
+    }
+    Ok(format!("servers-{}", short_hash(servers.join("\n").as_bytes())))
+}
+
+// The same server spelled out however it's written, so the same devices don't end up in two pools that each hand them
+// out. adb takes tcp:<port> as one on this host, which goes by a few names.
+fn normalize_server(server: &str) -> String {
+    let address = server.strip_prefix("tcp:").unwrap_or(server);
+    let (host, port) = address.rsplit_once(':').unwrap_or(("localhost", address));
+    let host = match host {
+        "localhost" | "127.0.0.1" | "[::1]" => "localhost",
+        host => host,
+    };
+    format!("tcp:{}:{}", host, port)
+}
+
+// <base>/adp-<namespace>, except that the first namespace to come along after an upgrade takes over the <base>/adp of
+// older versions, so the claims and quarantines in it aren't lost and those versions still see its claims.
+#[instrument]
+pub fn default_runtime_dir(base: &Path, namespace: &str) -> Result<PathBuf> {
+    let legacy = base.join(LEGACY_DIR);
+    let adopted_by = legacy.join(ADOPTED_BY);
+    if legacy.is_dir() && !adopted_by.exists() {
+        // Linked into place once it's written so nobody reads it half done, and only one of any racing to adopt it wins.
+        let partial = legacy.join(format!("{}.{}", ADOPTED_BY, std::process::id()));
+        std::fs::write(&partial, format!("{}\n", namespace))?;
+        let adopted = std::fs::hard_link(&partial, &adopted_by);
+        std::fs::remove_file(&partial)?;
+        match adopted {
+            Ok(()) => debug!(adopted = ?legacy, namespace),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let dir = match std::fs::read_to_string(&adopted_by) {
+        Ok(adopter) if adopter.trim() == namespace => legacy,
+        _ => base.join(format!("{}-{}", LEGACY_DIR, namespace)),
+    };
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn short_hash(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Registers the pool, or notes it's still in use, and returns the other pools on the host in the same namespace.
+#[instrument]
+pub fn register(registry: &Path, runtime_dir: &Path, namespace: &str, user: &str, now: SystemTime) -> Result<Vec<Pool>> {
+    match std::fs::create_dir(registry) {
+        // Like /tmp itself, anyone can register and nobody can remove anyone else's.
+        Ok(()) => set_mode(registry, 0o1777)?,
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e.into()),
+    }
+    check_shared(registry)?;
+    let own_dir = registry.join(uid());
+    own_dir_in_shared(&own_dir, 0o755)?;
+
+    let own = name(runtime_dir);
+    let own_path = own_dir.join(&own);
+    let contents = format!("{}\n{}\n{}\n", user, runtime_dir.display(), namespace);
+    let fresh = std::fs::metadata(&own_path)
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|used_at| now.duration_since(used_at).unwrap_or_default() < REFRESH_AFTER);
+    if !fresh || std::fs::read_to_string(&own_path).ok().as_deref() != Some(contents.as_str()) {
+        let mut file = File::create(&own_path)?;
+        file.write_all(contents.as_bytes())?;
+        file.set_modified(now)?;
+    }
+
+    let mut others = Vec::new();
+    for dir in std::fs::read_dir(registry)? {
+        let dir = dir?;
+        // Only real directories, the registry is anyone's to put things in.
+        if !dir.file_type()?.is_dir() {
+            continue;
+        }
+        for entry in std::fs::read_dir(dir.path())? {
+            let entry = entry?;
+            if entry.path() == own_path || !entry.file_type()?.is_file() {
+                continue;
+            }
+            let used_at = entry.metadata()?.modified()?;
+            if now.duration_since(used_at).unwrap_or_default() > STALE_AFTER {
+                continue;
+            }
+            let Ok(file) = open_no_follow(&entry.path()) else {
+                debug!(unreadable = ?entry.path());
+                continue;
+            };
+            let mut lines = BufReader::new(file).lines();
+            match (lines.next(), lines.next(), lines.next()) {
+                (Some(user), Some(runtime_dir), Some(theirs)) => if theirs? == namespace {
+                    others.push(Pool { user: user?, runtime_dir: runtime_dir?.into() });
+                },
+                _ => debug!(unreadable = ?entry.path()),
+            }
+        }
+    }
+    others.sort_by(|a, b| a.runtime_dir.cmp(&b.runtime_dir));
+    Ok(others)
+}
+
+// Makes the directory with the given mode if it's not there, and checks nobody else can write to it if it is. For
+// directories in one anyone can write to, where someone could have made it first to have adp write through a link of
+// theirs.
+pub fn own_dir_in_shared(dir: &Path, mode: u32) -> Result {
+    match std::fs::create_dir(dir) {
+        Ok(()) => set_mode(dir, mode)?,
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e.into()),
+    }
+    check_own(dir)
+}
+
+#[cfg(unix)]
+fn uid() -> String {
+    // Safe, it can't fail.
+    unsafe { libc::geteuid() }.to_string()
+}
+
+#[cfg(not(unix))]
+fn uid() -> String {
+    std::env::var("USERNAME").unwrap_or_default()
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?)
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result {
+    Ok(())
+}
+
+// Anyone could have made it, but if others can write to it they mustn't be able to swap out anyone else's files.
+#[cfg(unix)]
+fn check_shared(registry: &Path) -> Result {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = std::fs::symlink_metadata(registry)?;
+    if !metadata.is_dir() {
+        return Err(anyhow!("{:?} isn't a directory", registry));
+    }
+    if metadata.mode() & 0o002 != 0 && metadata.mode() & 0o1000 == 0 {
+        return Err(anyhow!("{:?} can be written to by anyone without the sticky bit set", registry));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_shared(_registry: &Path) -> Result {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn check_own(dir: &Path) -> Result {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = std::fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() {
+        return Err(anyhow!("{:?} isn't a directory", dir));
+    }
+    if metadata.uid().to_string() != uid() || metadata.mode() & 0o022 != 0 {
+        return Err(anyhow!("{:?} can be written to by someone else", dir));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_own(_dir: &Path) -> Result {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn open_no_follow(path: &Path) -> std::io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new().read(true).custom_flags(libc::O_NOFOLLOW).open(path)
+}
+
+#[cfg(not(unix))]
+fn open_no_follow(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().read(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    use temp_testdir::TempDir;
+
+    use crate::pools::{default_runtime_dir, name, namespace, own_dir_in_shared, register, uid, Pool};
+
+    type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+    #[test]
+    fn scopes_names_by_runtime_dir() {
+        let evan = TempDir::default();
+        let sam = TempDir::default();
+
+        assert_eq!(name(&evan), name(&evan));
+        assert_eq!(name(&evan), name(&evan.join(".")));
+        assert_ne!(name(&evan), name(&sam));
+    }
+
+    #[test]
+    fn finds_other_users_pools_on_the_host() -> Result {
+        let registry = TempDir::default();
+        // The per user cache dirs macOS falls back to.
+        let evan = TempDir::default();
+        let sam = TempDir::default();
+        let now = SystemTime::now();
+
+        assert_eq!(register(&registry, &evan, "default", "evan", now)?, vec![]);
+        // Another job from the same pool.
+        assert_eq!(register(&registry, &evan, "default", "evan", now)?, vec![]);
+        assert_eq!(register(&registry, &sam, "default", "sam", now)?, vec![
+            Pool { user: "evan".to_string(), runtime_dir: evan.to_path_buf() },
+        ]);
+        assert_eq!(register(&registry, &evan, "default", "evan", now)?, vec![
+            Pool { user: "sam".to_string(), runtime_dir: sam.to_path_buf() },
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn leaves_out_pools_for_other_devices() -> Result {
+        let registry = TempDir::default();
+        let evan = TempDir::default();
+        let sam = TempDir::default();
+        let now = SystemTime::now();
+        register(&registry, &sam, "servers-1234", "sam", now)?;
+
+        assert_eq!(register(&registry, &evan, "default", "evan", now)?, vec![]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn namespaces_pools_by_name_or_adb_servers() -> Result {
+        let servers = |servers: &[&str]| servers.iter().map(|server| server.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(namespace(None, &[])?, "default");
+        assert_eq!(namespace(Some("kiosk-app"), &servers(&["tcp:bench:5037"]))?, "kiosk-app");
+        assert_eq!(
+            namespace(None, &servers(&["tcp:a:5037", "tcp:b:5037"]))?,
+            namespace(None, &servers(&["tcp:b:5037", "tcp:a:5037"]))?,
+        );
+        assert_ne!(namespace(None, &servers(&["tcp:a:5037"]))?, namespace(None, &servers(&["tcp:b:5037"]))?);
+        assert!(namespace(Some("../evil"), &[]).is_err());
+        // adb's default server, however it's written.
+        for default in ["tcp:localhost:5037", "tcp:127.0.0.1:5037", "tcp:5037", "localhost:5037"] {
+            assert_eq!(namespace(None, &servers(&[default]))?, "default", "{}", default);
+        }
+        assert_eq!(
+            namespace(None, &servers(&["tcp:127.0.0.1:5037", "tcp:localhost:5038"]))?,
+            namespace(None, &servers(&["tcp:localhost:5037", "tcp:[::1]:5038"]))?,
+        );
+        assert_ne!(namespace(None, &servers(&["tcp:bench:5037"]))?, "default");
+
+        Ok(())
+    }
+
+    #[test]
+    fn adopts_the_legacy_runtime_dir() -> Result {
+        let base = TempDir::default();
+        std::fs::create_dir(base.join("adp"))?;
+        std::fs::write(base.join("adp").join("adp.lock"), "serial1:1\n")?;
+
+        // The first to come along after an upgrade keeps its state.
+        assert_eq!(default_runtime_dir(&base, "default")?, base.join("adp"));
+        assert_eq!(default_runtime_dir(&base, "default")?, base.join("adp"));
+        assert_eq!(default_runtime_dir(&base, "kiosk-app")?, base.join("adp-kiosk-app"));
+        assert_eq!(std::fs::read_dir(base.join("adp"))?.count(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn namespaces_runtime_dirs_on_fresh_hosts() -> Result {
+        let base = TempDir::default();
+
+        assert_eq!(default_runtime_dir(&base, "default")?, base.join("adp-default"));
+        assert!(base.join("adp-default").is_dir());
+        assert!(!base.join("adp").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_notes_a_pool_is_in_use_every_so_often() -> Result {
+        let registry = TempDir::default();
+        let evan = TempDir::default();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let used_at = || std::fs::metadata(registry.join(uid()).join(name(&evan)))?.modified();
+
+        register(&registry, &evan, "default", "evan", now)?;
+        register(&registry, &evan, "default", "evan", now + Duration::from_secs(60))?;
+        assert_eq!(used_at()?, now);
+        register(&registry, &evan, "default", "evan", now + Duration::from_secs(2 * 60 * 60))?;
+        assert_eq!(used_at()?, now + Duration::from_secs(2 * 60 * 60));
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_to_write_where_others_can() -> Result {
+        use std::os::unix::fs::PermissionsExt;
+        let registry = TempDir::default();
+        let evan = TempDir::default();
+        let sam = TempDir::default();
+        std::fs::create_dir(registry.join(uid()))?;
+        std::fs::set_permissions(registry.join(uid()), std::fs::Permissions::from_mode(0o777))?;
+
+        assert!(register(&registry, &evan, "default", "evan", SystemTime::now()).is_err());
+        assert_eq!(std::fs::read_dir(registry.join(uid()))?.count(), 0);
+
+        // Or that aren't what they seem.
+        std::fs::remove_dir(registry.join(uid()))?;
+        std::os::unix::fs::symlink(&sam, registry.join(uid()))?;
+        assert!(register(&registry, &evan, "default", "evan", SystemTime::now()).is_err());
+        assert_eq!(std::fs::read_dir(&sam)?.count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn makes_directories_of_ones_own_in_shared_ones() -> Result {
+        use std::os::unix::fs::PermissionsExt;
+        let tmp = TempDir::default();
+
+        own_dir_in_shared(&tmp.join("adp-evan"), 0o700)?;
+        assert_eq!(std::fs::metadata(tmp.join("adp-evan"))?.permissions().mode() & 0o777, 0o700);
+        own_dir_in_shared(&tmp.join("adp-evan"), 0o700)?;
+
+        std::fs::create_dir(tmp.join("adp-sam"))?;
+        std::fs::set_permissions(tmp.join("adp-sam"), std::fs::Permissions::from_mode(0o777))?;
+        assert!(own_dir_in_shared(&tmp.join("adp-sam"), 0o700).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn leaves_out_pools_that_havent_been_used_in_a_while() -> Result {
+        let registry = TempDir::default();
+        let evan = TempDir::default();
+        let sam = TempDir::default();
+        let now = SystemTime::now();
+        register(&registry, &sam, "default", "sam", now)?;
+        File::options().write(true).open(registry.join(uid()).join(name(&sam)))?
+            .set_modified(now - Duration::from_secs(2 * 24 * 60 * 60))?;
+
+        assert_eq!(register(&registry, &evan, "default", "evan", now)?, vec![]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::process::Command;
+use std::time::SystemTime;
+
+use anyhow::Context;
+use tracing::debug;
+
+use crate::config::{PowerCycleConfig, UsbPort};
+use crate::exitstatus::ExitStatusExt;
+use crate::runtime::Serial;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// Runs the power cycle command for the device's port.
+pub type Run<'a> = dyn Fn(&str) -> Result + 'a;
+
+// Tries power cycling the usb port of a bench device adb can't see anymore, ex: one whose usb stack hung, before
+// giving up on it.
+#[derive(Debug)]
+pub struct PowerCycler<'a> {
+    config: &'a PowerCycleConfig,
+    // Devices that were power cycled and when, until they come back or the timeout passes.
+    recovering: BTreeMap<Serial, SystemTime>,
+    // Devices that didn't come back, they're not tried again until they do.
+    lost: BTreeSet<Serial>,
+}
+
+impl<'a> PowerCycler<'a> {
+    pub fn new(config: &'a PowerCycleConfig) -> PowerCycler<'a> {
+        PowerCycler { config, recovering: BTreeMap::new(), lost: BTreeSet::new() }
+    }
+
+    // Power cycles devices that have gone missing, returns the ones that are lost as of this check.
+    pub fn check(&mut self, connected: &[Serial], now: SystemTime, run: &Run<'_>) -> Vec<Serial> {
+        let mut lost = Vec::new();
+        for (serial, port) in &self.config.devices {
+            if connected.contains(serial) {
+                if self.recovering.remove(serial).is_some() {
+                    eprintln!("adp: {} came back after power cycling it", serial);
+                }
+                self.lost.remove(serial);
+                continue;
+            }
+            if self.lost.contains(serial) {
+                continue;
+            }
+            match self.recovering.get(serial) {
+                Some(since) if now.duration_since(*since).unwrap_or_default() >= self.config.timeout.0 => {
+                    eprintln!("adp: {} didn't come back after power cycling it", serial);
+                    self.recovering.remove(serial);
+                    self.lost.insert(serial.clone());
+                    lost.push(serial.clone());
+                }
+                Some(_) => {}
+                None => {
+                    eprintln!("adp: {} isn't connected, power cycling usb port {} on hub {}", serial, port.port, port.hub);
+                    match run(&command(&self.config.command, serial, port)) {
+                        Ok(()) => {
+                            self.recovering.insert(serial.clone(), now);
+                        }
+                        Err(e) => {
+                            eprintln!("adp: failed to power cycle {}: {:#}", serial, e);
+                            self.lost.insert(serial.clone());
+                            lost.push(serial.clone());
+                        }
+                    }
+                }
+            }
+        }
+        debug!(recovering = ?self.recovering, lost = ?self.lost);
+        lost
+    }
+
+    // Devices that may still come back, so aren't gone yet as far as anyone else is concerned.
+    pub fn recovering(&self) -> impl Iterator<Item=&Serial> {
+        self.recovering.keys()
+    }
+}
+
+pub fn run(command: &str) -> Result {
+    Command::new("sh").arg("-c").arg(command).status()
+        .with_context(|| format!("failed to run {:?}", command))?
+        .exit_ok_()?;
+    Ok(())
+}
+
+fn command(template: &str, serial: &Serial, port: &UsbPort) -> String {
+    template
+        .replace("{{hub}}", &port.hub)
+        .replace("{{port}}", &port.port.to_string())
+        .replace("{{serial}}", serial)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use anyhow::anyhow;
+
+    use crate::config::Config;
+    use crate::power::PowerCycler;
+    use crate::runtime::Serial;
+
+    use super::Result;
+
+    #[test]
+    fn power_cycles_missing_devices_before_giving_up_on_them() -> Result {
+        let config = Config::parse("[power_cycle]\ntimeout = \"1m\"\n[power_cycle.devices]\n\
+            serial1 = { hub = \"1-1\", port = 2 }\nserial2 = { hub = \"1-1\", port = 3 }\n")?;
+        let mut cycler = PowerCycler::new(config.power_cycle.as_ref().unwrap());
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let (serial1, serial2) = (Serial::new("serial1")?, Serial::new("serial2")?);
+        let ran = RefCell::new(Vec::new());
+        let run = |command: &str| {
+            ran.borrow_mut().push(command.to_string());
+            Ok(())
+        };
+
+        assert!(cycler.check(&[serial1.clone(), serial2.clone()], at(0), &run).is_empty());
+        assert!(cycler.check(&[], at(10), &run).is_empty());
+        assert_eq!(*ran.borrow(), ["uhubctl -l 1-1 -p 2 -a cycle", "uhubctl -l 1-1 -p 3 -a cycle"]);
+        assert_eq!(cycler.recovering().collect::<Vec<_>>(), [&serial1, &serial2]);
+
+        let back = vec![serial1.clone()];
+        assert!(cycler.check(&back, at(30), &run).is_empty());
+        assert_eq!(cycler.check(&back, at(70), &run), vec![serial2]);
+        assert!(cycler.check(&back, at(80), &run).is_empty());
+        assert_eq!(ran.borrow().len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn gives_up_when_power_cycling_fails() -> Result {
+        let config = Config::parse("[power_cycle.devices]\nserial1 = { hub = \"1-1\", port = 2 }\n")?;
+        let mut cycler = PowerCycler::new(config.power_cycle.as_ref().unwrap());
+
+        let lost = cycler.check(&[], UNIX_EPOCH, &|_: &str| Err(anyhow!("uhubctl not found")));
+        assert_eq!(lost, [Serial::new("serial1")?]);
+
+        Ok(())
+    }
+}
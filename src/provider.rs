@@ -0,0 +1,85 @@
+use std::fmt::Debug;
+use std::process::Child;
+
+use anyhow::anyhow;
+
+use crate::config::AutoscaleConfig;
+use crate::resources::Cost;
+use crate::runtime::Serial;
+
+pub use self::avd::AvdProvider;
+pub use self::cuttlefish::CuttlefishProvider;
+pub use self::docker::DockerProvider;
+pub use self::genymotion::GenymotionProvider;
+
+mod avd;
+mod cuttlefish;
+mod docker;
+mod genymotion;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// Something that can bring devices into (and out of) existence on demand.
+pub trait Provider: Debug {
+    fn name(&self) -> &str;
+    // Resources an instance takes up on this host.
+    fn cost(&self) -> Cost;
+    // Starts a new instance. in_use reports serials that are already taken so they can be avoided.
+    fn create(&mut self, in_use: &dyn Fn(&Serial) -> bool) -> Result<Instance>;
+    fn destroy(&mut self, instance: Instance) -> Result;
+
+    fn is_running(&mut self, instance: &mut Instance) -> bool {
+        match &mut instance.child {
+            Some(child) => matches!(child.try_wait(), Ok(None)),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub serial: Serial,
+    // provider specific handle, ex: the emulator port or cloud instance id
+    id: String,
+    child: Option<Child>,
+}
+
+pub fn from_config(config: &AutoscaleConfig) -> Result<Box<dyn Provider>> {
+    let mut providers: Vec<Box<dyn Provider>> = Vec::new();
+    if let Some(avd) = &config.avd {
+        providers.push(Box::new(AvdProvider::new(avd)));
+    }
+    if let Some(cuttlefish) = &config.cuttlefish {
+        providers.push(Box::new(CuttlefishProvider::new(cuttlefish)));
+    }
+    if let Some(genymotion) = &config.genymotion {
+        providers.push(Box::new(GenymotionProvider::new(genymotion)));
+    }
+    if let Some(docker) = &config.docker {
+        providers.push(Box::new(DockerProvider::new(docker)));
+    }
+    match providers.len() {
+        0 => Err(anyhow!("[autoscale] needs a provider, one of [autoscale.avd], [autoscale.cuttlefish], [autoscale.genymotion] or [autoscale.docker]")),
+        1 => Ok(providers.remove(0)),
+        _ => Err(anyhow!("[autoscale] only supports a single provider")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::provider::from_config;
+
+    #[test]
+    fn requires_a_single_provider() -> super::Result {
+        let none = Config::parse("[autoscale]\nmax = 2\n")?;
+        let both = Config::parse("[autoscale.avd]\nname = \"a\"\n[autoscale.genymotion]\nrecipe = \"b\"\n")?;
+        let one = Config::parse("[autoscale.cuttlefish]\nhome = \"/home/vsoc-01\"\n")?;
+
+        assert!(from_config(none.autoscale.as_ref().unwrap()).is_err());
+        assert!(from_config(both.autoscale.as_ref().unwrap()).is_err());
+        assert_eq!(from_config(one.autoscale.as_ref().unwrap())?.name(), "cuttlefish");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,55 @@
+use anyhow::anyhow;
+use tracing::debug;
+
+use crate::adb::Adb;
+use crate::config::AvdConfig;
+use crate::emulator::{self, Emulator};
+use crate::provider::{Instance, Provider, Result};
+use crate::resources::Cost;
+use crate::runtime::Serial;
+
+#[derive(Debug)]
+pub struct AvdProvider {
+    name: String,
+    args: Vec<String>,
+    emulator: Emulator,
+    adb: Adb,
+}
+
+impl AvdProvider {
+    pub fn new(config: &AvdConfig) -> AvdProvider {
+        AvdProvider {
+            name: config.name.clone(),
+            args: config.args.clone(),
+            emulator: Emulator::new(&config.emulator),
+            adb: Adb::new("adb"),
+        }
+    }
+}
+
+impl Provider for AvdProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn cost(&self) -> Cost {
+        Cost { cpus: 2.0, memory_mb: 2048 }
+    }
+
+    fn create(&mut self, in_use: &dyn Fn(&Serial) -> bool) -> Result<Instance> {
+        let port = emulator::free_port(in_use).ok_or_else(|| anyhow!("no free emulator ports"))?;
+        let child = self.emulator.launch(&self.name, port, &self.args)?;
+        Ok(Instance { serial: emulator::serial(port), id: port.to_string(), child: Some(child) })
+    }
+
+    fn destroy(&mut self, mut instance: Instance) -> Result {
+        if let Some(child) = &mut instance.child {
+            if let Err(e) = self.adb.emu_kill(&instance.serial) {
+                debug!(emu_kill = %e);
+                let _ = child.kill();
+            }
+            child.wait()?;
+        }
+        Ok(())
+    }
+}
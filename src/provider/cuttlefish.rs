@@ -0,0 +1,74 @@
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use anyhow::anyhow;
+
+use crate::config::CuttlefishConfig;
+use crate::exitstatus::ExitStatusExt;
+use crate::provider::{Instance, Provider, Result};
+use crate::resources::Cost;
+use crate::runtime::Serial;
+
+// Cuttlefish instance n listens for adb on 6520 + (n - 1).
+const ADB_BASE_PORT: u32 = 6520;
+const MAX_INSTANCES: u32 = 128;
+
+#[derive(Debug)]
+pub struct CuttlefishProvider {
+    home: PathBuf,
+    args: Vec<String>,
+}
+
+impl CuttlefishProvider {
+    pub fn new(config: &CuttlefishConfig) -> CuttlefishProvider {
+        CuttlefishProvider {
+            home: config.home.clone(),
+            args: config.args.clone(),
+        }
+    }
+
+    fn command(&self, bin: &str, instance_num: &str) -> Command {
+        let mut command = Command::new(self.home.join("bin").join(bin));
+        command
+            .current_dir(&self.home)
+            .env("HOME", &self.home)
+            .env("CUTTLEFISH_INSTANCE", instance_num)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null());
+        command
+    }
+}
+
+fn serial(instance_num: u32) -> Serial {
+    Serial::new(format!("0.0.0.0:{}", ADB_BASE_PORT + instance_num - 1)).expect("cuttlefish serials are valid")
+}
+
+impl Provider for CuttlefishProvider {
+    fn name(&self) -> &str {
+        "cuttlefish"
+    }
+
+    fn cost(&self) -> Cost {
+        Cost { cpus: 2.0, memory_mb: 2048 }
+    }
+
+    fn create(&mut self, in_use: &dyn Fn(&Serial) -> bool) -> Result<Instance> {
+        let instance_num = (1..=MAX_INSTANCES).find(|num| !in_use(&serial(*num)))
+            .ok_or_else(|| anyhow!("no free cuttlefish instance numbers"))?;
+        let id = instance_num.to_string();
+        self.command("launch_cvd", &id)
+            .args(["--daemon", "--report_anonymous_usage_stats=n", "--num_instances=1"])
+            .arg(format!("--base_instance_num={}", instance_num))
+            .args(&self.args)
+            .status()?
+            .exit_ok_()?;
+        Ok(Instance { serial: serial(instance_num), id, child: None })
+    }
+
+    fn destroy(&mut self, instance: Instance) -> Result {
+        self.command("stop_cvd", &instance.id)
+            .status()?
+            .exit_ok_()?;
+        Ok(())
+    }
+}
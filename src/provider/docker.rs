@@ -0,0 +1,109 @@
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use tracing::debug;
+
+use crate::adb::Adb;
+use crate::config::DockerConfig;
+use crate::exitstatus::ExitStatusExt;
+use crate::provider::{Instance, Provider, Result};
+use crate::resources::Cost;
+use crate::runtime::{retry, Serial};
+
+// Host ports containers get their adb port published on.
+const FIRST_HOST_PORT: u16 = 15555;
+const LAST_HOST_PORT: u16 = 15655;
+
+// Emulators running in containers, ex: https://github.com/google/android-emulator-container-scripts
+#[derive(Debug)]
+pub struct DockerProvider {
+    image: String,
+    docker: PathBuf,
+    args: Vec<String>,
+    adb_port: u16,
+    connect_timeout: Duration,
+    adb: Adb,
+}
+
+impl DockerProvider {
+    pub fn new(config: &DockerConfig) -> DockerProvider {
+        DockerProvider {
+            image: config.image.clone(),
+            docker: config.docker.clone(),
+            args: config.args.clone(),
+            adb_port: config.adb_port,
+            connect_timeout: config.connect_timeout.0,
+            adb: Adb::new("adb"),
+        }
+    }
+
+    fn docker(&self, args: &[&str]) -> Result<String> {
+        let output = Command::new(&self.docker)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to run {:?}", self.docker))?
+            .wait_with_output()?;
+        output.status.exit_ok_()?;
+        Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+    }
+
+    fn remove(&self, id: &str) -> Result {
+        self.docker(&["rm", "--force", id])?;
+        Ok(())
+    }
+}
+
+fn serial(port: u16) -> Serial {
+    Serial::new(format!("127.0.0.1:{}", port)).expect("container serials are valid")
+}
+
+fn is_bindable(port: u16) -> bool {
+    TcpListener::bind(("127.0.0.1", port)).is_ok()
+}
+
+impl Provider for DockerProvider {
+    fn name(&self) -> &str {
+        &self.image
+    }
+
+    fn cost(&self) -> Cost {
+        Cost { cpus: 2.0, memory_mb: 2048 }
+    }
+
+    fn create(&mut self, in_use: &dyn Fn(&Serial) -> bool) -> Result<Instance> {
+        let port = (FIRST_HOST_PORT..=LAST_HOST_PORT)
+            .find(|port| !in_use(&serial(*port)) && is_bindable(*port))
+            .ok_or_else(|| anyhow!("no free host ports for containers"))?;
+        let publish = format!("127.0.0.1:{}:{}", port, self.adb_port);
+        let mut args = vec!["run", "--detach", "--rm", "--publish", &publish];
+        args.extend(self.args.iter().map(|arg| arg.as_str()));
+        args.push(&self.image);
+        let id = self.docker(&args)?;
+
+        let serial = serial(port);
+        let connected = retry(
+            retry::delay::Fixed::from(Duration::from_secs(1)).take(self.connect_timeout.as_secs() as usize),
+            || {
+                let connected = self.adb.connect(&serial)?;
+                debug!(serial = %serial, connected);
+                if connected { Ok(()) } else { Err(anyhow!("adb couldn't connect to {}", serial)) }
+            },
+        );
+        if let Err(e) = connected {
+            let _ = self.remove(&id);
+            return Err(e.context(format!("timed out waiting for container {}", id)));
+        }
+
+        Ok(Instance { serial, id, child: None })
+    }
+
+    fn destroy(&mut self, instance: Instance) -> Result {
+        let _ = self.adb.disconnect(&instance.serial);
+        self.remove(&instance.id)
+    }
+}
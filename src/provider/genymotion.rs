@@ -0,0 +1,74 @@
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use anyhow::anyhow;
+
+use crate::config::GenymotionConfig;
+use crate::exitstatus::ExitStatusExt;
+use crate::provider::{Instance, Provider, Result};
+use crate::resources::Cost;
+use crate::runtime::Serial;
+
+// Cloud instances from Genymotion SaaS, managed through the gmsaas cli.
+#[derive(Debug)]
+pub struct GenymotionProvider {
+    recipe: String,
+    gmsaas: PathBuf,
+    count: usize,
+}
+
+impl GenymotionProvider {
+    pub fn new(config: &GenymotionConfig) -> GenymotionProvider {
+        GenymotionProvider {
+            recipe: config.recipe.clone(),
+            gmsaas: config.gmsaas.clone(),
+            count: 0,
+        }
+    }
+
+    fn instances(&self, args: &[&str]) -> Result<String> {
+        let output = Command::new(&self.gmsaas)
+            .arg("instances")
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()?
+            .wait_with_output()?;
+        output.status.exit_ok_()?;
+        Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+    }
+}
+
+impl Provider for GenymotionProvider {
+    fn name(&self) -> &str {
+        &self.recipe
+    }
+
+    fn cost(&self) -> Cost {
+        // Runs in the cloud.
+        Cost { cpus: 0.0, memory_mb: 0 }
+    }
+
+    fn create(&mut self, _in_use: &dyn Fn(&Serial) -> bool) -> Result<Instance> {
+        self.count += 1;
+        let name = format!("adp-{}-{}", std::process::id(), self.count);
+        // Blocks until the instance is online.
+        let id = self.instances(&["start", &self.recipe, &name])?;
+        if id.is_empty() {
+            return Err(anyhow!("gmsaas didn't return an instance id"));
+        }
+        let serial = match self.instances(&["adbconnect", &id]).and_then(Serial::new) {
+            Ok(serial) => serial,
+            Err(e) => {
+                let _ = self.instances(&["stop", &id]);
+                return Err(e);
+            }
+        };
+        Ok(Instance { serial, id, child: None })
+    }
+
+    fn destroy(&mut self, instance: Instance) -> Result {
+        self.instances(&["stop", &instance.id])?;
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context};
+use tracing::{debug, instrument};
+
+use crate::config::{ProvisionConfig, WifiConfig};
+use crate::runtime::{Runtime, Serial};
+use crate::storage;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// Clocks closer than this to the host's are left alone.
+const MAX_DRIFT: Duration = Duration::from_secs(2);
+// How long a device gets to connect to the wifi network.
+const WIFI_TIMEOUT: Duration = Duration::from_secs(30);
+const WIFI_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// How long zygote gets to come back after being restarted.
+const RESTART_TIMEOUT: Duration = Duration::from_secs(30);
+const RESTART_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Puts the device into the state the config asks for before a job runs on it, undoing whatever the last job or
+// someone at the bench changed. Setting the clock and locale needs root, so a device that doesn't allow it is only
+// warned about.
+#[instrument(skip(runtime))]
+pub fn run(runtime: &impl Runtime, serial: &Serial, config: &ProvisionConfig) -> Result {
+    if config.sync_clock {
+        if let Err(e) = sync_clock(runtime, serial) {
+            eprintln!("adp: couldn't set the clock on {}: {:#}", serial, e);
+        }
+    }
+    if let Some(timezone) = &config.timezone {
+        if let Err(e) = set_prop(runtime, serial, "persist.sys.timezone", timezone) {
+            eprintln!("adp: couldn't set the timezone on {} to {}: {:#}", serial, timezone, e);
+        }
+    }
+    if let Some(locale) = &config.locale {
+        match set_prop(runtime, serial, "persist.sys.locale", locale) {
+            Ok(true) => if let Err(e) = restart_framework(runtime, serial) {
+                eprintln!("adp: couldn't restart the framework on {} to change its locale: {:#}", serial, e);
+            },
+            Ok(false) => {}
+            Err(e) => eprintln!("adp: couldn't set the locale on {} to {}: {:#}", serial, locale, e),
+        }
+    }
+    if config.airplane_mode_off {
+        if let Err(e) = airplane_mode_off(runtime, serial) {
+            eprintln!("adp: couldn't turn airplane mode off on {}: {:#}", serial, e);
+        }
+    }
+    if let Some(wifi) = &config.wifi {
+        if let Err(e) = connect_wifi(runtime, serial, wifi) {
+            eprintln!("adp: couldn't connect {} to {}: {:#}", serial, wifi.ssid, e);
+        }
+    }
+    Ok(())
+}
+
+// Checked after provisioning, a device that fails is no use to network dependent tests so it's better quarantined.
+#[instrument(skip(runtime))]
+pub fn check_network(runtime: &impl Runtime, serial: &Serial, config: &ProvisionConfig) -> Result {
+    if let Some(host) = &config.ping {
+        runtime.run_adb(serial, &["shell", "ping", "-c", "1", "-W", "5", &quote(host)])
+            .with_context(|| format!("{} can't reach {}", serial, host))?;
+    }
+    Ok(())
+}
+
+// Also checked after provisioning, a device that's run out of storage fails installs in ways that look like the app's
+// fault. Only fails when the device says it's low, one that couldn't be asked is left for the job to find out about.
+#[instrument(skip(runtime))]
+pub fn check_storage(runtime: &impl Runtime, serial: &Serial, config: &ProvisionConfig) -> Result {
+    let Some(min) = config.min_free_storage else { return Ok(()) };
+    let free = match storage::free(runtime, serial) {
+        Ok(free) if free >= min => return Ok(()),
+        Ok(free) => free,
+        Err(e) => {
+            eprintln!("adp: couldn't check the storage on {}: {:#}", serial, e);
+            return Ok(());
+        }
+    };
+    debug!(cleaning = %serial, free = %free);
+    if let Err(e) = storage::clean(runtime, serial, &config.cleanup_paths) {
+        eprintln!("adp: failed to clean up {}: {:#}", serial, e);
+    }
+    match storage::free(runtime, serial) {
+        Ok(free) if free < min => {
+            Err(anyhow!("{} only has {} of storage free after cleaning it up, less than {}", serial, free, min))
+        }
+        Ok(_) => Ok(()),
+        Err(e) => {
+            eprintln!("adp: couldn't check the storage on {}: {:#}", serial, e);
+            Ok(())
+        }
+    }
+}
+
+fn airplane_mode_off(runtime: &impl Runtime, serial: &Serial) -> Result {
+    if runtime.run_adb(serial, &["shell", "cmd", "connectivity", "airplane-mode"])? == "enabled" {
+        runtime.run_adb(serial, &["shell", "cmd", "connectivity", "airplane-mode", "disable"])?;
+    }
+    Ok(())
+}
+
+fn connect_wifi(runtime: &impl Runtime, serial: &Serial, wifi: &WifiConfig) -> Result {
+    let connected = || -> Result<bool> {
+        let status = runtime.run_adb(serial, &["shell", "cmd", "wifi", "status"])?;
+        Ok(status.lines().any(|line| line.contains("connected to") && line.contains(&format!("\"{}\"", wifi.ssid))))
+    };
+    if connected()? {
+        return Ok(());
+    }
+    runtime.run_adb(serial, &["shell", "cmd", "wifi", "set-wifi-enabled", "enabled"])?;
+    match &wifi.password {
+        Some(password) => runtime.run_adb(
+            serial,
+            &["shell", "cmd", "wifi", "connect-network", &quote(&wifi.ssid), "wpa2", &quote(password)],
+        )?,
+        None => runtime.run_adb(serial, &["shell", "cmd", "wifi", "connect-network", &quote(&wifi.ssid), "open"])?,
+    };
+    let start = Instant::now();
+    while !connected()? {
+        if start.elapsed() >= WIFI_TIMEOUT {
+            return Err(anyhow!("it didn't connect within {:?}", WIFI_TIMEOUT));
+        }
+        std::thread::sleep(WIFI_POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+// adb joins the args to a shell command run on the device, so ones from the config are quoted to be taken as is.
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+fn sync_clock(runtime: &impl Runtime, serial: &Serial) -> Result {
+    let output = runtime.run_adb(serial, &["shell", "date", "+%s"])?;
+    let device: u64 = output.parse().map_err(|_| anyhow!("unexpected output from date: {}", output))?;
+    let host = runtime.now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let drift = host.abs_diff(Duration::from_secs(device));
+    debug!(drift = ?drift);
+    if drift <= MAX_DRIFT {
+        return Ok(());
+    }
+    // Read again, asking the device may have taken a moment.
+    let now = runtime.now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    runtime.run_adb(serial, &["shell", "date", "-u", &format!("@{}", now)])?;
+    Ok(())
+}
+
+pub fn set_locale(runtime: &impl Runtime, serial: &Serial, locale: &str) -> Result {
+    if set_prop(runtime, serial, "persist.sys.locale", locale)? {
+        restart_framework(runtime, serial)?;
+    }
+    Ok(())
+}
+
+// The framework only picks up a new locale when it starts. sys.boot_completed stays set through the restart, so boot
+// is only waited for once zygote is back with a new pid.
+fn restart_framework(runtime: &impl Runtime, serial: &Serial) -> Result {
+    // Empty when it isn't running.
+    let zygote = || runtime.run_adb(serial, &["shell", "pidof", "zygote64", "zygote"])
+        .map(|pids| pids.trim().to_string())
+        .unwrap_or_default();
+    let before = zygote();
+    runtime.run_adb(serial, &["shell", "setprop", "ctl.restart", "zygote"])
+        .with_context(|| format!("failed to restart {} to change its locale", serial))?;
+    let start = Instant::now();
+    while !before.is_empty() {
+        let now = zygote();
+        if !now.is_empty() && now != before {
+            break;
+        }
+        if start.elapsed() >= RESTART_TIMEOUT {
+            return Err(anyhow!("zygote on {} didn't restart within {:?}", serial, RESTART_TIMEOUT));
+        }
+        std::thread::sleep(RESTART_POLL_INTERVAL);
+    }
+    runtime.wait_for_boot(serial)
+}
+
+// Returns whether the prop had to be changed.
+fn set_prop(runtime: &impl Runtime, serial: &Serial, name: &str, value: &str) -> Result<bool> {
+    if runtime.getprop(serial, name)? == value {
+        return Ok(false);
+    }
+    runtime.run_adb(serial, &["shell", "setprop", name, value])?;
+    Ok(true)
+}
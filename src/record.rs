@@ -0,0 +1,154 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context};
+use tracing::{debug, instrument};
+
+use crate::runtime::{Runtime, Serial};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// screenrecord won't go past 3 minutes, so longer jobs are recorded in segments of this long.
+const SEGMENT: &str = "180";
+// How long to keep trying to stop screenrecord, it can take a moment to start.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// Records the device's screen for as long as a job runs, to be able to see what a flaky UI test was doing.
+#[derive(Debug)]
+pub struct Recording {
+    serial: Serial,
+    // ex: <dir>/emulator-5554-1700000000.mp4
+    path: PathBuf,
+    // What the segments on the device start with, ex: /sdcard/adp-record-123.
+    prefix: String,
+    // Of the shell on the device that records one segment after another.
+    pid: u32,
+}
+
+impl Recording {
+    #[instrument(skip(runtime))]
+    pub fn start(runtime: &impl Runtime, serial: &Serial, dir: &Path, started_at: SystemTime) -> Result<Recording> {
+        std::fs::create_dir_all(dir).with_context(|| format!("failed to create {:?}", dir))?;
+        let secs = started_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let path = dir.join(format!("{}-{}.mp4", serial, secs));
+        let prefix = device_prefix();
+        let output = runtime.run_adb(serial, &["shell", &start_command(&prefix)])
+            .with_context(|| format!("failed to start screenrecord on {}", serial))?;
+        let pid = output.trim().parse()
+            .map_err(|_| anyhow!("failed to start screenrecord on {}, got {:?}", serial, output.trim()))?;
+        Ok(Recording { serial: serial.clone(), path, prefix, pid })
+    }
+
+    // Stops recording and pulls the recording off the device, returning where it was saved. Segments that fail to
+    // pull are left out of it.
+    #[instrument(skip(runtime))]
+    pub fn finish(self, runtime: &impl Runtime) -> Result<PathBuf> {
+        let serial = &self.serial;
+        let stop = format!("touch {}.stop; pkill -INT -P {} screenrecord", self.prefix, self.pid);
+        let running = format!("kill -0 {} 2>/dev/null && echo running", self.pid);
+        let start = Instant::now();
+        // Sent until the recording is done, in case screenrecord hadn't started yet the first time.
+        loop {
+            let _ = runtime.run_adb(serial, &["shell", &stop]);
+            if !runtime.run_adb(serial, &["shell", &running])?.contains("running") {
+                break;
+            }
+            if start.elapsed() >= STOP_TIMEOUT {
+                return Err(anyhow!("screenrecord on {} didn't stop", serial));
+            }
+            std::thread::sleep(STOP_POLL_INTERVAL);
+        }
+        let listed = runtime.run_adb(serial, &["shell", &format!("ls {}-*.mp4 2>/dev/null", self.prefix)])?;
+        let mut segments: Vec<(usize, &str)> = listed.lines()
+            .filter_map(|segment| {
+                let i = segment.strip_prefix(&self.prefix)?.strip_prefix('-')?.strip_suffix(".mp4")?;
+                Some((i.parse().ok()?, segment))
+            })
+            .collect();
+        segments.sort();
+        let mut pulled = Vec::new();
+        for (i, segment) in segments {
+            let local = segment_path(&self.path, i);
+            match runtime.run_adb(serial, &["pull", segment, &local.to_string_lossy()]) {
+                Ok(_) => pulled.push(local),
+                Err(e) => eprintln!("adp: failed to pull {} from {}, leaving it out of the recording: {:#}", segment, serial, e),
+            }
+        }
+        let _ = runtime.run_adb(serial, &["shell", &format!("rm -f {0}-*.mp4 {0}.stop", self.prefix)]);
+        match pulled.as_slice() {
+            [] => Err(anyhow!("nothing was recorded on {}", serial)),
+            [only] => {
+                std::fs::rename(only, &self.path)?;
+                Ok(self.path)
+            }
+            _ => {
+                concat(&pulled, &self.path)?;
+                for segment in &pulled {
+                    let _ = std::fs::remove_file(segment);
+                }
+                Ok(self.path)
+            }
+        }
+    }
+}
+
+// Of this process's segments on the device.
+pub fn device_prefix() -> String {
+    format!("/sdcard/adp-record-{}", std::process::id())
+}
+
+// Records one segment after another in the background on the device until there's a stop file, printing the pid of
+// the shell doing it so only its screenrecord is stopped.
+pub fn start_command(prefix: &str) -> String {
+    format!(
+        "rm -f {prefix}.stop; (i=0; while [ ! -e {prefix}.stop ]; do \
+         screenrecord --time-limit {SEGMENT} {prefix}-$i.mp4 || break; i=$((i+1)); done) \
+         </dev/null >/dev/null 2>&1 & echo $!",
+    )
+}
+
+// ex: emulator-5554-1700000000.1.mp4
+fn segment_path(path: &Path, i: usize) -> PathBuf {
+    path.with_extension(format!("{}.mp4", i + 1))
+}
+
+// Joined with ffmpeg without re-encoding, which they all allow for since they come from the same screenrecord.
+fn concat(segments: &[PathBuf], to: &Path) -> Result {
+    let list = to.with_extension("txt");
+    let contents: String = segments.iter()
+        .map(|segment| format!("file '{}'\n", segment.to_string_lossy().replace('\'', "'\\''")))
+        .collect();
+    std::fs::write(&list, contents)?;
+    let status = Command::new("ffmpeg")
+        .args(["-loglevel", "error", "-y", "-f", "concat", "-safe", "0", "-i"])
+        .arg(&list)
+        .args(["-c", "copy"])
+        .arg(to)
+        .stdin(Stdio::null())
+        .status();
+    let _ = std::fs::remove_file(&list);
+    debug!(status = ?status);
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        _ => Err(anyhow!(
+            "failed to join the recording with ffmpeg, its segments are in {:?}",
+            to.parent().unwrap_or(to),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::record::segment_path;
+
+    #[test]
+    fn names_segments_after_the_recording() {
+        let path = Path::new("recordings/emulator-5554-1700000000.mp4");
+
+        assert_eq!(segment_path(path, 0), Path::new("recordings/emulator-5554-1700000000.1.mp4"));
+    }
+}
@@ -0,0 +1,20 @@
+use std::fmt::Debug;
+
+use tracing::instrument;
+
+use crate::App;
+use crate::runtime::Runtime;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+#[instrument(skip(app))]
+pub fn run<R: Runtime + Debug>(app: &App<R>) -> Result {
+    let fixes = app.repair()?;
+    if fixes.is_empty() {
+        println!("nothing to fix");
+    }
+    for fix in fixes {
+        println!("{}", fix);
+    }
+    Ok(())
+}
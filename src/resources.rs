@@ -0,0 +1,81 @@
+use std::fmt::{Display, Formatter};
+
+use sysinfo::{System, SystemExt};
+
+use crate::config::ResourcesConfig;
+
+// Estimated resources a single running instance takes up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cost {
+    pub cpus: f64,
+    pub memory_mb: u64,
+}
+
+impl Display for Cost {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} cpus, {}MB", self.cpus, self.memory_mb)
+    }
+}
+
+// Total resources instances started by adp are allowed to use.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Budget {
+    pub cpus: f64,
+    pub memory_mb: u64,
+}
+
+impl Budget {
+    // Defaults to everything the host has.
+    pub fn new(config: &ResourcesConfig, sys: &System) -> Budget {
+        Budget {
+            cpus: config.cpus.unwrap_or(sys.processors().len() as f64),
+            memory_mb: config.memory_mb.map_or(sys.total_memory() / 1024, |memory| memory.0),
+        }
+    }
+
+    // Checks if one more instance fits alongside the ones already running, returning why not if it doesn't.
+    pub fn check(&self, running: usize, cost: Cost) -> Result<(), String> {
+        let count = (running + 1) as f64;
+        if cost.cpus * count > self.cpus {
+            return Err(format!("{} instances would need {} cpus but the budget is {}", running + 1, cost.cpus * count, self.cpus));
+        }
+        let memory_mb = cost.memory_mb * (running as u64 + 1);
+        if memory_mb > self.memory_mb {
+            return Err(format!("{} instances would need {}MB but the budget is {}MB", running + 1, memory_mb, self.memory_mb));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::resources::{Budget, Cost};
+
+    #[test]
+    fn fits_within_budget() {
+        let budget = Budget { cpus: 8.0, memory_mb: 32768 };
+
+        assert_eq!(budget.check(7, Cost { cpus: 1.0, memory_mb: 2048 }), Ok(()));
+    }
+
+    #[test]
+    fn rejects_over_cpu_budget() {
+        let budget = Budget { cpus: 8.0, memory_mb: 32768 };
+
+        assert!(budget.check(8, Cost { cpus: 1.0, memory_mb: 2048 }).is_err());
+    }
+
+    #[test]
+    fn rejects_over_memory_budget() {
+        let budget = Budget { cpus: 8.0, memory_mb: 8192 };
+
+        assert!(budget.check(4, Cost { cpus: 1.0, memory_mb: 2048 }).is_err());
+    }
+
+    #[test]
+    fn free_instances_always_fit() {
+        let budget = Budget { cpus: 1.0, memory_mb: 1024 };
+
+        assert_eq!(budget.check(100, Cost { cpus: 0.0, memory_mb: 0 }), Ok(()));
+    }
+}
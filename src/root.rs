@@ -0,0 +1,77 @@
+use std::fs::{File, OpenOptions, Permissions};
+use std::io::ErrorKind;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use tracing::instrument;
+
+use crate::duration::HumanDuration;
+use crate::filelock::{FileLockGuard, FileLockGuardExt};
+use crate::filter::DeviceFilter;
+use crate::runtime::{retry, Runtime, Serial};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// How long a device gets to come back after adbd restarts, one that doesn't would otherwise hold the job up forever.
+const RESTART_TIMEOUT: Duration = Duration::from_secs(30);
+
+// adb root only works on these.
+pub fn filter() -> DeviceFilter {
+    DeviceFilter::prop_regex("ro.build.type=userdebug|eng").unwrap()
+}
+
+// Restarts adbd on the device as root for a job that needs it, checking it took.
+#[instrument(skip(runtime))]
+pub fn enable(runtime: &impl Runtime, serial: &Serial) -> Result {
+    let _lock = lock(serial)?;
+    restart(runtime, serial, "root")?;
+    let uid = runtime.run_adb(serial, &["shell", "id", "-u"])?;
+    if uid != "0" {
+        return Err(anyhow!("adb root didn't take on {}, its shell is running as uid {}", serial, uid));
+    }
+    Ok(())
+}
+
+// Puts adbd back the way the next job expects it.
+#[instrument(skip(runtime))]
+pub fn disable(runtime: &impl Runtime, serial: &Serial) -> Result {
+    let _lock = lock(serial)?;
+    restart(runtime, serial, "unroot")
+}
+
+// adbd drops the connection while it restarts, so wait for it to come back.
+fn restart(runtime: &impl Runtime, serial: &Serial, command: &str) -> Result {
+    runtime.run_adb(serial, &[command]).with_context(|| format!("adb {} failed on {}", command, serial))?;
+    let polls = retry::delay::Fixed::from(Duration::from_secs(1)).take(RESTART_TIMEOUT.as_secs() as usize);
+    retry(polls, || match runtime.run_adb(serial, &["get-state"])?.as_str() {
+        "device" => Ok(()),
+        state => Err(anyhow!("it's {}", if state.is_empty() { "gone" } else { state })),
+    })
+    .with_context(|| format!("{} didn't come back within {} after adb {}", serial, HumanDuration(RESTART_TIMEOUT), command))
+}
+
+// Kept in the system temp dir rather than adp's own so any tool on the host, run by any user, can take it too before
+// restarting adbd.
+fn lock(serial: &Serial) -> Result<FileLockGuard> {
+    let path: PathBuf = std::env::temp_dir().join(format!("adp-root-{}.lock", serial));
+    let file = open_shared(&path).with_context(|| format!("failed to open {:?}", path))?;
+    Ok(file.into_lock_exclusive()?)
+}
+
+// Only read, flock doesn't need it writable, so it works whichever user created it. Created writable by anyone for
+// tools that do open it for writing.
+fn open_shared(path: &Path) -> std::io::Result<File> {
+    match File::open(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        result => return result,
+    }
+    match OpenOptions::new().write(true).create_new(true).mode(0o666).open(path) {
+        // Past the umask.
+        Ok(file) => file.set_permissions(Permissions::from_mode(0o666))?,
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e),
+    }
+    File::open(path)
+}
@@ -1,92 +1,551 @@
 use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::collections::hash_map::RandomState;
+use std::ffi::OsStr;
+use std::fmt::{Display, Formatter};
+use std::hash::BuildHasher;
+use std::ops::Deref;
 use std::path::Path;
-use std::time::Duration;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
 use ambassador::delegatable_trait;
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Serialize};
 use sysinfo::{System, SystemExt};
 use tracing::{debug, instrument};
 
-use crate::adb::Adb;
+use crate::adb::{Adb, AdbDevice, Battery, Target};
+use crate::config::BootCheck;
 
 pub type Result<T> = std::result::Result<T, anyhow::Error>;
 
-pub type Serial = String;
 pub type Pid = sysinfo::Pid;
 
+// A device's serial as adb reports it. Lock file lines start with the serial up to the first tab, so it can't
+// contain whitespace.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Serial(String);
+
+impl Serial {
+    pub fn new(serial: impl Into<String>) -> Result<Serial> {
+        let serial = serial.into();
+        if serial.is_empty() {
+            bail!("serial can't be empty");
+        }
+        if let Some(c) = serial.chars().find(|c| c.is_whitespace()) {
+            bail!("invalid serial {:?}, can't contain {:?}", serial, c);
+        }
+        Ok(Serial(serial))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    // For a device that shares its serial with another, ex: because of firmware that gives every unit the same one, so
+    // they're still pooled separately, ex: R58M123ABC@1-1.2 by the usb port it's plugged into.
+    pub fn shared(serial: &str, location: &str) -> Result<Serial> {
+        Serial::new(format!("{}@{}", serial, location))
+    }
+
+    // Emulators are named after their console port, ex: emulator-5554.
+    pub fn is_emulator(&self) -> bool {
+        self.0.strip_prefix("emulator-").is_some_and(|port| port.parse::<u16>().is_ok())
+    }
+}
+
+impl Deref for Serial {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Serial {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<OsStr> for Serial {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+impl std::borrow::Borrow<str> for Serial {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq<str> for Serial {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Serial {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl Display for Serial {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for Serial {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Serial> {
+        Serial::new(s)
+    }
+}
+
+impl TryFrom<String> for Serial {
+    type Error = anyhow::Error;
+
+    fn try_from(serial: String) -> Result<Serial> {
+        Serial::new(serial)
+    }
+}
+
+impl From<Serial> for String {
+    fn from(serial: Serial) -> String {
+        serial.0
+    }
+}
+
+// Serials more than one device reports, with whether any two of them are on the same adb server.
+pub fn shared_serials<'a>(devices: impl IntoIterator<Item=&'a AdbDevice>) -> BTreeMap<String, bool> {
+    let mut seen = BTreeSet::new();
+    let mut shared = BTreeMap::new();
+    for device in devices {
+        if !seen.insert((device.serial.as_str(), device.server.as_deref())) {
+            shared.insert(device.serial.clone(), true);
+        } else if seen.iter().any(|(serial, server)| *serial == device.serial && *server != device.server.as_deref()) {
+            shared.entry(device.serial.clone()).or_insert(false);
+        }
+    }
+    shared
+}
+
+// The serial the device goes by in the pool, its own unless it's shared. Then it's told apart by where it's connected,
+// which unlike its transport id stays the same when it reconnects, ex: after a usb blip. That's its adb server when the
+// others are on other servers, and the usb port it's plugged into when they're on the same one, falling back on the
+// transport id if adb doesn't say.
+pub fn pool_serial(device: &AdbDevice, shared: &BTreeMap<String, bool>) -> Result<Serial> {
+    let Some(same_server) = shared.get(&device.serial) else {
+        return Serial::new(&device.serial);
+    };
+    let server = device.server.as_deref().map(|socket| socket.strip_prefix("tcp:").unwrap_or(socket));
+    let port = same_server.then(|| device.attributes.get("usb").or(device.attributes.get("transport_id"))).flatten()
+        .map(String::as_str);
+    match (server, port) {
+        (Some(server), Some(port)) => Serial::shared(&device.serial, &format!("{}/{}", server, port)),
+        (Some(location), None) | (None, Some(location)) => Serial::shared(&device.serial, location),
+        (None, None) => Serial::new(&device.serial),
+    }
+}
+
+// The host an adb server is on going by its ADB_SERVER_SOCKET, None if it's this one, ex: tcp:bench2:5037 is on
+// bench2.
+pub fn server_host(server_socket: &str) -> Option<&str> {
+    let (host, _) = server_socket.strip_prefix("tcp:")?.rsplit_once(':')?;
+    match host.trim_start_matches('[').trim_end_matches(']') {
+        "" | "localhost" | "127.0.0.1" | "::1" => None,
+        host => Some(host),
+    }
+}
+
+// How far along waiting for a device to boot is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BootProgress<'a> {
+    // The prop or boot check that isn't there yet, ex: sys.boot_completed.
+    pub waiting_for: &'a str,
+    // Since it started waiting on the device.
+    pub elapsed: Duration,
+}
+
+// Most devices boot waited and health checked at once, each one is an adb shell every second.
+const MAX_BOOT_WAITS: usize = 8;
+
+// Past this many pids it's cheaper to refresh every process at once than each one on its own.
+const REFRESH_ALL_THRESHOLD: usize = 16;
+
 #[delegatable_trait]
 pub trait Runtime {
-    fn devices(&self) -> Result<Vec<Serial>>;
+    fn connected_devices(&self) -> Result<Vec<Serial>>;
     fn wait_for_boot(&self, serial: &Serial) -> Result<()>;
+    // Like wait_for_boot but calls progress with what it's still waiting on each time it checks, ex: for a progress bar.
+    fn wait_for_boot_with_progress(&self, serial: &Serial, _progress: &dyn Fn(&BootProgress)) -> Result<()> {
+        self.wait_for_boot(serial)
+    }
+    fn check_health(&self, serial: &Serial) -> Result<()>;
+    // Waits for each device to boot and pass its health check, several at a time, returning how each one went in the
+    // same order.
+    fn wait_until_healthy(&self, serials: &[Serial]) -> Vec<Result<()>> {
+        serials.iter().map(|serial| self.wait_for_boot(serial).and_then(|_| self.check_health(serial))).collect()
+    }
+    // Every device adb knows about, including ones that are offline or unauthorized.
+    fn adb_devices(&self) -> Result<Vec<AdbDevice>>;
+    fn getprop(&self, serial: &Serial, name: &str) -> Result<String>;
+    fn battery(&self, serial: &Serial) -> Result<Battery>;
+    // Runs adb against the device with the given args, ex: forward tcp:8081 tcp:8081, returning what it printed.
+    fn run_adb(&self, serial: &Serial, args: &[&str]) -> Result<String>;
+    // A png of what's on the device's screen.
+    fn screencap(&self, serial: &Serial) -> Result<Vec<u8>>;
+    // The adb server the device is connected through, for the job to talk to, None for the default one.
+    fn server_socket(&self, serial: &Serial) -> Option<String>;
+    // adb's id for the device's current connection, for `adb -t`, None if adb doesn't report one.
+    fn transport_id(&self, serial: &Serial) -> Option<String>;
+    // What adb calls the device, which is only different from its serial in the pool when another device shares it.
+    fn adb_serial(&self, serial: &Serial) -> String;
     fn is_running(&self, pid: Pid) -> Result<bool>;
+    // Which of the given pids are running, lets the runtime check them all in one go.
+    fn running(&self, pids: &[Pid]) -> Result<std::collections::BTreeSet<Pid>> {
+        let mut running = std::collections::BTreeSet::new();
+        for pid in pids.iter().copied().collect::<std::collections::BTreeSet<_>>() {
+            if self.is_running(pid)? {
+                running.insert(pid);
+            }
+        }
+        Ok(running)
+    }
+    fn now(&self) -> std::time::SystemTime;
+    // Source of randomness, ex: for tokens that need to be unique across hosts.
+    fn random(&self) -> u64;
 }
 
 #[derive(Debug)]
 pub struct RealRuntime {
-    adb: Adb,
+    // One for each adb server, large benches run several to get past the number of devices one can handle.
+    servers: Vec<Adb>,
+    // How each device was last seen.
+    connections: RefCell<BTreeMap<Serial, Connection>>,
+    // Devices adb reports with a serial that can't be used, so they're only warned about once.
+    ignored: RefCell<BTreeSet<String>>,
+    // Same for serials more than one device reports.
+    shared: RefCell<BTreeSet<String>>,
     sys: RefCell<System>,
+    // On top of the boot props, before a device counts as booted.
+    boot_checks: Vec<BootCheck>,
+}
+
+#[derive(Debug, Clone)]
+struct Connection {
+    // Index of the adb server the device is on.
+    server: usize,
+    // What adb calls the device.
+    serial: String,
+    transport_id: Option<String>,
+    // Another device has the same serial, so adb can only tell them apart by transport id.
+    shared: bool,
 }
 
 impl RealRuntime {
-    pub fn new(adb_path: impl AsRef<Path>) -> RealRuntime {
+    pub fn new(adb_path: impl AsRef<Path>, server_sockets: &[String]) -> RealRuntime {
+        let servers = if server_sockets.is_empty() {
+            vec![Adb::new(&adb_path)]
+        } else {
+            server_sockets.iter().map(|socket| Adb::with_server(&adb_path, socket)).collect()
+        };
         RealRuntime {
-            adb: Adb::new(adb_path),
+            servers,
+            connections: RefCell::new(BTreeMap::new()),
+            ignored: RefCell::new(BTreeSet::new()),
+            shared: RefCell::new(BTreeSet::new()),
             sys: RefCell::new(System::new()),
+            boot_checks: Vec::new(),
         }
     }
+
+    pub fn set_boot_checks(&mut self, checks: Vec<BootCheck>) {
+        self.boot_checks = checks;
+    }
+
+    // How the device is connected, listing devices again if it hasn't been seen yet.
+    fn connection(&self, serial: &Serial) -> Result<Connection> {
+        if !self.connections.borrow().contains_key(serial) {
+            self.connected_devices()?;
+        }
+        self.connections.borrow().get(serial).cloned().ok_or_else(|| anyhow!("{} isn't connected", serial))
+    }
+
+    // The server the device is connected through.
+    fn adb(&self, serial: &Serial) -> Result<&Adb> {
+        Ok(&self.servers[self.connection(serial)?.server])
+    }
+
+    // Runs f against the server the device is connected through. The device is addressed by its serial unless another
+    // device shares it, as the transport id changes whenever the device reconnects, ex: after `adb root`.
+    fn on_device<T>(&self, serial: &Serial, f: impl FnOnce(&Adb, Target<'_>) -> Result<T>) -> Result<T> {
+        let connection = self.connection(serial)?;
+        f(&self.servers[connection.server], connection.target())
+    }
 }
 
-impl Runtime for RealRuntime {
-    fn devices(&self) -> Result<Vec<Serial>> {
-        let mut devices = self.adb.devices()?;
+impl Connection {
+    fn target(&self) -> Target<'_> {
+        match &self.transport_id {
+            Some(transport_id) if self.shared => Target::TransportId(transport_id),
+            _ => Target::from(&self.serial),
+        }
+    }
+}
 
-        if devices.is_empty() {
-            // wait for a device and try again
-            self.adb.wait_for_device()?;
-            devices = self.adb.devices()?;
+fn wait_for_boot_on(adb: &Adb, device: Target<'_>, checks: &[BootCheck], progress: &dyn Fn(&BootProgress)) -> Result<()> {
+    let start = Instant::now();
+    let wait = |waiting_for: &str, check: &mut dyn FnMut() -> Result<()>| {
+        retry::<_, _, _, anyhow::Error, _>(
+            retry::delay::Fixed::from(Duration::from_secs(1)).take(60),
+            || check().inspect_err(|_| progress(&BootProgress { waiting_for, elapsed: start.elapsed() })),
+        )
+        .with_context(|| format!("timed out waiting for {}", waiting_for))
+    };
+    for (prop, expected_value) in [
+        ("init.svc.bootanim", "stopped"),
+        ("sys.boot_completed", "1"),
+    ] {
+        wait(prop, &mut || {
+            debug!("reading prop {}", prop);
+            let value = adb.shell_getprop(device, prop)?;
+            debug!(prop = %prop, value = %value);
+            if value != expected_value {
+                bail!("expected prop {} = {} but was {}", prop, expected_value, value);
+            }
+            Ok(())
+        })?;
+    }
+    let mut manufacturer = String::new();
+    if checks.iter().any(|check| check.manufacturer().is_some()) {
+        wait("ro.product.manufacturer", &mut || {
+            manufacturer = adb.shell_getprop(device, "ro.product.manufacturer")?;
+            if manufacturer.is_empty() {
+                bail!("prop ro.product.manufacturer isn't set");
+            }
+            Ok(())
+        })?;
+    }
+    let shell = |args: &[&str]| adb.shell(device, args);
+    for check in checks.iter().filter(|check| check.applies_to(&manufacturer)) {
+        wait(&check.to_string(), &mut || check_boot(&shell, check))?;
+    }
+
+    Ok(())
+}
+
+// Runs after the shell command to say how it went, adb shell only passes its exit status on with newer adb and devices.
+const EXIT_STATUS: &str = "adp-exit-status=";
+
+fn check_boot(shell: &dyn Fn(&[&str]) -> Result<String>, check: &BootCheck) -> Result<()> {
+    debug!(check = %check);
+    match check {
+        BootCheck::Prop { name, value, .. } => {
+            let actual = shell(&["getprop", name])?;
+            match value {
+                Some(value) if actual != *value => bail!("expected prop {} = {} but was {}", name, value, actual),
+                None if actual.is_empty() => bail!("prop {} isn't set", name),
+                _ => {}
+            }
+        }
+        BootCheck::Shell { command, .. } => {
+            let output = shell(&[&format!("{}; echo {}$?", command, EXIT_STATUS)])?;
+            let status = output.lines().last().and_then(|line| line.strip_prefix(EXIT_STATUS));
+            match status {
+                Some("0") => {}
+                Some(status) => bail!("`{}` exited with {}", command, status),
+                None => bail!("`{}` didn't finish: {:?}", command, output),
+            }
         }
+        BootCheck::Service { name, .. } => {
+            // ex: Service package: found
+            if !shell(&["service", "check", name])?.ends_with(": found") {
+                bail!("service {} isn't running", name);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_health_on(adb: &Adb, device: Target<'_>, serial: &Serial) -> Result<()> {
+    let output = adb.shell(device, &["echo", "ok"]).with_context(|| format!("{} failed health check", serial))?;
+    if output != "ok" {
+        return Err(anyhow!("{} failed health check, unexpected output: {}", serial, output));
+    }
+    Ok(())
+}
+
+// Runs f over the items on up to limit threads at once, returning what it gave for each one in the same order.
+fn in_parallel<T: Sync, U: Send>(items: &[T], limit: usize, f: impl Fn(&T) -> U + Sync) -> Vec<U> {
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, U)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..limit.min(items.len()))
+            .map(|_| scope.spawn(|| {
+                let mut results = Vec::new();
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(i) else {
+                        return results;
+                    };
+                    results.push((i, f(item)));
+                }
+            }))
+            .collect();
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
 
+impl Runtime for RealRuntime {
+    fn connected_devices(&self) -> Result<Vec<Serial>> {
+        let mut listed = Vec::new();
+        for (index, adb) in self.servers.iter().enumerate() {
+            let devices = adb.devices_long()
+                .with_context(|| format!("failed to list devices on {}", adb.server_socket().unwrap_or("adb")))?;
+            let server = adb.server_socket().map(String::from);
+            listed.extend(devices.into_iter().map(|device| (index, AdbDevice { server: server.clone(), ..device })));
+        }
+        let shared = shared_serials(listed.iter().map(|(_, device)| device));
+        for serial in shared.keys() {
+            if self.shared.borrow_mut().insert(serial.clone()) {
+                eprintln!(
+                    "adp: more than one device has the serial {}, they're pooled by where they're connected instead, \
+                    jobs on them have to use `adb -t $ADP_TRANSPORT_ID` as `adb -s` can't tell them apart",
+                    serial,
+                );
+            }
+        }
+        let mut devices = Vec::new();
+        let mut connections = BTreeMap::new();
+        for (index, device) in listed {
+            let serial = match pool_serial(&device, &shared) {
+                Ok(serial) => serial,
+                Err(e) => {
+                    if self.ignored.borrow_mut().insert(device.serial) {
+                        eprintln!("ignoring device: {:#}", e);
+                    }
+                    continue;
+                }
+            };
+            connections.insert(serial.clone(), Connection {
+                server: index,
+                shared: shared.contains_key(&device.serial),
+                transport_id: device.attributes.get("transport_id").cloned(),
+                serial: device.serial,
+            });
+            devices.push(serial);
+        }
+        debug!(connections = ?connections);
+        self.connections.replace(connections);
         Ok(devices)
     }
 
-    #[instrument]
     fn wait_for_boot(&self, serial: &Serial) -> Result<()> {
-        for (prop, expected_value) in [
-            ("init.svc.bootanim", "stopped"),
-            ("sys.boot_completed", "1"),
-        ] {
-            retry::<_, _, _, anyhow::Error, _>(
-                retry::delay::Fixed::from(Duration::from_secs(1)).take(60),
-                || {
-                    debug!("reading prop {}", prop);
-                    let value = self.adb.shell_getprop(serial, prop)?;
-                    debug!(prop = %prop, value = %value);
-                    if value != expected_value {
-                        Err(anyhow!(
-                            "expected prop {} = {} but was {}",
-                            prop,
-                            expected_value,
-                            value
-                        ))?;
-                    }
-                    Ok(())
-                },
-            )
-            .with_context(|| format!("timed out waiting for prop {}", prop))?;
+        self.wait_for_boot_with_progress(serial, &|_| {})
+    }
+
+    #[instrument(skip(progress))]
+    fn wait_for_boot_with_progress(&self, serial: &Serial, progress: &dyn Fn(&BootProgress)) -> Result<()> {
+        self.on_device(serial, |adb, device| wait_for_boot_on(adb, device, &self.boot_checks, progress))
+    }
+
+    #[instrument]
+    fn check_health(&self, serial: &Serial) -> Result<()> {
+        self.on_device(serial, |adb, device| check_health_on(adb, device, serial))
+    }
+
+    #[instrument]
+    fn wait_until_healthy(&self, serials: &[Serial]) -> Vec<Result<()>> {
+        // Look the connections up first, the runtime itself can't be shared between threads.
+        let connections: Vec<_> = serials.iter().map(|serial| self.connection(serial)).collect();
+        let (servers, checks) = (&self.servers, &self.boot_checks);
+        in_parallel(&connections.iter().zip(serials).collect::<Vec<_>>(), MAX_BOOT_WAITS, |(connection, serial)| {
+            let connection = connection.as_ref().map_err(|e| anyhow!("{:#}", e))?;
+            let adb = &servers[connection.server];
+            wait_for_boot_on(adb, connection.target(), checks, &|_| {})?;
+            check_health_on(adb, connection.target(), serial)
+        })
+    }
+
+    fn adb_devices(&self) -> Result<Vec<AdbDevice>> {
+        let mut devices = Vec::new();
+        for adb in &self.servers {
+            let server = adb.server_socket().map(String::from);
+            devices.extend(adb.devices_long()?.into_iter().map(|device| AdbDevice { server: server.clone(), ..device }));
         }
+        Ok(devices)
+    }
 
-        Ok(())
+    fn getprop(&self, serial: &Serial, name: &str) -> Result<String> {
+        self.on_device(serial, |adb, device| adb.shell_getprop(device, name))
+    }
+
+    fn battery(&self, serial: &Serial) -> Result<Battery> {
+        self.on_device(serial, |adb, device| adb.battery(device))
+    }
+
+    #[instrument]
+    fn run_adb(&self, serial: &Serial, args: &[&str]) -> Result<String> {
+        self.on_device(serial, |adb, device| adb.run(device, args))
+    }
+
+    #[instrument]
+    fn screencap(&self, serial: &Serial) -> Result<Vec<u8>> {
+        self.on_device(serial, |adb, device| adb.screencap(device))
+    }
+
+    fn server_socket(&self, serial: &Serial) -> Option<String> {
+        self.adb(serial).ok()?.server_socket().map(String::from)
+    }
+
+    // Listed again each time, it changes whenever the device reconnects.
+    fn transport_id(&self, serial: &Serial) -> Option<String> {
+        self.connected_devices().ok()?;
+        self.connections.borrow().get(serial)?.transport_id.clone()
+    }
+
+    fn adb_serial(&self, serial: &Serial) -> String {
+        self.connection(serial).map_or_else(|_| serial.to_string(), |connection| connection.serial)
     }
 
     fn is_running(&self, pid: Pid) -> Result<bool> {
         // There doesn't seem to be a way to tell if this failed?
         Ok(self.sys.borrow_mut().refresh_process(pid))
     }
+
+    #[instrument]
+    fn running(&self, pids: &[Pid]) -> Result<BTreeSet<Pid>> {
+        let pids: BTreeSet<Pid> = pids.iter().copied().collect();
+        let mut sys = self.sys.borrow_mut();
+        if pids.len() <= REFRESH_ALL_THRESHOLD {
+            return Ok(pids.into_iter().filter(|pid| sys.refresh_process(*pid)).collect());
+        }
+        sys.refresh_processes();
+        Ok(pids.into_iter().filter(|pid| sys.process(*pid).is_some()).collect())
+    }
+
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn random(&self) -> u64 {
+        // RandomState is seeded randomly for each process, and differently for each instance within one.
+        RandomState::new().hash_one(SystemTime::now())
+    }
 }
 
 // Wrapper to not have to unwrap internal error
 // https://github.com/jimmycuadra/retry/issues/38
-fn retry<I, O, R, E, OR>(iterable: I, mut operation: O) -> std::result::Result<R, E>
+pub(crate) fn retry<I, O, R, E, OR>(iterable: I, mut operation: O) -> std::result::Result<R, E>
 where
     I: IntoIterator<Item = Duration>,
     O: FnMut() -> OR,
@@ -100,3 +559,124 @@ where
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::adb::AdbDevice;
+    use crate::config::BootCheck;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use crate::runtime::{check_boot, in_parallel, pool_serial, Serial, server_host, shared_serials};
+
+    #[test]
+    fn runs_a_bounded_number_at_once() {
+        let running = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        let items: Vec<u64> = (0..10).collect();
+
+        let results = in_parallel(&items, 3, |i| {
+            most.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(10 - i));
+            running.fetch_sub(1, Ordering::SeqCst);
+            i * 2
+        });
+
+        assert_eq!(results, (0..10).map(|i| i * 2).collect::<Vec<_>>());
+        assert!((2..=3).contains(&most.load(Ordering::SeqCst)), "{:?}", most);
+    }
+
+    #[test]
+    fn rejects_invalid_serials() {
+        assert!(Serial::new("emulator-5554").is_ok());
+        assert!(Serial::new("").is_err());
+        assert!(Serial::new("R58M 123").is_err());
+        assert!(Serial::new("192.168.1.5:5555").is_ok());
+    }
+
+    #[test]
+    fn recognizes_emulators() {
+        assert!(Serial::new("emulator-5554").unwrap().is_emulator());
+        assert!(!Serial::new("emulator-x").unwrap().is_emulator());
+        assert!(!Serial::new("R58M123ABC").unwrap().is_emulator());
+    }
+
+    #[test]
+    fn pools_shared_serials_by_where_theyre_connected() {
+        let device = |serial: &str, usb: Option<&str>, transport_id: &str, server: Option<&str>| AdbDevice {
+            serial: serial.to_string(),
+            state: "device".to_string(),
+            attributes: usb.map(|usb| ("usb".to_string(), usb.to_string())).into_iter()
+                .chain([("transport_id".to_string(), transport_id.to_string())])
+                .collect(),
+            server: server.map(String::from),
+        };
+        let devices = [
+            device("0123456789ABCDEF", Some("1-1"), "1", None),
+            device("R58M123ABC", Some("1-2"), "2", None),
+            device("0123456789ABCDEF", Some("1-3"), "3", None),
+            device("0123456789ABCDEF", None, "4", Some("tcp:bench2:5037")),
+            device("emulator-5554", None, "5", Some("tcp:bench1:5037")),
+            device("emulator-5554", None, "1", Some("tcp:bench2:5037")),
+        ];
+        let shared = shared_serials(&devices);
+
+        assert_eq!(shared, BTreeMap::from([
+            ("0123456789ABCDEF".to_string(), true),
+            ("emulator-5554".to_string(), false),
+        ]));
+        let serials: Vec<String> = devices.iter().map(|device| pool_serial(device, &shared).unwrap().to_string()).collect();
+        assert_eq!(serials, [
+            "0123456789ABCDEF@1-1",
+            "R58M123ABC",
+            "0123456789ABCDEF@1-3",
+            "0123456789ABCDEF@bench2:5037/4",
+            "emulator-5554@bench1:5037",
+            "emulator-5554@bench2:5037",
+        ]);
+        // Reconnecting gives it a new transport id, but not a new place in the pool.
+        let reconnected = device("0123456789ABCDEF", Some("1-1"), "9", None);
+        assert_eq!(pool_serial(&reconnected, &shared).unwrap(), "0123456789ABCDEF@1-1");
+    }
+
+    #[test]
+    fn finds_the_host_of_remote_adb_servers() {
+        assert_eq!(server_host("tcp:bench2:5037"), Some("bench2"));
+        assert_eq!(server_host("tcp:[fd00::2]:5037"), Some("fd00::2"));
+        assert_eq!(server_host("tcp:localhost:5038"), None);
+        assert_eq!(server_host("tcp:5038"), None);
+    }
+
+    #[test]
+    fn checks_boot() {
+        let shell = |args: &[&str]| Ok(match args {
+            ["getprop", "sys.launcher.ready"] => "1".to_string(),
+            ["getprop", _] => String::new(),
+            ["service", "check", "package"] => "Service package: found".to_string(),
+            ["service", "check", service] => format!("Service {}: not found", service),
+            [command] if command.starts_with("pm path") => "package:/app/Launcher.apk\nadp-exit-status=0".to_string(),
+            // adb without the shell protocol exits 0 whatever the command did.
+            [_] => "adp-exit-status=1".to_string(),
+            _ => unreachable!(),
+        });
+        let prop = |name: &str, value: Option<&str>| BootCheck::Prop {
+            name: name.to_string(),
+            value: value.map(String::from),
+            manufacturer: None,
+        };
+        let command = |command: &str| BootCheck::Shell { command: command.to_string(), manufacturer: None };
+        let service = |name: &str| BootCheck::Service { name: name.to_string(), manufacturer: None };
+
+        assert!(check_boot(&shell, &prop("sys.launcher.ready", Some("1"))).is_ok());
+        assert!(check_boot(&shell, &prop("sys.launcher.ready", None)).is_ok());
+        assert!(check_boot(&shell, &prop("sys.launcher.ready", Some("2"))).is_err());
+        assert!(check_boot(&shell, &prop("sys.other", None)).is_err());
+        assert!(check_boot(&shell, &command("pm path com.sec.android.app.launcher")).is_ok());
+        let error = check_boot(&shell, &command("false")).unwrap_err();
+        assert_eq!(error.to_string(), "`false` exited with 1");
+        assert!(check_boot(&shell, &service("package")).is_ok());
+        assert!(check_boot(&shell, &service("window")).is_err());
+    }
+}
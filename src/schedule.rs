@@ -0,0 +1,181 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use serde::Deserialize;
+
+// When to do something, written like a crontab line, minute hour day-of-month month day-of-week, ex: "0 3 * * *" for
+// 3am every day. Fields take *, numbers, ranges like 1-5, steps like */15 and lists of those like 0,30. Times are UTC.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Schedule {
+    line: String,
+    // Bit n is set if n matches.
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Like cron, when both days and weekdays are restricted either one matching is enough.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    pub fn matches(&self, time: SystemTime) -> bool {
+        let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let days = secs / 86400;
+        let (month, day) = month_day(days);
+        // 1970-01-01 was a Thursday.
+        let weekday = (days + 4) % 7;
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (false, true) => has(self.days, day),
+            (true, false) => has(self.weekdays, weekday),
+            (false, false) => has(self.days, day) || has(self.weekdays, weekday),
+        };
+        has(self.minutes, secs / 60 % 60) && has(self.hours, secs / 3600 % 24) && has(self.months, month) && day_matches
+    }
+
+    // Whether the schedule matches any minute that started after `since`, up to and including `now`.
+    pub fn due(&self, since: SystemTime, now: SystemTime) -> bool {
+        let minute = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 60;
+        // A year is plenty to find a match in, if there's one at all.
+        (minute(since) + 1..=minute(now)).take(366 * 24 * 60)
+            .any(|minute| self.matches(UNIX_EPOCH + std::time::Duration::from_secs(minute * 60)))
+    }
+}
+
+fn has(bits: u64, n: u64) -> bool {
+    bits & (1 << n) != 0
+}
+
+// The month and day of month of days since the epoch, from http://howardhinnant.github.io/date_algorithms.html
+fn month_day(days: u64) -> (u64, u64) {
+    let doe = (days + 719468) % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (month, day)
+}
+
+// Bits set for each value the field matches, and whether it's *.
+fn parse_field(field: &str, name: &str, min: u64, max: u64) -> anyhow::Result<(u64, bool)> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u64 = step.parse().ok().filter(|step| *step > 0)
+                    .ok_or_else(|| anyhow!("invalid {} {:?}, the step has to be a number above 0", name, part))?;
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let number = |s: &str| s.parse::<u64>().ok().filter(|n| (min..=max).contains(n))
+            .ok_or_else(|| anyhow!("invalid {} {:?}, expected numbers from {} to {}", name, part, min, max));
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (number(start)?, number(end)?),
+                // ex: 5/15, from 5 on
+                None if step > 1 => (number(range)?, max),
+                None => (number(range)?, number(range)?),
+            },
+        };
+        if start > end {
+            return Err(anyhow!("invalid {} {:?}, the range is backwards", name, part));
+        }
+        for n in (start..=end).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok((bits, field == "*"))
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_ascii_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(anyhow!("invalid schedule {:?}, expected 5 fields like a crontab, ex: \"0 3 * * *\"", s));
+        };
+        let (minutes, _) = parse_field(minutes, "minute", 0, 59)?;
+        let (hours, _) = parse_field(hours, "hour", 0, 23)?;
+        let (days, any_day) = parse_field(days, "day of month", 1, 31)?;
+        let (months, _) = parse_field(months, "month", 1, 12)?;
+        let (mut weekdays, any_weekday) = parse_field(weekdays, "day of week", 0, 7)?;
+        // Sunday is 0 or 7.
+        if has(weekdays, 7) {
+            weekdays |= 1;
+        }
+        Ok(Schedule { line: fields.join(" "), minutes, hours, days, months, weekdays, any_day, any_weekday })
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use crate::schedule::Schedule;
+
+    // 2024-03-01 is a Friday.
+    fn at(day: u64, hour: u64, minute: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(19783 * 86400 + (day - 1) * 86400 + hour * 3600 + minute * 60)
+    }
+
+    #[test]
+    fn matches_like_cron() {
+        let nightly: Schedule = "0 3 * * *".parse().unwrap();
+        assert!(nightly.matches(at(1, 3, 0)));
+        assert!(!nightly.matches(at(1, 3, 1)));
+        assert!(!nightly.matches(at(1, 4, 0)));
+
+        let weekdays: Schedule = "*/15 9-17 * * 1-5".parse().unwrap();
+        assert!(weekdays.matches(at(1, 9, 45)));
+        assert!(!weekdays.matches(at(1, 9, 50)));
+        assert!(!weekdays.matches(at(2, 9, 45)), "saturday");
+
+        let sundays: Schedule = "30 2 1 * 7".parse().unwrap();
+        assert!(sundays.matches(at(3, 2, 30)), "sunday");
+        assert!(sundays.matches(at(1, 2, 30)), "the 1st");
+        assert!(!sundays.matches(at(2, 2, 30)));
+    }
+
+    #[test]
+    fn is_due_once_a_matching_minute_has_passed() {
+        let nightly: Schedule = "0 3 * * *".parse().unwrap();
+
+        assert!(!nightly.due(at(1, 2, 0), at(1, 2, 59)));
+        assert!(nightly.due(at(1, 2, 59), at(1, 3, 0)));
+        assert!(!nightly.due(at(1, 3, 0), at(1, 3, 5)));
+        assert!(nightly.due(at(1, 3, 0), at(2, 3, 5)));
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        let error = "0 3 * *".parse::<Schedule>().unwrap_err().to_string();
+        assert!(error.contains("expected 5 fields"), "{}", error);
+        let error = "0 24 * * *".parse::<Schedule>().unwrap_err().to_string();
+        assert!(error.contains("invalid hour \"24\""), "{}", error);
+        assert!("*/0 * * * *".parse::<Schedule>().is_err());
+        assert!("5-1 * * * *".parse::<Schedule>().is_err());
+    }
+}
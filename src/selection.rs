@@ -0,0 +1,26 @@
+use std::fmt::Debug;
+
+use crate::device_info::DeviceInfo;
+use crate::lockfile::{Entry, LockFileEntries};
+use crate::runtime::Serial;
+
+// Picks which of the free devices a job gets, for rules like preferring devices that match the API level a test
+// targets. Without one, devices that don't need a health check and have been idle the longest go first.
+pub trait SelectionPolicy: Debug {
+    // Candidates are the devices that are online and free right now, None leaves the job waiting.
+    fn choose(&self, candidates: &[DeviceInfo], history: &UsageHistory) -> Option<Serial>;
+}
+
+// How each device in the pool has been used, ex: when it was last released and how many jobs failed on it in a row.
+#[derive(Debug)]
+pub struct UsageHistory<'a>(&'a LockFileEntries);
+
+impl UsageHistory<'_> {
+    pub fn new(entries: &LockFileEntries) -> UsageHistory<'_> {
+        UsageHistory(entries)
+    }
+
+    pub fn get(&self, serial: &Serial) -> Option<&Entry> {
+        self.0.get(serial)
+    }
+}
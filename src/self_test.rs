@@ -0,0 +1,254 @@
+// Runs acquire/release storms from many processes against fake devices, using this host's real file locks and lock
+// file, and checks the pool never hands one device to two of them or loses track of one. Unlike the simulation tests
+// it runs on the machine itself, ex: to check a new bench or an OS upgrade before putting jobs on it.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::hash_map::RandomState;
+use std::fs::OpenOptions;
+use std::hash::BuildHasher;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, bail};
+use sysinfo::{System, SystemExt};
+use tracing::instrument;
+
+use crate::App;
+use crate::adb::{AdbDevice, Battery};
+use crate::runtime::{Pid, Runtime, Serial};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// The hidden command each worker process runs.
+pub const WORKER_COMMAND: &str = "self-test-worker";
+// Each worker puts a file for the device it's holding in here, which fails if another one already has it.
+const HELD_DIR: &str = "held";
+// Longest each worker holds a device for.
+const HOLD: Duration = Duration::from_millis(5);
+
+// Devices that are always connected and healthy, only their serials matter. Processes are real ones.
+#[derive(Debug)]
+pub struct SelfTestRuntime {
+    devices: Vec<Serial>,
+    sys: RefCell<System>,
+}
+
+impl SelfTestRuntime {
+    pub fn new(devices: usize) -> SelfTestRuntime {
+        SelfTestRuntime {
+            devices: (1..=devices).map(|i| Serial::new(format!("self-test-{}", i)).unwrap()).collect(),
+            sys: RefCell::new(System::new()),
+        }
+    }
+}
+
+impl Runtime for SelfTestRuntime {
+    fn connected_devices(&self) -> crate::runtime::Result<Vec<Serial>> {
+        Ok(self.devices.clone())
+    }
+
+    fn wait_for_boot(&self, _serial: &Serial) -> crate::runtime::Result<()> {
+        Ok(())
+    }
+
+    fn check_health(&self, _serial: &Serial) -> crate::runtime::Result<()> {
+        Ok(())
+    }
+
+    fn adb_devices(&self) -> crate::runtime::Result<Vec<AdbDevice>> {
+        Ok(self.devices.iter()
+            .map(|serial| AdbDevice {
+                serial: serial.to_string(),
+                state: "device".to_string(),
+                attributes: BTreeMap::new(),
+                server: None,
+            })
+            .collect())
+    }
+
+    fn getprop(&self, _serial: &Serial, _name: &str) -> crate::runtime::Result<String> {
+        Ok(String::new())
+    }
+
+    fn battery(&self, _serial: &Serial) -> crate::runtime::Result<Battery> {
+        Ok(Battery::default())
+    }
+
+    fn run_adb(&self, _serial: &Serial, _args: &[&str]) -> crate::runtime::Result<String> {
+        Ok(String::new())
+    }
+
+    fn screencap(&self, _serial: &Serial) -> crate::runtime::Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    fn server_socket(&self, _serial: &Serial) -> Option<String> {
+        None
+    }
+
+    fn transport_id(&self, _serial: &Serial) -> Option<String> {
+        None
+    }
+
+    fn adb_serial(&self, serial: &Serial) -> String {
+        serial.to_string()
+    }
+
+    fn is_running(&self, pid: Pid) -> crate::runtime::Result<bool> {
+        Ok(self.sys.borrow_mut().refresh_process(pid))
+    }
+
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn random(&self) -> u64 {
+        RandomState::new().hash_one(SystemTime::now())
+    }
+}
+
+// Starts the workers against a pool of its own in the runtime dir, so the real one isn't touched, and checks on it
+// once they're done. Half of them die holding a device on their last round, like a crashed job, for the others to
+// take it back.
+#[instrument]
+pub fn run(runtime_dir: &Path, devices: usize, workers: usize, rounds: usize) -> Result {
+    if devices == 0 || workers == 0 {
+        bail!("the self-test needs at least one device and one worker");
+    }
+    let dir = runtime_dir.join(format!("self-test-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join(HELD_DIR))?;
+    let exe = std::env::current_exe()?;
+    let start = Instant::now();
+    let children = (0..workers)
+        .map(|i| {
+            let mut command = Command::new(&exe);
+            command.arg(WORKER_COMMAND).arg(&dir)
+                .args(["--devices", &devices.to_string(), "--rounds", &rounds.to_string()])
+                .args(["--hold-ms", &HOLD.as_millis().to_string()])
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped());
+            if i % 2 == 1 {
+                command.arg("--abandon");
+            }
+            command.spawn()
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let mut problems = Vec::new();
+    for (i, child) in children.into_iter().enumerate() {
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            problems.push(format!("worker {} failed ({}): {}", i, output.status, String::from_utf8_lossy(&output.stderr).trim()));
+        }
+    }
+    problems.extend(check(&dir, devices)?);
+    if !problems.is_empty() {
+        return Err(anyhow!("self-test failed, left {:?} to look into:\n{}", dir, problems.join("\n")));
+    }
+    std::fs::remove_dir_all(&dir)?;
+    println!(
+        "self-test passed: {} workers claimed {} devices {} times in {:.1}s",
+        workers, devices, workers * rounds, start.elapsed().as_secs_f64(),
+    );
+    Ok(())
+}
+
+// Claims a device, checks it's the only one holding it for up to hold and releases it again, over and over. With
+// abandon it exits holding the device on the last round. Prints how long it spent claiming devices, for the benchmarks.
+#[instrument]
+pub fn worker(dir: &Path, devices: usize, rounds: usize, hold: Duration, abandon: bool) -> Result {
+    let app = App::new(SelfTestRuntime::new(devices), dir);
+    let pid = std::process::id() as Pid;
+    let mut acquiring = Duration::ZERO;
+    for round in 0..rounds {
+        let start = Instant::now();
+        let resource = app.acquire_resource(pid)?;
+        acquiring += start.elapsed();
+        check_held(&app, &resource.serial, pid, dir, hold)?;
+        if abandon && round + 1 == rounds {
+            std::process::exit(0);
+        }
+        resource.release()?;
+    }
+    println!("{}", acquiring.as_nanos());
+    Ok(())
+}
+
+fn check_held(app: &App<SelfTestRuntime>, serial: &Serial, pid: Pid, dir: &Path, hold: Duration) -> Result {
+    if !app.holds(serial, pid)? {
+        bail!("{} was handed to pid {} but the lock file doesn't have it down as claimed by it", serial, pid);
+    }
+    let held = dir.join(HELD_DIR).join(serial.as_str());
+    match OpenOptions::new().write(true).create_new(true).open(&held) {
+        Ok(mut file) => write!(file, "{}", pid)?,
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            let other = std::fs::read_to_string(&held).unwrap_or_default();
+            bail!("{} was handed to pid {} while pid {} still had it", serial, pid, other);
+        }
+        Err(e) => return Err(e.into()),
+    }
+    // Long enough for others to come looking for a device in the meantime.
+    if !hold.is_zero() {
+        std::thread::sleep(Duration::from_nanos(app.random() % hold.as_nanos() as u64));
+    }
+    std::fs::remove_file(&held)?;
+    Ok(())
+}
+
+// However the workers went, the pool should end up with every device in it and none of them claimed.
+fn check(dir: &Path, devices: usize) -> Result<Vec<String>> {
+    let app = App::new(SelfTestRuntime::new(devices), dir);
+    let entries = app.reconcile()?.entries;
+    let mut problems = Vec::new();
+    for serial in app.connected_devices()? {
+        match entries.get(&serial) {
+            None => problems.push(format!("{} is missing from the pool", serial)),
+            Some(entry) if entry.pid.is_some() => {
+                problems.push(format!("{} is still claimed by pid {}", serial, entry.pid.unwrap()));
+            }
+            Some(_) => {}
+        }
+    }
+    for held in std::fs::read_dir(dir.join(HELD_DIR))? {
+        problems.push(format!("{} was never let go of", held?.file_name().to_string_lossy()));
+    }
+    if dir.join("adp.lock.intent").exists() {
+        problems.push("a write to the lock file was left unfinished".to_string());
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use temp_testdir::TempDir;
+
+    use crate::self_test::{check, worker, HELD_DIR};
+
+    #[test]
+    fn leaves_a_clean_pool() -> anyhow::Result<()> {
+        let dir = TempDir::default();
+        std::fs::create_dir_all(dir.join(HELD_DIR))?;
+        worker(&dir, 2, 5, Duration::ZERO, false)?;
+
+        assert_eq!(check(&dir, 2)?, Vec::<String>::new());
+
+        Ok(())
+    }
+
+    #[test]
+    fn catches_a_device_handed_out_twice() -> anyhow::Result<()> {
+        let dir = TempDir::default();
+        std::fs::create_dir_all(dir.join(HELD_DIR))?;
+        std::fs::write(dir.join(HELD_DIR).join("self-test-1"), "1")?;
+
+        let error = worker(&dir, 1, 1, Duration::ZERO, false).unwrap_err().to_string();
+        assert!(error.contains("self-test-1 was handed to pid"), "{}", error);
+        assert!(error.contains("while pid 1 still had it"), "{}", error);
+
+        Ok(())
+    }
+}
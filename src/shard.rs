@@ -0,0 +1,384 @@
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context};
+use clap::CommandFactory;
+use tracing::{debug, instrument};
+
+use crate::App;
+use crate::cli::Cli;
+use crate::events::{EventLog, RebalanceRecord};
+use crate::exitstatus::ExitStatusExt;
+use crate::runtime::{Pid, Runtime, Serial};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+pub const COMMAND: &str = "shard";
+// Replaced in the command with the shard's tests.
+const PLACEHOLDER: &str = "{tests}";
+// Set on each shard, for scripts that also need to know which one they are, ex: to name their reports.
+const INDEX_VAR: &str = "ADP_SHARD_INDEX";
+const COUNT_VAR: &str = "ADP_SHARD_COUNT";
+// How often to check on the shards.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// Tests whose devices keep dropping out are probably what's taking them down, so they fail after this many.
+const MAX_DROPS: usize = 2;
+
+// Splits the tests in the file across as many shards as there are devices free, or as it's told, and runs the command
+// for each under its own adp with the shard's tests in place of {tests}. Shards beyond the free devices wait for one
+// like any other job. A shard whose device drops out mid-run has its tests dealt out again to new shards rather than
+// failing the run. Fails with the exit code of the first shard that failed.
+#[instrument(skip(app, events))]
+pub fn run<R: Runtime + Debug>(
+    app: &App<R>,
+    events: &EventLog,
+    tests: &Path,
+    shards: Option<usize>,
+    separator: &str,
+    command: &[OsString],
+) -> Result {
+    let tests = read_tests(tests)?;
+    if !command.iter().any(|arg| arg.to_string_lossy().contains(PLACEHOLDER)) {
+        return Err(anyhow!("the command needs {} in it for where each shard's tests go", PLACEHOLDER));
+    }
+    let shards = match shards {
+        Some(shards) => shards,
+        None => {
+            app.reconcile()?;
+            app.available()?
+        }
+    };
+    let mut queue = WorkQueue::new(split(&tests, shards));
+    // At most as many shards at once as it started with, no more devices are going to be free for the new ones.
+    let slots = queue.len();
+    eprintln!("adp: running {} tests in {} shards", tests.len(), slots);
+
+    let exe = std::env::current_exe()?;
+    let adp_args = adp_args(&std::env::args_os().skip(1).collect::<Vec<_>>());
+    let mut running = Running::default();
+    let mut failed = None;
+    loop {
+        while running.0.len() < slots {
+            let Some(work) = queue.next() else { break };
+            let mut shard = Command::new(&exe);
+            // Counting the shards dealt out again so far, for every index to be below it.
+            shard.args(&adp_args).args(expand(command, &work.tests, separator))
+                .env(INDEX_VAR, work.shard.to_string())
+                .env(COUNT_VAR, queue.shards().to_string());
+            debug!(work = ?work, shard = ?shard);
+            let spawned_at = secs(app.now());
+            let child = shard.spawn()?;
+            running.0.push(Shard { work, child, spawned_at });
+        }
+        if running.0.is_empty() {
+            break;
+        }
+        let Some(finished) = running.finished()? else {
+            std::thread::sleep(POLL_INTERVAL);
+            continue;
+        };
+        let Shard { work, mut child, spawned_at } = running.0.remove(finished);
+        let status = child.wait()?;
+        if status.success() {
+            continue;
+        }
+        let pid = child.id() as Pid;
+        match dropped_device(app, events, pid, spawned_at)? {
+            Some(serial) if work.drops < MAX_DROPS => {
+                let (shard, tests) = (work.shard, work.tests.len());
+                let record = RebalanceRecord {
+                    rebalanced_from: serial,
+                    pid,
+                    shard,
+                    tests,
+                    into: queue.rebalance(work, slots),
+                    at: secs(app.now()),
+                };
+                eprintln!(
+                    "adp: {} dropped out of shard {}, dealt its {} tests out again to shards {}",
+                    record.rebalanced_from, record.shard, record.tests,
+                    record.into.iter().map(|shard| shard.to_string()).collect::<Vec<_>>().join(", "),
+                );
+                if let Err(e) = events.append(&record) {
+                    eprintln!("adp: failed to record the rebalance in the event log: {:#}", e);
+                }
+            }
+            _ => {
+                eprintln!("adp: shard {} failed with {}", work.shard, status);
+                failed.get_or_insert(status);
+            }
+        }
+    }
+    if let Some(status) = failed {
+        status.exit_ok_()?;
+    }
+    Ok(())
+}
+
+// The device the shard's adp ran on, if it's no longer connected. Its adp records the lease under its pid, only leases
+// since the shard was spawned are its own, an older one is from another process that had the pid before.
+pub fn dropped_device<R: Runtime + Debug>(
+    app: &App<R>,
+    events: &EventLog,
+    pid: Pid,
+    spawned_at: u64,
+) -> Result<Option<Serial>> {
+    let leases = events.leases()?;
+    let Some(lease) = leases.into_iter().rev().find(|lease| lease.pid == pid && lease.started_at >= spawned_at) else {
+        return Ok(None);
+    };
+    if app.connected_devices()?.contains(&lease.serial) {
+        return Ok(None);
+    }
+    Ok(Some(lease.serial))
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[derive(Debug)]
+struct Shard {
+    work: Work,
+    child: Child,
+    // seconds since the epoch
+    spawned_at: u64,
+}
+
+// The shards running. If adp gives up on the run they're killed rather than left to carry on unattended.
+#[derive(Debug, Default)]
+struct Running(Vec<Shard>);
+
+impl Running {
+    // The first shard that's exited, if any.
+    fn finished(&mut self) -> Result<Option<usize>> {
+        for (i, shard) in self.0.iter_mut().enumerate() {
+            let exited = shard.child.try_wait()
+                .with_context(|| format!("failed to check on shard {}", shard.work.shard))?;
+            if exited.is_some() {
+                return Ok(Some(i));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        for shard in &mut self.0 {
+            if let Err(e) = shard.child.kill().and_then(|_| shard.child.wait()) {
+                eprintln!("adp: failed to stop shard {}: {}", shard.work.shard, e);
+            }
+        }
+    }
+}
+
+// Some of the tests, run by one adp.
+#[derive(Debug, Clone, PartialEq)]
+struct Work {
+    shard: usize,
+    tests: Vec<String>,
+    // How many devices have dropped out from under these tests.
+    drops: usize,
+}
+
+// The shards waiting on a free slot, in order. adp can't tell how far a shard got before its device dropped out, so
+// the whole of it is run again.
+#[derive(Debug)]
+struct WorkQueue {
+    pending: VecDeque<Work>,
+    // What the next new shard is numbered.
+    next_shard: usize,
+}
+
+impl WorkQueue {
+    fn new(split: Vec<Vec<String>>) -> WorkQueue {
+        let pending: VecDeque<Work> = split.into_iter().enumerate()
+            .map(|(shard, tests)| Work { shard, tests, drops: 0 })
+            .collect();
+        WorkQueue { next_shard: pending.len(), pending }
+    }
+
+    fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    // How many shards have been numbered, including the ones dealt out again.
+    fn shards(&self) -> usize {
+        self.next_shard
+    }
+
+    fn next(&mut self) -> Option<Work> {
+        self.pending.pop_front()
+    }
+
+    // Deals the tests out again in up to `ways` new shards, ahead of those that haven't started, so they don't end up
+    // the last to finish. Returns the new shards.
+    fn rebalance(&mut self, work: Work, ways: usize) -> Vec<usize> {
+        let split: Vec<Work> = split(&work.tests, ways).into_iter()
+            .map(|tests| {
+                self.next_shard += 1;
+                Work { shard: self.next_shard - 1, tests, drops: work.drops + 1 }
+            })
+            .collect();
+        let shards = split.iter().map(|work| work.shard).collect();
+        for work in split.into_iter().rev() {
+            self.pending.push_front(work);
+        }
+        shards
+    }
+}
+
+// One test per line, ex: a class or class#method for the instrumentation runner. Blank lines and # comments are
+// skipped.
+fn read_tests(path: &Path) -> Result<Vec<String>> {
+    let tests: Vec<String> = std::fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+    if tests.is_empty() {
+        return Err(anyhow!("there are no tests in {:?}", path));
+    }
+    Ok(tests)
+}
+
+// Dealt out in turn so tests listed together, ex: slow ones, end up spread across the shards. Never more shards than
+// tests, and at least one to wait for a device if none are free.
+fn split(tests: &[String], shards: usize) -> Vec<Vec<String>> {
+    let shards = shards.clamp(1, tests.len());
+    let mut split = vec![Vec::new(); shards];
+    for (i, test) in tests.iter().enumerate() {
+        split[i % shards].push(test.clone());
+    }
+    split
+}
+
+fn expand(command: &[OsString], tests: &[String], separator: &str) -> Vec<OsString> {
+    let tests = tests.join(separator);
+    command.iter()
+        .map(|arg| match arg.to_str() {
+            Some(arg) => arg.replace(PLACEHOLDER, &tests).into(),
+            None => arg.clone(),
+        })
+        .collect()
+}
+
+// adp's own options from its command line, for each shard's adp to run with the same ones. Those are the ones before
+// `shard`, and any after it that aren't shard's, up to the `--` before the command.
+fn adp_args(args: &[OsString]) -> Vec<OsString> {
+    let cli = Cli::command();
+    let shard = cli.find_subcommand(COMMAND).expect("shard is a subcommand");
+    let mut adp_args = Vec::new();
+    let mut in_shard = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if !in_shard && arg == COMMAND {
+            in_shard = true;
+            continue;
+        }
+        let (name, inline) = match arg.to_str().and_then(|arg| arg.strip_prefix("--")) {
+            Some(option) => match option.split_once('=') {
+                Some((name, _)) => (Some(name), true),
+                None => (Some(option), false),
+            },
+            None => (None, false),
+        };
+        let find = |command: &clap::Command| {
+            command.get_arguments().find(|argument| name.is_some() && argument.get_long() == name).cloned()
+        };
+        let own = in_shard && find(shard).is_some();
+        let takes_value = !inline
+            && find(shard).or_else(|| find(&cli)).is_some_and(|argument| argument.get_action().takes_values());
+        let value = if takes_value { args.next() } else { None };
+        if !own {
+            adp_args.extend([Some(arg), value].into_iter().flatten().cloned());
+        }
+    }
+    adp_args
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+
+    use temp_testdir::TempDir;
+
+    use crate::shard::{adp_args, expand, read_tests, split, Work, WorkQueue};
+
+    type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+    fn strings(strings: &[&str]) -> Vec<String> {
+        strings.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn os_strings(strings: &[&str]) -> Vec<OsString> {
+        strings.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn reads_one_test_per_line() -> Result {
+        let dir = TempDir::default();
+        let path = dir.join("tests.txt");
+        std::fs::write(&path, "# smoke\ncom.example.LoginTest\n\n  com.example.CartTest#addsItem  \n")?;
+
+        assert_eq!(read_tests(&path)?, strings(&["com.example.LoginTest", "com.example.CartTest#addsItem"]));
+
+        std::fs::write(&path, "# nothing yet\n")?;
+        assert!(read_tests(&path).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn deals_tests_out_across_shards() {
+        let tests = strings(&["a", "b", "c", "d", "e"]);
+
+        assert_eq!(split(&tests, 2), vec![strings(&["a", "c", "e"]), strings(&["b", "d"])]);
+        assert_eq!(split(&tests, 0), vec![tests.clone()]);
+        assert_eq!(split(&tests[..2], 4), vec![strings(&["a"]), strings(&["b"])]);
+    }
+
+    #[test]
+    fn deals_a_dropped_shards_tests_out_again_first() {
+        let mut queue = WorkQueue::new(vec![strings(&["a", "d"]), strings(&["b", "e"]), strings(&["c"])]);
+        let dropped = queue.next().unwrap();
+        assert_eq!(queue.next().unwrap().shard, 1);
+
+        assert_eq!(queue.rebalance(dropped, 3), vec![3, 4]);
+        assert_eq!(queue.shards(), 5);
+
+        assert_eq!(queue.next(), Some(Work { shard: 3, tests: strings(&["a"]), drops: 1 }));
+        assert_eq!(queue.next(), Some(Work { shard: 4, tests: strings(&["d"]), drops: 1 }));
+        assert_eq!(queue.next().unwrap().shard, 2);
+        assert_eq!(queue.next(), None);
+    }
+
+    #[test]
+    fn substitutes_the_shards_tests() {
+        let command = os_strings(&["./gradlew", "connectedCheck", "-Pandroid.testInstrumentationRunnerArguments.class={tests}"]);
+
+        assert_eq!(
+            expand(&command, &strings(&["a.LoginTest", "a.CartTest"]), ","),
+            os_strings(&["./gradlew", "connectedCheck", "-Pandroid.testInstrumentationRunnerArguments.class=a.LoginTest,a.CartTest"]),
+        );
+    }
+
+    #[test]
+    fn passes_adps_own_options_on_to_each_shard() {
+        let args = os_strings(&[
+            "--group", "shard", "--prefix", "shard", "--tests", "tests.txt", "--config=adp.toml", "--shards", "2",
+            "--log-dir", "logs", "--", "./gradlew", "--group", "x",
+        ]);
+
+        assert_eq!(adp_args(&args), os_strings(&["--group", "shard", "--prefix", "--config=adp.toml", "--log-dir", "logs"]));
+    }
+}
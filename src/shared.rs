@@ -0,0 +1,34 @@
+use std::time::Duration;
+
+use sysinfo::{System, SystemExt};
+
+use crate::config::SharedConfig;
+use crate::lockfile::Entry;
+
+// Settings for a pool whose state is shared with other hosts.
+#[derive(Debug, Clone)]
+pub struct Shared {
+    // Claims are only checked and cleaned up by the host that made them, pids mean nothing anywhere else.
+    pub host: String,
+    pub poll_interval: Duration,
+    pub lock_timeout: Duration,
+}
+
+impl Shared {
+    pub fn new(config: &SharedConfig) -> Shared {
+        Shared {
+            host: System::new().host_name().unwrap_or_default(),
+            poll_interval: config.poll_interval.0,
+            lock_timeout: config.lock_timeout.0,
+        }
+    }
+
+    pub fn is_local(&self, entry: &Entry) -> bool {
+        entry.owner.as_ref().is_none_or(|owner| owner.host == self.host)
+    }
+}
+
+// Tells apart claims from different hosts that happen to have the same pid.
+pub fn token(nonce: u64) -> String {
+    format!("{:016x}", nonce)
+}
@@ -0,0 +1,227 @@
+// Runs many interleaved acquires, releases, crashes and reconciles against a fully faked runtime and checks the pool
+// never hands one device to two processes or loses track of one.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use temp_testdir::TempDir;
+
+use crate::{App, Resource};
+use crate::adb::{AdbDevice, Battery};
+use crate::lockfile::Owner;
+use crate::runtime::{Pid, Runtime, Serial};
+use crate::shared::Shared;
+use crate::store::FileStore;
+
+type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+const DEVICES: usize = 4;
+const PROCESSES: usize = 10;
+const STEPS: usize = 500;
+
+// xorshift64*, good enough to pick what happens next and reproducible from a seed.
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+#[derive(Debug)]
+struct World {
+    devices: Vec<Serial>,
+    running: BTreeSet<Pid>,
+    clock: u64,
+    rng: Rng,
+}
+
+// Each host can only see its own processes.
+#[derive(Debug, Clone)]
+struct SimRuntime {
+    world: Rc<RefCell<World>>,
+    host: usize,
+    hosts: usize,
+}
+
+impl Runtime for SimRuntime {
+    fn connected_devices(&self) -> crate::runtime::Result<Vec<Serial>> {
+        Ok(self.world.borrow().devices.clone())
+    }
+
+    fn wait_for_boot(&self, _serial: &Serial) -> crate::runtime::Result<()> {
+        Ok(())
+    }
+
+    fn check_health(&self, _serial: &Serial) -> crate::runtime::Result<()> {
+        Ok(())
+    }
+
+    fn adb_devices(&self) -> crate::runtime::Result<Vec<AdbDevice>> {
+        Ok(self.world.borrow().devices.iter()
+            .map(|serial| AdbDevice {
+                serial: serial.to_string(),
+                state: "device".to_string(),
+                attributes: BTreeMap::new(),
+                server: None,
+            })
+            .collect())
+    }
+
+    fn getprop(&self, _serial: &Serial, _name: &str) -> crate::runtime::Result<String> {
+        Ok(String::new())
+    }
+
+    fn battery(&self, _serial: &Serial) -> crate::runtime::Result<Battery> {
+        Ok(Battery::default())
+    }
+
+    fn run_adb(&self, _serial: &Serial, _args: &[&str]) -> crate::runtime::Result<String> {
+        Ok(String::new())
+    }
+
+    fn screencap(&self, _serial: &Serial) -> crate::runtime::Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    fn server_socket(&self, _serial: &Serial) -> Option<String> {
+        None
+    }
+
+    fn transport_id(&self, _serial: &Serial) -> Option<String> {
+        None
+    }
+
+    fn adb_serial(&self, serial: &Serial) -> String {
+        serial.to_string()
+    }
+
+    fn is_running(&self, pid: Pid) -> crate::runtime::Result<bool> {
+        Ok(host_of(pid, self.hosts) == self.host && self.world.borrow().running.contains(&pid))
+    }
+
+    fn now(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.world.borrow().clock)
+    }
+
+    fn random(&self) -> u64 {
+        self.world.borrow_mut().rng.next()
+    }
+}
+
+fn host_of(pid: Pid, hosts: usize) -> usize {
+    pid as usize % hosts
+}
+
+fn simulate(seed: u64, hosts: &[&str]) -> Result {
+    let world = Rc::new(RefCell::new(World {
+        devices: (1..=DEVICES).map(|i| Serial::new(format!("serial{}", i)).unwrap()).collect(),
+        running: BTreeSet::new(),
+        clock: 0,
+        rng: Rng(seed),
+    }));
+
+    let runtime_dirs: Vec<_> = hosts.iter().map(|_| TempDir::default()).collect();
+    let shared_dir = TempDir::default();
+    let apps: Vec<_> = hosts.iter().enumerate()
+        .map(|(i, host)| {
+            let mut store = FileStore::new(&runtime_dirs[i]);
+            if hosts.len() > 1 {
+                let shared = Shared { host: host.to_string(), poll_interval: Duration::ZERO, lock_timeout: Duration::from_secs(1) };
+                store.set_shared(&shared_dir, shared);
+            }
+            let runtime = SimRuntime { world: world.clone(), host: i, hosts: hosts.len() };
+            let mut app = App::with_store(runtime, Box::new(store));
+            app.set_owner(Owner { user: "sim".to_string(), host: host.to_string(), cmd: "test".to_string() });
+            app
+        })
+        .collect();
+
+    // Every process belongs to one of the hosts, crashed ones are replaced with a fresh pid.
+    let mut processes: Vec<Pid> = (1..=PROCESSES as Pid).collect();
+    let mut next_pid = PROCESSES as Pid + 1;
+    world.borrow_mut().running.extend(processes.iter().copied());
+    let mut held: BTreeMap<Pid, Resource<SimRuntime>> = BTreeMap::new();
+
+    for step in 0..STEPS {
+        world.borrow_mut().clock += 1;
+        let roll = world.borrow_mut().rng.below(100);
+        let index = world.borrow_mut().rng.below(processes.len());
+        let pid = processes[index];
+        let host = host_of(pid, hosts.len());
+        let context = format!("seed {} step {} pid {}", seed, step, pid);
+
+        match roll {
+            0..=44 if !held.contains_key(&pid) => {
+                if let Some(resource) = apps[host].try_acquire_resource(pid)? {
+                    let holder = held.iter().find(|(_, other)| other.serial == resource.serial);
+                    assert!(holder.is_none(), "{}: {} handed out twice", context, resource.serial);
+                    held.insert(pid, resource);
+                }
+            }
+            0..=84 => {
+                if let Some(resource) = held.remove(&pid) {
+                    resource.release()?;
+                }
+            }
+            85..=91 => {
+                // Dies without releasing whatever it was holding.
+                if let Some(resource) = held.remove(&pid) {
+                    std::mem::forget(resource);
+                }
+                world.borrow_mut().running.remove(&pid);
+                processes[index] = next_pid;
+                world.borrow_mut().running.insert(next_pid);
+                next_pid += 1;
+            }
+            _ => {
+                apps[host].reconcile()?;
+            }
+        }
+
+        let entries = apps[host].entries()?;
+        for (pid, resource) in &held {
+            let owner = entries.get(&resource.serial).and_then(|entry| entry.pid);
+            assert_eq!(owner, Some(*pid), "{}: {} lost its claim on {}", context, pid, resource.serial);
+        }
+    }
+
+    // Once everyone is done and each host has cleaned up after its crashed processes the whole pool is free again.
+    for (_, resource) in std::mem::take(&mut held) {
+        resource.release()?;
+    }
+    for app in &apps {
+        app.reconcile()?;
+    }
+    for app in &apps {
+        app.reconcile()?;
+        assert_eq!(app.entries()?.count_available(), DEVICES, "seed {}: devices leaked", seed);
+    }
+    Ok(())
+}
+
+#[test]
+fn single_host_pool_keeps_invariants() -> Result {
+    for seed in 1..=5 {
+        simulate(seed, &["bench"])?;
+    }
+    Ok(())
+}
+
+#[test]
+fn shared_pool_keeps_invariants() -> Result {
+    for seed in 1..=5 {
+        simulate(seed, &["bench1", "bench2"])?;
+    }
+    Ok(())
+}
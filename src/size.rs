@@ -0,0 +1,104 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Deserializer};
+use serde::de::Visitor;
+
+const GIGABYTE: u64 = 1024;
+const TERABYTE: u64 = 1024 * GIGABYTE;
+
+// An amount of memory in megabytes, written like "2048M", "16G" or "1T". A bare number is megabytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Megabytes(pub u64);
+
+impl FromStr for Megabytes {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number: u64 = number.parse().map_err(|_| anyhow!("invalid size {:?}, expected a size like 2048M or 16G", s))?;
+        let scale = match unit.trim() {
+            "" | "M" | "MB" | "Mi" | "MiB" => 1,
+            "G" | "GB" | "Gi" | "GiB" => GIGABYTE,
+            "T" | "TB" | "Ti" | "TiB" => TERABYTE,
+            "Mb" | "Gb" | "Tb" => return Err(anyhow!("invalid size {:?}, that's bits, use an uppercase B for bytes", s)),
+            "m" | "g" | "t" => return Err(anyhow!("invalid size {:?}, use an uppercase M, G or T", s)),
+            "K" | "KB" | "Ki" | "KiB" | "B" => return Err(anyhow!("invalid size {:?}, sizes are in whole megabytes", s)),
+            _ => return Err(anyhow!("invalid size {:?}, expected a unit of M, G or T", s)),
+        };
+        number.checked_mul(scale).map(Megabytes).ok_or(anyhow!("invalid size {:?}, it's too big", s))
+    }
+}
+
+impl Display for Megabytes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            0 => write!(f, "0M"),
+            mb if mb.is_multiple_of(TERABYTE) => write!(f, "{}T", mb / TERABYTE),
+            mb if mb.is_multiple_of(GIGABYTE) => write!(f, "{}G", mb / GIGABYTE),
+            mb => write!(f, "{}M", mb),
+        }
+    }
+}
+
+// Also takes a bare number of megabytes, which is what these settings used to be.
+impl<'de> Deserialize<'de> for Megabytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(MegabytesVisitor)
+    }
+}
+
+struct MegabytesVisitor;
+
+impl Visitor<'_> for MegabytesVisitor {
+    type Value = Megabytes;
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "a size like \"2048M\" or \"16G\", or a number of megabytes")
+    }
+
+    fn visit_u64<E: serde::de::Error>(self, mb: u64) -> Result<Self::Value, E> {
+        Ok(Megabytes(mb))
+    }
+
+    fn visit_i64<E: serde::de::Error>(self, mb: i64) -> Result<Self::Value, E> {
+        u64::try_from(mb).map(Megabytes).map_err(|_| E::custom(format!("invalid size {}, it can't be negative", mb)))
+    }
+
+    fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<Self::Value, E> {
+        s.parse().map_err(E::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::size::Megabytes;
+
+    #[test]
+    fn parses_units() {
+        assert_eq!("2048".parse::<Megabytes>().unwrap(), Megabytes(2048));
+        assert_eq!("2048M".parse::<Megabytes>().unwrap(), Megabytes(2048));
+        assert_eq!("16G".parse::<Megabytes>().unwrap(), Megabytes(16 * 1024));
+        assert_eq!("16GiB".parse::<Megabytes>().unwrap(), Megabytes(16 * 1024));
+        assert_eq!("1T".parse::<Megabytes>().unwrap(), Megabytes(1024 * 1024));
+        assert_eq!(Megabytes(16 * 1024).to_string(), "16G");
+        assert_eq!(Megabytes(3072).to_string(), "3G");
+        assert_eq!(Megabytes(1500).to_string(), "1500M");
+    }
+
+    #[test]
+    fn rejects_ambiguous_sizes() {
+        let error = "16Gb".parse::<Megabytes>().unwrap_err().to_string();
+        assert!(error.contains("bits"), "{}", error);
+        let error = "16g".parse::<Megabytes>().unwrap_err().to_string();
+        assert!(error.contains("uppercase"), "{}", error);
+        let error = "512K".parse::<Megabytes>().unwrap_err().to_string();
+        assert!(error.contains("whole megabytes"), "{}", error);
+        assert!("".parse::<Megabytes>().is_err());
+        assert!("-1G".parse::<Megabytes>().is_err());
+        assert!("1.5G".parse::<Megabytes>().is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt::Debug;
+use std::time::{Duration, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::App;
+use crate::duration::HumanDuration;
+use crate::events::{EventLog, LeaseRecord};
+use crate::runtime::Runtime;
+use crate::status::print_table;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+const PHASES: [&str; 4] = ["adb devices", "lock file", "waiting", "boot wait"];
+
+// Where the time went between a job asking for a device and it starting on one, in milliseconds. Recorded with each
+// lease when [telemetry] latency is on.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Latency {
+    // Listing devices with adb.
+    pub devices_ms: u64,
+    // Reading and writing the lock file to claim one.
+    pub lock_file_ms: u64,
+    // Waiting for someone else to release one.
+    pub wait_ms: u64,
+    // Waiting for the device to boot and pass its health check.
+    pub boot_wait_ms: u64,
+}
+
+impl Latency {
+    pub fn total_ms(&self) -> u64 {
+        self.devices_ms + self.lock_file_ms + self.wait_ms + self.boot_wait_ms
+    }
+
+    // In the same order as PHASES.
+    fn phases(&self) -> [u64; 4] {
+        [self.devices_ms, self.lock_file_ms, self.wait_ms, self.boot_wait_ms]
+    }
+}
+
+pub fn add(ms: &mut u64, elapsed: Duration) {
+    *ms += elapsed.as_millis() as u64;
+}
+
+// How long a phase took across the leases that recorded it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PhaseStats {
+    pub phase: &'static str,
+    pub mean_ms: u64,
+    pub p50_ms: u64,
+    pub p95_ms: u64,
+    // Of all the time spent getting devices, so it's clear which phase dominates.
+    pub share: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JobStats {
+    pub jobs: usize,
+    pub failed: usize,
+    pub mean_duration_secs: u64,
+}
+
+#[instrument(skip(app, events))]
+pub fn run<R: Runtime + Debug>(app: &App<R>, events: &EventLog, since: HumanDuration, latency: bool, json: bool) -> Result {
+    let since = app.now().checked_sub(since.0).unwrap_or(UNIX_EPOCH).duration_since(UNIX_EPOCH)?.as_secs();
+    let leases: Vec<LeaseRecord> = events.leases()?.into_iter().filter(|lease| lease.started_at >= since).collect();
+    if !latency {
+        let stats = job_stats(&leases);
+        if json {
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        } else {
+            println!("{} jobs, {} failed, {}s on average", stats.jobs, stats.failed, stats.mean_duration_secs);
+        }
+        return Ok(());
+    }
+    let latencies: Vec<&Latency> = leases.iter().filter_map(|lease| lease.latency.as_ref()).collect();
+    if latencies.is_empty() {
+        println!("no jobs have recorded latency, turn it on with `latency = true` under [telemetry] in the config");
+        return Ok(());
+    }
+    let stats = latency_stats(&latencies);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+    let mut rows = vec![["PHASE", "MEAN", "P50", "P95", "SHARE"].map(str::to_string).to_vec()];
+    for phase in stats {
+        rows.push(vec![
+            phase.phase.to_string(),
+            format!("{}ms", phase.mean_ms),
+            format!("{}ms", phase.p50_ms),
+            format!("{}ms", phase.p95_ms),
+            format!("{:.0}%", phase.share * 100.0),
+        ]);
+    }
+    print_table(&rows);
+    println!("from {} jobs", latencies.len());
+    Ok(())
+}
+
+pub fn job_stats(leases: &[LeaseRecord]) -> JobStats {
+    let total: u64 = leases.iter().map(|lease| lease.duration).sum();
+    JobStats {
+        jobs: leases.len(),
+        failed: leases.iter().filter(|lease| lease.exit_code != Some(0)).count(),
+        mean_duration_secs: total.checked_div(leases.len() as u64).unwrap_or_default(),
+    }
+}
+
+pub fn latency_stats(latencies: &[&Latency]) -> Vec<PhaseStats> {
+    let total: u64 = latencies.iter().map(|latency| latency.total_ms()).sum();
+    PHASES.iter().enumerate()
+        .map(|(i, phase)| {
+            let mut times: Vec<u64> = latencies.iter().map(|latency| latency.phases()[i]).collect();
+            times.sort_unstable();
+            let sum: u64 = times.iter().sum();
+            PhaseStats {
+                phase,
+                mean_ms: sum / times.len() as u64,
+                p50_ms: percentile(&times, 50),
+                p95_ms: percentile(&times, 95),
+                share: if total == 0 { 0.0 } else { sum as f64 / total as f64 },
+            }
+        })
+        .collect()
+}
+
+// Nearest rank, of sorted times.
+fn percentile(times: &[u64], p: usize) -> u64 {
+    let rank = (p * times.len()).div_ceil(100).max(1);
+    times[rank - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stats::{latency_stats, Latency, PhaseStats};
+
+    #[test]
+    fn breaks_down_latency_by_phase() {
+        let latencies: Vec<Latency> = (1..=20)
+            .map(|i| Latency { devices_ms: 10, lock_file_ms: 1, wait_ms: if i == 20 { 5000 } else { 0 }, boot_wait_ms: 100 * i })
+            .collect();
+        let stats = latency_stats(&latencies.iter().collect::<Vec<_>>());
+
+        assert_eq!(stats[2], PhaseStats { phase: "waiting", mean_ms: 250, p50_ms: 0, p95_ms: 0, share: 5000.0 / 26220.0 });
+        assert_eq!(stats[3].p50_ms, 1000);
+        assert_eq!(stats[3].p95_ms, 1900);
+        assert_eq!(stats[3].mean_ms, 1050);
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::time::{Duration, SystemTime};
+
+use tracing::instrument;
+
+use crate::App;
+use crate::lockfile::Entry;
+use crate::runtime::{Runtime, Serial};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+#[instrument(skip(app))]
+pub fn run<R: Runtime + Debug>(app: &App<R>) -> Result {
+    let state = app.reconcile()?;
+
+    let now = app.now();
+    if let Some(pause) = state.entries.paused() {
+        let reason = pause.reason.as_ref().map(|reason| format!(": {}", reason)).unwrap_or_default();
+        println!("paused by {} {} ago{}", pause.user, format_age(now, pause.paused_at), reason);
+        println!();
+    }
+    // Devices are grouped by the host they're attached to, when some of them are on adb servers elsewhere.
+    let connected = app.connected_devices()?;
+    let attached: BTreeMap<&Serial, Option<String>> = state.entries.iter()
+        .map(|(serial, _)| (serial, connected.contains(serial).then(|| app.device_host(serial)).flatten()))
+        .collect();
+    let spread = attached.values().any(Option::is_some);
+    let mut entries: Vec<_> = state.entries.iter().collect();
+    entries.sort_by_key(|(serial, _)| &attached[serial]);
+
+    let mut header = vec!["SERIAL", "STATE", "SINCE", "PID", "USER", "HOST", "COMMAND"];
+    if spread {
+        header.insert(1, "ATTACHED TO");
+    }
+    let mut rows = vec![header.into_iter().map(String::from).collect::<Vec<_>>()];
+    for (serial, entry) in entries {
+        let status = describe(entry);
+        let since = if entry.pid.is_some() {
+            entry.claimed_at.map(|at| format!("claimed {} ago", format_age(now, at)))
+        } else {
+            entry.released_at.map(|at| format!("idle {}", format_age(now, at)))
+        };
+        let owner = entry.owner.clone().unwrap_or_default();
+        let mut row = vec![
+            serial.to_string(),
+            status.to_string(),
+            since.unwrap_or_default(),
+            entry.pid.map(|pid| pid.to_string()).unwrap_or_default(),
+            owner.user,
+            owner.host,
+            owner.cmd,
+        ];
+        if spread {
+            row.insert(1, attached[serial].clone().unwrap_or_else(|| "local".to_string()));
+        }
+        rows.push(row);
+    }
+    print_table(&rows);
+
+    if !state.waiters.is_empty() {
+        println!();
+        println!("{} waiting for a device", state.waiters.len());
+    }
+    Ok(())
+}
+
+// What a device is up to, as shown by status and top.
+pub fn describe(entry: &Entry) -> &'static str {
+    if entry.spent {
+        "spent"
+    } else if entry.pid.is_some() {
+        "in use"
+    } else if entry.quarantined {
+        "quarantined"
+    } else if entry.disconnected_at.is_some() {
+        "disconnected"
+    } else if entry.low_battery {
+        "charging"
+    } else if entry.available_after.is_some() {
+        "cooling down"
+    } else if entry.dirty {
+        "needs check"
+    } else {
+        "available"
+    }
+}
+
+// Coarse age such as "42m" or "3h5m", clock skew counts as no time at all.
+pub fn format_age(now: SystemTime, then: SystemTime) -> String {
+    let secs = now.duration_since(then).unwrap_or(Duration::ZERO).as_secs();
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{}d{}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h{}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m", minutes)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+pub fn print_table(rows: &[Vec<String>]) {
+    let columns = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+    let widths: Vec<_> = (0..columns)
+        .map(|i| rows.iter().filter_map(|row| row.get(i)).map(|cell| cell.chars().count()).max().unwrap_or(0))
+        .collect();
+    for row in rows {
+        let line: Vec<_> = row.iter().enumerate()
+            .map(|(i, cell)| format!("{:width$}", cell, width = widths[i]))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::status::format_age;
+
+    #[test]
+    fn formats_ages() {
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+
+        assert_eq!(format_age(at(1000), at(1000)), "0s");
+        assert_eq!(format_age(at(1000), at(958)), "42s");
+        assert_eq!(format_age(at(3000), at(480)), "42m");
+        assert_eq!(format_age(at(20000), at(1000)), "5h16m");
+        assert_eq!(format_age(at(200000), at(1000)), "2d7h");
+        assert_eq!(format_age(at(1000), at(2000)), "0s");
+    }
+}
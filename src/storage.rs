@@ -0,0 +1,44 @@
+use anyhow::anyhow;
+
+use crate::runtime::{Runtime, Serial};
+use crate::size::Megabytes;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// Available on the data partition, where apps get installed.
+pub fn free(runtime: &impl Runtime, serial: &Serial) -> Result<Megabytes> {
+    let output = runtime.run_adb(serial, &["shell", "df", "-k", "/data"])?;
+    parse_df(&output).ok_or_else(|| anyhow!("couldn't read free storage from df: {:?}", output))
+}
+
+// Clears every app's cache and removes the given paths, which are expanded by the device's shell, ex: /sdcard/Download/*
+pub fn clean(runtime: &impl Runtime, serial: &Serial, paths: &[impl AsRef<str>]) -> Result {
+    runtime.run_adb(serial, &["shell", "pm", "trim-caches", "1000G"])?;
+    for path in paths {
+        runtime.run_adb(serial, &["shell", &format!("rm -rf {}", path.as_ref())])?;
+    }
+    Ok(())
+}
+
+// ex:
+//   Filesystem       1K-blocks    Used Available Use% Mounted on
+//   /dev/block/dm-5  115234816 2345678 112889138   3% /data
+fn parse_df(output: &str) -> Option<Megabytes> {
+    let available: u64 = output.lines().last()?.split_ascii_whitespace().nth(3)?.parse().ok()?;
+    Some(Megabytes(available / 1024))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::size::Megabytes;
+    use crate::storage::parse_df;
+
+    #[test]
+    fn parses_free_storage() {
+        let df = "Filesystem       1K-blocks    Used Available Use% Mounted on\n\
+            /dev/block/dm-5  115234816 2345678   1048576   3% /data\n";
+
+        assert_eq!(parse_df(df), Some(Megabytes(1024)));
+        assert_eq!(parse_df(""), None);
+    }
+}
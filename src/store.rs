@@ -0,0 +1,198 @@
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(not(feature = "redis"))]
+use anyhow::anyhow;
+use tracing::debug;
+
+use crate::PoolState;
+use crate::config::Config;
+use crate::heartbeat;
+use crate::lockfile::{Entry, LockFileEntries, Owner};
+use crate::runtime::{Pid, Serial};
+use crate::shared::Shared;
+
+pub use self::file::{FileStore, LockMode};
+#[cfg(feature = "redis")]
+pub use self::redis::RedisStore;
+
+mod file;
+#[cfg(feature = "redis")]
+mod redis;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// Which of the given pids are still running, checked all at once.
+pub type Running<'a> = dyn Fn(&[Pid]) -> Result<BTreeSet<Pid>> + 'a;
+
+// Picks one of the available entries to claim, instead of the store's own preference.
+pub type Choose<'a> = dyn Fn(&LockFileEntries) -> Option<Serial> + 'a;
+
+// Where the state of the pool lives and how processes waiting on it find out a device has been released.
+pub trait PoolStore: Debug {
+    // Claims one of the given devices, cleaning up claims of processes that have stopped if there's nothing free.
+    fn acquire(
+        &self,
+        serials: &[Serial],
+        claim: &Claim,
+        choose: Option<&Choose<'_>>,
+        running: &Running<'_>,
+    ) -> Result<Option<Acquired>>;
+    // False if the claim had already been taken away, going by the token if it was given one, otherwise the pid.
+    fn release(&self, serial: &Serial, pid: Pid, token: Option<&str>, now: SystemTime) -> Result<bool>;
+    fn snapshot(&self) -> Result<PoolState>;
+    // Brings the state in line with the devices that are connected and the processes that are running.
+    fn reconcile(&self, serials: &[Serial], running: &Running<'_>, now: SystemTime) -> Result<PoolState>;
+    fn modify(&self, f: &mut dyn FnMut(&mut LockFileEntries)) -> Result;
+    // Blocks until a device may have been released, or the timeout has passed.
+    fn wait(&self, timeout: Option<Duration>) -> Result;
+
+    // Like reconcile, but describes everything that was out of line.
+    fn repair(&self, serials: &[Serial], running: &Running<'_>, now: SystemTime) -> Result<Vec<String>> {
+        self.reconcile(serials, running, now)?;
+        Ok(Vec::new())
+    }
+
+    // Claims taken back since this was last called from processes that didn't release them, ex: ones that died.
+    fn take_reclaimed(&self) -> Vec<(Serial, Pid)> {
+        Vec::new()
+    }
+
+    // Whether a claim was made from this host, only those can be checked on and cleaned up from here.
+    fn is_local(&self, _entry: &Entry) -> bool {
+        true
+    }
+
+    // Records that the given process is waiting on this many devices, so the daemon knows how much demand there is.
+    // A count of zero means it's done waiting.
+    fn want(&self, _pid: Pid, _count: usize, _now: SystemTime) -> Result {
+        Ok(())
+    }
+
+    // How often claims need to be renewed so they don't expire, None if they never do.
+    fn renew_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    // False if the claim has already expired.
+    fn renew(&self, _serial: &Serial, _token: Option<&str>, _now: SystemTime) -> Result<bool> {
+        Ok(true)
+    }
+
+    // Another handle on the same pool for a thread that renews claims, so they're kept alive whatever the claiming
+    // thread is busy with. None if claims never expire.
+    fn renewer(&self) -> Option<Box<dyn PoolStore + Send>> {
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Claim {
+    pub pid: Pid,
+    pub owner: Option<Owner>,
+    pub claimed_at: SystemTime,
+    // Random, for stores that need a token to identify the claim.
+    pub nonce: u64,
+    // Most devices the owner may hold at once, including this one.
+    pub quota: Option<usize>,
+    // Only claim one of these devices, any of them if None, ex: the ones in a --group.
+    pub eligible: Option<BTreeSet<Serial>>,
+    // Most jobs that may hold a device on the owner's host at once, including this one.
+    pub max_concurrent: Option<usize>,
+}
+
+impl Claim {
+    // Whether the owner already holds as many devices as they're allowed.
+    pub fn over_quota(&self, entries: &LockFileEntries) -> bool {
+        match (self.quota, &self.owner) {
+            (Some(quota), Some(owner)) => entries.claimed_by(&owner.user) >= quota,
+            _ => false,
+        }
+    }
+
+    // Whether the host already has as many jobs holding devices as it's allowed.
+    pub fn at_max_concurrent(&self, entries: &LockFileEntries) -> bool {
+        let host = self.owner.as_ref().map(|owner| owner.host.as_str());
+        self.max_concurrent.is_some_and(|max| entries.jobs_on(host) >= max)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Acquired {
+    pub serial: Serial,
+    // Identifies the claim for stores that need to tell apart claims from different hosts.
+    pub token: Option<String>,
+    // Needs a health check before it can be used.
+    pub dirty: bool,
+}
+
+// Renews a claim from a thread of its own until it's dropped.
+#[derive(Debug)]
+pub struct Renewal {
+    stop: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Renewal {
+    // Times are the given one moved along by how long it's been since, so they follow the runtime's clock. None if
+    // claims never expire.
+    pub fn start(
+        store: Box<dyn PoolStore + Send>,
+        serial: Serial,
+        token: Option<String>,
+        now: SystemTime,
+    ) -> Option<Renewal> {
+        let interval = store.renew_interval()?;
+        let (stop, stopped) = mpsc::channel();
+        let started = Instant::now();
+        let thread = std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                match store.renew(&serial, token.as_deref(), now + started.elapsed()) {
+                    Ok(true) => debug!(renewed = %serial),
+                    Ok(false) => {
+                        eprintln!("adp: lost claim on {}", serial);
+                        return;
+                    }
+                    Err(e) => eprintln!("adp: failed to renew claim on {}: {:#}", serial, e),
+                }
+            }
+        });
+        Some(Renewal { stop, thread: Some(thread) })
+    }
+}
+
+impl Drop for Renewal {
+    fn drop(&mut self) {
+        // Waits for a renewal that's under way, so it's over before the claim is released.
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+pub fn from_config(config: &Config, runtime_dir: &Path) -> Result<Box<dyn PoolStore>> {
+    match &config.redis {
+        #[cfg(feature = "redis")]
+        Some(redis) => return Ok(Box::new(RedisStore::new(redis)?)),
+        #[cfg(not(feature = "redis"))]
+        Some(_) => return Err(anyhow!("adp was built without redis support")),
+        None => {}
+    }
+    let mut store = FileStore::new(runtime_dir);
+    store.set_lock_format(config.lock_format);
+    store.set_lock_mode(config.lock_mode);
+    if let Some(grace) = &config.adb.reconnect_grace {
+        store.set_reconnect_grace(grace.0);
+    }
+    store.set_heartbeat(heartbeat::enabled(&config.heartbeat), config.heartbeat.timeout.0);
+    if let Some(shared) = &config.shared {
+        std::fs::create_dir_all(&shared.dir)?;
+        store.set_shared(&shared.dir, Shared::new(shared));
+    }
+    Ok(Box::new(store))
+}
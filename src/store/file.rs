@@ -0,0 +1,679 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::collections::hash_map::RandomState;
+use std::fs::{File, Metadata, OpenOptions};
+use std::hash::BuildHasher;
+use std::io::{BufReader, BufWriter, ErrorKind, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use fs2::FileExt;
+use serde::Deserialize;
+use tracing::{debug, instrument};
+
+use crate::PoolState;
+use crate::config::HeartbeatConfig;
+use crate::filelock::{self, FileLockGuard, FileLockGuardExt};
+use crate::lockfile::{Entry, LockFileEntries, LockFormat};
+use crate::runtime::{Pid, Serial};
+use crate::heartbeat;
+use crate::shared::{self, Shared};
+use crate::store::{Acquired, Choose, Claim, PoolStore, Result, Running};
+use crate::waiters::{WaiterId, Waiters};
+
+// How often to check whether the lock file has changed when waiting for a device.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Processes that crash don't touch the lock file, so check for their claims every so often even when it hasn't changed.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// The default store. Claims live in a lock file, changed in one go while holding a lock on it, and waiting processes
+// watch it for changes.
+//
+// Each claim is also backed by a lock on a file per device, which the claiming process holds for as long as it has the
+// device. The OS lets go of it when the process dies however it dies, so a claim whose device lock isn't held anymore
+// was abandoned, even if its pid has since been reused.
+#[derive(Debug)]
+pub struct FileStore {
+    lock_file_path: PathBuf,
+    waiters_path: PathBuf,
+    devices_dir: PathBuf,
+    shared: Option<Shared>,
+    // Claims from this process are kept alive by heartbeats rather than checked on by pid, ex: from inside a container.
+    heartbeat: bool,
+    // How long a claim kept alive by heartbeats lasts without one, whoever made it.
+    heartbeat_timeout: Duration,
+    // Tells this process apart on the waiters list from ones in other containers with the same pid, when it has
+    // heartbeats.
+    instance: Option<String>,
+    // How long devices that disconnected keep their place in the pool.
+    reconnect_grace: Duration,
+    format: LockFormat,
+    // Device locks this process is holding.
+    held: RefCell<BTreeMap<Serial, FileLockGuard>>,
+    // Processes this one has put on the waiters list, so ones that get a device straight away don't touch it.
+    waiting: RefCell<BTreeSet<Pid>>,
+    lock_mode: LockMode,
+    // Claims are only in the lock file, by choice or because device locks couldn't be taken, ex: SELinux denies them on
+    // a hardened CI image.
+    lock_file_only: Cell<bool>,
+    // What the lock file looked like when this process last checked it for a device.
+    seen: Cell<Option<Version>>,
+    // Claims taken back from processes that didn't release them, until they're taken for the observers.
+    reclaimed: RefCell<Vec<(Serial, Pid)>>,
+}
+
+// How claims are held on to.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockMode {
+    // Device locks wherever they can be taken, the lock file alone elsewhere.
+    #[default]
+    Auto,
+    // Fails instead of going without them.
+    DeviceLocks,
+    // For filesystems and containers where locks held for as long as a job runs are unavailable or unwanted. Jobs that
+    // died are only noticed by their pid, or their heartbeats.
+    LockFileOnly,
+}
+
+// Changes whenever the lock file is written.
+type Version = (SystemTime, u64);
+
+impl FileStore {
+    pub fn new(runtime_dir: impl AsRef<Path>) -> FileStore {
+        FileStore {
+            lock_file_path: runtime_dir.as_ref().join("adp.lock"),
+            waiters_path: runtime_dir.as_ref().join("adp.waiters"),
+            devices_dir: runtime_dir.as_ref().join("devices"),
+            shared: None,
+            heartbeat: false,
+            heartbeat_timeout: HeartbeatConfig::default().timeout.0,
+            instance: None,
+            reconnect_grace: Duration::ZERO,
+            format: LockFormat::default(),
+            held: RefCell::new(BTreeMap::new()),
+            waiting: RefCell::new(BTreeSet::new()),
+            lock_mode: LockMode::default(),
+            lock_file_only: Cell::new(false),
+            seen: Cell::new(None),
+            reclaimed: RefCell::new(Vec::new()),
+        }
+    }
+
+    // Moves the lock file to a directory shared with other hosts. Waiters stay local as it's only the local daemon
+    // that acts on them.
+    pub fn set_shared(&mut self, dir: impl AsRef<Path>, shared: Shared) {
+        self.lock_file_path = dir.as_ref().join("adp.lock");
+        self.shared = Some(shared);
+    }
+
+    pub fn set_heartbeat(&mut self, enabled: bool, timeout: Duration) {
+        self.heartbeat = enabled;
+        self.heartbeat_timeout = timeout;
+        self.instance = enabled.then(|| format!("{:x}", RandomState::new().hash_one(SystemTime::now())));
+    }
+
+    pub fn set_lock_mode(&mut self, mode: LockMode) {
+        self.lock_mode = mode;
+        self.lock_file_only.set(mode == LockMode::LockFileOnly);
+    }
+
+    pub fn set_reconnect_grace(&mut self, grace: Duration) {
+        self.reconnect_grace = grace;
+    }
+
+    pub fn set_lock_format(&mut self, format: LockFormat) {
+        self.format = format;
+    }
+
+    // The same pool without anything this one holds, for another thread.
+    fn fork(&self) -> FileStore {
+        FileStore {
+            lock_file_path: self.lock_file_path.clone(),
+            waiters_path: self.waiters_path.clone(),
+            devices_dir: self.devices_dir.clone(),
+            shared: self.shared.clone(),
+            heartbeat: self.heartbeat,
+            heartbeat_timeout: self.heartbeat_timeout,
+            instance: self.instance.clone(),
+            reconnect_grace: self.reconnect_grace,
+            format: self.format,
+            held: RefCell::new(BTreeMap::new()),
+            waiting: RefCell::new(BTreeSet::new()),
+            lock_mode: self.lock_mode,
+            lock_file_only: Cell::new(self.lock_file_only.get()),
+            seen: Cell::new(None),
+            reclaimed: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn waiter(&self, pid: Pid) -> WaiterId {
+        WaiterId { pid, instance: self.instance.clone() }
+    }
+
+    // Other hosts may have claimed devices this host can't see, those claims need to stick around. So do claims on
+    // devices adb has lost track of for as long as their job is still running, ex: while the adb server restarts, the
+    // job's device is still its own once it's back. Claims with heartbeats are left to those. Returns whether a device
+    // was just seen to be gone.
+    fn update_entries(
+        &self,
+        entries: &mut LockFileEntries,
+        serials: &[Serial],
+        running: &Running<'_>,
+        now: SystemTime,
+    ) -> Result<bool> {
+        let missing: Vec<Pid> = entries.iter()
+            .filter(|(serial, entry)| !serials.contains(serial) && self.is_local(entry) && entry.heartbeat_at.is_none())
+            .filter_map(|(_, entry)| entry.pid)
+            .collect();
+        let alive = if missing.is_empty() { BTreeSet::new() } else { running(&missing)? };
+        Ok(entries.update_with_grace(serials, now, self.reconnect_grace, |entry| {
+            entry.pid.is_some_and(|pid| !self.is_local(entry) || entry.heartbeat_at.is_some() || alive.contains(&pid))
+        }))
+    }
+
+    fn open_lock_file(&self) -> Result<FileLockGuard> {
+        let mut lock_file = self.open_lock_file_as_is()?;
+        self.recover(&mut lock_file)?;
+        Ok(lock_file)
+    }
+
+    // Without finishing a write that was interrupted.
+    fn open_lock_file_as_is(&self) -> Result<FileLockGuard> {
+        let timeout = self.shared.as_ref().map(|shared| shared.lock_timeout);
+        open_lock_file(&self.lock_file_path, timeout)
+    }
+
+    fn intent_path(&self) -> PathBuf {
+        let mut path = self.lock_file_path.clone().into_os_string();
+        path.push(".intent");
+        path.into()
+    }
+
+    // Must be called while holding the lock file. It's rewritten in place, so the new entries are recorded in an
+    // intent file first, for the next process to open it to finish the write if this one dies halfway through.
+    fn write_entries(&self, lock_file: &mut FileLockGuard, entries: &LockFileEntries) -> Result<()> {
+        let intent_path = self.intent_path();
+        let mut partial_path = intent_path.clone().into_os_string();
+        partial_path.push(".partial");
+        // Only renamed into place once it's all on disk, so an intent file is always complete.
+        let partial = File::create(&partial_path)?;
+        entries.write_as(&partial, self.format)?;
+        partial.sync_all()?;
+        std::fs::rename(&partial_path, &intent_path)?;
+        sync_dir(&intent_path)?;
+
+        overwrite(lock_file, entries, self.format)?;
+        std::fs::remove_file(&intent_path)?;
+        Ok(())
+    }
+
+    // Must be called while holding the lock file. Finishes writing the entries a process died in the middle of
+    // writing, false if there weren't any.
+    fn recover(&self, lock_file: &mut FileLockGuard) -> Result<bool> {
+        let intent_path = self.intent_path();
+        let intent = match File::open(&intent_path) {
+            Ok(intent) => intent,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let entries = LockFileEntries::read(BufReader::new(intent))?;
+        debug!(recovered = %entries);
+        overwrite(lock_file, &entries, self.format)?;
+        lock_file.seek(SeekFrom::Start(0))?;
+        std::fs::remove_file(&intent_path)?;
+        Ok(true)
+    }
+
+    fn release_stopped(
+        &self,
+        entries: &mut LockFileEntries,
+        running: &Running<'_>,
+        now: SystemTime,
+    ) -> Result<()> {
+        self.release_expired(entries, now)?;
+        let claimed = self.local_claims(entries);
+        let pids: Vec<Pid> = claimed.iter().map(|(_, pid)| *pid).collect();
+        let running = running(&pids)?;
+        self.release_not_running(entries, claimed, &running, now)
+    }
+
+    // Claims to check on by their pid. From inside a container that's none of them, the pids are from whichever
+    // container made the claim, and claims kept alive by heartbeats are never checked on that way.
+    fn local_claims(&self, entries: &LockFileEntries) -> Vec<(Serial, Pid)> {
+        if self.heartbeat {
+            return Vec::new();
+        }
+        entries.iter()
+            .filter(|(_, entry)| self.is_local(entry) && entry.heartbeat_at.is_none())
+            .filter_map(|(serial, entry)| Some((serial.clone(), entry.pid?)))
+            .collect()
+    }
+
+    // Takes back claims whose job stopped sending heartbeats, from any host as they don't depend on the pid.
+    fn release_expired(&self, entries: &mut LockFileEntries, now: SystemTime) -> Result<()> {
+        let expired: Vec<Serial> = entries.iter()
+            .filter(|(_, entry)| heartbeat::expired(entry, self.heartbeat_timeout, now))
+            .map(|(serial, _)| serial.clone())
+            .collect();
+        for serial in &expired {
+            self.unlock_device(serial)?;
+            if let Some(pid) = entries.get(serial).and_then(|entry| entry.pid) {
+                self.reclaimed.borrow_mut().push((serial.clone(), pid));
+            }
+        }
+        debug!(expired = ?expired);
+        entries.release_all(expired, now);
+        Ok(())
+    }
+
+    fn release_not_running(
+        &self,
+        entries: &mut LockFileEntries,
+        claimed: Vec<(Serial, Pid)>,
+        running: &BTreeSet<Pid>,
+        now: SystemTime,
+    ) -> Result<()> {
+        let mut dropped = Vec::new();
+        for (serial, pid) in claimed {
+            if !running.contains(&pid) || self.abandoned(&serial)? {
+                self.unlock_device(&serial)?;
+                self.reclaimed.borrow_mut().push((serial.clone(), pid));
+                dropped.push(serial);
+            }
+        }
+        debug!(dropped = ?dropped);
+        entries.release_all(dropped, now);
+        Ok(())
+    }
+
+    fn device_lock_path(&self, serial: &Serial) -> PathBuf {
+        self.devices_dir.join(format!("{}.lock", serial))
+    }
+
+    // Must be called while holding the lock file. False if a process is still holding on to the device, even though
+    // it isn't claimed anymore, ex: a job that was just killed and hasn't exited yet.
+    fn lock_device(&self, serial: &Serial) -> Result<bool> {
+        if self.lock_file_only.get() {
+            return Ok(true);
+        }
+        let path = self.device_lock_path(serial);
+        let file = std::fs::create_dir_all(&self.devices_dir).and_then(|_| {
+            OpenOptions::new().write(true).create(true).truncate(false).open(&path)
+        });
+        match file.and_then(|file| file.try_into_lock_exclusive()) {
+            Ok(lock) => {
+                self.held.borrow_mut().insert(serial.clone(), lock);
+                Ok(true)
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            // Claims still work without them, they're just not cleaned up as quickly when the pid is reused.
+            Err(e) if filelock::is_denied(&e) && self.lock_mode == LockMode::Auto => {
+                eprintln!("adp: can't lock {:?} ({}), checking on claims by their pid alone", path, e);
+                self.lock_file_only.set(true);
+                Ok(true)
+            }
+            Err(e) => Err(anyhow::Error::new(e).context(format!("failed to lock {:?}", path))),
+        }
+    }
+
+    // Must be called while holding the lock file, which makes it safe to remove the device lock.
+    fn unlock_device(&self, serial: &Serial) -> Result<()> {
+        self.held.borrow_mut().remove(serial);
+        match std::fs::remove_file(self.device_lock_path(serial)) {
+            Err(e) if e.kind() != ErrorKind::NotFound && !filelock::is_denied(&e) => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    // Must be called while holding the lock file. Whether the process that claimed the device went away without
+    // releasing it. There's only a device lock for claims made by versions of adp that take one, others can only be
+    // checked on by their pid.
+    fn abandoned(&self, serial: &Serial) -> Result<bool> {
+        if self.held.borrow().contains_key(serial) {
+            return Ok(false);
+        }
+        let file = match OpenOptions::new().write(true).open(self.device_lock_path(serial)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound || filelock::is_denied(&e) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        // Closing the file lets go of the lock straight away.
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || filelock::is_denied(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn reconcile_locked(
+        &self,
+        lock_file: &mut FileLockGuard,
+        serials: &[Serial],
+        running: &Running<'_>,
+        now: SystemTime,
+    ) -> Result<PoolState> {
+        let mut entries = LockFileEntries::read(BufReader::new(&**lock_file))?;
+        self.update_entries(&mut entries, serials, running, now)?;
+        let mut waiters = self.read_waiters()?;
+
+        self.release_expired(&mut entries, now)?;
+        // Check claims and waiters together so it's one trip to the runtime.
+        let claimed = self.local_claims(&entries);
+        let local_waiters = waiters.iter().filter(|id| id.instance.is_none()).map(|id| id.pid);
+        let pids: Vec<Pid> = claimed.iter().map(|(_, pid)| *pid).chain(local_waiters).collect();
+        let running = running(&pids)?;
+        self.release_not_running(&mut entries, claimed, &running, now)?;
+
+        // Waiters with an instance are in containers, their pids mean nothing here.
+        let stopped: Vec<WaiterId> = waiters.iter()
+            .filter(|id| match id.instance {
+                Some(_) => waiters.expired(id, self.heartbeat_timeout, now),
+                None => !running.contains(&id.pid),
+            })
+            .cloned()
+            .collect();
+        for id in &stopped {
+            waiters.remove(id);
+        }
+        self.write_waiters(&waiters)?;
+
+        self.write_entries(lock_file, &entries)?;
+
+        Ok(PoolState { entries, waiters })
+    }
+
+    // Must be called while holding the lock file.
+    fn read_waiters(&self) -> Result<Waiters> {
+        if !self.waiters_path.exists() {
+            return Ok(Waiters::default());
+        }
+        Ok(Waiters::read(File::open(&self.waiters_path)?)?)
+    }
+
+    // Must be called while holding the lock file.
+    fn write_waiters(&self, waiters: &Waiters) -> Result<()> {
+        waiters.write(File::create(&self.waiters_path)?)?;
+        Ok(())
+    }
+}
+
+impl PoolStore for FileStore {
+    #[instrument(skip(choose, running))]
+    fn acquire(
+        &self,
+        serials: &[Serial],
+        claim: &Claim,
+        choose: Option<&Choose<'_>>,
+        running: &Running<'_>,
+    ) -> Result<Option<Acquired>> {
+        let mut lock_file = self.open_lock_file()?;
+        debug!(lock_file = ?*lock_file);
+
+        let mut entries = LockFileEntries::read(BufReader::new(&*lock_file))?;
+        let disconnected = self.update_entries(&mut entries, serials, running, claim.claimed_at)?;
+
+        let acquire = |entries: &mut LockFileEntries| match choose {
+            _ if claim.over_quota(entries) || claim.at_max_concurrent(entries) => None,
+            Some(choose) => entries.acquire_with(claim.pid, claim.claimed_at, choose),
+            None => match &claim.eligible {
+                Some(eligible) => entries.acquire_from(eligible, claim.pid, claim.claimed_at),
+                None => entries.acquire(claim.pid, claim.claimed_at),
+            },
+        };
+        // Devices whose lock couldn't be taken are set aside as they were, so one stuck lock doesn't hold up the rest.
+        let mut set_aside = Vec::new();
+        let mut released_stopped = false;
+        let serial = loop {
+            let mut attempt = entries.clone();
+            let Some(claimed) = acquire(&mut attempt) else {
+                if released_stopped {
+                    break None;
+                }
+                // Check to see if any claimed serial is no longer running, and try again.
+                self.release_stopped(&mut entries, running, claim.claimed_at)?;
+                released_stopped = true;
+                continue;
+            };
+            match self.lock_device(&claimed) {
+                Ok(true) => {
+                    entries = attempt;
+                    break Some(claimed);
+                }
+                Ok(false) => debug!(still_held = %claimed),
+                Err(e) => eprintln!("adp: skipping {}: {:#}", claimed, e),
+            }
+            set_aside.extend(entries.remove(&claimed).map(|entry| (claimed, entry)));
+        };
+        for (serial, entry) in set_aside {
+            entries.insert(serial, entry);
+        }
+
+        let token = (self.shared.is_some() || self.heartbeat).then(|| shared::token(claim.nonce));
+        if let Some(serial) = &serial {
+            entries.set_owner(serial, claim.owner.clone());
+            entries.set_token(serial, token.clone());
+            entries.set_heartbeat(serial, self.heartbeat.then_some(claim.claimed_at));
+        }
+
+        debug!(serial = ?serial, entries = %entries);
+        let dirty = matches!(serial.as_ref().and_then(|serial| entries.get(serial)), Some(entry) if entry.dirty);
+
+        // Keep track of who's waiting so the daemon knows how much demand there is. Getting a device without having
+        // waited for one is the common case, it leaves them alone so it's one read and one write of the lock file.
+        if serial.is_none() || self.waiting.borrow().contains(&claim.pid) {
+            let mut waiters = self.read_waiters()?;
+            if serial.is_some() {
+                waiters.remove(&self.waiter(claim.pid));
+                self.waiting.borrow_mut().remove(&claim.pid);
+            } else {
+                waiters.insert(self.waiter(claim.pid), claim.claimed_at);
+                self.waiting.borrow_mut().insert(claim.pid);
+            }
+            self.write_waiters(&waiters)?;
+        }
+
+        // When a device was first seen to be gone that has to stick, otherwise while jobs only wait its grace period
+        // would start over each time and it'd keep its place forever.
+        if serial.is_some() || disconnected {
+            self.write_entries(&mut lock_file, &entries)?;
+        }
+        if serial.is_none() {
+            // Only needed to wait for a change.
+            self.seen.set(lock_file.metadata().ok().and_then(version));
+        }
+
+        Ok(serial.map(|serial| Acquired { serial, token, dirty }))
+    }
+
+    #[instrument]
+    fn release(&self, serial: &Serial, pid: Pid, token: Option<&str>, now: SystemTime) -> Result<bool> {
+        let mut lock_file = self.open_lock_file()?;
+        let mut entries = LockFileEntries::read(BufReader::new(&*lock_file))?;
+
+        debug!(serial = %serial, entries = %entries);
+        let released = match entries.get(serial) {
+            Some(entry) if entry.token.as_deref() == token && (token.is_some() || entry.pid == Some(pid)) => {
+                entries.release(serial.clone(), now);
+                true
+            }
+            // Someone else has cleaned up our claim and the device may already be in use again.
+            _ => false,
+        };
+        if released {
+            self.unlock_device(serial)?;
+        } else {
+            self.held.borrow_mut().remove(serial);
+        }
+        debug!(serial = %serial, entries = %entries);
+
+        self.write_entries(&mut lock_file, &entries)?;
+
+        Ok(released)
+    }
+
+    #[instrument]
+    fn snapshot(&self) -> Result<PoolState> {
+        let lock_file = self.open_lock_file()?;
+        let entries = LockFileEntries::read(BufReader::new(&*lock_file))?;
+        let waiters = self.read_waiters()?;
+        Ok(PoolState { entries, waiters })
+    }
+
+    // Brings the lock file and waiters in line with the devices that are actually connected and the processes that are
+    // actually running.
+    #[instrument(skip(running))]
+    fn reconcile(&self, serials: &[Serial], running: &Running<'_>, now: SystemTime) -> Result<PoolState> {
+        let mut lock_file = self.open_lock_file()?;
+        self.reconcile_locked(&mut lock_file, serials, running, now)
+    }
+
+    #[instrument(skip(running))]
+    fn repair(&self, serials: &[Serial], running: &Running<'_>, now: SystemTime) -> Result<Vec<String>> {
+        let mut lock_file = self.open_lock_file_as_is()?;
+        let recovered = self.recover(&mut lock_file)?;
+        let entries = LockFileEntries::read(BufReader::new(&*lock_file))?;
+        lock_file.seek(SeekFrom::Start(0))?;
+        let waiters = self.read_waiters()?;
+
+        let state = self.reconcile_locked(&mut lock_file, serials, running, now)?;
+
+        let mut fixes = Vec::new();
+        if recovered {
+            fixes.push("finished writing the lock file, adp died in the middle of updating it".to_string());
+        }
+        for (serial, entry) in entries.iter() {
+            match (entry.pid, state.entries.get(serial)) {
+                (_, None) => fixes.push(format!("removed {} which is no longer connected", serial)),
+                (Some(pid), Some(after)) if after.pid.is_none() => {
+                    fixes.push(format!("released {} from {} which is no longer running", serial, pid))
+                }
+                _ => {}
+            }
+        }
+        for (serial, _) in state.entries.iter() {
+            if !entries.contains(serial) {
+                fixes.push(format!("added {} which wasn't in the pool", serial));
+            }
+        }
+        for id in waiters.iter() {
+            if !state.waiters.contains(id) {
+                fixes.push(format!("removed waiter {} which is no longer running", id));
+            }
+        }
+        Ok(fixes)
+    }
+
+    // Reads the entries, applies the given changes and writes them back, all while holding the lock.
+    fn modify(&self, f: &mut dyn FnMut(&mut LockFileEntries)) -> Result {
+        let mut lock_file = self.open_lock_file()?;
+        let mut entries = LockFileEntries::read(BufReader::new(&*lock_file))?;
+        f(&mut entries);
+        self.write_entries(&mut lock_file, &entries)?;
+
+        Ok(())
+    }
+
+    fn take_reclaimed(&self) -> Vec<(Serial, Pid)> {
+        self.reclaimed.take()
+    }
+
+    fn wait(&self, timeout: Option<Duration>) -> Result {
+        // Devices released from other hosts won't wake us up.
+        if let Some(shared) = &self.shared {
+            std::thread::sleep(timeout.map_or(shared.poll_interval, |timeout| timeout.min(shared.poll_interval)));
+            return Ok(());
+        }
+        let deadline = Instant::now() + timeout.map_or(RECHECK_INTERVAL, |timeout| timeout.min(RECHECK_INTERVAL));
+        loop {
+            if std::fs::metadata(&self.lock_file_path).ok().and_then(version) != self.seen.get() {
+                break;
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            std::thread::sleep(left.min(WAIT_POLL_INTERVAL));
+        }
+        Ok(())
+    }
+
+    fn want(&self, pid: Pid, count: usize, now: SystemTime) -> Result {
+        // Waiters are only written while holding the lock file.
+        let _lock_file = self.open_lock_file()?;
+        let mut waiters = self.read_waiters()?;
+        waiters.want(self.waiter(pid), count, now);
+        if count == 0 {
+            self.waiting.borrow_mut().remove(&pid);
+        } else {
+            self.waiting.borrow_mut().insert(pid);
+        }
+        self.write_waiters(&waiters)
+    }
+
+    fn renew_interval(&self) -> Option<Duration> {
+        self.heartbeat.then(|| heartbeat::interval(self.heartbeat_timeout))
+    }
+
+    // Sends a heartbeat for the claim, false if it's already been taken back.
+    #[instrument]
+    fn renew(&self, serial: &Serial, token: Option<&str>, now: SystemTime) -> Result<bool> {
+        if !self.heartbeat {
+            return Ok(true);
+        }
+        let mut lock_file = self.open_lock_file()?;
+        let mut entries = LockFileEntries::read(BufReader::new(&*lock_file))?;
+        let alive = matches!(entries.get(serial), Some(entry) if entry.pid.is_some() && entry.token.as_deref() == token);
+        if alive {
+            entries.set_heartbeat(serial, Some(now));
+            self.write_entries(&mut lock_file, &entries)?;
+        }
+        Ok(alive)
+    }
+
+    fn renewer(&self) -> Option<Box<dyn PoolStore + Send>> {
+        self.heartbeat.then(|| Box::new(self.fork()) as Box<dyn PoolStore + Send>)
+    }
+
+    fn is_local(&self, entry: &Entry) -> bool {
+        self.shared.as_ref().is_none_or(|shared| shared.is_local(entry))
+    }
+}
+
+fn overwrite(lock_file: &mut FileLockGuard, entries: &LockFileEntries, format: LockFormat) -> Result<()> {
+    lock_file.seek(SeekFrom::Start(0))?;
+    lock_file.set_len(0)?;
+    entries.write_as(BufWriter::new(&**lock_file), format)?;
+    // On disk before the intent that would otherwise finish it is removed.
+    lock_file.sync_all()?;
+    Ok(())
+}
+
+// So a rename or removal in it survives a crash.
+fn sync_dir(path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn open_lock_file(path: impl AsRef<Path>, timeout: Option<Duration>) -> Result<FileLockGuard> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path.as_ref()).map_err(|e| filelock::explain(e, path.as_ref()))?;
+    let file = match timeout {
+        Some(timeout) => file.into_lock_exclusive_timeout(timeout)
+            .with_context(|| format!("timed out waiting for lock on {:?}", path.as_ref()))?,
+        None => file.into_lock_exclusive().map_err(|e| filelock::explain(e, path.as_ref()))?,
+    };
+    Ok(file)
+}
+
+fn version(metadata: Metadata) -> Option<Version> {
+    Some((metadata.modified().ok()?, metadata.len()))
+}
@@ -0,0 +1,244 @@
+use std::collections::BTreeMap;
+use std::io::BufReader;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Context};
+use redis::{Client, Commands, Connection, Script};
+use tracing::{debug, instrument};
+
+use crate::PoolState;
+use crate::config::RedisConfig;
+use crate::lockfile::{Entry, LockFileEntries};
+use crate::runtime::{Pid, Serial};
+use crate::shared;
+use crate::store::{Acquired, Choose, Claim, PoolStore, Result, Running};
+use crate::waiters::Waiters;
+
+// Only touches a claim if it's still ours.
+const RENEW: &str = r#"
+if redis.call("GET", KEYS[2]) == ARGV[1] then
+    redis.call("PEXPIRE", KEYS[1], ARGV[2])
+    return redis.call("PEXPIRE", KEYS[2], ARGV[2])
+end
+return 0
+"#;
+
+const RELEASE: &str = r#"
+if redis.call("GET", KEYS[2]) == ARGV[1] then
+    redis.call("DEL", KEYS[1], KEYS[2])
+    redis.call("HSET", KEYS[3], ARGV[2], ARGV[3])
+    return 1
+end
+return 0
+"#;
+
+// Each claim is stored as a key holding the claim in the lock file format, with a sibling key holding its token,
+// both expiring after the ttl. Release times are kept in a hash so the longest idle device can be picked first.
+#[derive(Debug, Clone)]
+pub struct RedisStore {
+    client: Client,
+    prefix: String,
+    ttl: Duration,
+    poll_interval: Duration,
+}
+
+impl RedisStore {
+    pub fn new(config: &RedisConfig) -> Result<RedisStore> {
+        let client = Client::open(config.url.as_str()).with_context(|| format!("invalid redis url {:?}", config.url))?;
+        Ok(RedisStore {
+            client,
+            prefix: config.prefix.clone(),
+            ttl: config.ttl.0,
+            poll_interval: config.poll_interval.0,
+        })
+    }
+
+    fn ttl_ms(&self) -> u64 {
+        self.ttl.as_millis() as u64
+    }
+
+    fn claims(&self) -> Result<LockFileEntries> {
+        let mut con = self.connection()?;
+        let keys: Vec<String> = con.scan_match(format!("{}:claim:*", self.prefix))?.collect();
+        let mut entries = LockFileEntries::default();
+        for key in keys {
+            // May have expired since the scan.
+            let value: Option<String> = con.get(&key)?;
+            if let Some(value) = value {
+                for (serial, entry) in LockFileEntries::read(BufReader::new(value.as_bytes()))?.iter() {
+                    entries.insert(serial.clone(), entry.clone());
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn connection(&self) -> Result<Connection> {
+        self.client.get_connection().context("failed to connect to redis")
+    }
+
+    fn claim_key(&self, serial: &Serial) -> String {
+        format!("{}:claim:{}", self.prefix, serial)
+    }
+
+    fn token_key(&self, serial: &Serial) -> String {
+        format!("{}:token:{}", self.prefix, serial)
+    }
+
+    fn released_key(&self) -> String {
+        format!("{}:released", self.prefix)
+    }
+}
+
+// Never released sorts first, then the longest idle.
+fn longest_idle_first(serials: &[Serial], released: &BTreeMap<Serial, Option<u64>>) -> Vec<Serial> {
+    let mut order = serials.to_vec();
+    order.sort_by_key(|serial| released.get(serial).copied().flatten());
+    order
+}
+
+impl PoolStore for RedisStore {
+    // Claims expire on their own so there's no need to check on processes.
+    #[instrument(skip(claim, choose, _running))]
+    fn acquire(
+        &self,
+        serials: &[Serial],
+        claim: &Claim,
+        choose: Option<&Choose<'_>>,
+        _running: &Running<'_>,
+    ) -> Result<Option<Acquired>> {
+        if choose.is_some() {
+            return Err(anyhow!("selection policies aren't supported with the redis store"));
+        }
+        if claim.quota.is_some() {
+            return Err(anyhow!("quotas aren't supported with the redis store"));
+        }
+        if claim.max_concurrent.is_some() {
+            return Err(anyhow!("max_concurrent isn't supported with the redis store"));
+        }
+        let token = shared::token(claim.nonce);
+        let mut con = self.connection()?;
+        let serials: Vec<Serial> = serials.iter()
+            .filter(|serial| claim.eligible.as_ref().is_none_or(|eligible| eligible.contains(*serial)))
+            .cloned()
+            .collect();
+        // One HGET per serial rather than an HMGET, which replies with a bare value rather than a list for one field.
+        let mut pipe = redis::pipe();
+        for serial in &serials {
+            pipe.hget(self.released_key(), serial.as_str());
+        }
+        let released: Vec<Option<u64>> = pipe.query(&mut con)?;
+        let released: BTreeMap<Serial, Option<u64>> = serials.iter().cloned().zip(released).collect();
+
+        for serial in longest_idle_first(&serials, &released) {
+            // The token key is the lock, the claim key is only there to describe it.
+            let taken: bool = redis::cmd("SET").arg(self.token_key(&serial)).arg(&token)
+                .arg("NX").arg("PX").arg(self.ttl_ms())
+                .query::<Option<String>>(&mut con)?
+                .is_some();
+            if !taken {
+                continue;
+            }
+            let mut entries = LockFileEntries::default();
+            entries.insert(serial.clone(), Entry {
+                pid: Some(claim.pid),
+                owner: claim.owner.clone(),
+                claimed_at: Some(claim.claimed_at),
+                ..Entry::default()
+            });
+            let mut value = Vec::new();
+            entries.write(&mut value)?;
+            let _: () = con.pset_ex(self.claim_key(&serial), value, self.ttl_ms())?;
+            debug!(acquired = %serial);
+            return Ok(Some(Acquired { serial, token: Some(token), dirty: false }));
+        }
+        Ok(None)
+    }
+
+    fn renew_interval(&self) -> Option<Duration> {
+        // Well before the claim expires so a slow round trip doesn't lose it.
+        Some(self.ttl / 3)
+    }
+
+    #[instrument]
+    fn renew(&self, serial: &Serial, token: Option<&str>, _now: SystemTime) -> Result<bool> {
+        let token = token.ok_or(anyhow!("missing claim token"))?;
+        let mut con = self.connection()?;
+        let renewed: i32 = Script::new(RENEW)
+            .key(self.claim_key(serial))
+            .key(self.token_key(serial))
+            .arg(token)
+            .arg(self.ttl_ms())
+            .invoke(&mut con)?;
+        Ok(renewed == 1)
+    }
+
+    fn renewer(&self) -> Option<Box<dyn PoolStore + Send>> {
+        Some(Box::new(self.clone()))
+    }
+
+    #[instrument]
+    fn release(&self, serial: &Serial, _pid: Pid, token: Option<&str>, now: SystemTime) -> Result<bool> {
+        let token = token.ok_or(anyhow!("missing claim token"))?;
+        let mut con = self.connection()?;
+        let now = now.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+        let released: i32 = Script::new(RELEASE)
+            .key(self.claim_key(serial))
+            .key(self.token_key(serial))
+            .key(self.released_key())
+            .arg(token)
+            .arg(serial.as_str())
+            .arg(now)
+            .invoke(&mut con)?;
+        Ok(released == 1)
+    }
+
+    #[instrument]
+    fn snapshot(&self) -> Result<PoolState> {
+        Ok(PoolState { entries: self.claims()?, waiters: Waiters::default() })
+    }
+
+    #[instrument(skip(_running))]
+    fn reconcile(&self, serials: &[Serial], _running: &Running<'_>, _now: SystemTime) -> Result<PoolState> {
+        let mut entries = self.claims()?;
+        for serial in serials {
+            if !entries.contains(serial) {
+                entries.insert(serial.clone(), Entry::default());
+            }
+        }
+        Ok(PoolState { entries, waiters: Waiters::default() })
+    }
+
+    fn modify(&self, _f: &mut dyn FnMut(&mut LockFileEntries)) -> Result {
+        Err(anyhow!("not supported with the redis store"))
+    }
+
+    fn wait(&self, timeout: Option<Duration>) -> Result {
+        std::thread::sleep(timeout.map_or(self.poll_interval, |timeout| timeout.min(self.poll_interval)));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::runtime::Serial;
+    use crate::store::redis::longest_idle_first;
+
+    fn serial(serial: &str) -> Serial {
+        serial.parse().unwrap()
+    }
+
+    #[test]
+    fn orders_by_each_serials_own_release_time() {
+        let serials = [serial("serial1"), serial("serial2"), serial("serial3")];
+        let released = BTreeMap::from([
+            (serial("serial1"), Some(30)),
+            (serial("serial2"), None),
+            (serial("serial3"), Some(10)),
+        ]);
+
+        assert_eq!(longest_idle_first(&serials, &released), [serial("serial2"), serial("serial3"), serial("serial1")]);
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use tracing::instrument;
+
+use crate::config::SweepConfig;
+use crate::duration::HumanDuration;
+use crate::runtime::{Runtime, Serial};
+use crate::storage;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// Removed along with app caches when a device is low on storage, left behind by instrumentation runs.
+const CLEANUP_PATHS: [&str; 1] = ["/data/local/tmp/*"];
+
+// Health checks an idle device, frees up its storage if it's running low and reboots it if it's been up too long.
+// Returns what was done to it.
+#[instrument(skip(runtime))]
+pub fn device(runtime: &impl Runtime, serial: &Serial, config: &SweepConfig) -> Result<Vec<String>> {
+    let mut done = Vec::new();
+    runtime.wait_for_boot(serial)?;
+    runtime.check_health(serial)?;
+    if let Some(min) = config.min_free_storage {
+        let free = storage::free(runtime, serial)?;
+        if free < min {
+            storage::clean(runtime, serial, &CLEANUP_PATHS)?;
+            done.push(format!("cleared caches, it had {} free", free));
+        }
+    }
+    if let Some(max) = config.max_uptime {
+        let uptime = uptime(runtime, serial)?;
+        if uptime > max.0 {
+            runtime.run_adb(serial, &["reboot"])?;
+            runtime.run_adb(serial, &["wait-for-disconnect"])?;
+            runtime.wait_for_boot(serial)?;
+            runtime.check_health(serial)?;
+            done.push(format!("rebooted, it had been up {}", HumanDuration(uptime)));
+        }
+    }
+    Ok(done)
+}
+
+fn uptime(runtime: &impl Runtime, serial: &Serial) -> Result<Duration> {
+    let output = runtime.run_adb(serial, &["shell", "cat", "/proc/uptime"])?;
+    parse_uptime(&output).with_context(|| format!("couldn't read uptime from /proc/uptime: {:?}", output))
+}
+
+// Seconds up and seconds idle, ex: 350735.47 234388.90
+fn parse_uptime(output: &str) -> Result<Duration> {
+    let secs: f64 = output.split_ascii_whitespace().next().unwrap_or_default().parse()?;
+    Ok(Duration::from_secs(secs as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::sweep::parse_uptime;
+
+    #[test]
+    fn parses_uptime() {
+        assert_eq!(parse_uptime("350735.47 234388.90").unwrap(), Duration::from_secs(350735));
+    }
+}
@@ -0,0 +1,83 @@
+use std::fmt::Debug;
+
+use tracing::debug;
+
+use crate::{emulator_console, App};
+use crate::config::FixturesConfig;
+use crate::fixtures;
+use crate::forward::{self, Forward};
+use crate::locale;
+use crate::root;
+use crate::runtime::{Runtime, Serial};
+
+// Undoes what was set up on the device for a job once it's done with it, however that ends, ex: with an error part way
+// through setting up. Dropped before the device is released, so the next job gets it back the way it was.
+#[derive(Debug)]
+pub struct Teardown<'a, R: Runtime + Debug> {
+    app: &'a App<'a, R>,
+    serial: Serial,
+    // Cleared once the device turns out not to be the job's.
+    armed: bool,
+    // adbd was restarted as root.
+    pub root: bool,
+    // The job asked for a locale.
+    pub locale: bool,
+    // The forwards and reverses that were set up.
+    pub forwards: Vec<(&'static str, &'a Forward)>,
+    // The job asked for its network to be shaped.
+    pub shaped: bool,
+    // The fixtures an emulator was put into.
+    pub fixtures: Option<&'a FixturesConfig>,
+}
+
+impl<'a, R: Runtime + Debug> Teardown<'a, R> {
+    pub fn new(app: &'a App<'a, R>, serial: &Serial) -> Teardown<'a, R> {
+        Teardown { app, serial: serial.clone(), armed: true, root: false, locale: false, forwards: Vec::new(), shaped: false, fixtures: None }
+    }
+
+    // Leaves the device as it is, ex: once it turns out to be another job's.
+    pub fn disarm(&mut self) {
+        self.armed = false;
+    }
+
+    fn reset_network(&self) {
+        let serial = &self.serial;
+        match emulator_console(self.app, serial) {
+            // Also after jobs that shaped it themselves with `adp emu`.
+            Some(port) => match fixtures::reset_network(port) {
+                Err(e) if self.shaped => eprintln!("adp: failed to put the network on {} back to full speed: {:#}", serial, e),
+                Err(e) => debug!(serial = %serial, error = %e),
+                Ok(()) => {}
+            },
+            None if self.shaped => if let Err(e) = fixtures::reset_device_network(self.app, serial) {
+                eprintln!("adp: failed to put the network on {} back to full speed: {:#}", serial, e);
+            },
+            None => {}
+        }
+    }
+}
+
+impl<R: Runtime + Debug> Drop for Teardown<'_, R> {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        self.reset_network();
+        if let Some((port, fixtures)) = emulator_console(self.app, &self.serial).zip(self.fixtures) {
+            if let Err(e) = fixtures::restore(port, fixtures) {
+                eprintln!("adp: failed to restore the fixtures on {}: {:#}", self.serial, e);
+            }
+        }
+        forward::tear_down(self.app, &self.serial, &self.forwards);
+        if self.locale {
+            if let Err(e) = locale::restore(self.app, &self.serial, self.root) {
+                eprintln!("adp: {:#}", e);
+            }
+        }
+        if self.root {
+            if let Err(e) = root::disable(self.app, &self.serial) {
+                eprintln!("adp: {:#}", e);
+            }
+        }
+    }
+}
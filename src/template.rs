@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+
+use anyhow::anyhow;
+
+use crate::device_info::DeviceInfo;
+use crate::emulator;
+
+pub type Result<T> = std::result::Result<T, anyhow::Error>;
+
+// Expands each `{{name}}` in the template from what adp knows about the device, ex: `{{model}}` or
+// `{{prop:ro.build.fingerprint}}`. Names the device has no value for, like `{{console_port}}` on a phone, expand to
+// nothing.
+pub fn expand(template: &str, device: &DeviceInfo) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        expanded.push_str(&rest[..start]);
+        let len = rest[start..].find("}}").ok_or_else(|| anyhow!("missing }}}} in {:?}", template))?;
+        let name = rest[start + 2..start + len].trim();
+        let value = value(name, device).ok_or_else(|| anyhow!("unknown {{{{{}}}}} in {:?}", name, template))?;
+        expanded.push_str(&value.unwrap_or_default());
+        rest = &rest[start + len + 2..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+// Expands every template in the env, for the job running on the device.
+pub fn expand_env(env: &BTreeMap<String, String>, device: &DeviceInfo) -> Result<Vec<(String, String)>> {
+    env.iter()
+        .map(|(name, template)| Ok((name.clone(), expand(template, device)?)))
+        .collect()
+}
+
+// What `{serial}`, `{api}` and `{abi}` in the job's args are replaced with, for tools that take the device on the
+// command line rather than from ANDROID_SERIAL, ex: `adp mytool --device {serial}`. The serial is the one adb knows the
+// device by. Doubling the braces, ex: `{{api}}`, passes the placeholder through as it is.
+const ARG_PLACEHOLDERS: [&str; 3] = ["{serial}", "{api}", "{abi}"];
+
+pub fn has_placeholders(args: &[OsString]) -> bool {
+    args.iter().filter_map(|arg| arg.to_str()).any(|arg| ARG_PLACEHOLDERS.iter().any(|placeholder| arg.contains(placeholder)))
+}
+
+pub fn expand_args(args: &[OsString], adb_serial: &str, device: &DeviceInfo) -> Result<Vec<OsString>> {
+    let value = |placeholder: &str| match placeholder {
+        "{serial}" => Ok(adb_serial.to_string()),
+        "{api}" => device.sdk.map(|sdk| sdk.to_string())
+            .ok_or_else(|| anyhow!("{{api}} is in the command but the API level of {} isn't known", device.serial)),
+        _ => device.abi.clone()
+            .ok_or_else(|| anyhow!("{{abi}} is in the command but the ABI of {} isn't known", device.serial)),
+    };
+    args.iter()
+        .map(|arg| {
+            let Some(mut rest) = arg.to_str() else { return Ok(arg.clone()) };
+            let mut expanded = String::new();
+            while !rest.is_empty() {
+                let escaped = ARG_PLACEHOLDERS.iter().find(|placeholder| rest.starts_with(&format!("{{{}}}", placeholder)));
+                let placeholder = ARG_PLACEHOLDERS.iter().find(|placeholder| rest.starts_with(*placeholder));
+                if let Some(escaped) = escaped {
+                    expanded.push_str(escaped);
+                    rest = &rest[escaped.len() + 2..];
+                } else if let Some(placeholder) = placeholder {
+                    expanded.push_str(&value(placeholder)?);
+                    rest = &rest[placeholder.len()..];
+                } else {
+                    let c = rest.chars().next().unwrap();
+                    expanded.push(c);
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+            Ok(expanded.into())
+        })
+        .collect()
+}
+
+// An --export-env, NAME to export the serial as or NAME=TEMPLATE to export anything else, ex: DEVICE_MODEL={{model}}.
+pub fn export(arg: &str) -> Result<(String, Option<String>)> {
+    let (name, template) = match arg.split_once('=') {
+        Some((name, template)) => (name, Some(template.to_string())),
+        None => (arg, None),
+    };
+    if name.is_empty() {
+        return Err(anyhow!("missing the variable name in {}", arg));
+    }
+    Ok((name.to_string(), template))
+}
+
+// Props the env refers to, so they can be read along with the rest of the device's info.
+pub fn props(env: &BTreeMap<String, String>) -> Vec<&str> {
+    env.values()
+        .flat_map(|template| template.split("{{").skip(1))
+        .filter_map(|rest| rest.split_once("}}")?.0.trim().strip_prefix("prop:"))
+        .collect()
+}
+
+// None for names that don't exist, Some(None) for ones the device has no value for.
+fn value(name: &str, device: &DeviceInfo) -> Option<Option<String>> {
+    if let Some(prop) = name.strip_prefix("prop:") {
+        return Some(device.props.get(prop).cloned());
+    }
+    let console_port = emulator::console_port(&device.serial);
+    let value = match name {
+        "serial" => Some(device.serial.to_string()),
+        "model" => device.model.clone(),
+        "abi" => device.abi.clone(),
+        "api_level" => device.sdk.map(|sdk| sdk.to_string()),
+        "transport" => Some(device.transport.to_string()),
+        "console_port" => console_port.map(|port| port.to_string()),
+        "adb_port" => console_port.and_then(|port| port.checked_add(1)).map(|port| port.to_string()),
+        _ => return None,
+    };
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::ffi::OsString;
+
+    use crate::device_info::{DeviceInfo, Transport};
+    use crate::runtime::Serial;
+    use crate::template::{expand, expand_args, export, has_placeholders, props};
+
+    use super::Result;
+
+    fn device(serial: &str) -> DeviceInfo {
+        DeviceInfo {
+            serial: Serial::new(serial).unwrap(),
+            state: "device".to_string(),
+            model: Some("Pixel 8".to_string()),
+            abi: Some("arm64-v8a".to_string()),
+            sdk: Some(34),
+            transport: Transport::Emulator,
+            host: None,
+            props: BTreeMap::from([("ro.build.id".to_string(), "AP1A".to_string())]),
+            capabilities: None,
+        }
+    }
+
+    #[test]
+    fn expands_device_info() -> Result<()> {
+        let device = device("emulator-5556");
+
+        assert_eq!(expand("{{model}} (API {{ api_level }})", &device)?, "Pixel 8 (API 34)");
+        assert_eq!(expand("{{console_port}}/{{adb_port}}", &device)?, "5556/5557");
+        assert_eq!(expand("{{prop:ro.build.id}}{{prop:ro.missing}}", &device)?, "AP1A");
+        assert_eq!(expand("no templates", &device)?, "no templates");
+
+        Ok(())
+    }
+
+    #[test]
+    fn expands_missing_values_to_nothing() -> Result<()> {
+        assert_eq!(expand("port={{console_port}}", &device("R58M123"))?, "port=");
+        assert_eq!(expand("port={{adb_port}}", &device("emulator-65535"))?, "port=");
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_unknown_names() {
+        let device = device("emulator-5554");
+
+        assert_eq!(expand("{{nope}}", &device).unwrap_err().to_string(), "unknown {{nope}} in \"{{nope}}\"");
+        assert!(expand("{{model", &device).is_err());
+    }
+
+    #[test]
+    fn finds_props() {
+        let env = BTreeMap::from([
+            ("BUILD".to_string(), "{{prop:ro.build.id}}-{{ prop:ro.build.type }}".to_string()),
+            ("NAME".to_string(), "{{model}}".to_string()),
+        ]);
+
+        assert_eq!(props(&env), ["ro.build.id", "ro.build.type"]);
+    }
+
+    #[test]
+    fn parses_exports() -> Result<()> {
+        assert_eq!(export("DEVICE_SERIAL")?, ("DEVICE_SERIAL".to_string(), None));
+        assert_eq!(export("DEVICE_MODEL={{model}}")?, ("DEVICE_MODEL".to_string(), Some("{{model}}".to_string())));
+        assert!(export("={{model}}").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn expands_placeholders_in_args() -> Result<()> {
+        let args: Vec<OsString> = ["mytool", "--device", "{serial}", "--out=results-{api}-{abi}"].map(OsString::from).into();
+
+        assert!(has_placeholders(&args));
+        assert!(!has_placeholders(&args[..2]));
+        assert_eq!(expand_args(&args, "emulator-5554", &device("emulator-5554"))?, [
+            "mytool", "--device", "emulator-5554", "--out=results-34-arm64-v8a",
+        ]);
+        let args: Vec<OsString> = ["echo", "{{api}} is {api}", "{x}"].map(OsString::from).into();
+        assert_eq!(expand_args(&args, "emulator-5554", &device("emulator-5554"))?, ["echo", "{api} is 34", "{x}"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_to_expand_what_isnt_known() {
+        let args: Vec<OsString> = ["mytool", "--out=results-{api}.xml"].map(OsString::from).into();
+        let device = DeviceInfo { sdk: None, ..device("R58M123") };
+
+        let error = expand_args(&args, "R58M123", &device).unwrap_err();
+        assert_eq!(error.to_string(), "{api} is in the command but the API level of R58M123 isn't known");
+    }
+}
@@ -0,0 +1,34 @@
+use std::time::SystemTime;
+
+use crate::adb::Battery;
+use crate::config::ThermalConfig;
+
+impl ThermalConfig {
+    // When a device released at the given temperature can be used again, None if it's cool enough to use right away.
+    pub fn cooldown_until(&self, battery: &Battery, now: SystemTime) -> Option<SystemTime> {
+        let temperature = battery.temperature?;
+        (temperature > self.max).then(|| now + self.cooldown.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::adb::Battery;
+    use crate::config::Config;
+
+    type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+    #[test]
+    fn rests_hot_devices() -> Result {
+        let config = Config::parse("[thermal]\nmax = 40\ncooldown = \"10m\"\n")?.thermal.unwrap();
+        let hot = Battery { temperature: Some(42.5), ..Battery::default() };
+        let cool = Battery { temperature: Some(35.0), ..Battery::default() };
+
+        assert_eq!(config.cooldown_until(&hot, UNIX_EPOCH), Some(UNIX_EPOCH + Duration::from_secs(600)));
+        assert_eq!(config.cooldown_until(&cool, UNIX_EPOCH), None);
+        assert_eq!(config.cooldown_until(&Battery::default(), UNIX_EPOCH), None);
+        Ok(())
+    }
+}
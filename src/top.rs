@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ratatui::Frame;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Row, Table};
+use tracing::instrument;
+
+use crate::{App, PoolState};
+use crate::config::LeaseConfig;
+use crate::duration::HumanDuration;
+use crate::events::{EventLog, LeaseRecord};
+use crate::runtime::Runtime;
+use crate::status::{describe, format_age};
+use crate::waiters::WaiterId;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// There's no way to be told the pool changed, so it's read again this often.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+// Finished leases shown from the event log.
+const RECENT: usize = 10;
+
+// What's on screen, put together from the pool and the event log on each refresh.
+#[derive(Debug, Default)]
+struct View {
+    devices: Vec<Vec<String>>,
+    waiting: Vec<Vec<String>>,
+    recent: Vec<Vec<String>>,
+    free: usize,
+}
+
+// The waiters file doesn't say when a process started waiting, so that's only known from when top first saw it.
+#[derive(Debug, Default)]
+struct Top {
+    first_seen: BTreeMap<WaiterId, SystemTime>,
+}
+
+#[instrument(skip(app, events, lease))]
+pub fn run<R: Runtime + Debug>(app: &App<R>, events: &EventLog, lease: &LeaseConfig) -> Result {
+    let mut top = Top::default();
+    let mut terminal = ratatui::init();
+    let result = (|| loop {
+        let now = app.now();
+        let view = top.refresh(&app.snapshot()?, &events.leases()?, lease, now);
+        terminal.draw(|frame| draw(frame, &view))?;
+        if event::poll(REFRESH_INTERVAL)? {
+            if let Event::Key(key) = event::read()? {
+                let quit = key.kind == KeyEventKind::Press && match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => true,
+                    KeyCode::Char('c') => key.modifiers.contains(KeyModifiers::CONTROL),
+                    _ => false,
+                };
+                if quit {
+                    return Ok(());
+                }
+            }
+        }
+    })();
+    ratatui::restore();
+    result
+}
+
+impl Top {
+    fn refresh(&mut self, state: &PoolState, leases: &[LeaseRecord], lease: &LeaseConfig, now: SystemTime) -> View {
+        self.first_seen.retain(|id, _| state.waiters.contains(id));
+        for id in state.waiters.iter() {
+            self.first_seen.entry(id.clone()).or_insert(now);
+        }
+
+        let devices = state.entries.iter()
+            .map(|(serial, entry)| {
+                let held = match (entry.pid, entry.claimed_at, entry.released_at) {
+                    (Some(_), Some(at), _) => format_age(now, at),
+                    (None, _, Some(at)) => format!("idle {}", format_age(now, at)),
+                    _ => String::new(),
+                };
+                let owner = entry.owner.clone().unwrap_or_default();
+                vec![
+                    serial.to_string(),
+                    describe(entry).to_string(),
+                    held,
+                    lease.limit(serial).map(|limit| HumanDuration(limit).to_string()).unwrap_or_default(),
+                    entry.pid.map(|pid| pid.to_string()).unwrap_or_default(),
+                    owner.user,
+                    owner.cmd,
+                ]
+            })
+            .collect();
+        let waiting = self.first_seen.iter()
+            .map(|(id, since)| vec![id.to_string(), state.waiters.wants(id).to_string(), format_age(now, *since)])
+            .collect();
+        let recent = leases.iter().rev().take(RECENT)
+            .map(|lease| {
+                let ended = UNIX_EPOCH + Duration::from_secs(lease.started_at + lease.duration);
+                vec![
+                    lease.serial.to_string(),
+                    lease.user.clone(),
+                    lease.cmd.clone(),
+                    HumanDuration(Duration::from_secs(lease.duration)).to_string(),
+                    lease.exit_code.map(|code| code.to_string()).unwrap_or_else(|| "killed".to_string()),
+                    format!("{} ago", format_age(now, ended)),
+                ]
+            })
+            .collect();
+        View { devices, waiting, recent, free: state.entries.count_available() }
+    }
+}
+
+fn draw(frame: &mut Frame, view: &View) {
+    let [summary, devices, waiting, recent] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Min(3),
+        Constraint::Length(view.waiting.len().clamp(1, 10) as u16 + 3),
+        Constraint::Length(view.recent.len().max(1) as u16 + 3),
+    ]).areas(frame.area());
+
+    frame.render_widget(
+        Line::from(format!(
+            "{} devices, {} free, {} waiting, q to quit",
+            view.devices.len(), view.free, view.waiting.len(),
+        )),
+        summary,
+    );
+    frame.render_widget(
+        table("Devices", &["SERIAL", "STATE", "HELD", "LIMIT", "PID", "USER", "COMMAND"], &view.devices),
+        devices,
+    );
+    frame.render_widget(table("Waiting", &["PID", "WANTS", "WAITING"], &view.waiting), waiting);
+    frame.render_widget(
+        table("Recent", &["SERIAL", "USER", "COMMAND", "DURATION", "EXIT", "ENDED"], &view.recent),
+        recent,
+    );
+}
+
+fn table<'a>(title: &'a str, header: &[&'a str], rows: &'a [Vec<String>]) -> Table<'a> {
+    // Sized to fit the widest cell, the last column takes whatever is left.
+    let widths: Vec<_> = (0..header.len())
+        .map(|i| {
+            let width = rows.iter().filter_map(|row| row.get(i)).map(|cell| cell.chars().count())
+                .chain([header[i].len()])
+                .max()
+                .unwrap_or(0);
+            if i + 1 == header.len() { Constraint::Fill(1) } else { Constraint::Length(width as u16) }
+        })
+        .collect();
+    Table::new(rows.iter().map(|row| Row::new(row.iter().map(String::as_str))), widths)
+        .header(Row::new(header.iter().copied()).style(Style::new().add_modifier(Modifier::BOLD)))
+        .block(Block::bordered().title(title))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use ratatui::Terminal;
+    use ratatui::backend::TestBackend;
+
+    use crate::PoolState;
+    use crate::config::LeaseConfig;
+    use crate::duration::HumanDuration;
+    use crate::events::LeaseRecord;
+    use crate::lockfile::{Entry, LockFileEntries, Owner};
+    use crate::runtime::Serial;
+    use crate::top::{draw, Top};
+    use crate::waiters::{WaiterId, Waiters};
+
+    #[test]
+    fn shows_devices_waiters_and_recent_leases() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut entries = LockFileEntries::default();
+        entries.insert(Serial::new("emulator-5554").unwrap(), Entry {
+            pid: Some(42),
+            owner: Some(Owner { user: "ci".to_string(), host: "bench".to_string(), cmd: "./gradlew".to_string() }),
+            claimed_at: Some(start),
+            ..Entry::default()
+        });
+        entries.insert(Serial::new("emulator-5556").unwrap(), Entry { released_at: Some(start), ..Entry::default() });
+        let mut waiters = Waiters::default();
+        waiters.want(WaiterId::local(43), 2, start);
+        let state = PoolState { entries, waiters };
+        let leases = [LeaseRecord {
+            serial: Serial::new("emulator-5556").unwrap(),
+            pid: 41,
+            user: "dev".to_string(),
+            host: "bench".to_string(),
+            cmd: "pytest".to_string(),
+            team: None,
+            started_at: 1_000_000 - 120,
+            duration: 90,
+            exit_code: Some(1),
+            props: BTreeMap::new(),
+            latency: None,
+            bypassed: false,
+            failed_on: Vec::new(),
+        }];
+        let lease = LeaseConfig { max: Some(HumanDuration(Duration::from_secs(30 * 60))), ..LeaseConfig::default() };
+
+        let mut top = Top::default();
+        top.refresh(&state, &leases, &lease, start);
+        let view = top.refresh(&state, &leases, &lease, start + Duration::from_secs(5 * 60));
+        let mut terminal = Terminal::new(TestBackend::new(100, 24)).unwrap();
+        terminal.draw(|frame| draw(frame, &view)).unwrap();
+        let screen: Vec<String> = terminal.backend().buffer().content().chunks(100)
+            .map(|line| line.iter().map(|cell| cell.symbol()).collect())
+            .collect();
+        let screen = screen.join("\n");
+
+        assert!(screen.contains("2 devices, 1 free, 1 waiting"), "{}", screen);
+        assert!(screen.contains("emulator-5554 in use    5m      30m   42  ci   ./gradlew"), "{}", screen);
+        assert!(screen.contains("emulator-5556 available idle 5m"), "{}", screen);
+        // Waiting since top first saw it.
+        assert!(screen.contains("43  2     5m"), "{}", screen);
+        assert!(screen.contains("emulator-5556 dev  pytest  90s      1    5m ago"), "{}", screen);
+    }
+}
@@ -0,0 +1,152 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use serde::Serialize;
+use tracing::instrument;
+
+use crate::App;
+use crate::duration::HumanDuration;
+use crate::events::{EventLog, LeaseRecord};
+use crate::runtime::Runtime;
+use crate::status::print_table;
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum GroupBy {
+    #[default]
+    Team,
+    User,
+}
+
+// Device time used by a team or user, for attributing what the bench costs.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Usage {
+    // None for jobs run without --team.
+    pub name: Option<String>,
+    pub jobs: usize,
+    pub device_minutes: u64,
+}
+
+#[instrument(skip(app, events))]
+pub fn run<R: Runtime + Debug>(app: &App<R>, events: &EventLog, since: HumanDuration, by: GroupBy, json: bool) -> Result {
+    let now = app.now();
+    let leases = events.leases()?;
+    let since = now.checked_sub(since.0).unwrap_or(UNIX_EPOCH);
+    if let Some(oldest) = events.is_full()?.then(|| recorded_since(&leases, since)).flatten() {
+        let back = HumanDuration(now.duration_since(oldest).unwrap_or_default());
+        eprintln!("adp: the event log only goes back {}, older jobs have been dropped and aren't counted", back);
+    }
+    let usage = usage(&leases, since, now, by);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&usage)?);
+        return Ok(());
+    }
+    let header = match by {
+        GroupBy::Team => "TEAM",
+        GroupBy::User => "USER",
+    };
+    let mut rows = vec![vec![header.to_string(), "JOBS".to_string(), "DEVICE MINUTES".to_string()]];
+    for usage in usage {
+        rows.push(vec![usage.name.unwrap_or_else(|| "-".to_string()), usage.jobs.to_string(), usage.device_minutes.to_string()]);
+    }
+    print_table(&rows);
+    Ok(())
+}
+
+// When the oldest lease recorded started, if that's after `since`.
+fn recorded_since(leases: &[LeaseRecord], since: SystemTime) -> Option<SystemTime> {
+    let oldest = UNIX_EPOCH + Duration::from_secs(leases.iter().map(|lease| lease.started_at).min()?);
+    (oldest > since).then_some(oldest)
+}
+
+// Only the part of each lease after `since` counts, so a long job that started before it isn't billed for all of it.
+// Most used first.
+pub fn usage(leases: &[LeaseRecord], since: SystemTime, now: SystemTime, by: GroupBy) -> Vec<Usage> {
+    let mut used: BTreeMap<Option<String>, (usize, Duration)> = BTreeMap::new();
+    for lease in leases {
+        let started_at = UNIX_EPOCH + Duration::from_secs(lease.started_at);
+        let ended_at = (started_at + Duration::from_secs(lease.duration)).min(now);
+        if ended_at <= since {
+            continue;
+        }
+        let used_for = ended_at.duration_since(started_at.max(since)).unwrap_or_default();
+        let name = match by {
+            GroupBy::Team => lease.team.clone(),
+            GroupBy::User => Some(lease.user.clone()),
+        };
+        let (jobs, total) = used.entry(name).or_default();
+        *jobs += 1;
+        *total += used_for;
+    }
+    let mut usage: Vec<Usage> = used.into_iter()
+        .map(|(name, (jobs, total))| Usage { name, jobs, device_minutes: total.as_secs().div_ceil(60) })
+        .collect();
+    usage.sort_by_key(|usage| Reverse(usage.device_minutes));
+    usage
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::events::LeaseRecord;
+    use crate::usage::{GroupBy, recorded_since, Usage, usage};
+
+    fn lease(user: &str, team: Option<&str>, started_at: u64, duration: u64) -> LeaseRecord {
+        LeaseRecord {
+            serial: "serial1".parse().unwrap(),
+            pid: 1,
+            user: user.to_string(),
+            host: "bench".to_string(),
+            cmd: "./gradlew connectedAndroidTest".to_string(),
+            team: team.map(str::to_string),
+            started_at,
+            duration,
+            exit_code: Some(0),
+            props: BTreeMap::new(),
+            latency: None,
+            bypassed: false,
+            failed_on: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn adds_up_device_time_since_a_point() {
+        let leases = [
+            // Only the last 10 minutes of this one are after since.
+            lease("alice", Some("payments"), 0, 20 * 60),
+            lease("bob", Some("payments"), 1200, 5 * 60),
+            lease("carol", Some("search"), 1200, 30 * 60),
+            lease("dave", None, 1200, 90),
+            // Over before since.
+            lease("erin", Some("maps"), 0, 60),
+        ];
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+
+        assert_eq!(usage(&leases, at(600), at(10_000), GroupBy::Team), [
+            Usage { name: Some("search".to_string()), jobs: 1, device_minutes: 30 },
+            Usage { name: Some("payments".to_string()), jobs: 2, device_minutes: 15 },
+            Usage { name: None, jobs: 1, device_minutes: 2 },
+        ]);
+        assert_eq!(usage(&leases, at(600), at(10_000), GroupBy::User)[1], Usage {
+            name: Some("alice".to_string()),
+            jobs: 1,
+            device_minutes: 10,
+        });
+    }
+
+    #[test]
+    fn knows_when_the_window_reaches_past_the_oldest_lease() {
+        let leases = [lease("alice", None, 1200, 60), lease("bob", None, 600, 60)];
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+
+        assert_eq!(recorded_since(&leases, at(0)), Some(at(600)));
+        assert_eq!(recorded_since(&leases, at(600)), None);
+        assert_eq!(recorded_since(&[], at(0)), None);
+    }
+}
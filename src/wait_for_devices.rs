@@ -0,0 +1,67 @@
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use tracing::{debug, instrument};
+
+use crate::App;
+use crate::duration::HumanDuration;
+use crate::runtime::{Pid, Runtime, Serial};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// Blocks until there are at least `count` healthy devices in the pool, so a pipeline can hold off fanning out into
+// that many shards. While waiting it counts as demand, so the daemon will start instances if it can.
+#[instrument(skip(app))]
+pub fn run<R: Runtime + Debug>(app: &App<R>, count: usize, timeout: Option<HumanDuration>) -> Result {
+    let pid = std::process::id() as Pid;
+    app.want_devices(pid, count)?;
+    let result = wait(app, count, timeout.map(|timeout| timeout.0), POLL_INTERVAL);
+    app.want_devices(pid, 0)?;
+    let healthy = result?;
+    println!("{} devices ready", healthy);
+    Ok(())
+}
+
+pub fn wait<R: Runtime + Debug>(
+    app: &App<R>,
+    count: usize,
+    timeout: Option<Duration>,
+    poll_interval: Duration,
+) -> Result<usize> {
+    let start = Instant::now();
+    let mut healthy = BTreeSet::<Serial>::new();
+    loop {
+        let state = app.reconcile()?;
+        healthy.retain(|serial| state.entries.contains(serial));
+        let unchecked: Vec<Serial> = state.entries.iter()
+            .filter(|(serial, entry)| !healthy.contains(*serial) && !entry.spent && !entry.quarantined && !entry.low_battery)
+            .map(|(serial, _)| serial.clone())
+            .collect();
+        for (serial, result) in unchecked.iter().zip(app.wait_until_healthy(&unchecked)) {
+            match result {
+                Ok(_) => {
+                    healthy.insert(serial.clone());
+                }
+                Err(e) => debug!(serial = %serial, error = %e),
+            }
+        }
+        debug!(healthy = healthy.len(), count);
+        // None of them can be claimed until it's resumed.
+        if healthy.len() >= count && state.entries.paused().is_none() {
+            return Ok(healthy.len());
+        }
+        if let Some(timeout) = timeout {
+            if start.elapsed() >= timeout {
+                if state.entries.paused().is_some() {
+                    return Err(anyhow!("timed out with the pool paused, waiting for `adp resume`"));
+                }
+                return Err(anyhow!("timed out with {} of {} devices ready", healthy.len(), count));
+            }
+        }
+        std::thread::sleep(poll_interval);
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tracing::{debug, instrument};
+
+use crate::runtime::Pid;
+
+type Result<T> = std::io::Result<T>;
+
+// A process waiting for a device. Ones in containers of their own all have much the same pids, often 1, so they're
+// told apart by an instance picked at random too.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WaiterId {
+    pub pid: Pid,
+    pub instance: Option<String>,
+}
+
+impl WaiterId {
+    pub fn local(pid: Pid) -> WaiterId {
+        WaiterId { pid, instance: None }
+    }
+}
+
+impl Display for WaiterId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.instance {
+            Some(instance) => write!(f, "{}@{}", self.pid, instance),
+            None => write!(f, "{}", self.pid),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Wanted {
+    count: usize,
+    // When a waiter with an instance last checked for a device. Its pid can't be checked on from anywhere else, so
+    // this is how it's noticed it stopped.
+    seen_at: Option<SystemTime>,
+}
+
+// Processes currently blocked waiting for a device, along with how many devices each of them wants.
+#[derive(Debug, Default)]
+pub struct Waiters(BTreeMap<WaiterId, Wanted>);
+
+impl Waiters {
+    pub fn insert(&mut self, id: WaiterId, now: SystemTime) {
+        self.want(id, 1, now);
+    }
+
+    pub fn want(&mut self, id: WaiterId, count: usize, now: SystemTime) {
+        if count == 0 {
+            self.remove(&id);
+        } else {
+            let seen_at = id.instance.is_some().then_some(now);
+            self.0.insert(id, Wanted { count, seen_at });
+        }
+    }
+
+    pub fn remove(&mut self, id: &WaiterId) {
+        self.0.remove(id);
+    }
+
+    pub fn contains(&self, id: &WaiterId) -> bool {
+        self.0.contains_key(id)
+    }
+
+    // How many devices the process is waiting for.
+    pub fn wants(&self, id: &WaiterId) -> usize {
+        self.0.get(id).map_or(0, |wanted| wanted.count)
+    }
+
+    // Whether the waiter has an instance and hasn't checked for a device in longer than the timeout.
+    pub fn expired(&self, id: &WaiterId, timeout: Duration, now: SystemTime) -> bool {
+        self.0.get(id)
+            .and_then(|wanted| wanted.seen_at)
+            .is_some_and(|at| now.duration_since(at).unwrap_or_default() > timeout)
+    }
+
+    // How many devices are wanted between all the waiters.
+    pub fn demand(&self) -> usize {
+        self.0.values().map(|wanted| wanted.count).sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&WaiterId> {
+        self.0.keys()
+    }
+
+    // Each line is a pid, with @ and an instance if it has one, followed by a tab and how many devices it wants if
+    // that's more than one, and another tab and when it was last seen in seconds since the epoch if it has an instance.
+    #[instrument]
+    pub fn read<R: Read + Debug>(reader: R) -> Result<Waiters> {
+        let reader = BufReader::new(reader);
+        let waiters = reader.lines()
+            .filter(|line| !matches!(line, Ok(line) if line.is_empty()))
+            .map(|line| line.map(|line| {
+                let mut fields = line.split('\t');
+                let id = fields.next().unwrap_or_default();
+                let (pid, instance) = match id.split_once('@') {
+                    Some((pid, instance)) => (pid, Some(instance.to_string())),
+                    None => (id, None),
+                };
+                let count = fields.next().map_or(1, |count| count.parse().expect("invalid count"));
+                let seen_at = fields.next()
+                    .map(|secs| UNIX_EPOCH + Duration::from_secs(secs.parse().expect("invalid time")));
+                (WaiterId { pid: pid.parse().expect("invalid pid"), instance }, Wanted { count, seen_at })
+            }))
+            .collect::<Result<_>>()?;
+        let waiters = Waiters(waiters);
+        debug!(waiters = %waiters);
+        Ok(waiters)
+    }
+
+    #[instrument]
+    pub fn write<W: Write + Debug>(&self, writer: W) -> Result<()> {
+        let mut writer = BufWriter::new(writer);
+        for (id, wanted) in &self.0 {
+            match wanted.seen_at {
+                Some(at) => {
+                    let secs = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                    writeln!(writer, "{}\t{}\t{}", id, wanted.count, secs)?
+                }
+                None if wanted.count == 1 => writeln!(writer, "{}", id)?,
+                None => writeln!(writer, "{}\t{}", id, wanted.count)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for Waiters {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, id) in self.0.keys().enumerate() {
+            if i != 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", id)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Result};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::waiters::{WaiterId, Waiters};
+
+    #[test]
+    fn reads_and_writes_waiters() -> Result<()> {
+        let mut waiters = Waiters::read("2\n1\n".as_bytes())?;
+        waiters.insert(WaiterId::local(3), UNIX_EPOCH);
+        waiters.remove(&WaiterId::local(2));
+        let mut output = Vec::new();
+        waiters.write(Cursor::new(&mut output))?;
+
+        assert_eq!(String::from_utf8(output).unwrap(), "1\n3\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn tracks_how_many_devices_are_wanted() -> Result<()> {
+        let mut waiters = Waiters::read("1\n2\t3\n".as_bytes())?;
+        waiters.want(WaiterId::local(4), 2, UNIX_EPOCH);
+
+        assert_eq!(waiters.len(), 3);
+        assert_eq!(waiters.demand(), 6);
+
+        let mut output = Vec::new();
+        waiters.write(Cursor::new(&mut output))?;
+
+        assert_eq!(String::from_utf8(output).unwrap(), "1\n2\t3\n4\t2\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn tells_apart_waiters_in_containers() -> Result<()> {
+        let now = UNIX_EPOCH + Duration::from_secs(100);
+        let mut waiters = Waiters::read("1@a\t1\t90\n".as_bytes())?;
+        let other = WaiterId { pid: 1, instance: Some("b".to_string()) };
+        waiters.want(other.clone(), 2, now);
+
+        assert_eq!(waiters.len(), 2);
+        assert_eq!(waiters.wants(&other), 2);
+        assert!(waiters.expired(&WaiterId { pid: 1, instance: Some("a".to_string()) }, Duration::from_secs(5), now));
+        assert!(!waiters.expired(&other, Duration::from_secs(5), now));
+
+        let mut output = Vec::new();
+        waiters.write(Cursor::new(&mut output))?;
+
+        assert_eq!(String::from_utf8(output).unwrap(), "1@a\t1\t90\n1@b\t2\t100\n");
+
+        Ok(())
+    }
+}
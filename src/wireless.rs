@@ -0,0 +1,152 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use toml_edit::{Array, DocumentMut, Item, Table};
+use tracing::{debug, instrument};
+
+use crate::adb::Adb;
+use crate::config::{AdbConfig, Config};
+use crate::runtime::{Runtime, Serial};
+
+pub type Result<T = ()> = std::result::Result<T, anyhow::Error>;
+
+// Advertised by devices with wireless debugging on, with the port it's listening on right now.
+const CONNECT_SERVICE: &str = "_adb-tls-connect._tcp";
+
+// Wireless devices join the pool through the first adb server.
+pub fn adb(config: &AdbConfig) -> Adb {
+    match config.servers.first() {
+        Some(server_socket) => Adb::with_server(crate::ADB_PATH, server_socket),
+        None => Adb::new(crate::ADB_PATH),
+    }
+}
+
+// Pairs with a device from its wireless debugging screen, connects to it and adds it to the config, so the daemon keeps
+// it connected from then on. The port it's connected on isn't the one it's paired on, it's looked up over mDNS unless
+// given.
+#[instrument(skip(adb, code))]
+pub fn pair(adb: &Adb, config_path: Option<&Path>, addr: &str, code: &str, connect: Option<String>) -> Result {
+    if !adb.pair(addr, code)? {
+        return Err(anyhow!("failed to pair with {}, check the code and that the pairing dialog is still open", addr));
+    }
+    let connect = match connect {
+        Some(connect) => connect,
+        None => find(adb, host(addr))?.ok_or_else(|| {
+            anyhow!("paired with {} but couldn't find it over mDNS, pass --connect <host:port> from its wireless \
+                debugging screen", addr)
+        })?,
+    };
+    if !adb.connect(&connect)? {
+        return Err(anyhow!("paired with {} but failed to connect to {}", addr, connect));
+    }
+    let path = Config::path(config_path).ok_or_else(|| anyhow!("there's no config dir to add {} to", connect))?;
+    if remember(&path, &connect)? {
+        println!("connected to {} and added it to {:?}", connect, path);
+    } else {
+        println!("connected to {}", connect);
+    }
+    Ok(())
+}
+
+// Connects to any endpoint that isn't connected. Wireless debugging listens on a new port each time it's turned on, ex:
+// when the device reboots, so if the endpoint doesn't answer the device is looked up over mDNS by its host. One endpoint
+// failing doesn't stop the rest from being reconnected.
+#[instrument(skip(runtime, adb))]
+pub fn reconnect(runtime: &impl Runtime, adb: &Adb, endpoints: &[String]) -> Result {
+    let connected = runtime.connected_devices()?;
+    let mut errors = Vec::new();
+    for endpoint in endpoints {
+        let host = host(endpoint);
+        if connected.iter().any(|serial| host_of(serial) == Some(host)) {
+            continue;
+        }
+        debug!(reconnecting = %endpoint);
+        match reconnect_endpoint(adb, endpoint) {
+            Ok(true) => {}
+            Ok(false) => eprintln!("adp: couldn't reconnect to {}, check wireless debugging is still on", endpoint),
+            Err(e) => errors.push(format!("{}: {:#}", endpoint, e)),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(anyhow!("failed to reconnect to {}", errors.join(", ")));
+    }
+    Ok(())
+}
+
+fn reconnect_endpoint(adb: &Adb, endpoint: &str) -> Result<bool> {
+    if adb.connect(endpoint)? {
+        return Ok(true);
+    }
+    match find(adb, host(endpoint))? {
+        Some(addr) => adb.connect(&addr),
+        None => Ok(false),
+    }
+}
+
+// Where the device on the host is listening for adb connect.
+fn find(adb: &Adb, host: &str) -> Result<Option<String>> {
+    Ok(adb.mdns_services()?.into_iter()
+        .find(|service| service.service == CONNECT_SERVICE && self::host(&service.addr) == host)
+        .map(|service| service.addr))
+}
+
+// Adds the endpoint to [wireless] in the config file, keeping everything else in it as it was. Returns whether it
+// wasn't already there.
+fn remember(path: &Path, endpoint: &str) -> Result<bool> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {:?}", path)),
+    };
+    let Some(contents) = add_endpoint(&contents, endpoint).with_context(|| format!("invalid config {:?}", path))? else {
+        return Ok(false);
+    };
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, contents).with_context(|| format!("failed to write {:?}", path))?;
+    Ok(true)
+}
+
+// The config with the endpoint added, None if it's already there.
+fn add_endpoint(contents: &str, endpoint: &str) -> Result<Option<String>> {
+    let mut doc: DocumentMut = contents.parse()?;
+    let wireless = doc.entry("wireless").or_insert_with(|| Item::Table(Table::new()))
+        .as_table_mut().ok_or_else(|| anyhow!("wireless isn't a table"))?;
+    let endpoints = wireless.entry("endpoints").or_insert_with(|| Item::Value(Array::new().into()))
+        .as_array_mut().ok_or_else(|| anyhow!("wireless.endpoints isn't an array"))?;
+    if endpoints.iter().any(|existing| existing.as_str() == Some(endpoint)) {
+        return Ok(None);
+    }
+    endpoints.push(endpoint);
+    Ok(Some(doc.to_string()))
+}
+
+fn host(addr: &str) -> &str {
+    addr.rsplit_once(':').map_or(addr, |(host, _)| host)
+}
+
+// Devices connected over tcp go by their address.
+fn host_of(serial: &Serial) -> Option<&str> {
+    serial.split_once(':').map(|(host, _)| host)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wireless::add_endpoint;
+
+    use super::Result;
+
+    #[test]
+    fn adds_endpoints_keeping_the_rest_of_the_config() -> Result<()> {
+        let config = "# the bench\nwork_dir = \"/var/tmp/adp\"\n";
+
+        let config = add_endpoint(config, "192.168.1.20:37123")?.unwrap();
+        assert_eq!(config, "# the bench\nwork_dir = \"/var/tmp/adp\"\n\n[wireless]\nendpoints = [\"192.168.1.20:37123\"]\n");
+        let config = add_endpoint(&config, "192.168.1.21:40111")?.unwrap();
+        assert!(config.ends_with("endpoints = [\"192.168.1.20:37123\", \"192.168.1.21:40111\"]\n"), "{}", config);
+        assert_eq!(add_endpoint(&config, "192.168.1.20:37123")?, None);
+
+        Ok(())
+    }
+}